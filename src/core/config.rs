@@ -5,7 +5,14 @@
 //! with proper validation and default values.
 
 use super::{ReplicationError, ReplicationResult};
+use crate::events::filter::RowFilter;
+use crate::events::retry::RetryPolicy;
+use crate::events::router::{MessageKind, Operation, RouteRule};
+use crate::events::transform::{ColumnAction, ColumnTransform};
 use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -13,6 +20,8 @@ pub enum EventSinkType {
     Http,
     Hook0,
     Stdout,
+    /// WebAssembly component implementing the `sink` world of `wit/plugin.wit`
+    Wasm,
 }
 
 impl std::fmt::Display for EventSinkType {
@@ -21,10 +30,273 @@ impl std::fmt::Display for EventSinkType {
             EventSinkType::Http => write!(f, "http"),
             EventSinkType::Hook0 => write!(f, "hook0"),
             EventSinkType::Stdout => write!(f, "stdout"),
+            EventSinkType::Wasm => write!(f, "wasm"),
         }
     }
 }
 
+/// How replication messages are handed to the event sink
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeliveryMode {
+    /// One sink call per replication message (default)
+    Message,
+    /// Buffer everything between Begin and Commit and deliver one transaction envelope
+    Transaction,
+}
+
+impl std::fmt::Display for DeliveryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryMode::Message => write!(f, "message"),
+            DeliveryMode::Transaction => write!(f, "transaction"),
+        }
+    }
+}
+
+/// JSON shape of the events sent to the HTTP and STDOUT sinks
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventFormat {
+    /// Protocol-level messages with positional tuple data (default)
+    Raw,
+    /// Row changes keyed by column name with values converted to native JSON types
+    Typed,
+    /// Debezium change event envelope
+    Debezium,
+}
+
+impl std::fmt::Display for EventFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventFormat::Raw => write!(f, "raw"),
+            EventFormat::Typed => write!(f, "typed"),
+            EventFormat::Debezium => write!(f, "debezium"),
+        }
+    }
+}
+
+/// How the HTTP sink carries events as CloudEvents 1.0
+#[derive(Clone, Debug, PartialEq)]
+pub enum CloudEventsMode {
+    /// The whole CloudEvent is the JSON body (`application/cloudevents+json`)
+    Structured,
+    /// Attributes are `ce-*` headers and the body is the event data
+    Binary,
+}
+
+impl std::fmt::Display for CloudEventsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloudEventsMode::Structured => write!(f, "structured"),
+            CloudEventsMode::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Whether existing rows are read before streaming changes
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotMode {
+    /// Only stream changes (default)
+    Never,
+    /// Read every published table when the slot is created, then stream
+    Initial,
+}
+
+impl std::fmt::Display for SnapshotMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotMode::Never => write!(f, "never"),
+            SnapshotMode::Initial => write!(f, "initial"),
+        }
+    }
+}
+
+/// Tables a bootstrapped publication covers
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublicationScope {
+    /// `FOR ALL TABLES` (default)
+    AllTables,
+    /// Listed tables (`schema.table` or `table`) and every table of the listed schemas
+    Objects {
+        tables: Vec<String>,
+        schemas: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for PublicationScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublicationScope::AllTables => write!(f, "all tables"),
+            PublicationScope::Objects { tables, schemas } => {
+                write!(
+                    f,
+                    "tables [{}], schemas [{}]",
+                    tables.join(", "),
+                    schemas.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Whether large in-progress transactions are streamed before they commit
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamingMode {
+    /// Transactions are only sent once committed
+    Off,
+    /// Large transactions are streamed in segments (protocol version 2+)
+    On,
+    /// Streamed segments may interleave and carry abort positions (protocol version 4)
+    Parallel,
+}
+
+impl std::fmt::Display for StreamingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamingMode::Off => write!(f, "off"),
+            StreamingMode::On => write!(f, "on"),
+            StreamingMode::Parallel => write!(f, "parallel"),
+        }
+    }
+}
+
+/// Which transactions replayed from replication origins are dropped
+#[derive(Clone, Debug, PartialEq)]
+pub enum OriginFilter {
+    /// Deliver every transaction regardless of its origin (default)
+    Any,
+    /// Only deliver local transactions, like `origin = none` on PostgreSQL 16+
+    None,
+    /// Drop transactions replayed from one of the named origins
+    Exclude(Vec<String>),
+}
+
+impl OriginFilter {
+    /// Whether a transaction replayed from `origin` should be dropped
+    pub fn drops(&self, origin: &str) -> bool {
+        match self {
+            OriginFilter::Any => false,
+            OriginFilter::None => true,
+            OriginFilter::Exclude(names) => names.iter().any(|name| name == origin),
+        }
+    }
+}
+
+impl std::fmt::Display for OriginFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginFilter::Any => write!(f, "any"),
+            OriginFilter::None => write!(f, "none"),
+            OriginFilter::Exclude(names) => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+/// Where last-seen row values are kept to resolve unchanged TOAST columns
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToastCacheMode {
+    /// No cache, unchanged columns are only resolved from the old image or by re-fetching
+    Off,
+    /// Bounded in-memory cache, lost on restart
+    Memory,
+    /// One file per row under `TOAST_CACHE_DIR`, kept across restarts
+    Disk,
+}
+
+impl std::fmt::Display for ToastCacheMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToastCacheMode::Off => write!(f, "off"),
+            ToastCacheMode::Memory => write!(f, "memory"),
+            ToastCacheMode::Disk => write!(f, "disk"),
+        }
+    }
+}
+
+/// Where the last transaction acknowledged by the sink is recorded
+#[derive(Clone, Debug, PartialEq)]
+pub enum CheckpointStoreMode {
+    /// No checkpoint, transactions streamed again after a crash are delivered again (default)
+    Off,
+    /// JSON file under `CHECKPOINT_FILE`, fsynced on every update
+    File,
+    /// Row of `CHECKPOINT_TABLE` in the database of `CHECKPOINT_DATABASE_URL`
+    Postgres,
+}
+
+impl std::fmt::Display for CheckpointStoreMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointStoreMode::Off => write!(f, "off"),
+            CheckpointStoreMode::File => write!(f, "file"),
+            CheckpointStoreMode::Postgres => write!(f, "postgres"),
+        }
+    }
+}
+
+/// Where events the sink gave up on are kept
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeadLetterQueueMode {
+    /// No queue, a failed delivery interrupts replication (default)
+    Off,
+    /// JSON Lines file under `DLQ_FILE`
+    File,
+    /// Rows of `DLQ_TABLE` in the database of `DLQ_DATABASE_URL`
+    Postgres,
+}
+
+impl std::fmt::Display for DeadLetterQueueMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeadLetterQueueMode::Off => write!(f, "off"),
+            DeadLetterQueueMode::File => write!(f, "file"),
+            DeadLetterQueueMode::Postgres => write!(f, "postgres"),
+        }
+    }
+}
+
+/// What happens to an event the script fails on
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptErrorPolicy {
+    /// Interrupt replication, as a blocking sink failure does (default)
+    Fail,
+    /// Drop the event for the sink it was bound for
+    Skip,
+    /// Write the event to the dead-letter queue, to run it through the script again on replay
+    DeadLetter,
+}
+
+impl std::fmt::Display for ScriptErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptErrorPolicy::Fail => write!(f, "fail"),
+            ScriptErrorPolicy::Skip => write!(f, "skip"),
+            ScriptErrorPolicy::DeadLetter => write!(f, "dead-letter"),
+        }
+    }
+}
+
+/// One destination of the event stream and the events routed to it
+#[derive(Debug, Clone)]
+pub struct SinkConfig {
+    /// Route name, also the sink recorded in its dead letters
+    pub name: String,
+    pub sink: EventSinkType,
+    pub http_endpoint_url: Option<String>,
+    pub hook0_api_url: Option<String>,
+    pub hook0_application_id: Option<Uuid>,
+    pub hook0_api_token: Option<String>,
+    pub wasm_plugin: Option<PathBuf>,
+    /// Whether a WebAssembly sink may open network connections
+    #[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+    pub wasm_allow_network: bool,
+    /// Format of the events this sink receives
+    pub event_format: EventFormat,
+    pub retry: RetryPolicy,
+    pub rule: RouteRule,
+    /// Whether a failed delivery holds back acknowledgement
+    pub blocking: bool,
+}
+
 /// Configuration for the replication checker with validation
 #[derive(Debug, Clone)]
 pub struct ReplicationConfig {
@@ -37,6 +309,50 @@ pub struct ReplicationConfig {
     pub hook0_api_url: Option<String>,
     pub hook0_application_id: Option<Uuid>,
     pub hook0_api_token: Option<String>,
+    pub wasm_plugin: Option<PathBuf>,
+    pub delivery_mode: DeliveryMode,
+    pub event_format: EventFormat,
+    pub debezium_topic_prefix: String,
+    pub debezium_schemas: bool,
+    pub cloudevents: Option<CloudEventsMode>,
+    pub stream_spill_dir: PathBuf,
+    pub stream_spill_threshold_bytes: usize,
+    pub proto_version: u32,
+    pub streaming: StreamingMode,
+    pub two_phase: bool,
+    pub logical_messages: bool,
+    pub hook0_message_prefix: String,
+    pub origin_filter: OriginFilter,
+    pub binary_tuples: bool,
+    pub toast_cache: ToastCacheMode,
+    pub toast_cache_dir: PathBuf,
+    pub toast_cache_capacity: usize,
+    pub toast_refetch: bool,
+    pub bootstrap: bool,
+    pub slot_temporary: bool,
+    pub slot_failover: bool,
+    pub publication_scope: PublicationScope,
+    pub snapshot_mode: SnapshotMode,
+    pub signal_table: Option<String>,
+    pub incremental_snapshot_chunk_size: usize,
+    pub incremental_snapshot_state_file: PathBuf,
+    pub reconnect_initial_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    pub reconnect_max_attempts: u32,
+    pub checkpoint_store: CheckpointStoreMode,
+    pub checkpoint_file: PathBuf,
+    pub checkpoint_table: String,
+    pub checkpoint_connection_string: Option<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub dead_letter_queue: DeadLetterQueueMode,
+    pub dlq_file: PathBuf,
+    pub dlq_table: String,
+    pub dlq_connection_string: Option<String>,
+    pub sinks: Vec<SinkConfig>,
+    pub column_transforms: Vec<ColumnTransform>,
+    pub transform_hash_salt: Option<String>,
+    pub script_file: Option<PathBuf>,
+    pub script_error_policy: ScriptErrorPolicy,
 }
 
 impl ReplicationConfig {
@@ -54,13 +370,76 @@ impl ReplicationConfig {
     /// Optional (with defaults):
     /// - `SLOT_NAME`: Replication slot name (default: "sub")
     /// - `PUB_NAME`: Publication name (default: "pub")
-    /// - `EVENT_SINK`: Event sink type - "http", "hook0", or "stdout" (default: "stdout")
+    /// - `EVENT_SINK`: Event sink type - "http", "hook0", "stdout" or "wasm" (default: "stdout")
+    /// - `DELIVERY_MODE`: "message" or "transaction" (default: "message")
+    /// - `EVENT_FORMAT`: "raw", "typed" or "debezium" JSON events for the HTTP and STDOUT sinks (default: "raw")
+    /// - `DEBEZIUM_TOPIC_PREFIX`: Logical server name reported in Debezium events (default: "walpipe")
+    /// - `DEBEZIUM_SCHEMAS`: "true" to include the schema section in Debezium events (default: "false")
+    /// - `STREAM_SPILL_DIR`: Directory for streamed transactions spilled to disk (default: "<tmp>/walpipe")
+    /// - `STREAM_SPILL_THRESHOLD_BYTES`: In-memory size of a streamed transaction before it spills (default: 64 MiB)
+    /// - `PROTO_VERSION`: pgoutput protocol version, 1 to 4 (default: 2)
+    /// - `STREAMING`: "off", "on" or "parallel" (default: "on", "off" with protocol version 1)
+    /// - `TWO_PHASE`: "true" to decode prepared transactions, requires protocol version 3+ (default: "false")
+    /// - `LOGICAL_MESSAGES`: "true" to receive `pg_logical_emit_message` payloads (default: "false")
+    /// - `BINARY_TUPLES`: "true" to receive column values in the binary wire format (default: "false")
+    /// - `TOAST_CACHE`: "off", "memory" or "disk" cache for unchanged TOAST columns (default: "off")
+    /// - `TOAST_CACHE_DIR`: Directory of the disk TOAST cache (default: "<tmp>/walpipe-toast")
+    /// - `TOAST_CACHE_CAPACITY`: Rows kept by the TOAST cache (default: 10000)
+    /// - `TOAST_REFETCH`: "true" to re-read unresolved TOAST columns from the table (default: "false")
+    /// - `ORIGIN_FILTER`: "any", "none" or a comma-separated list of origin names to drop (default: "any")
+    /// - `BOOTSTRAP`: "true" to create the replication slot and publication when missing (default: "false")
+    /// - `SLOT_TEMPORARY`: "true" to bootstrap a temporary slot, dropped on disconnect (default: "false")
+    /// - `SLOT_FAILOVER`: "true" to bootstrap a slot synchronized to standbys, PostgreSQL 17+ (default: "false")
+    /// - `PUBLICATION_TABLES`: Comma-separated tables of a bootstrapped publication (default: all tables)
+    /// - `PUBLICATION_SCHEMAS`: Comma-separated schemas of a bootstrapped publication, PostgreSQL 15+ (default: all tables)
+    /// - `SNAPSHOT_MODE`: "never" or "initial" to read existing rows when the slot is created (default: "never")
+    /// - `SIGNAL_TABLE`: `schema.table` receiving incremental snapshot requests and watermarks (default: none)
+    /// - `INCREMENTAL_SNAPSHOT_CHUNK_SIZE`: Rows read per incremental snapshot chunk (default: 1024)
+    /// - `INCREMENTAL_SNAPSHOT_STATE_FILE`: Progress of incremental snapshots (default: "<tmp>/walpipe-<slot>-snapshot.json")
+    /// - `RECONNECT_INITIAL_DELAY_MS`: Delay before the first reconnection attempt (default: 500)
+    /// - `RECONNECT_MAX_DELAY_MS`: Upper bound of the doubling reconnection delay (default: 30000)
+    /// - `RECONNECT_MAX_ATTEMPTS`: Consecutive failed reconnections before giving up, 0 for no limit (default: 0)
+    /// - `CHECKPOINT_STORE`: "off", "file" or "postgres" record of the last delivered transaction (default: "off")
+    /// - `CHECKPOINT_FILE`: File of the "file" checkpoint store (default: "<tmp>/walpipe-<slot>-checkpoint.json")
+    /// - `CHECKPOINT_TABLE`: `schema.table` of the "postgres" checkpoint store (default: "walpipe_checkpoints")
+    /// - `CHECKPOINT_DATABASE_URL`: Database of the "postgres" checkpoint store (default: `DATABASE_URL`)
+    /// - `METRICS_ADDR`: Address serving Prometheus metrics on `/metrics`, e.g. "0.0.0.0:9187" (default: none)
+    /// - `DLQ_STORE`: "off", "file" or "postgres" queue for events the sink gave up on (default: "off")
+    /// - `DLQ_FILE`: JSON Lines file of the "file" queue (default: "<tmp>/walpipe-<slot>-dlq.jsonl")
+    /// - `DLQ_TABLE`: `schema.table` of the "postgres" queue (default: "walpipe_dead_letters")
+    /// - `DLQ_DATABASE_URL`: Database of the "postgres" queue (default: `DATABASE_URL`)
+    /// - `TRANSFORMS`: Comma-separated `[schema.]table.column=action` column transforms, action being "drop", "hash", "mask", "mask:<n>" or "const:<value>" (default: none)
+    /// - `TRANSFORM_HASH_SALT`: Salt of the "hash" transform (required when hashing columns)
+    /// - `SCRIPT_FILE`: Rhai script whose `process(event)` function rewrites row changes, or a ".wasm" component implementing the `transform` world of `wit/plugin.wit` (default: none)
+    /// - `SCRIPT_ERROR_POLICY`: "fail", "skip" or "dead-letter" for events the script fails on (default: "fail")
     ///
     /// Optional (event sink specific):
     /// - `HTTP_ENDPOINT_URL`: URL for HTTP event sink (required when using "http")
+    /// - `HTTP_RETRY_MAX_ATTEMPTS`: Attempts per event before giving up, 0 for no limit (default: 5)
+    /// - `HTTP_RETRY_BASE_DELAY_MS`: Upper bound of the jittered delay before the first retry (default: 1000)
+    /// - `HTTP_RETRY_MAX_DELAY_MS`: Upper bound of the doubling retry delay (default: 30000)
+    /// - `HTTP_RETRY_STATUSES`: Comma-separated statuses and ranges retried, other errors are fatal (default: "408,429,500-599")
+    /// - `CLOUDEVENTS_MODE`: "off", "structured" or "binary" CloudEvents for the HTTP sink (default: "off")
     /// - `HOOK0_API_URL`: Hook0 API URL (required when using "hook0")
     /// - `HOOK0_APPLICATION_ID`: Hook0 application UUID (required when using "hook0")
     /// - `HOOK0_API_TOKEN`: Hook0 API token (required when using "hook0")
+    /// - `HOOK0_MESSAGE_PREFIX`: Logical message prefix turned into Hook0 events (default: "hook0")
+    /// - `HOOK0_RETRY_MAX_ATTEMPTS`, `HOOK0_RETRY_BASE_DELAY_MS`, `HOOK0_RETRY_MAX_DELAY_MS`: Retry policy of the Hook0 sink, as for HTTP
+    /// - `WASM_PLUGIN`: WebAssembly component of the "wasm" sink (required when using "wasm")
+    /// - `WASM_RETRY_MAX_ATTEMPTS`, `WASM_RETRY_BASE_DELAY_MS`, `WASM_RETRY_MAX_DELAY_MS`: Retry policy of the WebAssembly sink, as for HTTP
+    /// - `WASM_ALLOW_NETWORK`: "true" to let the WebAssembly sink open network connections (default: "false")
+    /// - `EVENT_FILTER`: Row filter expression, e.g. "changed(status) AND status = 'shipped'" (default: every row)
+    ///
+    /// Optional (routed sinks, replacing `EVENT_SINK`):
+    /// - `SINKS`: Comma-separated names of the sinks the stream is routed to
+    /// - `SINK_<NAME>_TYPE`: "http", "hook0", "stdout" or "wasm" (required for each sink)
+    /// - `SINK_<NAME>_HTTP_ENDPOINT_URL`, `SINK_<NAME>_HOOK0_*`, `SINK_<NAME>_WASM_PLUGIN`, `SINK_<NAME>_WASM_ALLOW_NETWORK`, `SINK_<NAME>_RETRY_*`: As above, for this sink
+    /// - `SINK_<NAME>_EVENT_FORMAT`: Event format of this sink, as `EVENT_FORMAT` (default: `EVENT_FORMAT`)
+    /// - `SINK_<NAME>_TABLES`: Comma-separated `schema.table` or `table` glob patterns (default: every table)
+    /// - `SINK_<NAME>_OPERATIONS`: Comma-separated "insert", "update", "delete", "truncate" or "read" (default: every operation)
+    /// - `SINK_<NAME>_KINDS`: Comma-separated "transaction", "relation", "change" or "message" (default: every kind)
+    /// - `SINK_<NAME>_FILTER`: Row filter expression of this sink, as `EVENT_FILTER`
+    /// - `SINK_<NAME>_BLOCKING`: "false" to acknowledge transactions even when this sink fails (default: "true")
     pub fn from_env() -> ReplicationResult<Self> {
         // Required: Database connection string
         let connection_string = env::var("DATABASE_URL").map_err(|_| {
@@ -73,11 +452,13 @@ impl ReplicationConfig {
 
         // Optional with default: event sink type
         let event_sink = env::var("EVENT_SINK").ok();
+        let event_sink_set = event_sink.is_some();
 
         // Optional: event sink specific configuration
         let http_endpoint_url = env::var("HTTP_ENDPOINT_URL").ok();
         let hook0_api_url = env::var("HOOK0_API_URL").ok();
         let hook0_api_token = env::var("HOOK0_API_TOKEN").ok();
+        let wasm_plugin = env::var("WASM_PLUGIN").ok();
 
         // Parse Hook0 application ID as UUID if provided
         let hook0_application_id = env::var("HOOK0_APPLICATION_ID")
@@ -85,7 +466,7 @@ impl ReplicationConfig {
             .and_then(|s| Uuid::parse_str(&s).ok());
 
         // Validate the configuration
-        Self::validate_and_create(
+        let mut config = Self::validate_and_create(
             connection_string,
             publication_name,
             slot_name,
@@ -94,6 +475,797 @@ impl ReplicationConfig {
             hook0_api_url,
             hook0_application_id,
             hook0_api_token,
+            wasm_plugin,
+        )?;
+
+        // Optional with default: delivery mode
+        config.delivery_mode = Self::parse_delivery_mode(env::var("DELIVERY_MODE").ok())?;
+        config.event_format =
+            Self::parse_event_format("EVENT_FORMAT", env::var("EVENT_FORMAT").ok())?;
+        if let Ok(prefix) = env::var("DEBEZIUM_TOPIC_PREFIX") {
+            if prefix.trim().is_empty() {
+                return Err(ReplicationError::config(
+                    "DEBEZIUM_TOPIC_PREFIX cannot be empty",
+                ));
+            }
+            config.debezium_topic_prefix = prefix;
+        }
+        config.debezium_schemas =
+            Self::parse_flag("DEBEZIUM_SCHEMAS", env::var("DEBEZIUM_SCHEMAS").ok())?;
+        config.cloudevents = Self::parse_choice(
+            "CLOUDEVENTS_MODE",
+            env::var("CLOUDEVENTS_MODE").ok(),
+            &[
+                ("off", None),
+                ("structured", Some(CloudEventsMode::Structured)),
+                ("binary", Some(CloudEventsMode::Binary)),
+            ],
+        )?;
+
+        // Optional with defaults: streamed transaction reassembly
+        if let Ok(dir) = env::var("STREAM_SPILL_DIR") {
+            if dir.trim().is_empty() {
+                return Err(ReplicationError::config("STREAM_SPILL_DIR cannot be empty"));
+            }
+            config.stream_spill_dir = PathBuf::from(dir);
+        }
+        if let Ok(threshold) = env::var("STREAM_SPILL_THRESHOLD_BYTES") {
+            config.stream_spill_threshold_bytes = threshold.parse().map_err(|_| {
+                ReplicationError::config("STREAM_SPILL_THRESHOLD_BYTES must be a number of bytes")
+            })?;
+        }
+
+        // Optional with defaults: pgoutput protocol options
+        let (proto_version, streaming, two_phase) = Self::parse_protocol_options(
+            env::var("PROTO_VERSION").ok(),
+            env::var("STREAMING").ok(),
+            env::var("TWO_PHASE").ok(),
+        )?;
+        config.proto_version = proto_version;
+        config.streaming = streaming;
+        config.two_phase = two_phase;
+
+        config.logical_messages =
+            Self::parse_flag("LOGICAL_MESSAGES", env::var("LOGICAL_MESSAGES").ok())?;
+        config.binary_tuples = Self::parse_flag("BINARY_TUPLES", env::var("BINARY_TUPLES").ok())?;
+        if let Ok(prefix) = env::var("HOOK0_MESSAGE_PREFIX") {
+            config.hook0_message_prefix = prefix;
+        }
+
+        // Optional with defaults: slot and publication provisioning
+        config.bootstrap = Self::parse_flag("BOOTSTRAP", env::var("BOOTSTRAP").ok())?;
+        config.slot_temporary =
+            Self::parse_flag("SLOT_TEMPORARY", env::var("SLOT_TEMPORARY").ok())?;
+        config.slot_failover = Self::parse_flag("SLOT_FAILOVER", env::var("SLOT_FAILOVER").ok())?;
+        if config.slot_temporary && config.slot_failover {
+            return Err(ReplicationError::config(
+                "SLOT_FAILOVER cannot be used with SLOT_TEMPORARY",
+            ));
+        }
+        config.publication_scope = Self::parse_publication_scope(
+            env::var("PUBLICATION_TABLES").ok(),
+            env::var("PUBLICATION_SCHEMAS").ok(),
+        );
+
+        config.snapshot_mode = Self::parse_choice(
+            "SNAPSHOT_MODE",
+            env::var("SNAPSHOT_MODE").ok(),
+            &[
+                ("never", SnapshotMode::Never),
+                ("initial", SnapshotMode::Initial),
+            ],
+        )?;
+
+        // Optional: incremental snapshots requested through a signal table
+        config.signal_table = env::var("SIGNAL_TABLE")
+            .ok()
+            .map(|table| table.trim().to_string())
+            .filter(|table| !table.is_empty());
+        if config.signal_table.is_some() && config.binary_tuples {
+            return Err(ReplicationError::config(
+                "SIGNAL_TABLE cannot be used with BINARY_TUPLES",
+            ));
+        }
+        if let Ok(chunk_size) = env::var("INCREMENTAL_SNAPSHOT_CHUNK_SIZE") {
+            config.incremental_snapshot_chunk_size = match chunk_size.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(ReplicationError::config(
+                        "INCREMENTAL_SNAPSHOT_CHUNK_SIZE must be a positive number of rows",
+                    ));
+                }
+                Ok(chunk_size) => chunk_size,
+            };
+        }
+        if let Ok(file) = env::var("INCREMENTAL_SNAPSHOT_STATE_FILE") {
+            config.incremental_snapshot_state_file = PathBuf::from(file);
+        }
+
+        // Optional with defaults: reconnection after a lost connection or a sink failure
+        for (name, value) in [
+            (
+                "RECONNECT_INITIAL_DELAY_MS",
+                &mut config.reconnect_initial_delay_ms,
+            ),
+            ("RECONNECT_MAX_DELAY_MS", &mut config.reconnect_max_delay_ms),
+        ] {
+            if let Ok(delay) = env::var(name) {
+                *value = delay.parse().map_err(|_| {
+                    ReplicationError::config(format!("{} must be a number of milliseconds", name))
+                })?;
+            }
+        }
+        if config.reconnect_initial_delay_ms > config.reconnect_max_delay_ms {
+            return Err(ReplicationError::config(
+                "RECONNECT_INITIAL_DELAY_MS cannot exceed RECONNECT_MAX_DELAY_MS",
+            ));
+        }
+        if let Ok(attempts) = env::var("RECONNECT_MAX_ATTEMPTS") {
+            config.reconnect_max_attempts = attempts.parse().map_err(|_| {
+                ReplicationError::config("RECONNECT_MAX_ATTEMPTS must be a number of attempts")
+            })?;
+        }
+
+        // Optional with defaults: unchanged TOAST column resolution
+        config.toast_cache = Self::parse_choice(
+            "TOAST_CACHE",
+            env::var("TOAST_CACHE").ok(),
+            &[
+                ("off", ToastCacheMode::Off),
+                ("memory", ToastCacheMode::Memory),
+                ("disk", ToastCacheMode::Disk),
+            ],
+        )?;
+        if let Ok(dir) = env::var("TOAST_CACHE_DIR") {
+            config.toast_cache_dir = PathBuf::from(dir);
+        }
+        if let Ok(capacity) = env::var("TOAST_CACHE_CAPACITY") {
+            config.toast_cache_capacity = capacity.parse().map_err(|_| {
+                ReplicationError::config("TOAST_CACHE_CAPACITY must be a number of rows")
+            })?;
+        }
+        config.toast_refetch = Self::parse_flag("TOAST_REFETCH", env::var("TOAST_REFETCH").ok())?;
+
+        // Optional with default: loop prevention for bidirectional setups
+        config.origin_filter = Self::parse_origin_filter(env::var("ORIGIN_FILTER").ok())?;
+
+        // Optional with defaults: record of delivered transactions
+        config.checkpoint_store = Self::parse_choice(
+            "CHECKPOINT_STORE",
+            env::var("CHECKPOINT_STORE").ok(),
+            &[
+                ("off", CheckpointStoreMode::Off),
+                ("file", CheckpointStoreMode::File),
+                ("postgres", CheckpointStoreMode::Postgres),
+            ],
+        )?;
+        if let Ok(file) = env::var("CHECKPOINT_FILE") {
+            config.checkpoint_file = PathBuf::from(file);
+        }
+        if let Ok(table) = env::var("CHECKPOINT_TABLE") {
+            if table.trim().is_empty() {
+                return Err(ReplicationError::config("CHECKPOINT_TABLE cannot be empty"));
+            }
+            config.checkpoint_table = table.trim().to_string();
+        }
+        config.checkpoint_connection_string = env::var("CHECKPOINT_DATABASE_URL").ok();
+
+        // Optional with defaults: dead-letter queue
+        config.dead_letter_queue = Self::parse_choice(
+            "DLQ_STORE",
+            env::var("DLQ_STORE").ok(),
+            &[
+                ("off", DeadLetterQueueMode::Off),
+                ("file", DeadLetterQueueMode::File),
+                ("postgres", DeadLetterQueueMode::Postgres),
+            ],
+        )?;
+        if let Ok(file) = env::var("DLQ_FILE") {
+            config.dlq_file = PathBuf::from(file);
+        }
+        if let Ok(table) = env::var("DLQ_TABLE") {
+            if table.trim().is_empty() {
+                return Err(ReplicationError::config("DLQ_TABLE cannot be empty"));
+            }
+            config.dlq_table = table.trim().to_string();
+        }
+        config.dlq_connection_string = env::var("DLQ_DATABASE_URL").ok();
+
+        // Optional: several routed sinks instead of the single EVENT_SINK
+        config.sinks = match env::var("SINKS") {
+            Ok(names) => {
+                if env::var("EVENT_FILTER").is_ok() {
+                    return Err(ReplicationError::config(
+                        "EVENT_FILTER cannot be used with SINKS, set SINK_<NAME>_FILTER instead",
+                    ));
+                }
+                if event_sink_set {
+                    return Err(ReplicationError::config(
+                        "EVENT_SINK cannot be used with SINKS, set SINK_<NAME>_TYPE instead",
+                    ));
+                }
+                if env::var("WASM_ALLOW_NETWORK").is_ok() {
+                    return Err(ReplicationError::config(
+                        "WASM_ALLOW_NETWORK cannot be used with SINKS, set SINK_<NAME>_WASM_ALLOW_NETWORK instead",
+                    ));
+                }
+                Self::parse_sinks(&names, &config.event_format)?
+            }
+            Err(_) => {
+                let retry = match config.event_sink {
+                    EventSinkType::Http => {
+                        let mut retry = Self::parse_retry_policy("HTTP")?;
+                        Self::parse_retry_statuses("HTTP", &mut retry)?;
+                        retry
+                    }
+                    EventSinkType::Hook0 => Self::parse_retry_policy("HOOK0")?,
+                    EventSinkType::Wasm => Self::parse_retry_policy("WASM")?,
+                    EventSinkType::Stdout => RetryPolicy::default(),
+                };
+                vec![SinkConfig {
+                    name: config.event_sink_type().to_string(),
+                    sink: config.event_sink_type().clone(),
+                    http_endpoint_url: config.http_endpoint_url.clone(),
+                    hook0_api_url: config.hook0_api_url.clone(),
+                    hook0_application_id: config.hook0_application_id,
+                    hook0_api_token: config.hook0_api_token.clone(),
+                    wasm_plugin: config.wasm_plugin.clone(),
+                    wasm_allow_network: Self::parse_flag(
+                        "WASM_ALLOW_NETWORK",
+                        env::var("WASM_ALLOW_NETWORK").ok(),
+                    )?,
+                    event_format: config.event_format.clone(),
+                    retry,
+                    rule: RouteRule {
+                        filter: Self::parse_filter("EVENT_FILTER")?,
+                        ..RouteRule::default()
+                    },
+                    blocking: true,
+                }]
+            }
+        };
+
+        // Optional: column transforms
+        if let Ok(transforms) = env::var("TRANSFORMS") {
+            config.column_transforms = transforms
+                .split(',')
+                .filter(|transform| !transform.trim().is_empty())
+                .map(|transform| {
+                    transform.parse().map_err(|e| {
+                        ReplicationError::config(format!("Invalid TRANSFORMS entry: {}", e))
+                    })
+                })
+                .collect::<ReplicationResult<_>>()?;
+        }
+        config.transform_hash_salt = env::var("TRANSFORM_HASH_SALT")
+            .ok()
+            .filter(|salt| !salt.is_empty());
+        if config.transform_hash_salt.is_none()
+            && config
+                .column_transforms
+                .iter()
+                .any(|transform| transform.action == ColumnAction::Hash)
+        {
+            return Err(ReplicationError::config(
+                "TRANSFORM_HASH_SALT is required to hash columns",
+            ));
+        }
+
+        // Optional: event script
+        config.script_file = env::var("SCRIPT_FILE")
+            .ok()
+            .filter(|file| !file.trim().is_empty())
+            .map(PathBuf::from);
+        if !cfg!(feature = "wasm")
+            && config.script_file.as_ref().is_some_and(|file| {
+                file.extension()
+                    .is_some_and(|extension| extension == "wasm")
+            })
+        {
+            return Err(ReplicationError::config(
+                "SCRIPT_FILE components require walpipe to be built with the 'wasm' feature",
+            ));
+        }
+        config.script_error_policy = Self::parse_choice(
+            "SCRIPT_ERROR_POLICY",
+            env::var("SCRIPT_ERROR_POLICY").ok(),
+            &[
+                ("fail", ScriptErrorPolicy::Fail),
+                ("skip", ScriptErrorPolicy::Skip),
+                ("dead-letter", ScriptErrorPolicy::DeadLetter),
+            ],
+        )?;
+        if config.script_error_policy == ScriptErrorPolicy::DeadLetter
+            && config.dead_letter_queue == DeadLetterQueueMode::Off
+        {
+            return Err(ReplicationError::config(
+                "SCRIPT_ERROR_POLICY 'dead-letter' requires DLQ_STORE",
+            ));
+        }
+
+        // Optional: metrics endpoint
+        if let Ok(addr) = env::var("METRICS_ADDR") {
+            config.metrics_addr = Some(addr.parse().map_err(|_| {
+                ReplicationError::config(
+                    "METRICS_ADDR must be a socket address such as 0.0.0.0:9187",
+                )
+            })?);
+        }
+
+        Ok(config)
+    }
+
+    /// Parse and cross-check the pgoutput protocol version, streaming mode and two-phase flag
+    fn parse_protocol_options(
+        proto_version: Option<String>,
+        streaming: Option<String>,
+        two_phase: Option<String>,
+    ) -> ReplicationResult<(u32, StreamingMode, bool)> {
+        let proto_version = match proto_version {
+            None => 2,
+            Some(version) => match version.trim().parse::<u32>() {
+                Ok(version @ 1..=4) => version,
+                _ => {
+                    return Err(ReplicationError::config(
+                        "PROTO_VERSION must be one of: 1, 2, 3 or 4",
+                    ));
+                }
+            },
+        };
+
+        let streaming = match streaming.map(|mode| mode.to_lowercase()).as_deref() {
+            None if proto_version == 1 => StreamingMode::Off,
+            None | Some("on") | Some("true") => StreamingMode::On,
+            Some("off") | Some("false") => StreamingMode::Off,
+            Some("parallel") => StreamingMode::Parallel,
+            Some(_) => {
+                return Err(ReplicationError::config(
+                    "STREAMING must be one of: 'off', 'on' or 'parallel'",
+                ));
+            }
+        };
+
+        let two_phase = Self::parse_flag("TWO_PHASE", two_phase)?;
+
+        if streaming == StreamingMode::On && proto_version < 2 {
+            return Err(ReplicationError::config(
+                "STREAMING 'on' requires PROTO_VERSION 2 or higher",
+            ));
+        }
+        if streaming == StreamingMode::Parallel && proto_version < 4 {
+            return Err(ReplicationError::config(
+                "STREAMING 'parallel' requires PROTO_VERSION 4",
+            ));
+        }
+        if two_phase && proto_version < 3 {
+            return Err(ReplicationError::config(
+                "TWO_PHASE requires PROTO_VERSION 3 or higher",
+            ));
+        }
+
+        Ok((proto_version, streaming, two_phase))
+    }
+
+    /// Validate a sink type and the settings it requires
+    ///
+    /// `type_var` names the variable holding `sink_type` and `prefix` is put in
+    /// front of the sink-specific variable names in error messages.
+    #[allow(clippy::too_many_arguments)]
+    fn parse_sink_type(
+        type_var: &str,
+        prefix: &str,
+        sink_type: &str,
+        http_endpoint_url: Option<&str>,
+        hook0_api_url: Option<&str>,
+        hook0_application_id: Option<Uuid>,
+        hook0_api_token: Option<&str>,
+        wasm_plugin: Option<&str>,
+    ) -> ReplicationResult<EventSinkType> {
+        match sink_type.to_lowercase().as_str() {
+            "http" => {
+                // HTTP endpoint URL is required for HTTP sink
+                match http_endpoint_url.filter(|url| !url.trim().is_empty()) {
+                    None => Err(ReplicationError::config(format!(
+                        "{}HTTP_ENDPOINT_URL is required when using 'http' event sink",
+                        prefix
+                    ))),
+                    // Validate HTTP endpoint URL format
+                    Some(url) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                        Err(ReplicationError::config(format!(
+                            "{}HTTP_ENDPOINT_URL must start with http:// or https://",
+                            prefix
+                        )))
+                    }
+                    Some(_) => Ok(EventSinkType::Http),
+                }
+            }
+            "hook0" => {
+                // All Hook0 fields are required for Hook0 sink
+                if hook0_api_url.is_none_or(|url| url.trim().is_empty()) {
+                    Err(ReplicationError::config(format!(
+                        "{}HOOK0_API_URL is required when using 'hook0' event sink",
+                        prefix
+                    )))
+                } else if hook0_application_id.is_none() {
+                    Err(ReplicationError::config(format!(
+                        "{}HOOK0_APPLICATION_ID is required when using 'hook0' event sink",
+                        prefix
+                    )))
+                } else if hook0_api_token.is_none_or(|token| token.trim().is_empty()) {
+                    Err(ReplicationError::config(format!(
+                        "{}HOOK0_API_TOKEN is required when using 'hook0' event sink",
+                        prefix
+                    )))
+                } else if hook0_api_url
+                    .is_some_and(|url| !url.starts_with("http://") && !url.starts_with("https://"))
+                {
+                    // Validate Hook0 API URL format
+                    Err(ReplicationError::config(format!(
+                        "{}HOOK0_API_URL must start with http:// or https://",
+                        prefix
+                    )))
+                } else {
+                    Ok(EventSinkType::Hook0)
+                }
+            }
+            // STDOUT sink requires no additional configuration
+            "stdout" => Ok(EventSinkType::Stdout),
+            "wasm" => {
+                if !cfg!(feature = "wasm") {
+                    Err(ReplicationError::config(format!(
+                        "{} 'wasm' requires walpipe to be built with the 'wasm' feature",
+                        type_var
+                    )))
+                } else if wasm_plugin.is_none_or(|plugin| plugin.trim().is_empty()) {
+                    Err(ReplicationError::config(format!(
+                        "{}WASM_PLUGIN is required when using 'wasm' event sink",
+                        prefix
+                    )))
+                } else {
+                    Ok(EventSinkType::Wasm)
+                }
+            }
+            _ => Err(ReplicationError::config(format!(
+                "{} must be one of: 'http', 'hook0', 'stdout' or 'wasm'",
+                type_var
+            ))),
+        }
+    }
+
+    /// Parse the routed sinks named in `SINKS` from their `SINK_<NAME>_*` variables
+    ///
+    /// Sinks without an event format of their own use `event_format`.
+    fn parse_sinks(names: &str, event_format: &EventFormat) -> ReplicationResult<Vec<SinkConfig>> {
+        let mut sinks: Vec<SinkConfig> = Vec::new();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(ReplicationError::config(format!(
+                    "Sink name '{}' can only contain alphanumeric characters and underscores",
+                    name
+                )));
+            }
+            if sinks
+                .iter()
+                .any(|sink| sink.name.eq_ignore_ascii_case(name))
+            {
+                return Err(ReplicationError::config(format!(
+                    "Sink '{}' is listed twice in SINKS",
+                    name
+                )));
+            }
+
+            let prefix = format!("SINK_{}", name.to_uppercase());
+            let var = |setting: &str| env::var(format!("{}_{}", prefix, setting)).ok();
+            let type_var = format!("{}_TYPE", prefix);
+            let sink_type = var("TYPE").ok_or_else(|| {
+                ReplicationError::config(format!("{} is required for sink '{}'", type_var, name))
+            })?;
+            let hook0_application_id = match var("HOOK0_APPLICATION_ID") {
+                Some(id) => Some(Uuid::parse_str(&id).map_err(|_| {
+                    ReplicationError::config(format!(
+                        "{}_HOOK0_APPLICATION_ID must be a UUID",
+                        prefix
+                    ))
+                })?),
+                None => None,
+            };
+            let (http_endpoint_url, hook0_api_url, hook0_api_token, wasm_plugin) = (
+                var("HTTP_ENDPOINT_URL"),
+                var("HOOK0_API_URL"),
+                var("HOOK0_API_TOKEN"),
+                var("WASM_PLUGIN"),
+            );
+            let sink = Self::parse_sink_type(
+                &type_var,
+                &format!("{}_", prefix),
+                &sink_type,
+                http_endpoint_url.as_deref(),
+                hook0_api_url.as_deref(),
+                hook0_application_id,
+                hook0_api_token.as_deref(),
+                wasm_plugin.as_deref(),
+            )?;
+
+            let mut retry = Self::parse_retry_policy(&prefix)?;
+            Self::parse_retry_statuses(&prefix, &mut retry)?;
+
+            let list = |setting: &str| -> Vec<String> {
+                var(setting)
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|item| item.trim().to_lowercase())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            };
+            let operations = list("OPERATIONS")
+                .iter()
+                .map(|operation| match operation.as_str() {
+                    "insert" => Ok(Operation::Insert),
+                    "update" => Ok(Operation::Update),
+                    "delete" => Ok(Operation::Delete),
+                    "truncate" => Ok(Operation::Truncate),
+                    "read" => Ok(Operation::Read),
+                    _ => Err(ReplicationError::config(format!(
+                        "{}_OPERATIONS must list 'insert', 'update', 'delete', 'truncate' or 'read'",
+                        prefix
+                    ))),
+                })
+                .collect::<ReplicationResult<_>>()?;
+            let kinds = list("KINDS")
+                .iter()
+                .map(|kind| match kind.as_str() {
+                    "transaction" => Ok(MessageKind::Transaction),
+                    "relation" => Ok(MessageKind::Relation),
+                    "change" => Ok(MessageKind::Change),
+                    "message" => Ok(MessageKind::Message),
+                    _ => Err(ReplicationError::config(format!(
+                        "{}_KINDS must list 'transaction', 'relation', 'change' or 'message'",
+                        prefix
+                    ))),
+                })
+                .collect::<ReplicationResult<_>>()?;
+            let tables = var("TABLES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|table| table.trim().to_string())
+                .filter(|table| !table.is_empty())
+                .collect();
+
+            let wasm_allow_network = Self::parse_flag(
+                &format!("{}_WASM_ALLOW_NETWORK", prefix),
+                var("WASM_ALLOW_NETWORK"),
+            )?;
+            let event_format = match var("EVENT_FORMAT") {
+                Some(format) => {
+                    Self::parse_event_format(&format!("{}_EVENT_FORMAT", prefix), Some(format))?
+                }
+                None => event_format.clone(),
+            };
+            let blocking_var = format!("{}_BLOCKING", prefix);
+            let blocking = match var("BLOCKING") {
+                Some(blocking) => Self::parse_flag(&blocking_var, Some(blocking))?,
+                None => true,
+            };
+
+            sinks.push(SinkConfig {
+                name: name.to_string(),
+                sink,
+                http_endpoint_url,
+                hook0_api_url,
+                hook0_application_id,
+                hook0_api_token,
+                wasm_plugin: wasm_plugin.map(PathBuf::from),
+                wasm_allow_network,
+                event_format,
+                retry,
+                rule: RouteRule {
+                    tables,
+                    operations,
+                    kinds,
+                    filter: Self::parse_filter(&format!("{}_FILTER", prefix))?,
+                },
+                blocking,
+            });
+        }
+
+        if sinks.is_empty() {
+            return Err(ReplicationError::config(
+                "SINKS must be a comma-separated list of sink names",
+            ));
+        }
+        Ok(sinks)
+    }
+
+    /// Parse the row filter expression in the `name` variable, if set
+    fn parse_filter(name: &str) -> ReplicationResult<Option<RowFilter>> {
+        match env::var(name) {
+            Ok(filter) if !filter.trim().is_empty() => filter.parse().map(Some).map_err(|e| {
+                ReplicationError::config(format!("{} is not a valid filter: {}", name, e))
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse the `<prefix>_RETRY_*` attempts and delays of a sink's retry policy
+    fn parse_retry_policy(prefix: &str) -> ReplicationResult<RetryPolicy> {
+        let mut policy = RetryPolicy::default();
+
+        let name = format!("{}_RETRY_MAX_ATTEMPTS", prefix);
+        if let Ok(attempts) = env::var(&name) {
+            policy.max_attempts = match attempts.trim().parse() {
+                Ok(0) => None,
+                Ok(attempts) => Some(attempts),
+                Err(_) => {
+                    return Err(ReplicationError::config(format!(
+                        "{} must be a number of attempts",
+                        name
+                    )));
+                }
+            };
+        }
+        for (setting, value) in [
+            ("BASE_DELAY_MS", &mut policy.base_delay),
+            ("MAX_DELAY_MS", &mut policy.max_delay),
+        ] {
+            let name = format!("{}_RETRY_{}", prefix, setting);
+            if let Ok(delay) = env::var(&name) {
+                *value = Duration::from_millis(delay.trim().parse().map_err(|_| {
+                    ReplicationError::config(format!("{} must be a number of milliseconds", name))
+                })?);
+            }
+        }
+        if policy.base_delay > policy.max_delay {
+            return Err(ReplicationError::config(format!(
+                "{}_RETRY_BASE_DELAY_MS cannot exceed {}_RETRY_MAX_DELAY_MS",
+                prefix, prefix
+            )));
+        }
+        Ok(policy)
+    }
+
+    /// Parse `<prefix>_RETRY_STATUSES` into the statuses an HTTP sink retries
+    fn parse_retry_statuses(prefix: &str, policy: &mut RetryPolicy) -> ReplicationResult<()> {
+        let name = format!("{}_RETRY_STATUSES", prefix);
+        if let Ok(statuses) = env::var(&name) {
+            policy.retryable_statuses = Self::parse_statuses(&statuses).ok_or_else(|| {
+                ReplicationError::config(format!(
+                    "{} must be a comma-separated list of statuses or ranges such as 500-599",
+                    name
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Parse a list of HTTP statuses and inclusive ranges, e.g. "408,429,500-599"
+    fn parse_statuses(statuses: &str) -> Option<Vec<std::ops::RangeInclusive<u16>>> {
+        statuses
+            .split(',')
+            .map(str::trim)
+            .filter(|status| !status.is_empty())
+            .map(|status| {
+                let (first, last) = status.split_once('-').unwrap_or((status, status));
+                let first: u16 = first.trim().parse().ok()?;
+                let last: u16 = last.trim().parse().ok()?;
+                (100 <= first && first <= last && last <= 599).then_some(first..=last)
+            })
+            .collect()
+    }
+
+    /// Parse an on/off option, defaulting to off
+    fn parse_flag(name: &str, value: Option<String>) -> ReplicationResult<bool> {
+        match value.map(|flag| flag.to_lowercase()).as_deref() {
+            None | Some("false") | Some("off") => Ok(false),
+            Some("true") | Some("on") => Ok(true),
+            Some(_) => Err(ReplicationError::config(format!(
+                "{} must be either 'true' or 'false'",
+                name
+            ))),
+        }
+    }
+
+    /// Parse an option taking one of `choices`, defaulting to the first one
+    fn parse_choice<T: Clone>(
+        name: &str,
+        value: Option<String>,
+        choices: &[(&str, T)],
+    ) -> ReplicationResult<T> {
+        let Some(value) = value.map(|choice| choice.to_lowercase()) else {
+            return Ok(choices[0].1.clone());
+        };
+        match choices.iter().find(|(choice, _)| *choice == value) {
+            Some((_, parsed)) => Ok(parsed.clone()),
+            None => {
+                let mut names: Vec<String> = choices
+                    .iter()
+                    .map(|(choice, _)| format!("'{}'", choice))
+                    .collect();
+                let last = names.pop().unwrap_or_default();
+                Err(ReplicationError::config(format!(
+                    "{} must be one of: {} or {}",
+                    name,
+                    names.join(", "),
+                    last
+                )))
+            }
+        }
+    }
+
+    /// Parse the publication scope, defaulting to all tables when no table or schema is listed
+    fn parse_publication_scope(
+        tables: Option<String>,
+        schemas: Option<String>,
+    ) -> PublicationScope {
+        let split = |list: Option<String>| -> Vec<String> {
+            list.unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        };
+        let (tables, schemas) = (split(tables), split(schemas));
+
+        if tables.is_empty() && schemas.is_empty() {
+            PublicationScope::AllTables
+        } else {
+            PublicationScope::Objects { tables, schemas }
+        }
+    }
+
+    /// Parse the origin filter: "any", "none" or a list of origin names
+    fn parse_origin_filter(origin_filter: Option<String>) -> ReplicationResult<OriginFilter> {
+        let origin_filter = match origin_filter {
+            None => return Ok(OriginFilter::Any),
+            Some(filter) => filter,
+        };
+
+        match origin_filter.trim().to_lowercase().as_str() {
+            "any" => Ok(OriginFilter::Any),
+            "none" => Ok(OriginFilter::None),
+            _ => {
+                let names: Vec<String> = origin_filter
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                if names.is_empty() {
+                    return Err(ReplicationError::config(
+                        "ORIGIN_FILTER must be 'any', 'none' or a comma-separated list of origin names",
+                    ));
+                }
+                Ok(OriginFilter::Exclude(names))
+            }
+        }
+    }
+
+    /// Parse the delivery mode, defaulting to one sink call per message
+    fn parse_delivery_mode(delivery_mode: Option<String>) -> ReplicationResult<DeliveryMode> {
+        Self::parse_choice(
+            "DELIVERY_MODE",
+            delivery_mode,
+            &[
+                ("message", DeliveryMode::Message),
+                ("transaction", DeliveryMode::Transaction),
+            ],
+        )
+    }
+
+    /// Parse the event format, defaulting to the raw protocol-level JSON
+    fn parse_event_format(
+        name: &str,
+        event_format: Option<String>,
+    ) -> ReplicationResult<EventFormat> {
+        Self::parse_choice(
+            name,
+            event_format,
+            &[
+                ("raw", EventFormat::Raw),
+                ("typed", EventFormat::Typed),
+                ("debezium", EventFormat::Debezium),
+            ],
         )
     }
 
@@ -107,6 +1279,7 @@ impl ReplicationConfig {
         hook0_api_url: Option<String>,
         hook0_application_id: Option<Uuid>,
         hook0_api_token: Option<String>,
+        wasm_plugin: Option<String>,
     ) -> ReplicationResult<Self> {
         // Validate connection string
         if connection_string.trim().is_empty() {
@@ -140,74 +1313,22 @@ impl ReplicationConfig {
         }
 
         // Validate event sink configuration
+        let event_sink_val = Self::parse_sink_type(
+            "EVENT_SINK",
+            "",
+            event_sink.as_deref().unwrap_or("stdout"),
+            http_endpoint_url.as_deref(),
+            hook0_api_url.as_deref(),
+            hook0_application_id,
+            hook0_api_token.as_deref(),
+            wasm_plugin.as_deref(),
+        );
 
-        let event_sink_val: Result<EventSinkType, ReplicationError> = match event_sink.as_ref() {
-            Some(service) => {
-                let service_lower = service.to_lowercase();
-
-                match service_lower.as_str() {
-                    "http" => {
-                        // HTTP endpoint URL is required for HTTP sink
-                        if http_endpoint_url.is_none()
-                            || http_endpoint_url.as_ref().unwrap().trim().is_empty()
-                        {
-                            Err(ReplicationError::config(
-                                "HTTP_ENDPOINT_URL is required when using 'http' event sink",
-                            ))
-                        } else {
-                            // Validate HTTP endpoint URL format
-                            let url = http_endpoint_url.as_ref().unwrap();
-                            if !url.starts_with("http://") && !url.starts_with("https://") {
-                                Err(ReplicationError::config(
-                                    "HTTP_ENDPOINT_URL must start with http:// or https://",
-                                ))
-                            } else {
-                                Ok(EventSinkType::Http)
-                            }
-                        }
-                    }
-                    "hook0" => {
-                        // All Hook0 fields are required for Hook0 sink
-                        if hook0_api_url.is_none() || hook0_api_url.as_ref().unwrap().trim().is_empty()
-                        {
-                            Err(ReplicationError::config(
-                                "HOOK0_API_URL is required when using 'hook0' event sink",
-                            ))
-                        } else if hook0_application_id.is_none() {
-                            Err(ReplicationError::config(
-                                "HOOK0_APPLICATION_ID is required when using 'hook0' event sink",
-                            ))
-                        } else if hook0_api_token.is_none()
-                            || hook0_api_token.as_ref().unwrap().trim().is_empty()
-                        {
-                            Err(ReplicationError::config(
-                                "HOOK0_API_TOKEN is required when using 'hook0' event sink",
-                            ))
-                        } else {
-                            // Validate Hook0 API URL format
-                            let url = hook0_api_url.as_ref().unwrap();
-                            if !url.starts_with("http://") && !url.starts_with("https://") {
-                                Err(ReplicationError::config(
-                                    "HOOK0_API_URL must start with http:// or https://",
-                                ))
-                            } else {
-                                Ok(EventSinkType::Hook0)
-                            }
-                        }
-                    }
-                    "stdout" => {
-                        // STDOUT sink requires no additional configuration
-                        Ok(EventSinkType::Stdout)
-                    }
-                    _ => Err(ReplicationError::config(
-                        "EVENT_SINK must be one of: 'http', 'hook0', or 'stdout'",
-                    )),
-                }
-            }
-            None => Err(ReplicationError::config(
-                "EVENT_SINK must be one of: 'http', 'hook0', or 'stdout'",
-            ))
-        };
+        let incremental_snapshot_state_file =
+            env::temp_dir().join(format!("walpipe-{}-snapshot.json", slot_name));
+        let checkpoint_file =
+            env::temp_dir().join(format!("walpipe-{}-checkpoint.json", slot_name));
+        let dlq_file = env::temp_dir().join(format!("walpipe-{}-dlq.jsonl", slot_name));
 
         Ok(Self {
             connection_string,
@@ -219,6 +1340,50 @@ impl ReplicationConfig {
             hook0_api_url,
             hook0_application_id,
             hook0_api_token,
+            wasm_plugin: wasm_plugin.map(PathBuf::from),
+            delivery_mode: DeliveryMode::Message,
+            event_format: EventFormat::Raw,
+            debezium_topic_prefix: "walpipe".to_string(),
+            debezium_schemas: false,
+            cloudevents: None,
+            stream_spill_dir: env::temp_dir().join("walpipe"),
+            stream_spill_threshold_bytes: 64 * 1024 * 1024,
+            proto_version: 2,
+            streaming: StreamingMode::On,
+            two_phase: false,
+            logical_messages: false,
+            hook0_message_prefix: "hook0".to_string(),
+            origin_filter: OriginFilter::Any,
+            binary_tuples: false,
+            toast_cache: ToastCacheMode::Off,
+            toast_cache_dir: env::temp_dir().join("walpipe-toast"),
+            toast_cache_capacity: 10_000,
+            toast_refetch: false,
+            bootstrap: false,
+            slot_temporary: false,
+            slot_failover: false,
+            publication_scope: PublicationScope::AllTables,
+            snapshot_mode: SnapshotMode::Never,
+            signal_table: None,
+            incremental_snapshot_chunk_size: 1024,
+            incremental_snapshot_state_file,
+            reconnect_initial_delay_ms: 500,
+            reconnect_max_delay_ms: 30_000,
+            reconnect_max_attempts: 0,
+            checkpoint_store: CheckpointStoreMode::Off,
+            checkpoint_file,
+            checkpoint_table: "walpipe_checkpoints".to_string(),
+            checkpoint_connection_string: None,
+            metrics_addr: None,
+            dead_letter_queue: DeadLetterQueueMode::Off,
+            dlq_file,
+            dlq_table: "walpipe_dead_letters".to_string(),
+            dlq_connection_string: None,
+            sinks: Vec::new(),
+            column_transforms: Vec::new(),
+            transform_hash_salt: None,
+            script_file: None,
+            script_error_policy: ScriptErrorPolicy::Fail,
         })
     }
 
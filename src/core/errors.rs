@@ -3,6 +3,9 @@
 //! Provides structured error handling using thiserror for better error reporting
 //! and debugging capabilities throughout the replication system.
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use thiserror::Error;
 
 /// Main error type for the PostgreSQL replication checker application
@@ -60,11 +63,58 @@ pub enum ReplicationError {
     #[error("Sink error")]
     Sink { message: String, sink: String },
 
+    /// Event a sink could not deliver, rejected as invalid (`permanent`) or
+    /// failing in a way a retry may fix
+    #[error("Delivery to {sink} sink failed: {message}")]
+    Delivery {
+        message: String,
+        sink: String,
+        permanent: bool,
+        attempts: Vec<DeliveryAttempt>,
+    },
+
     /// Generic error for compatibility
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// One failed attempt to deliver an event to a sink
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    pub attempted_at: DateTime<Utc>,
+    pub error: String,
+    /// Response status, when the endpoint answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Delay the endpoint asked for before the next attempt
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl DeliveryAttempt {
+    /// Records an attempt that just failed with `error`
+    pub fn failed<S: Into<String>>(error: S) -> Self {
+        Self {
+            attempted_at: Utc::now(),
+            error: error.into(),
+            status: None,
+            retry_after: None,
+        }
+    }
+
+    /// Records the response status the attempt failed with
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Records the delay the endpoint asked for with `Retry-After`
+    pub fn with_retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
 /// Result type alias for convenience
 pub type ReplicationResult<T> = std::result::Result<T, ReplicationError>;
 
@@ -108,6 +158,21 @@ impl ReplicationError {
         }
     }
 
+    /// Create a delivery error for an event a sink failed to deliver
+    pub fn delivery<S: Into<String>>(
+        sink: &str,
+        message: S,
+        permanent: bool,
+        attempts: Vec<DeliveryAttempt>,
+    ) -> Self {
+        Self::Delivery {
+            message: message.into(),
+            sink: sink.to_string(),
+            permanent,
+            attempts,
+        }
+    }
+
     /// Create a buffer operation error
     pub fn buffer<S: Into<String>>(message: S) -> Self {
         Self::BufferOperation {
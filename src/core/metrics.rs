@@ -0,0 +1,202 @@
+//! Process metrics in the Prometheus text exposition format
+//!
+//! Gauges and counters are atomics updated along the replication path. When
+//! `METRICS_ADDR` is set, [`serve`] answers `GET /metrics` with their values.
+
+use crate::core::errors::ReplicationResult;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Largest request head read before answering
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Values exported by the metrics endpoint
+pub struct Metrics {
+    /// Highest LSN received from the server
+    pub received_lsn: AtomicU64,
+    /// LSN last confirmed to the server as flushed
+    pub flushed_lsn: AtomicU64,
+    /// Commit end LSN last recorded by the checkpoint store
+    pub checkpoint_lsn: AtomicU64,
+    /// Transactions acknowledged by the sink
+    pub transactions_delivered: AtomicU64,
+    /// Transactions skipped because the checkpoint store records them as delivered
+    pub transactions_skipped: AtomicU64,
+    /// Events written to the dead-letter queue
+    pub dead_letters: AtomicU64,
+    /// Events a non-blocking sink failed to deliver, dropped for that sink
+    pub events_dropped: AtomicU64,
+    /// Row changes a sink's filter skipped
+    pub events_filtered: AtomicU64,
+    /// Row changes the event script failed on
+    pub script_errors: AtomicU64,
+}
+
+/// Metrics of this process
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            received_lsn: AtomicU64::new(0),
+            flushed_lsn: AtomicU64::new(0),
+            checkpoint_lsn: AtomicU64::new(0),
+            transactions_delivered: AtomicU64::new(0),
+            transactions_skipped: AtomicU64::new(0),
+            dead_letters: AtomicU64::new(0),
+            events_dropped: AtomicU64::new(0),
+            events_filtered: AtomicU64::new(0),
+            script_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let metrics: [(&str, &str, &str, &AtomicU64); 9] = [
+            (
+                "walpipe_received_lsn",
+                "gauge",
+                "Highest WAL position received from the server",
+                &self.received_lsn,
+            ),
+            (
+                "walpipe_flushed_lsn",
+                "gauge",
+                "WAL position confirmed to the server as flushed",
+                &self.flushed_lsn,
+            ),
+            (
+                "walpipe_checkpoint_lsn",
+                "gauge",
+                "Commit end position last recorded by the checkpoint store",
+                &self.checkpoint_lsn,
+            ),
+            (
+                "walpipe_transactions_delivered_total",
+                "counter",
+                "Transactions acknowledged by the event sink",
+                &self.transactions_delivered,
+            ),
+            (
+                "walpipe_transactions_skipped_total",
+                "counter",
+                "Transactions skipped as already delivered according to the checkpoint",
+                &self.transactions_skipped,
+            ),
+            (
+                "walpipe_dead_letters_total",
+                "counter",
+                "Events the sink gave up on, written to the dead-letter queue",
+                &self.dead_letters,
+            ),
+            (
+                "walpipe_events_dropped_total",
+                "counter",
+                "Events a non-blocking sink failed to deliver, dropped for that sink",
+                &self.events_dropped,
+            ),
+            (
+                "walpipe_events_filtered_total",
+                "counter",
+                "Row changes skipped by a sink's row filter and acknowledged without delivery",
+                &self.events_filtered,
+            ),
+            (
+                "walpipe_script_errors_total",
+                "counter",
+                "Row changes the event script failed on, handled by the script error policy",
+                &self.script_errors,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        output
+    }
+}
+
+/// Serves `GET /metrics` on `addr` until the process exits
+///
+/// Binds before returning, so that an unusable address is reported at startup.
+pub async fn serve(addr: SocketAddr) -> ReplicationResult<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream).await {
+                            debug!("Metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_SIZE
+    {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+
+    let request_line = request
+        .split(|byte| *byte == b'\r')
+        .next()
+        .unwrap_or_default();
+    let path = request_line.split(|byte| *byte == b' ').nth(1);
+    let (status, body) = match path {
+        Some(b"/metrics") if request_line.starts_with(b"GET ") => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.checkpoint_lsn.store(23861232, Ordering::Relaxed);
+        metrics
+            .transactions_delivered
+            .fetch_add(2, Ordering::Relaxed);
+
+        let output = metrics.render();
+        assert!(
+            output
+                .contains("# TYPE walpipe_checkpoint_lsn gauge\nwalpipe_checkpoint_lsn 23861232\n")
+        );
+        assert!(output.contains("# TYPE walpipe_transactions_delivered_total counter\nwalpipe_transactions_delivered_total 2\n"));
+    }
+}
@@ -6,6 +6,7 @@
 pub mod config;
 pub mod email_config;
 pub mod errors;
+pub mod metrics;
 
 // Re-export for convenience
 pub use config::ReplicationConfig;
@@ -0,0 +1,943 @@
+//! Row filters of routed sinks
+//!
+//! A filter is a boolean expression over the columns of a row change, written
+//! in a small SQL-like language:
+//!
+//! ```text
+//! changed(status) AND status = 'shipped'
+//! old.amount < new.amount OR region IN ('eu', 'uk')
+//! email LIKE '%@example.com' AND deleted_at IS NULL AND NOT archived
+//! ```
+//!
+//! `new.col` is the row after the change and `old.col` the row before it; a
+//! bare `col` is the new row, or the old one for deletes. Unquoted names are
+//! folded to lower case as in SQL, `"Name"` keeps its case.
+//!
+//! Evaluation follows SQL's three-valued logic: comparing with NULL, or with a
+//! value walpipe does not know (a column the table lacks, an old row the
+//! replica identity does not send, an unchanged TOAST value), is unknown, and
+//! a row only matches when the whole expression is true.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use super::sink::pg_type_conversion::{ColumnValue, decode_column};
+use crate::protocol::messages::{RelationCache, RelationInfo, ReplicationMessage, TupleData};
+
+/// Predicate on row changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowFilter {
+    expression: Expr,
+    source: String,
+}
+
+impl RowFilter {
+    /// Whether `message` passes the filter
+    ///
+    /// Only row changes are filtered; truncates and other messages always
+    /// pass. A change of a relation missing from `relations` does not.
+    pub fn matches(&self, message: &ReplicationMessage, relations: &RelationCache) -> bool {
+        let (relation_id, change) = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            }
+            | ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                ..
+            } => (*relation_id, Change::Insert { new: tuple_data }),
+            ReplicationMessage::Update {
+                relation_id,
+                key_type,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => (
+                *relation_id,
+                Change::Update {
+                    old: old_tuple_data
+                        .as_ref()
+                        .map(|old| (old, *key_type == Some('K'))),
+                    new: new_tuple_data,
+                },
+            ),
+            ReplicationMessage::Delete {
+                relation_id,
+                key_type,
+                tuple_data,
+                ..
+            } => (
+                *relation_id,
+                Change::Delete {
+                    old: (tuple_data, *key_type == 'K'),
+                },
+            ),
+            _ => return true,
+        };
+        let Some(relation) = relations.get(relation_id) else {
+            return false;
+        };
+        let row = Row {
+            relation: &relation,
+            change,
+        };
+        self.expression.evaluate(&row) == Some(true)
+    }
+}
+
+impl FromStr for RowFilter {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let expression = parser.expression()?;
+        if let Some((token, offset)) = parser.tokens.get(parser.position) {
+            return Err(format!("unexpected {} at position {}", token, offset));
+        }
+        Ok(Self {
+            expression,
+            source: source.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for RowFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Row image a column is read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Image {
+    Old,
+    New,
+    /// New row, or old row of a delete
+    Current,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(Number),
+    Text(String),
+}
+
+impl Value {
+    fn from_column(value: &ColumnValue) -> Self {
+        match value {
+            ColumnValue::Int(value) => Value::Number(Number::Int(*value)),
+            ColumnValue::Float(value) => Value::Number(Number::Float(*value)),
+            ColumnValue::Numeric(value) => Number::parse(value)
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::Text(value.clone())),
+            ColumnValue::Bool(value) => Value::Bool(*value),
+            ColumnValue::String(value) => Value::Text(value.clone()),
+            ColumnValue::Unchanged => Value::Null,
+            other => Value::Text(other.try_into().unwrap_or_default()),
+        }
+    }
+
+    /// SQL ordering of two values, `None` if either is NULL or they cannot be compared
+    ///
+    /// Text compares with a number as the number it spells.
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.compare(b),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (Value::Number(a), Value::Text(b)) => a.compare(&Number::parse(b)?),
+            (Value::Text(a), Value::Number(b)) => Number::parse(a)?.compare(b),
+            _ => None,
+        }
+    }
+
+    /// `IS DISTINCT FROM`: inequality where NULL equals NULL
+    fn is_distinct_from(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => false,
+            (Value::Null, _) | (_, Value::Null) => true,
+            _ => self.compare(other) != Some(Ordering::Equal),
+        }
+    }
+
+    fn text(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Bool(value) => Some(value.to_string()),
+            Value::Number(value) => Some(value.to_string()),
+            Value::Text(value) => Some(value.clone()),
+        }
+    }
+}
+
+/// Number compared without rounding
+///
+/// Integers compare as integers and numerics as decimals, digit by digit; only
+/// a comparison involving a float is done in floating point, as in PostgreSQL.
+#[derive(Debug, Clone, PartialEq)]
+enum Number {
+    Int(i64),
+    /// Decimal number in plain notation, as written
+    Decimal(String),
+    Float(f64),
+}
+
+impl Number {
+    /// Reads an integer, a decimal, or a float such as `1e10` or `NaN`
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Ok(value) = text.parse() {
+            Some(Number::Int(value))
+        } else if decimal_parts(text).is_some() {
+            Some(Number::Decimal(text.to_string()))
+        } else {
+            text.parse().ok().map(Number::Float)
+        }
+    }
+
+    fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(b)),
+            (Number::Float(a), b) => a.partial_cmp(&b.to_f64()),
+            (a, Number::Float(b)) => a.to_f64().partial_cmp(b),
+            (a, b) => compare_decimals(&a.to_string(), &b.to_string()),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(value) => *value as f64,
+            Number::Decimal(value) => value.parse().unwrap_or(f64::NAN),
+            Number::Float(value) => *value,
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Int(value) => write!(f, "{}", value),
+            Number::Decimal(value) => write!(f, "{}", value),
+            Number::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Sign, integer digits without leading zeros and fraction digits without
+/// trailing zeros of a decimal number in plain notation
+fn decimal_parts(text: &str) -> Option<(bool, &str, &str)> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if integer.is_empty() && fraction.is_empty()
+        || !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+    let integer = integer.trim_start_matches('0');
+    let fraction = fraction.trim_end_matches('0');
+    let zero = integer.is_empty() && fraction.is_empty();
+    Some((negative && !zero, integer, fraction))
+}
+
+/// Exact order of two decimal numbers in plain notation
+fn compare_decimals(a: &str, b: &str) -> Option<Ordering> {
+    let (a_negative, a_integer, a_fraction) = decimal_parts(a)?;
+    let (b_negative, b_integer, b_fraction) = decimal_parts(b)?;
+    let magnitude = a_integer
+        .len()
+        .cmp(&b_integer.len())
+        .then_with(|| a_integer.cmp(b_integer))
+        .then_with(|| a_fraction.cmp(b_fraction));
+    Some(match (a_negative, b_negative) {
+        (false, false) => magnitude,
+        (true, true) => magnitude.reverse(),
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Column { image: Image, name: String },
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Comparison, Operand),
+    IsNull(Operand),
+    In(Operand, Vec<Value>),
+    Like(Operand, String),
+    Changed(String),
+    /// Boolean column used as a condition
+    Truth(Operand),
+}
+
+impl Expr {
+    /// Truth value of the expression, `None` when unknown
+    fn evaluate(&self, row: &Row) -> Option<bool> {
+        match self {
+            Expr::And(left, right) => match (left.evaluate(row), right.evaluate(row)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(left, right) => match (left.evaluate(row), right.evaluate(row)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Not(expression) => expression.evaluate(row).map(|value| !value),
+            Expr::Compare(left, comparison, right) => {
+                let ordering = row.operand(left)?.compare(&row.operand(right)?)?;
+                Some(match comparison {
+                    Comparison::Equal => ordering == Ordering::Equal,
+                    Comparison::NotEqual => ordering != Ordering::Equal,
+                    Comparison::Less => ordering == Ordering::Less,
+                    Comparison::LessOrEqual => ordering != Ordering::Greater,
+                    Comparison::Greater => ordering == Ordering::Greater,
+                    Comparison::GreaterOrEqual => ordering != Ordering::Less,
+                })
+            }
+            Expr::IsNull(operand) => Some(row.operand(operand)? == Value::Null),
+            Expr::In(operand, values) => {
+                let value = row.operand(operand)?;
+                let mut unknown = false;
+                for candidate in values {
+                    match value.compare(candidate) {
+                        Some(Ordering::Equal) => return Some(true),
+                        Some(_) => {}
+                        None => unknown = true,
+                    }
+                }
+                (!unknown).then_some(false)
+            }
+            Expr::Like(operand, pattern) => {
+                let text = row.operand(operand)?.text()?;
+                let text: Vec<char> = text.chars().collect();
+                let pattern: Vec<char> = pattern.chars().collect();
+                Some(like_matches(&pattern, &text))
+            }
+            Expr::Changed(name) => row.changed(name),
+            Expr::Truth(operand) => match row.operand(operand)? {
+                Value::Bool(value) => Some(value),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Matches `text` against a LIKE pattern where `%` is any sequence, `_` any
+/// character and `\` escapes the next one
+fn like_matches(pattern: &[char], text: &[char]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some('%'), _) => {
+            like_matches(&pattern[1..], text)
+                || (!text.is_empty() && like_matches(pattern, &text[1..]))
+        }
+        (Some('_'), Some(_)) => like_matches(&pattern[1..], &text[1..]),
+        (Some('\\'), Some(actual)) if pattern.get(1) == Some(actual) => {
+            like_matches(&pattern[2..], &text[1..])
+        }
+        (Some(expected), Some(actual)) if *expected != '\\' && expected == actual => {
+            like_matches(&pattern[1..], &text[1..])
+        }
+        _ => false,
+    }
+}
+
+/// Row images of a change
+enum Change<'a> {
+    /// Insert or snapshot read
+    Insert {
+        new: &'a TupleData,
+    },
+    /// The old row is only sent when the replica identity requires it, and
+    /// only holds key columns when flagged
+    Update {
+        old: Option<(&'a TupleData, bool)>,
+        new: &'a TupleData,
+    },
+    Delete {
+        old: (&'a TupleData, bool),
+    },
+}
+
+/// Change of a known relation, the filter's evaluation context
+struct Row<'a> {
+    relation: &'a RelationInfo,
+    change: Change<'a>,
+}
+
+impl Row<'_> {
+    fn operand(&self, operand: &Operand) -> Option<Value> {
+        match operand {
+            Operand::Column { image, name } => self.column(*image, name),
+            Operand::Literal(value) => Some(value.clone()),
+        }
+    }
+
+    /// Value of a column, `None` when unknown
+    fn column(&self, image: Image, name: &str) -> Option<Value> {
+        let index = self
+            .relation
+            .columns
+            .iter()
+            .position(|column| column.column_name == name)?;
+        let (old, new) = match &self.change {
+            Change::Insert { new } => (None, Some(*new)),
+            // Without an old row, an update's old values are unknown
+            Change::Update { old: None, .. } if image == Image::Old => return None,
+            Change::Update { old, new } => (*old, Some(*new)),
+            Change::Delete { old } => (Some(*old), None),
+        };
+        let image = match image {
+            Image::Current if new.is_none() => Image::Old,
+            Image::Current => Image::New,
+            image => image,
+        };
+
+        let value = match image {
+            Image::Old => {
+                let Some((tuple, key_only)) = old else {
+                    return Some(Value::Null);
+                };
+                if key_only && self.relation.columns[index].key_flag & 1 == 0 {
+                    return None;
+                }
+                self.decode(tuple, index)?
+            }
+            _ => {
+                let Some(tuple) = new else {
+                    return Some(Value::Null);
+                };
+                let value = self.decode(tuple, index)?;
+                if matches!(value, Some(ColumnValue::Unchanged)) {
+                    // An unchanged TOAST value is only known from the old row
+                    return match old {
+                        Some(_) => self.column(Image::Old, name),
+                        None => None,
+                    };
+                }
+                value
+            }
+        };
+        match value {
+            Some(ColumnValue::Unchanged) => None,
+            Some(value) => Some(Value::from_column(&value)),
+            None => Some(Value::Null),
+        }
+    }
+
+    /// Decoded column of a tuple, `Some(None)` for NULL
+    fn decode(&self, tuple: &TupleData, index: usize) -> Option<Option<ColumnValue>> {
+        let column = tuple.columns.get(index)?;
+        Some(decode_column(
+            self.relation.columns[index].column_type,
+            column,
+        ))
+    }
+
+    /// Whether an update changed the column; other changes never do
+    fn changed(&self, name: &str) -> Option<bool> {
+        let Change::Update { new, .. } = self.change else {
+            return Some(false);
+        };
+        let index = self
+            .relation
+            .columns
+            .iter()
+            .position(|column| column.column_name == name)?;
+        if new.columns.get(index)?.data_type == 'u' {
+            return Some(false);
+        }
+        let old = self.column(Image::Old, name)?;
+        let new = self.column(Image::New, name)?;
+        Some(old.is_distinct_from(&new))
+    }
+}
+
+/// Operators and punctuation, two-character ones first
+const SYMBOLS: [&str; 11] = ["<=", ">=", "<>", "!=", "=", "<", ">", "(", ")", ",", "."];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    QuotedIdentifier(String),
+    String(String),
+    Number(Number),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "'{}'", name),
+            Token::QuotedIdentifier(name) => write!(f, "'\"{}\"'", name),
+            Token::String(value) => write!(f, "string '{}'", value),
+            Token::Number(value) => write!(f, "number {}", value),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+/// Splits a filter into tokens, each with its character offset
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = if c == '\'' || c == '"' {
+            // Quotes are escaped by doubling them, as in SQL
+            let mut value = String::new();
+            loop {
+                i += 1;
+                match chars.get(i) {
+                    None => return Err(format!("unterminated quote at position {}", start)),
+                    Some(&quote) if quote == c && chars.get(i + 1) == Some(&c) => {
+                        value.push(c);
+                        i += 1;
+                    }
+                    Some(&quote) if quote == c => break,
+                    Some(&other) => value.push(other),
+                }
+            }
+            i += 1;
+            if c == '\'' {
+                Token::String(value)
+            } else {
+                Token::QuotedIdentifier(value)
+            }
+        } else if c.is_ascii_digit()
+            || (c == '-' && chars.get(i + 1).is_some_and(|next| next.is_ascii_digit()))
+        {
+            i += 1;
+            while chars
+                .get(i)
+                .is_some_and(|next| next.is_ascii_digit() || *next == '.')
+            {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            Token::Number(
+                Number::parse(&number)
+                    .ok_or_else(|| format!("invalid number {} at position {}", number, start))?,
+            )
+        } else if c.is_alphabetic() || c == '_' {
+            while chars
+                .get(i)
+                .is_some_and(|next| next.is_alphanumeric() || *next == '_')
+            {
+                i += 1;
+            }
+            Token::Identifier(chars[start..i].iter().collect())
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS
+                .into_iter()
+                .find(|symbol| rest.starts_with(symbol))
+                .ok_or_else(|| format!("unexpected '{}' at position {}", c, start))?;
+            i += symbol.len();
+            Token::Symbol(symbol)
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, from the loosest operator to the tightest:
+/// `OR`, `AND`, `NOT`, then predicates
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<&Token> {
+        self.tokens
+            .get(self.position + ahead)
+            .map(|(token, _)| token)
+    }
+
+    fn peek_symbol(&self, ahead: usize, symbol: &str) -> bool {
+        matches!(self.peek_at(ahead), Some(Token::Symbol(found)) if *found == symbol)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(name)) if name.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.peek_symbol(0, symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", symbol)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        match self.tokens.get(self.position) {
+            Some((token, offset)) => format!(
+                "expected {} at position {}, found {}",
+                expected, offset, token
+            ),
+            None => format!("expected {} at the end of the filter", expected),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut expression = self.conjunction()?;
+        while self.eat_keyword("OR") {
+            expression = Expr::Or(Box::new(expression), Box::new(self.conjunction()?));
+        }
+        Ok(expression)
+    }
+
+    fn conjunction(&mut self) -> Result<Expr, String> {
+        let mut expression = self.negation()?;
+        while self.eat_keyword("AND") {
+            expression = Expr::And(Box::new(expression), Box::new(self.negation()?));
+        }
+        Ok(expression)
+    }
+
+    fn negation(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.negation()?)));
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr, String> {
+        if self.eat_symbol("(") {
+            let expression = self.expression()?;
+            self.expect_symbol(")")?;
+            return Ok(expression);
+        }
+        if self.peek_keyword("changed") && self.peek_symbol(1, "(") {
+            self.position += 2;
+            let name = self.column_name()?;
+            self.expect_symbol(")")?;
+            return Ok(Expr::Changed(name));
+        }
+
+        let operand = self.operand()?;
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            if !self.eat_keyword("NULL") {
+                return Err(self.unexpected("NULL"));
+            }
+            return Ok(negate(Expr::IsNull(operand), negated));
+        }
+        let negated = self.eat_keyword("NOT");
+        if self.eat_keyword("IN") {
+            self.expect_symbol("(")?;
+            let mut values = vec![self.literal()?];
+            while self.eat_symbol(",") {
+                values.push(self.literal()?);
+            }
+            self.expect_symbol(")")?;
+            return Ok(negate(Expr::In(operand, values), negated));
+        }
+        if self.eat_keyword("LIKE") {
+            let Some(Token::String(pattern)) = self.peek().cloned() else {
+                return Err(self.unexpected("a quoted pattern"));
+            };
+            self.position += 1;
+            return Ok(negate(Expr::Like(operand, pattern), negated));
+        }
+        if negated {
+            return Err(self.unexpected("IN or LIKE"));
+        }
+
+        let comparison = match self.peek() {
+            Some(Token::Symbol("=")) => Comparison::Equal,
+            Some(Token::Symbol("!=" | "<>")) => Comparison::NotEqual,
+            Some(Token::Symbol("<")) => Comparison::Less,
+            Some(Token::Symbol("<=")) => Comparison::LessOrEqual,
+            Some(Token::Symbol(">")) => Comparison::Greater,
+            Some(Token::Symbol(">=")) => Comparison::GreaterOrEqual,
+            _ => return Ok(Expr::Truth(operand)),
+        };
+        self.position += 1;
+        Ok(Expr::Compare(operand, comparison, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.peek_literal()
+            || !matches!(
+                self.peek(),
+                Some(Token::Identifier(_) | Token::QuotedIdentifier(_))
+            )
+        {
+            return Ok(Operand::Literal(self.literal()?));
+        }
+        let image = match self.peek() {
+            Some(Token::Identifier(name)) if self.peek_symbol(1, ".") => {
+                match name.to_lowercase().as_str() {
+                    "old" => Image::Old,
+                    "new" => Image::New,
+                    _ => return Err(self.unexpected("a column name, old.column or new.column")),
+                }
+            }
+            _ => Image::Current,
+        };
+        if image != Image::Current {
+            self.position += 2;
+        }
+        Ok(Operand::Column {
+            image,
+            name: self.column_name()?,
+        })
+    }
+
+    fn peek_literal(&self) -> bool {
+        ["true", "false", "null"]
+            .iter()
+            .any(|keyword| self.peek_keyword(keyword))
+    }
+
+    fn column_name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = name.to_lowercase();
+                self.position += 1;
+                Ok(name)
+            }
+            Some(Token::QuotedIdentifier(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a column name")),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        let value = match self.peek() {
+            Some(Token::String(value)) => Value::Text(value.clone()),
+            Some(Token::Number(value)) => Value::Number(value.clone()),
+            _ if self.peek_keyword("true") => Value::Bool(true),
+            _ if self.peek_keyword("false") => Value::Bool(false),
+            _ if self.peek_keyword("null") => Value::Null,
+            _ => return Err(self.unexpected("a value")),
+        };
+        self.position += 1;
+        Ok(value)
+    }
+}
+
+fn negate(expression: Expr, negated: bool) -> Expr {
+    if negated {
+        Expr::Not(Box::new(expression))
+    } else {
+        expression
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{
+        delete, insert, keyed_relation, null, row, text, tuple, unchanged, update,
+    };
+
+    fn relations() -> RelationCache {
+        let relations = RelationCache::new();
+        relations.insert(keyed_relation(
+            1,
+            "public",
+            "orders",
+            &["id", "status", "email"],
+        ));
+        relations
+    }
+
+    fn matches(filter: &str, message: &ReplicationMessage) -> bool {
+        filter
+            .parse::<RowFilter>()
+            .unwrap()
+            .matches(message, &relations())
+    }
+
+    #[test]
+    fn test_filter_update_images() {
+        let shipped = update(
+            1,
+            Some(row(&[Some("7"), Some("paid"), None])),
+            row(&[Some("7"), Some("shipped"), None]),
+        );
+        assert!(matches("changed(status) AND status = 'shipped'", &shipped));
+        assert!(matches(
+            "old.status = 'paid' and new.status <> 'paid'",
+            &shipped
+        ));
+        assert!(matches(
+            "NOT changed(id) AND id >= 5 AND email IS NULL",
+            &shipped
+        ));
+        assert!(matches("status IN ('shipped', 'delivered')", &shipped));
+        assert!(!matches("status NOT IN ('shipped', 'delivered')", &shipped));
+        assert!(matches(
+            "status LIKE 'sh%' AND status NOT LIKE '_aid'",
+            &shipped
+        ));
+        // Comparisons with NULL are unknown, even negated
+        assert!(!matches("email = 'a@example.com'", &shipped));
+        assert!(!matches("NOT email = 'a@example.com'", &shipped));
+        // Columns the table lacks are unknown
+        assert!(!matches("amount IS NULL", &shipped));
+
+        // Without an old row, whether the status changed is unknown
+        let no_identity = update(1, None, row(&[Some("7"), Some("shipped"), None]));
+        assert!(!matches("changed(status)", &no_identity));
+        assert!(matches("changed(status) OR id = 7", &no_identity));
+        // An unchanged TOAST value did not change
+        let toasted = update(1, None, tuple(vec![text("7"), unchanged(), null()]));
+        assert!(matches("NOT changed(status)", &toasted));
+    }
+
+    #[test]
+    fn test_filter_insert_and_delete() {
+        let insert = insert(1, row(&[Some("7"), Some("new"), Some("a@example.com")]));
+        assert!(matches(
+            "old.status IS NULL AND NOT changed(status)",
+            &insert
+        ));
+        assert!(matches("email LIKE '%@example.com'", &insert));
+
+        // A delete's bare columns are its old row, limited to the key by default
+        let delete = delete(1, row(&[Some("7"), None, None]));
+        assert!(matches("id = 7 AND new.id IS NULL", &delete));
+        assert!(!matches("status IS NULL", &delete));
+
+        // Only row changes are filtered
+        assert!(matches("id = 8", &ReplicationMessage::StreamStop));
+    }
+
+    #[test]
+    fn test_filter_compares_numbers_exactly() {
+        use crate::protocol::fixtures::{column, relation};
+
+        let relations = RelationCache::new();
+        relations.insert(relation(
+            2,
+            "public",
+            "ledger",
+            vec![
+                column("id", 20, true),
+                column("amount", 1700, false),
+                column("ratio", 701, false),
+            ],
+        ));
+        let entry = insert(
+            2,
+            row(&[
+                Some("9007199254740993"),
+                Some("0.30000000000000000001"),
+                Some("0.5"),
+            ]),
+        );
+        let matches = |filter: &str| {
+            filter
+                .parse::<RowFilter>()
+                .unwrap()
+                .matches(&entry, &relations)
+        };
+
+        // Beyond 2^53, where f64 no longer tells the integers apart
+        assert!(matches("id = 9007199254740993"));
+        assert!(!matches("id = 9007199254740992"));
+        assert!(matches("id > '9007199254740992'"));
+        // Numerics keep all their digits
+        assert!(matches("amount > 0.3 AND amount < 0.30000000000000000002"));
+        assert!(matches("amount <> 0.300000000000000000010001"));
+        assert!(matches("amount > -1.5 AND -0.0 < amount AND amount <> 0"));
+        assert!(matches("ratio = 0.50 AND ratio < 1"));
+    }
+
+    #[test]
+    fn test_filter_syntax_errors() {
+        for (filter, error) in [
+            ("status = ", "expected a value at the end of the filter"),
+            ("status == 'a'", "expected a value at position 8, found '='"),
+            ("(id = 1", "expected ')' at the end of the filter"),
+            (
+                "status LIKE 5",
+                "expected a quoted pattern at position 12, found number 5",
+            ),
+            ("name = 'o''brien", "unterminated quote at position 7"),
+            ("id = 1 id", "unexpected 'id' at position 7"),
+            (
+                "orders.id = 1",
+                "expected a column name, old.column or new.column at position 0, found 'orders'",
+            ),
+        ] {
+            assert_eq!(
+                filter.parse::<RowFilter>().unwrap_err(),
+                error,
+                "{}",
+                filter
+            );
+        }
+        assert_eq!(
+            r#""Status" = 'a' OR TRUE"#.parse::<RowFilter>().unwrap().to_string(),
+            r#""Status" = 'a' OR TRUE"#
+        );
+    }
+}
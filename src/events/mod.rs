@@ -3,19 +3,45 @@
 //! This module contains all components for handling and processing replication events,
 //! including different event sinks (HTTP, Hook0, STDOUT) and event formatting.
 
-use async_trait::async_trait;
 use crate::core::errors::ReplicationResult;
 use crate::protocol::messages::ReplicationMessage;
+use async_trait::async_trait;
 
-pub mod sink;
+pub mod filter;
+#[cfg(feature = "wasm")]
+pub mod plugin;
 pub mod processors;
+pub mod retry;
+pub mod router;
+pub mod script;
+pub mod sink;
+pub mod transaction;
+pub mod transform;
 
 // Re-export for convenience
 pub use sink::EventSinkRegistry;
+pub use transaction::TransactionBatch;
 
 /// EventSink trait for common event sending functionality
 #[async_trait]
 pub trait EventSink: Send + Sync {
     /// Send a replication event
     async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()>;
-}
\ No newline at end of file
+
+    /// Send a whole transaction as one envelope
+    ///
+    /// The default implementation falls back to `send_event` for every message
+    /// of the transaction, Begin and Commit included.
+    async fn send_transaction(&self, transaction: &TransactionBatch) -> ReplicationResult<()> {
+        for message in transaction.messages() {
+            self.send_event(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Report an event given up on after its retries were exhausted
+    ///
+    /// Called by [`retry::RetryingSink`]; sinks with a notification channel
+    /// override it, the default does nothing.
+    async fn notify_failure(&self, _message: &str) {}
+}
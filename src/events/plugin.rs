@@ -0,0 +1,435 @@
+//! WebAssembly plugins, run with wasmtime as WASI components
+//!
+//! A plugin is a component implementing a world of `wit/plugin.wit`: a `sink`
+//! receiving formatted events like the built-in sinks, or a `transform`
+//! rewriting row changes like an event script. Plugins run in a WASI sandbox
+//! without environment variables, files or network, with their stderr going
+//! to walpipe's; each sink may be granted network access on its own.
+//!
+//! An instance is kept across calls, so plugins can hold state, and replaced
+//! after a trap. Each call is limited in fuel, so that a runaway plugin fails
+//! the event instead of stalling replication: running out of fuel traps.
+
+use async_trait::async_trait;
+use std::path::Path;
+use tokio::sync::Mutex;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime::{Config, Engine, Store};
+use wasmtime_wasi::{IoView, WasiCtx, WasiCtxBuilder, WasiView};
+
+use super::sink::event_formatter::SinkFormatter;
+use super::{EventSink, TransactionBatch};
+use crate::core::errors::{DeliveryAttempt, ReplicationError, ReplicationResult};
+use crate::protocol::messages::ReplicationMessage;
+
+mod bindings {
+    pub mod sink {
+        wasmtime::component::bindgen!({ path: "wit/plugin.wit", world: "sink", async: true });
+    }
+    pub mod transform {
+        wasmtime::component::bindgen!({ path: "wit/plugin.wit", world: "transform", async: true });
+    }
+}
+
+use bindings::sink::{Outcome, Sink, SinkPre};
+use bindings::transform::{Transform, TransformPre};
+
+/// Fuel a single call may consume, about one unit per WebAssembly instruction
+const FUEL_PER_CALL: u64 = 1_000_000_000;
+
+/// Fuel consumed between yields to the runtime, so that a long call does not
+/// hold up the other tasks
+const FUEL_YIELD_INTERVAL: u64 = 10_000_000;
+
+/// Host side of a plugin instance
+struct PluginState {
+    ctx: WasiCtx,
+    table: ResourceTable,
+}
+
+impl IoView for PluginState {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+}
+
+impl WasiView for PluginState {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+/// Compiles the component at `path` and links it with WASI
+fn load_component(path: &Path) -> ReplicationResult<(Engine, Component, Linker<PluginState>)> {
+    let error = |e: wasmtime::Error| {
+        ReplicationError::config(format!(
+            "Failed to load WebAssembly plugin {}: {:#}",
+            path.display(),
+            e
+        ))
+    };
+    let mut config = Config::new();
+    config.async_support(true).consume_fuel(true);
+    let engine = Engine::new(&config).map_err(error)?;
+    let component = Component::from_file(&engine, path).map_err(error)?;
+    let mut linker = Linker::new(&engine);
+    wasmtime_wasi::add_to_linker_async(&mut linker).map_err(error)?;
+    Ok((engine, component, linker))
+}
+
+/// Store of a new instance, in a sandbox of its own
+fn new_store(engine: &Engine, allow_network: bool) -> wasmtime::Result<Store<PluginState>> {
+    let mut wasi = WasiCtxBuilder::new();
+    wasi.inherit_stderr();
+    if allow_network {
+        wasi.inherit_network().allow_ip_name_lookup(true);
+    } else {
+        wasi.allow_tcp(false).allow_udp(false);
+    }
+    let state = PluginState {
+        ctx: wasi.build(),
+        table: ResourceTable::new(),
+    };
+    let mut store = Store::new(engine, state);
+    store.fuel_async_yield_interval(Some(FUEL_YIELD_INTERVAL))?;
+    Ok(store)
+}
+
+/// Event sink handing events to a plugin implementing the `sink` world
+pub struct WasmEventSink {
+    pre: SinkPre<PluginState>,
+    allow_network: bool,
+    formatter: SinkFormatter,
+    instance: Mutex<Option<(Store<PluginState>, Sink)>>,
+}
+
+impl WasmEventSink {
+    /// Loads the plugin at `path`, which must export the functions of the `sink` world
+    pub fn load(
+        path: &Path,
+        allow_network: bool,
+        formatter: SinkFormatter,
+    ) -> ReplicationResult<Self> {
+        let (_, component, linker) = load_component(path)?;
+        let pre = linker
+            .instantiate_pre(&component)
+            .and_then(SinkPre::new)
+            .map_err(|e| {
+                ReplicationError::config(format!(
+                    "{} is not a walpipe sink plugin: {:#}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        Ok(Self {
+            pre,
+            allow_network,
+            formatter,
+            instance: Mutex::new(None),
+        })
+    }
+
+    /// Hands one formatted event or transaction to the plugin
+    ///
+    /// Failures are returned as delivery errors: permanent when the plugin
+    /// rejects the event or traps, to be retried when it asks to. A trap,
+    /// running out of fuel included, would most likely recur on every attempt.
+    async fn deliver(&self, event: &serde_json::Value, transaction: bool) -> ReplicationResult<()> {
+        let (message, permanent, reason) = match self.call(&event.to_string(), transaction).await {
+            Ok(Outcome::Accept) => return Ok(()),
+            Ok(Outcome::Reject(reason)) => (
+                format!("WebAssembly plugin rejected the event: {}", reason),
+                true,
+                reason,
+            ),
+            Ok(Outcome::Retry(reason)) => (
+                format!("WebAssembly plugin failed to deliver the event: {}", reason),
+                false,
+                reason,
+            ),
+            Err(e) => {
+                let reason = format!("WebAssembly plugin failed: {:#}", e);
+                (reason.clone(), true, reason)
+            }
+        };
+        Err(ReplicationError::delivery(
+            "wasm",
+            message,
+            permanent,
+            vec![DeliveryAttempt::failed(reason)],
+        ))
+    }
+
+    async fn call(&self, event: &str, transaction: bool) -> wasmtime::Result<Outcome> {
+        let mut instance = self.instance.lock().await;
+        let (mut store, sink) = match instance.take() {
+            Some(instance) => instance,
+            None => {
+                let mut store = new_store(self.pre.engine(), self.allow_network)?;
+                let sink = self.pre.instantiate_async(&mut store).await?;
+                (store, sink)
+            }
+        };
+
+        store.set_fuel(FUEL_PER_CALL)?;
+        let outcome = if transaction {
+            sink.call_send_transaction(&mut store, event).await
+        } else {
+            sink.call_send_event(&mut store, event).await
+        };
+        // A trapped instance cannot be entered again
+        if outcome.is_ok() {
+            *instance = Some((store, sink));
+        }
+        outcome
+    }
+}
+
+#[async_trait]
+impl EventSink for WasmEventSink {
+    async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()> {
+        for json_event in self.formatter.format(event) {
+            self.deliver(&json_event, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_transaction(&self, transaction: &TransactionBatch) -> ReplicationResult<()> {
+        let json_event = self.formatter.format_transaction(transaction);
+        self.deliver(&json_event, true).await
+    }
+}
+
+/// Plugin implementing the `transform` world, run in place of a Rhai script
+///
+/// Transforms never have network access.
+pub struct WasmTransform {
+    pre: TransformPre<PluginState>,
+    instance: Option<(Store<PluginState>, Transform)>,
+}
+
+impl WasmTransform {
+    /// Loads the plugin at `path`, which must export the function of the `transform` world
+    pub fn load(path: &Path) -> ReplicationResult<Self> {
+        let (_, component, linker) = load_component(path)?;
+        let pre = linker
+            .instantiate_pre(&component)
+            .and_then(TransformPre::new)
+            .map_err(|e| {
+                ReplicationError::config(format!(
+                    "{} is not a walpipe transform plugin: {:#}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        Ok(Self {
+            pre,
+            instance: None,
+        })
+    }
+
+    /// JSON the plugin returns for the JSON of an event, or the error it
+    /// returned or trapped with
+    pub async fn transform(&mut self, event: &str) -> Result<String, String> {
+        match self.call(event).await {
+            Ok(result) => result,
+            Err(e) => Err(format!("WebAssembly plugin failed: {:#}", e)),
+        }
+    }
+
+    async fn call(&mut self, event: &str) -> wasmtime::Result<Result<String, String>> {
+        let (mut store, transform) = match self.instance.take() {
+            Some(instance) => instance,
+            None => {
+                let mut store = new_store(self.pre.engine(), false)?;
+                let transform = self.pre.instantiate_async(&mut store).await?;
+                (store, transform)
+            }
+        };
+
+        store.set_fuel(FUEL_PER_CALL)?;
+        let result = transform.call_transform(&mut store, event).await;
+        // A trapped instance cannot be entered again
+        if result.is_ok() {
+            self.instance = Some((store, transform));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Core module shared by the fixtures: `accept` returns the first case of
+    /// a result, `echo` and `retry` return their input as the payload of the
+    /// second and third case, and `trap` traps
+    const CORE_MODULE: &str = r#"
+        (core module $m
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 1024))
+            (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $heap))
+                (global.set $heap (i32.add (global.get $heap) (local.get 3)))
+                (local.get $ptr))
+            (func (export "accept") (param i32 i32) (result i32)
+                (i32.store8 (i32.const 16) (i32.const 0))
+                (i32.const 16))
+            (func (export "echo") (param i32 i32) (result i32)
+                (i32.store8 (i32.const 16) (i32.const 1))
+                (i32.store (i32.const 20) (local.get 0))
+                (i32.store (i32.const 24) (local.get 1))
+                (i32.const 16))
+            (func (export "retry") (param i32 i32) (result i32)
+                (i32.store8 (i32.const 16) (i32.const 2))
+                (i32.store (i32.const 20) (local.get 0))
+                (i32.store (i32.const 24) (local.get 1))
+                (i32.const 16))
+            (func (export "trap") (param i32 i32) (result i32)
+                unreachable))
+        (core instance $i (instantiate $m))
+    "#;
+
+    fn fixture(name: &str, exports: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("walpipe-test-{}-{}.wat", name, std::process::id()));
+        std::fs::write(&path, format!("(component {} {})", CORE_MODULE, exports)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_sink_outcomes() {
+        let path = fixture(
+            "sink",
+            r#"
+            (type $outcome' (variant (case "accept") (case "reject" string) (case "retry" string)))
+            (export $outcome "outcome" (type $outcome'))
+            (func (export "send-event") (param "event" string) (result $outcome)
+                (canon lift (core func $i "accept") (memory $i "memory") (realloc (func $i "realloc"))))
+            (func (export "send-transaction") (param "transaction" string) (result $outcome)
+                (canon lift (core func $i "echo") (memory $i "memory") (realloc (func $i "realloc"))))
+            "#,
+        );
+        let sink = WasmEventSink::load(&path, false, SinkFormatter::Raw).unwrap();
+        let begin = ReplicationMessage::Begin {
+            final_lsn: 0,
+            timestamp: 0,
+            xid: 1,
+        };
+        sink.send_event(&begin).await.unwrap();
+
+        let error = sink
+            .deliver(&serde_json::json!({"xid": 1}), true)
+            .await
+            .unwrap_err();
+        let ReplicationError::Delivery {
+            message, permanent, ..
+        } = error
+        else {
+            panic!("expected a delivery error, got {:?}", error);
+        };
+        assert!(permanent);
+        assert_eq!(
+            message,
+            r#"WebAssembly plugin rejected the event: {"xid":1}"#
+        );
+
+        // A sink plugin is not a transform
+        assert!(WasmTransform::load(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sink_retries_and_traps() {
+        use crate::events::retry::{RetryPolicy, RetryingSink};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let path = fixture(
+            "sink-retry",
+            r#"
+            (type $outcome' (variant (case "accept") (case "reject" string) (case "retry" string)))
+            (export $outcome "outcome" (type $outcome'))
+            (func (export "send-event") (param "event" string) (result $outcome)
+                (canon lift (core func $i "retry") (memory $i "memory") (realloc (func $i "realloc"))))
+            (func (export "send-transaction") (param "transaction" string) (result $outcome)
+                (canon lift (core func $i "trap") (memory $i "memory") (realloc (func $i "realloc"))))
+            "#,
+        );
+        let sink = Arc::new(WasmEventSink::load(&path, false, SinkFormatter::Raw).unwrap());
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let retrying = RetryingSink::new(sink.clone(), policy, Arc::default());
+
+        // A retry is attempted again until the attempts are exhausted
+        let begin = ReplicationMessage::Begin {
+            final_lsn: 0,
+            timestamp: 0,
+            xid: 1,
+        };
+        let error = retrying.send_event(&begin).await.unwrap_err();
+        let ReplicationError::Delivery {
+            permanent,
+            attempts,
+            ..
+        } = error
+        else {
+            panic!("expected a delivery error, got {:?}", error);
+        };
+        assert!(!permanent);
+        assert_eq!(attempts.len(), 3);
+        assert!(sink.instance.lock().await.is_some());
+
+        // A trap fails the event at once and drops the instance
+        let error = sink
+            .deliver(&serde_json::json!({"xid": 1}), true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ReplicationError::Delivery {
+                permanent: true,
+                ..
+            }
+        ));
+        assert!(sink.instance.lock().await.is_none());
+
+        // The next event runs on a new instance
+        let error = sink
+            .deliver(&serde_json::json!({"xid": 2}), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ReplicationError::Delivery {
+                permanent: false,
+                ..
+            }
+        ));
+        assert!(sink.instance.lock().await.is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transform_returns_plugin_result() {
+        let path = fixture(
+            "transform",
+            r#"
+            (func (export "transform") (param "event" string) (result (result string (error string)))
+                (canon lift (core func $i "echo") (memory $i "memory") (realloc (func $i "realloc"))))
+            "#,
+        );
+        let mut transform = WasmTransform::load(&path).unwrap();
+        // The fixture fails with its input
+        assert_eq!(transform.transform("dropped").await.unwrap_err(), "dropped");
+        // The instance is kept across calls
+        assert!(transform.instance.is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
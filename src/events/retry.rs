@@ -0,0 +1,410 @@
+//! Retry policy shared by the event sinks
+//!
+//! Sinks make a single delivery attempt and report a failure as
+//! [`ReplicationError::Delivery`]. [`RetryingSink`] wraps a sink and sends the
+//! event again according to its [`RetryPolicy`], until it is delivered, the
+//! failure is classified as fatal or the attempts are exhausted.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use super::{EventSink, TransactionBatch};
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::ReplicationMessage;
+
+/// How often and how fast a failed delivery is attempted again
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts before giving up, `None` to retry until delivered
+    pub max_attempts: Option<u32>,
+    /// Upper bound of the delay before the first retry
+    pub base_delay: Duration,
+    /// Upper bound of the doubling delay
+    pub max_delay: Duration,
+    /// Response statuses worth retrying; other error statuses are fatal
+    pub retryable_statuses: Vec<RangeInclusive<u16>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(5),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            // Timeouts, rate limiting and server errors
+            retryable_statuses: vec![408..=408, 429..=429, 500..=599],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a response with `status` may succeed if sent again
+    pub fn is_retryable(&self, status: u16) -> bool {
+        self.retryable_statuses
+            .iter()
+            .any(|statuses| statuses.contains(&status))
+    }
+
+    /// Whether no attempt is left after `attempts` failed ones
+    pub fn is_exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+
+    /// Delay before the attempt following the `attempt`th failed one
+    ///
+    /// A `Retry-After` sent by the endpoint is honored up to `max_delay`.
+    /// Otherwise the delay is drawn uniformly between zero and the exponential
+    /// backoff ("full jitter"), so that instances failing together do not retry in step.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let backoff_ms = u64::try_from(backoff.as_millis()).unwrap_or(u64::MAX);
+        Duration::from_millis(rand::random_range(0..=backoff_ms))
+    }
+}
+
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_attempts {
+            Some(max) => write!(f, "{} attempts", max)?,
+            None => write!(f, "unlimited attempts")?,
+        }
+        write!(
+            f,
+            ", backoff {}ms to {}ms",
+            self.base_delay.as_millis(),
+            self.max_delay.as_millis()
+        )
+    }
+}
+
+/// Parses a `Retry-After` header: a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means the endpoint is ready now
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// How often the wait before a retry checks for shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Event sink sending events again according to a [`RetryPolicy`]
+///
+/// A retry sends the whole event or transaction again, so the endpoint may
+/// receive parts of it twice when one event is made of several requests.
+/// Retrying stops once `shutdown_signal` is set, failing the event so that it
+/// is streamed again on the next start.
+pub struct RetryingSink {
+    sink: Arc<dyn EventSink + Send + Sync>,
+    policy: RetryPolicy,
+    shutdown_signal: Arc<AtomicBool>,
+    skip_unreachable: bool,
+}
+
+impl RetryingSink {
+    pub fn new(
+        sink: Arc<dyn EventSink + Send + Sync>,
+        policy: RetryPolicy,
+        shutdown_signal: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            sink,
+            policy,
+            shutdown_signal,
+            skip_unreachable: false,
+        }
+    }
+
+    /// Skip events whose last attempt got no response once their retries are
+    /// exhausted, instead of failing delivery
+    pub fn with_skip_unreachable(mut self, skip_unreachable: bool) -> Self {
+        self.skip_unreachable = skip_unreachable;
+        self
+    }
+
+    /// Waits for `delay`, returning false when shutdown was requested meanwhile
+    async fn wait(&self, delay: Duration) -> bool {
+        let mut remaining = delay;
+        while !self.shutdown_signal.load(Ordering::SeqCst) {
+            if remaining.is_zero() {
+                return true;
+            }
+            let step = remaining.min(SHUTDOWN_POLL_INTERVAL);
+            tokio::time::sleep(step).await;
+            remaining -= step;
+        }
+        false
+    }
+
+    async fn retry<'a, F, Fut>(&'a self, send: F) -> ReplicationResult<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = ReplicationResult<()>> + 'a,
+    {
+        let mut attempts = Vec::new();
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let (message, sink, permanent, failed) = match send().await {
+                Ok(()) => return Ok(()),
+                Err(ReplicationError::Delivery {
+                    message,
+                    sink,
+                    permanent,
+                    attempts,
+                }) => (message, sink, permanent, attempts),
+                Err(e) => return Err(e),
+            };
+
+            let last = failed.last();
+            let retry_after = last.and_then(|attempt| attempt.retry_after);
+            let status = last.and_then(|attempt| attempt.status);
+            let unreachable = last.is_some() && status.is_none();
+            let permanent =
+                permanent || status.is_some_and(|status| !self.policy.is_retryable(status));
+            attempts.extend(failed);
+
+            if permanent {
+                error!(
+                    "{} sink rejected the event, not retrying: {}",
+                    sink, message
+                );
+                return Err(ReplicationError::delivery(&sink, message, true, attempts));
+            }
+            if self.policy.is_exhausted(attempt) {
+                let message = format!(
+                    "Failed to send replication event after {} attempts: {}",
+                    attempt, message
+                );
+                error!("{}", message);
+                self.sink.notify_failure(&message).await;
+                if self.skip_unreachable && unreachable {
+                    warn!("Skipping the event {} sink could not reach", sink);
+                    return Ok(());
+                }
+                return Err(ReplicationError::delivery(&sink, message, false, attempts));
+            }
+
+            let delay = self.policy.delay(attempt, retry_after);
+            match self.policy.max_attempts {
+                Some(max) => warn!(
+                    "{} sink delivery failed, retrying in {}ms (attempt {}/{}): {}",
+                    sink,
+                    delay.as_millis(),
+                    attempt,
+                    max,
+                    message
+                ),
+                None => warn!(
+                    "{} sink delivery failed, retrying in {}ms (attempt {}): {}",
+                    sink,
+                    delay.as_millis(),
+                    attempt,
+                    message
+                ),
+            }
+            if !self.wait(delay).await {
+                // Not a delivery error, so that the event is not dead-lettered
+                return Err(ReplicationError::Sink {
+                    message: format!(
+                        "Shutdown requested before the event was delivered: {}",
+                        message
+                    ),
+                    sink,
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for RetryingSink {
+    async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()> {
+        self.retry(|| self.sink.send_event(event)).await
+    }
+
+    async fn send_transaction(&self, transaction: &TransactionBatch) -> ReplicationResult<()> {
+        self.retry(|| self.sink.send_transaction(transaction)).await
+    }
+
+    async fn notify_failure(&self, message: &str) {
+        self.sink.notify_failure(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::errors::DeliveryAttempt;
+    use std::sync::atomic::AtomicU32;
+
+    /// Sink failing with the given status a number of times before accepting,
+    /// without a status when the endpoint is unreachable
+    struct FlakySink {
+        failures: u32,
+        status: Option<u16>,
+        calls: AtomicU32,
+    }
+
+    #[async_trait]
+    impl EventSink for FlakySink {
+        async fn send_event(&self, _event: &ReplicationMessage) -> ReplicationResult<()> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                let attempt = DeliveryAttempt::failed("rejected");
+                return Err(ReplicationError::delivery(
+                    "test",
+                    format!("status {:?}", self.status),
+                    false,
+                    vec![match self.status {
+                        Some(status) => attempt.with_status(status),
+                        None => attempt,
+                    }],
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    fn policy(max_attempts: Option<u32>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            ..RetryPolicy::default()
+        }
+    }
+
+    async fn send(failures: u32, status: u16, policy: RetryPolicy) -> (ReplicationResult<()>, u32) {
+        let sink = Arc::new(FlakySink {
+            failures,
+            status: Some(status),
+            calls: AtomicU32::new(0),
+        });
+        let result = RetryingSink::new(sink.clone(), policy, Arc::default())
+            .send_event(&ReplicationMessage::StreamStop)
+            .await;
+        (result, sink.calls.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_delivered_or_exhausted() {
+        let (result, calls) = send(2, 503, policy(Some(3))).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        let (result, calls) = send(10, 503, policy(Some(3))).await;
+        assert_eq!(calls, 3);
+        match result {
+            Err(ReplicationError::Delivery {
+                permanent,
+                attempts,
+                ..
+            }) => {
+                assert!(!permanent);
+                assert_eq!(attempts.len(), 3);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let (result, calls) = send(6, 429, policy(None)).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 7);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_event_is_skipped_only_when_unreachable() {
+        for (status, skipped) in [(None, true), (Some(503), false)] {
+            let sink = Arc::new(FlakySink {
+                failures: 10,
+                status,
+                calls: AtomicU32::new(0),
+            });
+            let result = RetryingSink::new(sink.clone(), policy(Some(3)), Arc::default())
+                .with_skip_unreachable(true)
+                .send_event(&ReplicationMessage::StreamStop)
+                .await;
+            assert_eq!(result.is_ok(), skipped);
+            assert_eq!(sink.calls.load(Ordering::SeqCst), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_interrupts_the_wait_before_a_retry() {
+        let sink = Arc::new(FlakySink {
+            failures: 10,
+            status: Some(503),
+            calls: AtomicU32::new(0),
+        });
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+            ..policy(None)
+        };
+        let retrying = RetryingSink::new(sink.clone(), policy, shutdown.clone());
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shutdown.store(true, Ordering::SeqCst);
+        };
+        let (result, ()) = tokio::join!(retrying.send_event(&ReplicationMessage::StreamStop), stop);
+        assert!(matches!(result, Err(ReplicationError::Sink { .. })));
+        assert_eq!(sink.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fatal_status_is_not_retried() {
+        let (result, calls) = send(10, 422, policy(Some(5))).await;
+        assert_eq!(calls, 1);
+        assert!(matches!(
+            result,
+            Err(ReplicationError::Delivery {
+                permanent: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_delay_honors_retry_after_and_caps_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(120))),
+            policy.max_delay
+        );
+        for attempt in 1..40 {
+            assert!(policy.delay(attempt, None) <= policy.max_delay);
+        }
+
+        assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
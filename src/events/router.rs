@@ -0,0 +1,334 @@
+//! Fan-out of the event stream to several sinks
+//!
+//! Each route pairs a sink with a rule selecting the messages it receives, by
+//! table, row operation and message kind, and optionally a [`RowFilter`] on the
+//! rows of its changes. Routes are independent: a message matching several
+//! rules is sent to each of their sinks in turn.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::debug;
+
+use super::filter::RowFilter;
+use super::{EventSink, TransactionBatch};
+use crate::core::metrics::METRICS;
+use crate::protocol::messages::{RelationCache, RelationInfo, ReplicationMessage};
+
+/// Row operation carried by a change message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    Truncate,
+    /// Row read by an initial or incremental snapshot
+    Read,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Insert => write!(f, "insert"),
+            Operation::Update => write!(f, "update"),
+            Operation::Delete => write!(f, "delete"),
+            Operation::Truncate => write!(f, "truncate"),
+            Operation::Read => write!(f, "read"),
+        }
+    }
+}
+
+/// Broad category of a replication message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Transaction boundaries and origins: Begin, Commit and their prepared counterparts
+    Transaction,
+    /// Schema of a table or type
+    Relation,
+    /// Row changes and snapshot reads
+    Change,
+    /// Logical decoding messages
+    Message,
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageKind::Transaction => write!(f, "transaction"),
+            MessageKind::Relation => write!(f, "relation"),
+            MessageKind::Change => write!(f, "change"),
+            MessageKind::Message => write!(f, "message"),
+        }
+    }
+}
+
+impl MessageKind {
+    fn of(message: &ReplicationMessage) -> Self {
+        match message {
+            ReplicationMessage::Relation { .. } | ReplicationMessage::Type { .. } => {
+                MessageKind::Relation
+            }
+            ReplicationMessage::Insert { .. }
+            | ReplicationMessage::Update { .. }
+            | ReplicationMessage::Delete { .. }
+            | ReplicationMessage::Truncate { .. }
+            | ReplicationMessage::Read { .. } => MessageKind::Change,
+            ReplicationMessage::LogicalMessage { .. } => MessageKind::Message,
+            _ => MessageKind::Transaction,
+        }
+    }
+}
+
+/// Messages a route receives; an empty list places no restriction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteRule {
+    /// Glob patterns (`*`, `?`) on `schema.table`, or on the table name in any
+    /// schema for patterns without a dot
+    pub tables: Vec<String>,
+    /// Row operations of change messages
+    pub operations: Vec<Operation>,
+    pub kinds: Vec<MessageKind>,
+    /// Predicate on the rows of the changes matched by the rule
+    pub filter: Option<RowFilter>,
+}
+
+impl RouteRule {
+    /// Whether `message` is selected by the tables, operations and kinds of
+    /// this rule, before its row filter
+    ///
+    /// Table and operation restrictions only apply to the messages that have
+    /// them; a truncate matches when one of its tables does.
+    pub fn matches(&self, message: &ReplicationMessage, relations: &RelationCache) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&MessageKind::of(message)) {
+            return false;
+        }
+
+        let operation = match message {
+            ReplicationMessage::Insert { .. } => Some(Operation::Insert),
+            ReplicationMessage::Update { .. } => Some(Operation::Update),
+            ReplicationMessage::Delete { .. } => Some(Operation::Delete),
+            ReplicationMessage::Truncate { .. } => Some(Operation::Truncate),
+            ReplicationMessage::Read { .. } => Some(Operation::Read),
+            _ => None,
+        };
+        if let Some(operation) = operation
+            && !self.operations.is_empty()
+            && !self.operations.contains(&operation)
+        {
+            return false;
+        }
+
+        if self.tables.is_empty() {
+            return true;
+        }
+        match message {
+            ReplicationMessage::Relation { relation } => self.matches_table(relation),
+            ReplicationMessage::Insert { relation_id, .. }
+            | ReplicationMessage::Update { relation_id, .. }
+            | ReplicationMessage::Delete { relation_id, .. }
+            | ReplicationMessage::Read { relation_id, .. } => relations
+                .get(*relation_id)
+                .is_some_and(|relation| self.matches_table(&relation)),
+            ReplicationMessage::Truncate { relation_ids, .. } => relation_ids.iter().any(|oid| {
+                relations
+                    .get(*oid)
+                    .is_some_and(|relation| self.matches_table(&relation))
+            }),
+            _ => true,
+        }
+    }
+
+    fn matches_table(&self, relation: &RelationInfo) -> bool {
+        self.tables
+            .iter()
+            .any(|pattern| table_matches(pattern, relation))
+    }
+}
+
+/// Matches a relation against a glob pattern on `schema.table`, or on the
+/// table name in any schema for a pattern without a dot
+pub(crate) fn table_matches(pattern: &str, relation: &RelationInfo) -> bool {
+    if pattern.contains('.') {
+        let qualified = format!("{}.{}", relation.namespace, relation.relation_name);
+        glob_matches(pattern.as_bytes(), qualified.as_bytes())
+    } else {
+        glob_matches(pattern.as_bytes(), relation.relation_name.as_bytes())
+    }
+}
+
+/// Matches `text` against a pattern where `*` is any sequence and `?` any character
+///
+/// On a mismatch only the last `*` seen takes one more character, which
+/// keeps matching linear in the text for each star.
+pub(crate) fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Positions after the last `*` in the pattern and of the text it resumes at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&expected) if expected == b'?' || expected == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// How a route handles a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Routing {
+    /// The route's rule does not select the message
+    Ignored,
+    /// The rule selects the change but the route's row filter skips it
+    Filtered,
+    Received,
+}
+
+/// A sink and the messages routed to it
+pub struct Route {
+    pub name: String,
+    pub sink: Arc<dyn EventSink + Send + Sync>,
+    pub rule: RouteRule,
+    /// Whether a failed delivery holds back acknowledgement of the transaction
+    pub blocking: bool,
+}
+
+/// Routes of the event stream, in configuration order
+pub struct EventRouter {
+    routes: Vec<Route>,
+    relations: RelationCache,
+}
+
+impl EventRouter {
+    /// `relations` is the server's relation cache, used to match table patterns
+    pub fn new(routes: Vec<Route>, relations: RelationCache) -> Self {
+        Self { routes, relations }
+    }
+
+    /// Route named `name`, used to replay dead letters to the sink they failed on
+    pub fn route(&self, name: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| route.name == name)
+    }
+
+    /// Routes receiving `message`, counting the routes whose row filter skips it
+    pub fn routes_for(&self, message: &ReplicationMessage) -> Vec<&Route> {
+        let mut routes = Vec::new();
+        for route in &self.routes {
+            match self.routing(route, message) {
+                Routing::Received => routes.push(route),
+                Routing::Filtered => filtered(route),
+                Routing::Ignored => {}
+            }
+        }
+        routes
+    }
+
+    /// How `route` handles `message`
+    fn routing(&self, route: &Route, message: &ReplicationMessage) -> Routing {
+        if !route.rule.matches(message, &self.relations) {
+            return Routing::Ignored;
+        }
+        match &route.rule.filter {
+            Some(filter) if !filter.matches(message, &self.relations) => Routing::Filtered,
+            _ => Routing::Received,
+        }
+    }
+
+    /// Routes receiving part of `batch`, each with the positions of the
+    /// changes routed to it
+    ///
+    /// A route receives the transaction when one of its changes matches, or
+    /// when the transaction has no changes and the route takes transaction
+    /// messages.
+    pub fn split_transaction(&self, batch: &TransactionBatch) -> Vec<(&Route, Vec<usize>)> {
+        self.routes
+            .iter()
+            .filter_map(|route| {
+                let mut changes = Vec::new();
+                for (position, change) in batch.changes.iter().enumerate() {
+                    match self.routing(route, change) {
+                        Routing::Received => changes.push(position),
+                        Routing::Filtered => filtered(route),
+                        Routing::Ignored => {}
+                    }
+                }
+                let routed = if batch.changes.is_empty() {
+                    route.rule.matches(&batch.begin, &self.relations)
+                } else {
+                    !changes.is_empty()
+                };
+                routed.then_some((route, changes))
+            })
+            .collect()
+    }
+}
+
+/// Counts a change skipped by the row filter of `route`
+fn filtered(route: &Route) {
+    debug!("Change skipped by the filter of {} sink", route.name);
+    METRICS.events_filtered.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{begin, insert, relation, values};
+
+    #[test]
+    fn test_rule_matches_tables_operations_and_kinds() {
+        let relations = RelationCache::new();
+        relations.insert(relation(1, "orders", "line_items", Vec::new()));
+        relations.insert(relation(2, "public", "outbox", Vec::new()));
+
+        let rule = RouteRule {
+            tables: vec!["orders.*".to_string(), "out?ox".to_string()],
+            operations: vec![Operation::Insert],
+            kinds: Vec::new(),
+            filter: None,
+        };
+        assert!(rule.matches(&insert(1, values(&[])), &relations));
+        assert!(rule.matches(&insert(2, values(&[])), &relations));
+        // Unknown relations never match a table pattern
+        assert!(!rule.matches(&insert(3, values(&[])), &relations));
+        let begin = begin(0, 1);
+        assert!(rule.matches(&begin, &relations));
+
+        let changes_only = RouteRule {
+            kinds: vec![MessageKind::Change],
+            ..RouteRule::default()
+        };
+        assert!(changes_only.matches(&insert(3, values(&[])), &relations));
+        assert!(!changes_only.matches(&begin, &relations));
+
+        assert!(glob_matches(b"*_audit", b"orders_audit"));
+        assert!(!glob_matches(b"orders.*", b"public.orders"));
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        assert!(glob_matches(b"", b""));
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"a*b*c", b"axxbyyc"));
+        assert!(glob_matches(b"a*c", b"abcbc"));
+        assert!(glob_matches(b"?*?", b"ab"));
+        assert!(!glob_matches(b"?*?", b"a"));
+        assert!(!glob_matches(b"a*b", b"abba_"));
+
+        // Would take exponential time with naive backtracking
+        let text = [b'a'; 64];
+        assert!(!glob_matches(b"a*a*a*a*a*a*a*a*a*a*a*a*b", &text));
+    }
+}
@@ -0,0 +1,818 @@
+//! Row changes rewritten by a user script, written in Rhai
+//!
+//! The script defines `fn process(event)`, called with every row change as a
+//! typed event: a map with `schema`, `table`, `op`, `before`, `after`, `pk`,
+//! `xid`, `commit_lsn` and `commit_timestamp`, plus `unchanged` for unchanged
+//! TOAST columns and `snapshot_lsn` for snapshot reads. It returns the event,
+//! modified or not, an array of events, or `()` to drop the change:
+//!
+//! ```rhai
+//! fn process(event) {
+//!     if event.table == "sessions" { return (); }
+//!     event.after.remove("password");
+//!     event.after.source = "walpipe";
+//!     event
+//! }
+//! ```
+//!
+//! Returned events are turned back into replication messages, so that every
+//! sink and output format receives them. Columns the script removes from
+//! every row image are dropped, and new keys become columns typed after the
+//! column of the same name in the event's table when walpipe knows that table,
+//! otherwise after their first non-null value. An event whose columns or table
+//! differ from its input's gets a relation of its own, sent to each sink before
+//! its first change. Its OID is derived from the table and columns, so that it
+//! stays the same across restarts.
+//!
+//! The script runs once per change, whichever sinks receive it.
+//!
+//! With the `wasm` feature, a script file ending in `.wasm` is a WebAssembly
+//! component implementing the `transform` world of `wit/plugin.wit` instead:
+//! its `transform` function receives the JSON of the same events and returns
+//! the JSON of an event, an array of events or `null`.
+
+use rhai::{AST, Dynamic, Engine, Scope};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::{debug, info};
+
+use super::TransactionBatch;
+#[cfg(feature = "wasm")]
+use super::plugin::WasmTransform;
+use super::sink::typed_formatter::TypedEventFormatter;
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::{
+    ColumnData, ColumnInfo, RelationCache, RelationInfo, ReplicationMessage, TupleData,
+};
+use crate::utils::binary::Oid;
+
+/// Operations a single call of the script may run, so that a runaway loop
+/// fails the event instead of stalling replication
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Bit set in the OIDs of the relations of reshaped events, which take the
+/// upper half of the OID range, away from those of the server's tables
+const SCRIPT_OIDS: Oid = 1 << 31;
+
+const BOOL_OID: Oid = 16;
+const INT8_OID: Oid = 20;
+const TEXT_OID: Oid = 25;
+const FLOAT8_OID: Oid = 701;
+const JSONB_OID: Oid = 3802;
+
+/// Code run on every row change
+enum Runtime {
+    Rhai {
+        engine: Box<Engine>,
+        ast: AST,
+    },
+    #[cfg(feature = "wasm")]
+    Wasm(WasmTransform),
+}
+
+/// Runs the `process` function of a script on the row changes bound for the sinks
+pub struct ScriptProcessor {
+    runtime: Runtime,
+    /// Builds the events passed to the script, from the relations as the sinks receive them
+    formatter: TypedEventFormatter,
+    relations: RelationCache,
+    /// Relations of the reshaped events by OID, stored in `relations` as well
+    shapes: HashMap<Oid, RelationInfo>,
+    /// Reshaped relations already sent to each route
+    announced: HashSet<(String, Oid)>,
+}
+
+impl ScriptProcessor {
+    /// Compiles the script at `path`
+    ///
+    /// `relations` are the relations as the sinks receive them; the relations
+    /// of reshaped events are added to them.
+    pub fn load(path: &Path, relations: RelationCache) -> ReplicationResult<Self> {
+        #[cfg(feature = "wasm")]
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "wasm")
+        {
+            let plugin = WasmTransform::load(path)?;
+            return Ok(Self::new(Runtime::Wasm(plugin), relations));
+        }
+        let source = fs::read_to_string(path).map_err(|e| {
+            ReplicationError::config(format!("Failed to read script {}: {}", path.display(), e))
+        })?;
+        Self::compile(&source, &path.display().to_string(), relations)
+    }
+
+    fn compile(source: &str, name: &str, relations: RelationCache) -> ReplicationResult<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        // Keeps the script's output off stdout, where the stdout sink writes events
+        engine.on_print(|text| info!("Script: {}", text));
+        engine.on_debug(|text, _, position| debug!("Script at {}: {}", position, text));
+
+        let ast = engine.compile(source).map_err(|e| {
+            ReplicationError::config(format!("Failed to compile script {}: {}", name, e))
+        })?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == "process" && function.params.len() == 1)
+        {
+            return Err(ReplicationError::config(format!(
+                "Script {} must define fn process(event)",
+                name
+            )));
+        }
+
+        Ok(Self::new(
+            Runtime::Rhai {
+                engine: Box::new(engine),
+                ast,
+            },
+            relations,
+        ))
+    }
+
+    fn new(runtime: Runtime, relations: RelationCache) -> Self {
+        Self {
+            runtime,
+            formatter: TypedEventFormatter::new(relations.clone()),
+            relations,
+            shapes: HashMap::new(),
+            announced: HashSet::new(),
+        }
+    }
+
+    /// Forgets the reshaped relations, once the sinks' relation cache was cleared
+    pub fn reset(&mut self) {
+        self.shapes.clear();
+        self.announced.clear();
+    }
+
+    /// Tracks the transaction opened by a Begin or closed by a Commit, whose
+    /// xid and commit position the following events carry
+    pub fn observe(&self, message: &ReplicationMessage) {
+        if matches!(
+            message,
+            ReplicationMessage::Begin { .. }
+                | ReplicationMessage::BeginPrepare { .. }
+                | ReplicationMessage::Commit { .. }
+                | ReplicationMessage::Prepare { .. }
+        ) {
+            self.formatter.format(message);
+        }
+    }
+
+    /// Messages the script makes of `message`
+    ///
+    /// Messages other than row changes, and changes of relations not received
+    /// yet, are returned as they are. See [`ScriptProcessor::announce`] for
+    /// the relations of reshaped events.
+    pub async fn process(
+        &mut self,
+        message: &ReplicationMessage,
+    ) -> Result<Vec<ReplicationMessage>, String> {
+        let relation = change_relation(message).and_then(|oid| self.relations.get(oid));
+        let (Some(relation), Some(input)) = (relation, self.formatter.format(message)) else {
+            return Ok(vec![message.clone()]);
+        };
+
+        let events = match self.call(&input).await? {
+            Value::Null => Vec::new(),
+            Value::Array(events) => events,
+            event => vec![event],
+        };
+
+        let mut messages = Vec::new();
+        for event in events {
+            let Value::Object(event) = event else {
+                return Err(format!(
+                    "the script must return a map, an array of maps or (), not {}",
+                    event
+                ));
+            };
+            messages.push(self.rebuild(message, &relation, &input, &event)?);
+        }
+        Ok(messages)
+    }
+
+    /// Messages the script makes of each change of `batch`
+    pub async fn process_batch(
+        &mut self,
+        batch: &TransactionBatch,
+    ) -> Result<Vec<Vec<ReplicationMessage>>, String> {
+        self.observe(&batch.begin);
+        let mut changes = Vec::new();
+        for change in &batch.changes {
+            changes.push(self.process(change).await?);
+        }
+        self.observe(&batch.commit);
+        Ok(changes)
+    }
+
+    /// `messages` made by the script as the `route` sink receives them: a
+    /// change of a reshaped relation the route has not received yet is
+    /// preceded by its Relation message
+    pub fn announce(
+        &mut self,
+        messages: &[ReplicationMessage],
+        route: &str,
+    ) -> Vec<ReplicationMessage> {
+        let mut announced = Vec::with_capacity(messages.len());
+        for message in messages {
+            if let Some(relation) = change_relation(message).and_then(|oid| self.shapes.get(&oid))
+                && self.announced.insert((route.to_string(), relation.oid))
+            {
+                announced.push(ReplicationMessage::Relation {
+                    relation: relation.clone(),
+                });
+            }
+            announced.push(message.clone());
+        }
+        announced
+    }
+
+    /// Result of the script for the typed event `input`
+    async fn call(&mut self, input: &Value) -> Result<Value, String> {
+        match &mut self.runtime {
+            Runtime::Rhai { engine, ast } => {
+                let event = rhai::serde::to_dynamic(input).map_err(|e| e.to_string())?;
+                let result: Dynamic = engine
+                    .call_fn(&mut Scope::new(), ast, "process", (event,))
+                    .map_err(|e| e.to_string())?;
+                rhai::serde::from_dynamic(&result).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "wasm")]
+            Runtime::Wasm(plugin) => {
+                let output = plugin.transform(&input.to_string()).await?;
+                serde_json::from_str(&output)
+                    .map_err(|e| format!("transform returned invalid JSON: {}", e))
+            }
+        }
+    }
+
+    /// Change described by an `event` the script returned for `message`,
+    /// whose typed event was `input`
+    fn rebuild(
+        &mut self,
+        message: &ReplicationMessage,
+        input_relation: &RelationInfo,
+        input: &Value,
+        event: &Map<String, Value>,
+    ) -> Result<ReplicationMessage, String> {
+        let op = event
+            .get("op")
+            .and_then(Value::as_str)
+            .ok_or("event must have an op")?;
+        let before = row_image(event, "before")?;
+        let after = row_image(event, "after")?;
+        let unchanged = unchanged_columns(event);
+
+        let relation = self.relation(input_relation, input, event);
+        let (input_before, input_after) = match message {
+            ReplicationMessage::Update {
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => (old_tuple_data.as_ref(), Some(new_tuple_data)),
+            ReplicationMessage::Delete { tuple_data, .. } => (Some(tuple_data), None),
+            ReplicationMessage::Insert { tuple_data, .. }
+            | ReplicationMessage::Read { tuple_data, .. } => (None, Some(tuple_data)),
+            _ => (None, None),
+        };
+        let tuple = |image: Option<&Map<String, Value>>, key: &str, source, unchanged: &[&str]| {
+            image.map(|image| {
+                let original = input.get(key).zip(source);
+                build_tuple(&relation, image, original, input_relation, unchanged)
+            })
+        };
+        let before = tuple(before, "before", input_before, &[]);
+        let after = tuple(after, "after", input_after, &unchanged);
+
+        let (is_stream, xid, lsn, key_type) = match message {
+            ReplicationMessage::Insert {
+                is_stream,
+                xid,
+                lsn,
+                ..
+            } => (*is_stream, *xid, *lsn, None),
+            ReplicationMessage::Update {
+                is_stream,
+                xid,
+                lsn,
+                key_type,
+                ..
+            } => (*is_stream, *xid, *lsn, *key_type),
+            ReplicationMessage::Delete {
+                is_stream,
+                xid,
+                lsn,
+                key_type,
+                ..
+            } => (*is_stream, *xid, *lsn, Some(*key_type)),
+            _ => (false, None, 0, None),
+        };
+        // Keeps a key-only old row as such, so that its other columns stay unknown
+        let key_type = key_type.unwrap_or('O');
+        let relation_id = relation.oid;
+        Ok(match op {
+            "insert" => ReplicationMessage::Insert {
+                relation_id,
+                tuple_data: after.ok_or("an insert must have an after row")?,
+                is_stream,
+                xid,
+                lsn,
+            },
+            "update" => ReplicationMessage::Update {
+                relation_id,
+                key_type: before.as_ref().map(|_| key_type),
+                old_tuple_data: before,
+                new_tuple_data: after.ok_or("an update must have an after row")?,
+                is_stream,
+                xid,
+                lsn,
+            },
+            "delete" => ReplicationMessage::Delete {
+                relation_id,
+                key_type,
+                tuple_data: before.ok_or("a delete must have a before row")?,
+                is_stream,
+                xid,
+                lsn,
+            },
+            "read" => ReplicationMessage::Read {
+                relation_id,
+                tuple_data: after.ok_or("a read must have an after row")?,
+                snapshot_lsn: event
+                    .get("snapshot_lsn")
+                    .and_then(Value::as_u64)
+                    .unwrap_or_default(),
+            },
+            op => return Err(format!("unknown op '{}'", op)),
+        })
+    }
+
+    /// Relation of an `event` returned for a change of `input_relation`
+    ///
+    /// The input relation is kept when the event has its table and columns;
+    /// otherwise the event gets a relation of its own.
+    fn relation(
+        &mut self,
+        input_relation: &RelationInfo,
+        input: &Value,
+        event: &Map<String, Value>,
+    ) -> RelationInfo {
+        let input_columns = column_names(input.as_object());
+        let event_columns = column_names(Some(event));
+        let mut columns: Vec<ColumnInfo> = input_relation
+            .columns
+            .iter()
+            .filter(|column| {
+                // Columns missing from both input images, like the non-key
+                // columns of a key-only old row, are not dropped
+                !input_columns.contains(column.column_name.as_str())
+                    || event_columns.contains(column.column_name.as_str())
+            })
+            .cloned()
+            .collect();
+
+        let text = |key: &str, default: &str| {
+            event
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or(default)
+                .to_string()
+        };
+        let (namespace, relation_name) = (
+            text("schema", &input_relation.namespace),
+            text("table", &input_relation.relation_name),
+        );
+        let images: Vec<&Map<String, Value>> = ["after", "before"]
+            .into_iter()
+            .filter_map(|image| event.get(image).and_then(Value::as_object))
+            .collect();
+        let mut table = None;
+        for name in images.iter().flat_map(|image| image.keys()) {
+            if columns.iter().any(|column| &column.column_name == name) {
+                continue;
+            }
+            let table =
+                table.get_or_insert_with(|| self.relations.find(&namespace, &relation_name));
+            let source = table.as_ref().and_then(|table| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.column_name == name)
+            });
+            let (column_type, atttypmod) = match source {
+                Some(column) => (column.column_type, column.atttypmod),
+                None => {
+                    let value = images
+                        .iter()
+                        .filter_map(|image| image.get(name))
+                        .find(|value| !value.is_null());
+                    (value.map_or(TEXT_OID, type_of), -1)
+                }
+            };
+            columns.push(ColumnInfo {
+                key_flag: 0,
+                column_name: name.clone(),
+                column_type,
+                atttypmod,
+            });
+        }
+
+        let shaped = RelationInfo {
+            oid: input_relation.oid,
+            namespace,
+            relation_name,
+            replica_identity: input_relation.replica_identity,
+            column_count: columns.len() as i16,
+            columns,
+        };
+        if same_shape(&shaped, input_relation) {
+            return input_relation.clone();
+        }
+
+        let mut oid = shape_oid(&shaped);
+        loop {
+            match self.shapes.get(&oid) {
+                Some(shape) if same_shape(shape, &shaped) => return shape.clone(),
+                // Another shape with the same hash takes the next OID
+                Some(_) => oid = SCRIPT_OIDS | oid.wrapping_add(1),
+                None => break,
+            }
+        }
+        let relation = RelationInfo { oid, ..shaped };
+        debug!(
+            "Script output {}.{} has relation OID {}",
+            relation.namespace, relation.relation_name, relation.oid
+        );
+        self.relations.insert(relation.clone());
+        self.shapes.insert(oid, relation.clone());
+        relation
+    }
+}
+
+/// Relation of a row change
+fn change_relation(message: &ReplicationMessage) -> Option<Oid> {
+    match message {
+        ReplicationMessage::Insert { relation_id, .. }
+        | ReplicationMessage::Update { relation_id, .. }
+        | ReplicationMessage::Delete { relation_id, .. }
+        | ReplicationMessage::Read { relation_id, .. } => Some(*relation_id),
+        _ => None,
+    }
+}
+
+/// OID of the relation of reshaped events of `shape`, a hash (FNV-1a) of its
+/// name and columns that is the same on every run
+fn shape_oid(shape: &RelationInfo) -> Oid {
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut write = |bytes: &[u8]| {
+        // 0xff never occurs in UTF-8 and separates the fields
+        for byte in bytes.iter().chain(&[0xff]) {
+            hash = (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193);
+        }
+    };
+    write(shape.namespace.as_bytes());
+    write(shape.relation_name.as_bytes());
+    for column in &shape.columns {
+        write(column.column_name.as_bytes());
+        write(&column.column_type.to_be_bytes());
+        write(&column.atttypmod.to_be_bytes());
+        write(&column.key_flag.to_be_bytes());
+    }
+    SCRIPT_OIDS | hash
+}
+
+/// Row image `key` of an event, `None` when missing or null
+fn row_image<'a>(
+    event: &'a Map<String, Value>,
+    key: &str,
+) -> Result<Option<&'a Map<String, Value>>, String> {
+    match event.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Object(image)) => Ok(Some(image)),
+        Some(other) => Err(format!("event {} must be a map, not {}", key, other)),
+    }
+}
+
+/// Unchanged TOAST columns an event lists
+fn unchanged_columns(event: &Map<String, Value>) -> Vec<&str> {
+    event
+        .get("unchanged")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// Columns present in the row images of an event, unchanged ones included
+fn column_names(event: Option<&Map<String, Value>>) -> HashSet<&str> {
+    let Some(event) = event else {
+        return HashSet::new();
+    };
+    let mut names: HashSet<&str> = unchanged_columns(event).into_iter().collect();
+    for image in ["before", "after"] {
+        if let Some(Value::Object(image)) = event.get(image) {
+            names.extend(image.keys().map(String::as_str));
+        }
+    }
+    names
+}
+
+/// Type of a column added by the script, after its first non-null value
+fn type_of(value: &Value) -> Oid {
+    match value {
+        Value::Bool(_) => BOOL_OID,
+        Value::Number(number) if number.is_f64() => FLOAT8_OID,
+        Value::Number(_) => INT8_OID,
+        Value::Array(_) | Value::Object(_) => JSONB_OID,
+        Value::Null | Value::String(_) => TEXT_OID,
+    }
+}
+
+/// Whether two relations have the same name and columns
+fn same_shape(a: &RelationInfo, b: &RelationInfo) -> bool {
+    a.namespace == b.namespace
+        && a.relation_name == b.relation_name
+        && a.columns.len() == b.columns.len()
+        && a.columns.iter().zip(&b.columns).all(|(a, b)| {
+            a.column_name == b.column_name
+                && a.column_type == b.column_type
+                && a.atttypmod == b.atttypmod
+                && a.key_flag == b.key_flag
+        })
+}
+
+/// Tuple of `relation` holding the values of a row `image`
+///
+/// A value the script left as it was in the `original` image keeps its
+/// column data, binary values included. Missing columns are NULL, or
+/// unchanged TOAST values when listed in `unchanged`.
+fn build_tuple(
+    relation: &RelationInfo,
+    image: &Map<String, Value>,
+    original: Option<(&Value, &TupleData)>,
+    input_relation: &RelationInfo,
+    unchanged: &[&str],
+) -> TupleData {
+    let columns: Vec<ColumnData> = relation
+        .columns
+        .iter()
+        .map(|info| {
+            let name = info.column_name.as_str();
+            let Some(value) = image.get(name) else {
+                let data_type = if unchanged.contains(&name) { 'u' } else { 'n' };
+                return ColumnData {
+                    data_type,
+                    length: 0,
+                    data: String::new(),
+                    binary: None,
+                };
+            };
+            let kept = original.and_then(|(original, tuple)| {
+                let position = input_relation
+                    .columns
+                    .iter()
+                    .position(|column| column.column_name == name)?;
+                (original.get(name) == Some(value))
+                    .then(|| tuple.columns.get(position).cloned())
+                    .flatten()
+            });
+            kept.unwrap_or_else(|| column_data(value))
+        })
+        .collect();
+    TupleData {
+        column_count: columns.len() as i16,
+        columns,
+        processed_length: 0,
+    }
+}
+
+/// Text column data of a value set by the script
+fn column_data(value: &Value) -> ColumnData {
+    let data = match value {
+        Value::Null => {
+            return ColumnData {
+                data_type: 'n',
+                length: 0,
+                data: String::new(),
+                binary: None,
+            };
+        }
+        Value::Bool(value) => if *value { "t" } else { "f" }.to_string(),
+        Value::String(value) => value.clone(),
+        other => other.to_string(),
+    };
+    ColumnData {
+        data_type: 't',
+        length: data.len() as i32,
+        data,
+        binary: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::protocol::fixtures::{INT4_OID, column, relation, text, tuple, unchanged};
+
+    fn relations() -> RelationCache {
+        let relations = RelationCache::new();
+        relations.insert(relation(
+            42,
+            "public",
+            "users",
+            vec![
+                column("id", INT4_OID, true),
+                column("email", TEXT_OID, false),
+                column("bio", TEXT_OID, false),
+            ],
+        ));
+        relations
+    }
+
+    fn script(source: &str, relations: RelationCache) -> ScriptProcessor {
+        ScriptProcessor::compile(source, "test.rhai", relations).unwrap()
+    }
+
+    fn update() -> ReplicationMessage {
+        let new = tuple(vec![text("1"), text("ada@example.com"), unchanged()]);
+        crate::protocol::fixtures::update(42, None, new)
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_event_keeps_its_message() {
+        let mut processor = script("fn process(event) { event }", relations());
+        let messages = processor.process(&update()).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        let ReplicationMessage::Update {
+            relation_id,
+            new_tuple_data,
+            ..
+        } = &messages[0]
+        else {
+            panic!("expected an update, got {:?}", messages[0]);
+        };
+        assert_eq!(*relation_id, 42);
+        assert_eq!(new_tuple_data.columns[1].data, "ada@example.com");
+        assert_eq!(new_tuple_data.columns[2].data_type, 'u');
+
+        let mut processor = script(
+            r#"fn process(event) { if event.after.id == 1 { () } else { event } }"#,
+            relations(),
+        );
+        assert!(processor.process(&update()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reshaped_event_gets_its_own_relation() {
+        let relations = relations();
+        let mut processor = script(
+            r#"
+            fn process(event) {
+                event.after.remove("email");
+                event.after.verified = true;
+                let audit = event;
+                audit.table = "users_audit";
+                [event, audit]
+            }
+            "#,
+            relations.clone(),
+        );
+
+        let messages = processor.process(&update()).await.unwrap();
+        let oids: Vec<Oid> = messages
+            .iter()
+            .map(|message| match message {
+                ReplicationMessage::Update { relation_id, .. } => *relation_id,
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect();
+        assert_ne!(oids[0], oids[1]);
+        assert!(oids.iter().all(|oid| oid & SCRIPT_OIDS != 0));
+
+        let relation = relations.get(oids[0]).unwrap();
+        let columns: Vec<(&str, Oid)> = relation
+            .columns
+            .iter()
+            .map(|column| (column.column_name.as_str(), column.column_type))
+            .collect();
+        assert_eq!(
+            columns,
+            [("id", 23), ("bio", TEXT_OID), ("verified", BOOL_OID)]
+        );
+        let ReplicationMessage::Update { new_tuple_data, .. } = &messages[0] else {
+            panic!("expected an update, got {:?}", messages[0]);
+        };
+        assert_eq!(new_tuple_data.columns[1].data_type, 'u');
+        assert_eq!(new_tuple_data.columns[2].data, "t");
+
+        // Relations are announced once per route
+        let announced = processor.announce(&messages, "stdout");
+        assert!(matches!(
+            &announced[..],
+            [
+                ReplicationMessage::Relation { .. },
+                ReplicationMessage::Update { .. },
+                ReplicationMessage::Relation { .. },
+                ReplicationMessage::Update { .. }
+            ]
+        ));
+        assert_eq!(processor.announce(&messages, "stdout").len(), 2);
+        assert_eq!(processor.announce(&messages, "audit").len(), 4);
+
+        // The same shapes get the same OIDs on the next run
+        let mut restarted = script(
+            r#"fn process(event) { event.after.remove("email"); event.after.verified = true; event }"#,
+            relations.clone(),
+        );
+        let messages = restarted.process(&update()).await.unwrap();
+        let ReplicationMessage::Update { relation_id, .. } = &messages[0] else {
+            panic!("expected an update, got {:?}", messages[0]);
+        };
+        assert_eq!(*relation_id, oids[0]);
+    }
+
+    #[tokio::test]
+    async fn test_null_column_typed_after_its_table() {
+        let relations = relations();
+        relations.insert(relation(
+            43,
+            "public",
+            "users_audit",
+            vec![
+                column("id", INT4_OID, true),
+                column("email", TEXT_OID, false),
+                column("bio", TEXT_OID, false),
+                column("verified", BOOL_OID, false),
+            ],
+        ));
+        let mut processor = script(
+            r#"
+            fn process(event) {
+                event.after.verified = ();
+                event.after.note = ();
+                let audit = event;
+                audit.table = "users_audit";
+                [event, audit]
+            }
+            "#,
+            relations.clone(),
+        );
+
+        let messages = processor.process(&update()).await.unwrap();
+        let types: Vec<Vec<(String, Oid)>> = messages
+            .iter()
+            .map(|message| {
+                let ReplicationMessage::Update { relation_id, .. } = message else {
+                    panic!("expected an update, got {:?}", message);
+                };
+                let relation = relations.get(*relation_id).unwrap();
+                relation.columns[3..]
+                    .iter()
+                    .map(|column| (column.column_name.clone(), column.column_type))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            types,
+            [
+                // Nothing tells the type of an unknown table's column
+                vec![
+                    ("note".to_string(), TEXT_OID),
+                    ("verified".to_string(), TEXT_OID)
+                ],
+                vec![
+                    ("note".to_string(), TEXT_OID),
+                    ("verified".to_string(), BOOL_OID)
+                ],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_script_errors() {
+        let mut processor = script(
+            r#"fn process(event) { event.op = "upsert"; event }"#,
+            relations(),
+        );
+        let error = processor.process(&update()).await.unwrap_err();
+        assert_eq!(error, "unknown op 'upsert'");
+
+        let mut processor = script(r#"fn process(event) { throw "rejected" }"#, relations());
+        assert!(
+            processor
+                .process(&update())
+                .await
+                .unwrap_err()
+                .contains("rejected")
+        );
+
+        let missing =
+            ScriptProcessor::compile("fn transform(event) { event }", "test.rhai", relations());
+        assert!(missing.is_err());
+    }
+}
@@ -3,34 +3,101 @@
 //! Provides various event sink implementations for sending replication events
 //! to different destinations including HTTP endpoints, Hook0, and STDOUT.
 
-use crate::core::errors::ReplicationResult;
 use super::EventSink;
+use super::retry::RetryingSink;
+use super::router::{EventRouter, Route};
+use crate::core::errors::ReplicationResult;
+use crate::protocol::messages::RelationCache;
+use std::sync::atomic::AtomicBool;
+use tracing::info;
 
+pub mod cloudevents;
+pub mod debezium_formatter;
 pub mod event_formatter;
 pub mod hook0;
 pub mod hook0_error;
 pub mod http;
 pub mod pg_type_conversion;
 pub mod stdout;
+pub mod typed_formatter;
 
 /// Registry for managing and creating event sinks
 pub struct EventSinkRegistry;
 
 impl EventSinkRegistry {
+    /// Create the router delivering to every configured sink
+    ///
+    /// `relations` is the server's relation cache, used to match table patterns
+    /// and evaluate row filters. `sink_relations` holds the relations as the
+    /// sinks receive them, after column transforms, for sinks emitting typed events.
+    /// Retries stop waiting once `shutdown_signal` is set.
+    pub fn create_router(
+        config: &crate::core::config::ReplicationConfig,
+        relations: RelationCache,
+        sink_relations: RelationCache,
+        shutdown_signal: std::sync::Arc<AtomicBool>,
+    ) -> ReplicationResult<EventRouter> {
+        let mut routes = Vec::new();
+        for sink in &config.sinks {
+            info!("Initializing {} event sink '{}'", sink.sink, sink.name);
+            routes.push(Route {
+                name: sink.name.clone(),
+                sink: Self::create_sink(
+                    sink,
+                    config,
+                    sink_relations.clone(),
+                    shutdown_signal.clone(),
+                )?,
+                rule: sink.rule.clone(),
+                blocking: sink.blocking,
+            });
+        }
+        Ok(EventRouter::new(routes, relations))
+    }
+
     /// Create an event sink based on configuration
+    ///
+    /// `relations` is the server's relation cache, used by sinks emitting typed events.
+    /// The HTTP, Hook0 and WebAssembly sinks are wrapped in their configured retry policy.
     pub fn create_sink(
-        sink_type: &crate::core::config::EventSinkType,
+        sink_config: &crate::core::config::SinkConfig,
         config: &crate::core::config::ReplicationConfig,
+        relations: RelationCache,
+        shutdown_signal: std::sync::Arc<AtomicBool>,
     ) -> ReplicationResult<std::sync::Arc<dyn EventSink + Send + Sync>> {
-        match sink_type {
+        let database =
+            crate::utils::connection::database_name(&config.connection_string).unwrap_or_default();
+        let debezium = debezium_formatter::DebeziumConfig {
+            topic_prefix: config.debezium_topic_prefix.clone(),
+            database: database.clone(),
+            include_schema: config.debezium_schemas,
+        };
+        let formatter = event_formatter::SinkFormatter::new(
+            &sink_config.event_format,
+            relations.clone(),
+            debezium,
+        );
+        match sink_config.sink {
             crate::core::config::EventSinkType::Http => {
-                if let Some(ref url) = config.http_endpoint_url {
+                if let Some(ref url) = sink_config.http_endpoint_url {
                     let http_config = http::HttpEventSinkConfig {
                         endpoint_url: url.clone(),
                     };
-                    let sink = http::HttpEventSink::new(http_config)
+                    let mut sink = http::HttpEventSink::new(http_config, formatter)
                         .map_err(|e| crate::core::errors::ReplicationError::config(e))?;
-                    Ok(std::sync::Arc::new(sink) as std::sync::Arc<dyn EventSink + Send + Sync>)
+                    if let Some(mode) = &config.cloudevents {
+                        sink = sink.with_cloudevents(cloudevents::CloudEvents::new(
+                            mode.clone(),
+                            &database,
+                            &config.slot_name,
+                            relations,
+                        ));
+                    }
+                    Ok(std::sync::Arc::new(RetryingSink::new(
+                        std::sync::Arc::new(sink),
+                        sink_config.retry.clone(),
+                        shutdown_signal,
+                    )))
                 } else {
                     Err(crate::core::errors::ReplicationError::config(
                         "HTTP endpoint URL required for HTTP sink",
@@ -39,18 +106,31 @@ impl EventSinkRegistry {
             }
             crate::core::config::EventSinkType::Hook0 => {
                 if let (Some(ref api_url), Some(app_id), Some(ref api_token)) = (
-                    config.hook0_api_url.as_ref(),
-                    config.hook0_application_id,
-                    config.hook0_api_token.as_ref(),
+                    sink_config.hook0_api_url.as_ref(),
+                    sink_config.hook0_application_id,
+                    sink_config.hook0_api_token.as_ref(),
                 ) {
+                    let without_dead_letters =
+                        config.dead_letter_queue == crate::core::config::DeadLetterQueueMode::Off;
                     let hook0_config = hook0::Hook0EventSinkConfig {
                         api_url: api_url.to_string(),
                         application_id: app_id,
                         api_token: api_token.to_string(),
+                        message_prefix: config.hook0_message_prefix.clone(),
+                        skip_undeliverable: without_dead_letters,
                     };
                     let sink = hook0::Hook0EventSink::new(hook0_config)
                         .map_err(|e| crate::core::errors::ReplicationError::config(e))?;
-                    Ok(std::sync::Arc::new(sink) as std::sync::Arc<dyn EventSink + Send + Sync>)
+                    // Events that never reached Hook0 are skipped when there
+                    // is no dead-letter queue to keep them
+                    Ok(std::sync::Arc::new(
+                        RetryingSink::new(
+                            std::sync::Arc::new(sink),
+                            sink_config.retry.clone(),
+                            shutdown_signal,
+                        )
+                        .with_skip_unreachable(without_dead_letters),
+                    ))
                 } else {
                     Err(crate::core::errors::ReplicationError::config(
                         "Hook0 API URL, application ID, and token required for Hook0 sink",
@@ -58,9 +138,35 @@ impl EventSinkRegistry {
                 }
             }
             crate::core::config::EventSinkType::Stdout => {
-                let sink = stdout::StdoutEventSink {};
+                let sink = stdout::StdoutEventSink::with_formatter(formatter);
                 Ok(std::sync::Arc::new(sink) as std::sync::Arc<dyn EventSink + Send + Sync>)
             }
+            #[cfg(feature = "wasm")]
+            crate::core::config::EventSinkType::Wasm => {
+                let Some(ref path) = sink_config.wasm_plugin else {
+                    return Err(crate::core::errors::ReplicationError::config(
+                        "Plugin path required for WebAssembly sink",
+                    ));
+                };
+                let sink = super::plugin::WasmEventSink::load(
+                    path,
+                    sink_config.wasm_allow_network,
+                    formatter,
+                )?;
+                Ok(std::sync::Arc::new(RetryingSink::new(
+                    std::sync::Arc::new(sink),
+                    sink_config.retry.clone(),
+                    shutdown_signal,
+                )))
+            }
+            #[cfg(not(feature = "wasm"))]
+            crate::core::config::EventSinkType::Wasm => {
+                let path = sink_config.wasm_plugin.clone().unwrap_or_default();
+                Err(crate::core::errors::ReplicationError::config(format!(
+                    "WebAssembly plugin {} requires walpipe built with the wasm feature",
+                    path.display()
+                )))
+            }
         }
     }
 }
\ No newline at end of file
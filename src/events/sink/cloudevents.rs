@@ -0,0 +1,389 @@
+//! CloudEvents 1.0 wrapping for the HTTP sink
+//!
+//! Every event produced by the sink's formatter becomes the `data` of a
+//! CloudEvent, sent either in structured mode (one `application/cloudevents+json`
+//! body) or in binary mode (attributes as `ce-*` headers, data as the body).
+//!
+//! - `id`: `<commit_lsn>-<xid>-<change lsn>`, suffixed with `-<index>` when a change is
+//!   formatted into several events (`snapshot-<lsn>-<relation>-<key>` for rows of the
+//!   initial snapshot), so a retried or replayed change keeps its ids
+//! - `source`: `/walpipe/<database>/<slot>`
+//! - `type`: `walpipe.<schema>.<table>.<op>` for row changes, `walpipe.<message>` otherwise
+//! - `time`: the commit timestamp
+
+use serde_json::{Value, json};
+use std::sync::Mutex;
+
+use super::event_formatter::EventFormatter;
+use super::typed_formatter::TransactionContext;
+use crate::core::config::CloudEventsMode;
+use crate::events::TransactionBatch;
+use crate::protocol::messages::{RelationCache, ReplicationMessage, TupleData};
+use crate::utils::binary::Oid;
+use crate::utils::timestamp::postgres_timestamp_to_datetime;
+
+/// A CloudEvent ready to be sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloudEvent {
+    pub id: String,
+    pub source: String,
+    pub event_type: String,
+    pub subject: Option<String>,
+    pub time: Option<String>,
+    pub data: Value,
+}
+
+impl CloudEvent {
+    /// Structured content mode body
+    pub fn to_structured(&self) -> Value {
+        let mut event = json!({
+            "specversion": "1.0",
+            "id": self.id,
+            "source": self.source,
+            "type": self.event_type,
+            "datacontenttype": "application/json",
+            "data": self.data,
+        });
+        if let Some(subject) = &self.subject {
+            event["subject"] = json!(subject);
+        }
+        if let Some(time) = &self.time {
+            event["time"] = json!(time);
+        }
+        event
+    }
+
+    /// Binary content mode headers, the body being `data`
+    pub fn binary_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("ce-specversion", "1.0".to_string()),
+            ("ce-id", self.id.clone()),
+            ("ce-source", self.source.clone()),
+            ("ce-type", self.event_type.clone()),
+            ("content-type", "application/json".to_string()),
+        ];
+        if let Some(subject) = &self.subject {
+            headers.push(("ce-subject", subject.clone()));
+        }
+        if let Some(time) = &self.time {
+            headers.push(("ce-time", time.clone()));
+        }
+        headers
+    }
+
+    /// Body and headers of the HTTP request carrying the event in `mode`
+    pub fn into_request(self, mode: &CloudEventsMode) -> (Value, Vec<(&'static str, String)>) {
+        match mode {
+            CloudEventsMode::Binary => {
+                let headers = self.binary_headers();
+                (self.data, headers)
+            }
+            CloudEventsMode::Structured => (
+                self.to_structured(),
+                vec![("content-type", "application/cloudevents+json".to_string())],
+            ),
+        }
+    }
+}
+
+/// Builds CloudEvents around formatted events, identifying them by the change they come from
+pub struct CloudEvents {
+    pub mode: CloudEventsMode,
+    source: String,
+    relations: RelationCache,
+    transaction: Mutex<Option<TransactionContext>>,
+}
+
+impl CloudEvents {
+    /// `database` and `slot` name the event `source`
+    pub fn new(
+        mode: CloudEventsMode,
+        database: &str,
+        slot: &str,
+        relations: RelationCache,
+    ) -> Self {
+        Self {
+            mode,
+            source: format!("/walpipe/{}/{}", database, slot),
+            relations,
+            transaction: Mutex::new(None),
+        }
+    }
+
+    /// Wraps the events formatted from `message`
+    ///
+    /// Ids only depend on the message, so wrapping it again on a retry or a
+    /// dead letter replay yields the same ids.
+    pub fn wrap(&self, message: &ReplicationMessage, events: Vec<Value>) -> Vec<CloudEvent> {
+        let mut transaction = self
+            .transaction
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(context) = TransactionContext::from_begin(message) {
+            *transaction = Some(context);
+        }
+
+        let position = self.position(transaction.as_ref(), message);
+        let time = match message {
+            ReplicationMessage::LogicalMessage {
+                transactional: false,
+                ..
+            }
+            | ReplicationMessage::Read { .. } => None,
+            _ => transaction
+                .as_ref()
+                .and_then(|context| iso_time(context.commit_timestamp)),
+        };
+        let (event_type, subject) = self.event_type(message);
+
+        let count = events.len();
+        let events = events
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| CloudEvent {
+                // A change formatted into several events numbers them
+                id: if count > 1 {
+                    format!("{}-{}", position, index)
+                } else {
+                    position.clone()
+                },
+                source: self.source.clone(),
+                event_type: event_type.clone(),
+                subject: subject.clone(),
+                time: time.clone(),
+                data,
+            })
+            .collect();
+
+        if matches!(
+            message,
+            ReplicationMessage::Commit { .. } | ReplicationMessage::Prepare { .. }
+        ) {
+            *transaction = None;
+        }
+        events
+    }
+
+    /// Wraps a whole transaction envelope as a single `walpipe.transaction` event
+    pub fn wrap_transaction(&self, transaction: &TransactionBatch, data: Value) -> CloudEvent {
+        CloudEvent {
+            id: format!("{}-{}", transaction.commit_lsn, transaction.xid),
+            source: self.source.clone(),
+            event_type: "walpipe.transaction".to_string(),
+            subject: None,
+            time: iso_time(transaction.commit_timestamp),
+            data,
+        }
+    }
+
+    /// `<commit_lsn>-<xid>-<change>` position of a message, `<change>` being
+    /// its WAL position or, for messages without one, its name
+    fn position(
+        &self,
+        transaction: Option<&TransactionContext>,
+        message: &ReplicationMessage,
+    ) -> String {
+        let change = match message {
+            // Snapshot rows precede every transaction and are identified by their key
+            ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                snapshot_lsn,
+            } => {
+                return format!(
+                    "snapshot-{}-{}-{}",
+                    snapshot_lsn,
+                    relation_id,
+                    self.row_key(*relation_id, tuple_data)
+                );
+            }
+            // Non-transactional messages are positioned by their own LSN
+            ReplicationMessage::LogicalMessage {
+                transactional: false,
+                lsn,
+                ..
+            } => return format!("0-0-{}", lsn),
+            ReplicationMessage::Insert { lsn, .. }
+            | ReplicationMessage::Update { lsn, .. }
+            | ReplicationMessage::Delete { lsn, .. }
+            | ReplicationMessage::Truncate { lsn, .. }
+            | ReplicationMessage::LogicalMessage { lsn, .. } => lsn.to_string(),
+            ReplicationMessage::Commit { commit_lsn, .. } => commit_lsn.to_string(),
+            ReplicationMessage::Prepare { prepare_lsn, .. } => prepare_lsn.to_string(),
+            ReplicationMessage::Relation { relation } => format!("relation-{}", relation.oid),
+            ReplicationMessage::Type { oid, .. } => format!("type-{}", oid),
+            _ => raw_type(message),
+        };
+
+        let (commit_lsn, xid) = match (transaction, message) {
+            (Some(context), _) => (context.commit_lsn, context.xid),
+            // Messages ending a transaction outside Begin/Commit carry its identity
+            (
+                None,
+                ReplicationMessage::StreamCommit {
+                    commit_lsn, xid, ..
+                }
+                | ReplicationMessage::CommitPrepared {
+                    commit_lsn, xid, ..
+                },
+            ) => (*commit_lsn, *xid),
+            (
+                None,
+                ReplicationMessage::StreamPrepare {
+                    prepare_lsn, xid, ..
+                },
+            ) => (*prepare_lsn, *xid),
+            (
+                None,
+                ReplicationMessage::RollbackPrepared {
+                    rollback_end_lsn,
+                    xid,
+                    ..
+                },
+            ) => (*rollback_end_lsn, *xid),
+            (
+                None,
+                ReplicationMessage::StreamStart { xid, .. }
+                | ReplicationMessage::StreamAbort { xid, .. },
+            ) => (0, *xid),
+            (None, _) => (0, 0),
+        };
+        format!("{}-{}-{}", commit_lsn, xid, change)
+    }
+
+    /// Replica identity values of a snapshot row, every value if the table has no key
+    fn row_key(&self, relation_id: Oid, tuple: &TupleData) -> String {
+        let relation = self.relations.get(relation_id);
+        let keyed = |index: usize| {
+            relation.as_ref().is_some_and(|relation| {
+                relation
+                    .columns
+                    .get(index)
+                    .is_some_and(|column| column.key_flag & 1 != 0)
+            })
+        };
+        let has_key = (0..tuple.columns.len()).any(keyed);
+        tuple
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !has_key || keyed(*index))
+            .map(|(_, column)| column.data.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// CloudEvents `type` and `subject` (`<schema>.<table>`) of a message
+    fn event_type(&self, message: &ReplicationMessage) -> (String, Option<String>) {
+        let table_event = |relation_id: &Oid, op: &str| {
+            self.relations.get(*relation_id).map(|relation| {
+                let subject = format!("{}.{}", relation.namespace, relation.relation_name);
+                (format!("walpipe.{}.{}", subject, op), Some(subject))
+            })
+        };
+
+        let typed = match message {
+            ReplicationMessage::Insert { relation_id, .. } => table_event(relation_id, "insert"),
+            ReplicationMessage::Read { relation_id, .. } => table_event(relation_id, "read"),
+            ReplicationMessage::Update { relation_id, .. } => table_event(relation_id, "update"),
+            ReplicationMessage::Delete { relation_id, .. } => table_event(relation_id, "delete"),
+            ReplicationMessage::Truncate { relation_ids, .. } if relation_ids.len() == 1 => {
+                table_event(&relation_ids[0], "truncate")
+            }
+            _ => None,
+        };
+        typed.unwrap_or_else(|| (format!("walpipe.{}", raw_type(message)), None))
+    }
+}
+
+/// Name of a message in the raw event format
+fn raw_type(message: &ReplicationMessage) -> String {
+    EventFormatter::format(message)["type"]
+        .as_str()
+        .unwrap_or("event")
+        .to_string()
+}
+
+fn iso_time(timestamp: i64) -> Option<String> {
+    postgres_timestamp_to_datetime(timestamp)
+        .map(|datetime| datetime.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{insert, keyed_relation, row};
+
+    fn cloudevents() -> CloudEvents {
+        let relations = RelationCache::new();
+        relations.insert(keyed_relation(42, "public", "users", &["id"]));
+        CloudEvents::new(CloudEventsMode::Structured, "app", "sub", relations)
+    }
+
+    #[test]
+    fn test_cloudevents_ids_and_attributes() {
+        let cloudevents = cloudevents();
+        let begin = ReplicationMessage::Begin {
+            final_lsn: 900,
+            timestamp: 1_000_000,
+            xid: 5,
+        };
+        cloudevents.wrap(&begin, vec![json!({"type": "begin"})]);
+
+        let insert = at_lsn(insert(42, row(&[Some("1")])), 880);
+        let events = cloudevents.wrap(&insert, vec![json!({"op": "insert"})]);
+        assert_eq!(events[0].id, "900-5-880");
+        assert_eq!(events[0].source, "/walpipe/app/sub");
+        assert_eq!(events[0].event_type, "walpipe.public.users.insert");
+        assert_eq!(events[0].subject.as_deref(), Some("public.users"));
+        assert_eq!(events[0].time.as_deref(), Some("2000-01-01T00:00:01Z"));
+
+        let structured = events[0].to_structured();
+        assert_eq!(structured["specversion"], "1.0");
+        assert_eq!(structured["data"], json!({"op": "insert"}));
+
+        let (body, headers) = events[0].clone().into_request(&CloudEventsMode::Binary);
+        assert_eq!(body, json!({"op": "insert"}));
+        assert!(headers.contains(&("ce-id", "900-5-880".to_string())));
+    }
+
+    #[test]
+    fn test_cloudevents_ids_survive_rewrapping() {
+        let cloudevents = cloudevents();
+        let begin = ReplicationMessage::Begin {
+            final_lsn: 900,
+            timestamp: 1_000_000,
+            xid: 5,
+        };
+        cloudevents.wrap(&begin, vec![json!({"type": "begin"})]);
+
+        // A retry wraps the same change again: its ids must not move
+        let insert = at_lsn(insert(42, row(&[Some("1")])), 880);
+        let fan_out = || vec![json!({"part": 0}), json!({"part": 1})];
+        let first = cloudevents.wrap(&insert, fan_out());
+        let second = cloudevents.wrap(&insert, fan_out());
+        let ids = |events: &[CloudEvent]| events.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&first), vec!["900-5-880-0", "900-5-880-1"]);
+        assert_eq!(ids(&first), ids(&second));
+
+        let read = ReplicationMessage::Read {
+            relation_id: 42,
+            tuple_data: row(&[Some("7")]),
+            snapshot_lsn: 100,
+        };
+        let snapshot = cloudevents.wrap(&read, vec![json!({"op": "read"})]);
+        assert_eq!(snapshot[0].id, "snapshot-100-42-7");
+        assert_eq!(
+            cloudevents.wrap(&read, vec![json!({})])[0].id,
+            snapshot[0].id
+        );
+    }
+
+    fn at_lsn(mut message: ReplicationMessage, position: u64) -> ReplicationMessage {
+        if let ReplicationMessage::Insert { lsn, .. } = &mut message {
+            *lsn = position;
+        }
+        message
+    }
+}
@@ -0,0 +1,524 @@
+//! Debezium-compatible change event envelope
+//!
+//! Produces the value part of the events emitted by Debezium's PostgreSQL
+//! connector, so consumers written against Debezium keep working:
+//!
+//! ```json
+//! {"before": null, "after": {"id": 1, "name": "alice"},
+//!  "source": {"connector": "postgresql", "db": "app", "schema": "public",
+//!             "table": "users", "txId": 742, "lsn": 23861232, "ts_ms": 1714557600123, ...},
+//!  "op": "c", "ts_ms": 1714557600456, "transaction": null}
+//! ```
+//!
+//! Values follow Debezium's default converters with `decimal.handling.mode=string`:
+//! dates are days since the epoch, timestamps microseconds since the epoch,
+//! `timestamptz` an ISO 8601 string, json/jsonb strings and bytea base64.
+//! With the schema section enabled every event is wrapped as `{"schema", "payload"}`
+//! like Kafka Connect's JSON converter with `schemas.enable=true`.
+//!
+//! `source.lsn` is the WAL position of the change itself, and `source.sequence`
+//! the `["<commit lsn>","<lsn>"]` pair, so consumers can order and deduplicate
+//! changes within a transaction.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{NaiveDate, SecondsFormat};
+use serde_json::{Map, Value, json};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use super::event_formatter::EventFormatter;
+use super::pg_type_conversion::{ColumnValue, PgType, decode_column};
+use super::typed_formatter::TransactionContext;
+use crate::events::TransactionBatch;
+use crate::protocol::messages::{RelationCache, RelationInfo, ReplicationMessage, TupleData};
+use crate::utils::binary::Oid;
+use crate::utils::timestamp::postgres_timestamp_to_datetime;
+
+/// Placeholder Debezium emits for unchanged TOAST columns
+const UNAVAILABLE_VALUE: &str = "__debezium_unavailable_value";
+
+/// Settings shared by every event of a Debezium formatter
+#[derive(Debug, Clone)]
+pub struct DebeziumConfig {
+    /// Logical server name, Debezium's `topic.prefix` (`source.name`)
+    pub topic_prefix: String,
+    /// Database name reported in `source.db`
+    pub database: String,
+    /// Wrap events as `{"schema", "payload"}`
+    pub include_schema: bool,
+}
+
+/// Formats replication messages as Debezium change events
+pub struct DebeziumEventFormatter {
+    relations: RelationCache,
+    config: DebeziumConfig,
+    transaction: Mutex<Option<TransactionContext>>,
+}
+
+impl DebeziumEventFormatter {
+    pub fn new(relations: RelationCache, config: DebeziumConfig) -> Self {
+        Self {
+            relations,
+            config,
+            transaction: Mutex::new(None),
+        }
+    }
+
+    /// Format a replication message delivered on its own
+    ///
+    /// A truncate yields one event per table, as Debezium does. Begin, Commit and
+    /// schema messages only update the formatter's context and yield nothing.
+    pub fn format(&self, message: &ReplicationMessage) -> Vec<Value> {
+        let mut transaction = self
+            .transaction
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match message {
+            ReplicationMessage::Begin { .. } | ReplicationMessage::BeginPrepare { .. } => {
+                *transaction = TransactionContext::from_begin(message);
+                Vec::new()
+            }
+            ReplicationMessage::Commit { .. } | ReplicationMessage::Prepare { .. } => {
+                *transaction = None;
+                Vec::new()
+            }
+            _ => self.change_events(message, transaction.as_ref()),
+        }
+    }
+
+    /// Format a whole transaction as a JSON array of its change events
+    pub fn format_transaction(&self, transaction: &TransactionBatch) -> Value {
+        let context = TransactionContext::from_begin(&transaction.begin);
+        Value::Array(
+            transaction
+                .changes
+                .iter()
+                .flat_map(|message| self.change_events(message, context.as_ref()))
+                .collect(),
+        )
+    }
+
+    /// Debezium events for a change message
+    ///
+    /// Row changes of relations not received yet fall back to the raw format.
+    fn change_events(
+        &self,
+        message: &ReplicationMessage,
+        transaction: Option<&TransactionContext>,
+    ) -> Vec<Value> {
+        let events = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                lsn,
+                ..
+            } => self.relation(*relation_id).map(|relation| {
+                vec![self.row_event(&relation, "c", None, Some(tuple_data), *lsn, transaction)]
+            }),
+            ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                snapshot_lsn,
+            } => self.relation(*relation_id).map(|relation| {
+                let mut event =
+                    self.row_event(&relation, "r", None, Some(tuple_data), 0, transaction);
+                let payload = match self.config.include_schema {
+                    true => &mut event["payload"],
+                    false => &mut event,
+                };
+                payload["source"]["snapshot"] = json!("true");
+                payload["source"]["lsn"] = json!(snapshot_lsn);
+                vec![event]
+            }),
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                lsn,
+                ..
+            } => self.relation(*relation_id).map(|relation| {
+                vec![self.row_event(
+                    &relation,
+                    "u",
+                    old_tuple_data.as_ref(),
+                    Some(new_tuple_data),
+                    *lsn,
+                    transaction,
+                )]
+            }),
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                lsn,
+                ..
+            } => self.relation(*relation_id).map(|relation| {
+                vec![self.row_event(&relation, "d", Some(tuple_data), None, *lsn, transaction)]
+            }),
+            ReplicationMessage::Truncate {
+                relation_ids, lsn, ..
+            } => Some(
+                relation_ids
+                    .iter()
+                    .filter_map(|oid| self.relation(*oid))
+                    .map(|relation| self.row_event(&relation, "t", None, None, *lsn, transaction))
+                    .collect(),
+            ),
+            ReplicationMessage::LogicalMessage {
+                transactional,
+                lsn,
+                prefix,
+                content,
+                ..
+            } => Some(vec![self.message_event(
+                *transactional,
+                *lsn,
+                prefix,
+                content,
+                transaction,
+            )]),
+            // Schema, origin and stream control messages have no Debezium equivalent
+            ReplicationMessage::Relation { .. }
+            | ReplicationMessage::Type { .. }
+            | ReplicationMessage::Origin { .. }
+            | ReplicationMessage::StreamStart { .. }
+            | ReplicationMessage::StreamStop => Some(Vec::new()),
+            _ => None,
+        };
+        events.unwrap_or_else(|| vec![EventFormatter::format(message)])
+    }
+
+    fn relation(&self, oid: Oid) -> Option<RelationInfo> {
+        let relation = self.relations.get(oid);
+        if relation.is_none() {
+            warn!(
+                "No relation cached for OID {}, using the raw event format",
+                oid
+            );
+        }
+        relation
+    }
+
+    /// `source` block describing where a change comes from
+    ///
+    /// `lsn` is the position of the change, the commit LSN standing in when unknown.
+    fn source(
+        &self,
+        relation: Option<&RelationInfo>,
+        lsn: Option<u64>,
+        transaction: Option<&TransactionContext>,
+    ) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "connector": "postgresql",
+            "name": self.config.topic_prefix,
+            "ts_ms": transaction.and_then(|t| commit_millis(t.commit_timestamp)),
+            "snapshot": "false",
+            "db": self.config.database,
+            "sequence": transaction.zip(lsn).map(|(t, lsn)| {
+                json!([t.commit_lsn.to_string(), lsn.to_string()]).to_string()
+            }),
+            "schema": relation.map(|r| r.namespace.as_str()),
+            "table": relation.map(|r| r.relation_name.as_str()),
+            "txId": transaction.map(|t| t.xid),
+            "lsn": lsn.or(transaction.map(|t| t.commit_lsn)),
+            "xmin": null,
+        })
+    }
+
+    fn row_event(
+        &self,
+        relation: &RelationInfo,
+        op: &str,
+        before: Option<&TupleData>,
+        after: Option<&TupleData>,
+        lsn: u64,
+        transaction: Option<&TransactionContext>,
+    ) -> Value {
+        let lsn = Some(lsn).filter(|lsn| *lsn > 0);
+        let payload = json!({
+            "before": before.map(|tuple| row_struct(relation, tuple)),
+            "after": after.map(|tuple| row_struct(relation, tuple)),
+            "source": self.source(Some(relation), lsn, transaction),
+            "op": op,
+            "ts_ms": now_millis(),
+            "transaction": null,
+        });
+        self.wrap(payload, || self.envelope_schema(relation))
+    }
+
+    fn message_event(
+        &self,
+        transactional: bool,
+        lsn: u64,
+        prefix: &str,
+        content: &[u8],
+        transaction: Option<&TransactionContext>,
+    ) -> Value {
+        let transaction = transaction.filter(|_| transactional);
+        let payload = json!({
+            "op": "m",
+            "ts_ms": now_millis(),
+            "source": self.source(None, Some(lsn), transaction),
+            "message": {
+                "prefix": prefix,
+                "content": BASE64.encode(content),
+            },
+        });
+        self.wrap(payload, || {
+            json!({
+                "type": "struct",
+                "optional": false,
+                "name": "io.debezium.connector.postgresql.MessageValue",
+                "fields": [
+                    {"type": "string", "optional": false, "field": "op"},
+                    {"type": "int64", "optional": true, "field": "ts_ms"},
+                    source_schema(),
+                    {
+                        "type": "struct",
+                        "optional": true,
+                        "name": "io.debezium.connector.postgresql.Message",
+                        "field": "message",
+                        "fields": [
+                            {"type": "string", "optional": false, "field": "prefix"},
+                            {"type": "bytes", "optional": true, "field": "content"},
+                        ],
+                    },
+                ],
+            })
+        })
+    }
+
+    fn wrap(&self, payload: Value, schema: impl FnOnce() -> Value) -> Value {
+        if self.config.include_schema {
+            json!({ "schema": schema(), "payload": payload })
+        } else {
+            payload
+        }
+    }
+
+    /// Kafka Connect schema of a row change event of `relation`
+    fn envelope_schema(&self, relation: &RelationInfo) -> Value {
+        let prefix = format!(
+            "{}.{}.{}",
+            self.config.topic_prefix, relation.namespace, relation.relation_name
+        );
+        let value_fields = relation
+            .columns
+            .iter()
+            .map(|column| {
+                let (field_type, name) = field_type(column.column_type);
+                let mut field = json!({
+                    "type": field_type,
+                    // Nullability is not part of the Relation message; only key columns are known to be set
+                    "optional": column.key_flag & 1 == 0,
+                    "field": column.column_name,
+                });
+                if let Some(name) = name {
+                    field["name"] = json!(name);
+                    field["version"] = json!(1);
+                }
+                field
+            })
+            .collect::<Vec<_>>();
+        let row_schema = |field: &str| {
+            json!({
+                "type": "struct",
+                "optional": true,
+                "name": format!("{}.Value", prefix),
+                "field": field,
+                "fields": value_fields,
+            })
+        };
+
+        json!({
+            "type": "struct",
+            "optional": false,
+            "name": format!("{}.Envelope", prefix),
+            "version": 1,
+            "fields": [
+                row_schema("before"),
+                row_schema("after"),
+                source_schema(),
+                {"type": "string", "optional": false, "field": "op"},
+                {"type": "int64", "optional": true, "field": "ts_ms"},
+                {
+                    "type": "struct",
+                    "optional": true,
+                    "name": "event.block",
+                    "version": 1,
+                    "field": "transaction",
+                    "fields": [
+                        {"type": "string", "optional": false, "field": "id"},
+                        {"type": "int64", "optional": false, "field": "total_order"},
+                        {"type": "int64", "optional": false, "field": "data_collection_order"},
+                    ],
+                },
+            ],
+        })
+    }
+}
+
+/// Maps a tuple to a Debezium row struct
+fn row_struct(relation: &RelationInfo, tuple: &TupleData) -> Value {
+    let mut row = Map::new();
+    for (info, column) in relation.columns.iter().zip(&tuple.columns) {
+        let value = decode_column(info.column_type, column)
+            .map(|value| debezium_value(&value))
+            .unwrap_or(Value::Null);
+        row.insert(info.column_name.clone(), value);
+    }
+    Value::Object(row)
+}
+
+/// JSON value in the representation of Debezium's default converters
+fn debezium_value(value: &ColumnValue) -> Value {
+    match value {
+        ColumnValue::Bytes(bytes) => json!(BASE64.encode(bytes)),
+        ColumnValue::Json(json) => json!(json.to_string()),
+        ColumnValue::Date(date) => {
+            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("valid date");
+            json!((*date - epoch).num_days())
+        }
+        ColumnValue::Timestamp(timestamp) => json!(timestamp.and_utc().timestamp_micros()),
+        ColumnValue::TimestampTZ(timestamp) => {
+            json!(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        }
+        ColumnValue::Unchanged => json!(UNAVAILABLE_VALUE),
+        other => other.to_json(),
+    }
+}
+
+/// Kafka Connect type and semantic type name of a PostgreSQL column type
+fn field_type(column_type: Oid) -> (&'static str, Option<&'static str>) {
+    match PgType::try_from(column_type) {
+        Ok(PgType::Bool) => ("boolean", None),
+        Ok(PgType::Int2) => ("int16", None),
+        Ok(PgType::Int4) => ("int32", None),
+        Ok(PgType::Int8) | Ok(PgType::Oid) => ("int64", None),
+        Ok(PgType::Float4) => ("float", None),
+        Ok(PgType::Float8) => ("double", None),
+        Ok(PgType::Bytea) => ("bytes", None),
+        Ok(PgType::Json) | Ok(PgType::Jsonb) => ("string", Some("io.debezium.data.Json")),
+        Ok(PgType::Uuid) => ("string", Some("io.debezium.data.Uuid")),
+        Ok(PgType::Date) => ("int32", Some("io.debezium.time.Date")),
+        Ok(PgType::Timestamp) => ("int64", Some("io.debezium.time.MicroTimestamp")),
+        Ok(PgType::Timestamptz) => ("string", Some("io.debezium.time.ZonedTimestamp")),
+        _ => ("string", None),
+    }
+}
+
+/// Kafka Connect schema of the `source` block
+fn source_schema() -> Value {
+    let field = |field_type: &str, optional: bool, name: &str| json!({"type": field_type, "optional": optional, "field": name});
+    json!({
+        "type": "struct",
+        "optional": false,
+        "name": "io.debezium.connector.postgresql.Source",
+        "field": "source",
+        "fields": [
+            field("string", false, "version"),
+            field("string", false, "connector"),
+            field("string", false, "name"),
+            field("int64", true, "ts_ms"),
+            field("string", true, "snapshot"),
+            field("string", false, "db"),
+            field("string", true, "sequence"),
+            field("string", true, "schema"),
+            field("string", true, "table"),
+            field("int64", true, "txId"),
+            field("int64", true, "lsn"),
+            field("int64", true, "xmin"),
+        ],
+    })
+}
+
+/// Commit timestamp in milliseconds since the Unix epoch
+fn commit_millis(timestamp: i64) -> Option<i64> {
+    postgres_timestamp_to_datetime(timestamp).map(|datetime| datetime.timestamp_millis())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{begin, column, insert, relation, text, tuple};
+
+    fn formatter(include_schema: bool) -> DebeziumEventFormatter {
+        let relations = RelationCache::new();
+        relations.insert(relation(
+            42,
+            "public",
+            "orders",
+            vec![
+                column("id", 20, true),
+                column("placed_on", 1082, false),
+                column("total", 1700, false),
+            ],
+        ));
+        DebeziumEventFormatter::new(
+            relations,
+            DebeziumConfig {
+                topic_prefix: "walpipe".to_string(),
+                database: "shop".to_string(),
+                include_schema,
+            },
+        )
+    }
+
+    fn order() -> ReplicationMessage {
+        insert(42, tuple(vec![text("7"), text("1970-01-11"), text("9.90")]))
+    }
+
+    #[test]
+    fn test_debezium_insert_envelope() {
+        let formatter = formatter(false);
+        formatter.format(&begin(500, 9));
+
+        let mut order = order();
+        if let ReplicationMessage::Insert { lsn, .. } = &mut order {
+            *lsn = 480;
+        }
+        let events = formatter.format(&order);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["op"], "c");
+        assert_eq!(event["before"], Value::Null);
+        assert_eq!(
+            event["after"],
+            json!({"id": 7, "placed_on": 10, "total": "9.90"})
+        );
+        assert_eq!(event["source"]["db"], "shop");
+        assert_eq!(event["source"]["table"], "orders");
+        assert_eq!(event["source"]["txId"], 9);
+        assert_eq!(event["source"]["lsn"], 480);
+        assert_eq!(event["source"]["sequence"], r#"["500","480"]"#);
+        assert_eq!(event["source"]["ts_ms"], 946_684_800_000i64);
+    }
+
+    #[test]
+    fn test_debezium_schema_section() {
+        let events = formatter(true).format(&order());
+        let schema = &events[0]["schema"];
+        assert_eq!(schema["name"], "walpipe.public.orders.Envelope");
+        assert_eq!(
+            schema["fields"][1]["fields"][1],
+            json!({
+                "type": "int32",
+                "optional": true,
+                "field": "placed_on",
+                "name": "io.debezium.time.Date",
+                "version": 1,
+            })
+        );
+        assert_eq!(events[0]["payload"]["op"], "c");
+    }
+}
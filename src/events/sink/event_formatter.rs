@@ -3,8 +3,56 @@
 //! Provides functions for formatting replication events into different formats
 //! for various output destinations.
 
+use super::debezium_formatter::{DebeziumConfig, DebeziumEventFormatter};
+use super::typed_formatter::TypedEventFormatter;
+use crate::core::config::EventFormat;
+use crate::events::TransactionBatch;
+use crate::protocol::messages::{RelationCache, ReplicationMessage};
 use serde_json::json;
-use crate::protocol::messages::ReplicationMessage;
+use std::sync::Arc;
+
+/// Event format chosen for a sink
+#[derive(Clone)]
+pub enum SinkFormatter {
+    /// Protocol-level events from [`EventFormatter`]
+    Raw,
+    /// Schema-aware events from [`TypedEventFormatter`]
+    Typed(Arc<TypedEventFormatter>),
+    /// Debezium change events from [`DebeziumEventFormatter`]
+    Debezium(Arc<DebeziumEventFormatter>),
+}
+
+impl SinkFormatter {
+    pub fn new(format: &EventFormat, relations: RelationCache, debezium: DebeziumConfig) -> Self {
+        match format {
+            EventFormat::Raw => SinkFormatter::Raw,
+            EventFormat::Typed => {
+                SinkFormatter::Typed(Arc::new(TypedEventFormatter::new(relations)))
+            }
+            EventFormat::Debezium => {
+                SinkFormatter::Debezium(Arc::new(DebeziumEventFormatter::new(relations, debezium)))
+            }
+        }
+    }
+
+    /// Format a single message into the events to send, possibly none
+    pub fn format(&self, message: &ReplicationMessage) -> Vec<serde_json::Value> {
+        match self {
+            SinkFormatter::Raw => vec![EventFormatter::format(message)],
+            SinkFormatter::Typed(formatter) => formatter.format(message).into_iter().collect(),
+            SinkFormatter::Debezium(formatter) => formatter.format(message),
+        }
+    }
+
+    /// Format a whole transaction as one envelope
+    pub fn format_transaction(&self, transaction: &TransactionBatch) -> serde_json::Value {
+        match self {
+            SinkFormatter::Raw => EventFormatter::format_transaction(transaction),
+            SinkFormatter::Typed(formatter) => formatter.format_transaction(transaction),
+            SinkFormatter::Debezium(formatter) => formatter.format_transaction(transaction),
+        }
+    }
+}
 
 /// Event formatter to convert replication events to JSON
 pub struct EventFormatter;
@@ -50,6 +98,7 @@ impl EventFormatter {
                 tuple_data,
                 is_stream,
                 xid,
+                lsn: _,
             } => json!({
                 "type": "insert",
                 "relation_id": relation_id,
@@ -64,6 +113,7 @@ impl EventFormatter {
                 new_tuple_data,
                 is_stream,
                 xid,
+                lsn: _,
             } => json!({
                 "type": "update",
                 "relation_id": relation_id,
@@ -79,6 +129,7 @@ impl EventFormatter {
                 tuple_data,
                 is_stream,
                 xid,
+                lsn: _,
             } => json!({
                 "type": "delete",
                 "relation_id": relation_id,
@@ -92,6 +143,7 @@ impl EventFormatter {
                 flags,
                 is_stream,
                 xid,
+                lsn: _,
             } => json!({
                 "type": "truncate",
                 "relation_ids": relation_ids,
@@ -99,6 +151,16 @@ impl EventFormatter {
                 "is_stream": is_stream,
                 "xid": xid,
             }),
+            ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                snapshot_lsn,
+            } => json!({
+                "type": "read",
+                "relation_id": relation_id,
+                "tuple_data": Self::format_tuple_data(tuple_data),
+                "snapshot_lsn": snapshot_lsn,
+            }),
             ReplicationMessage::StreamStart { xid, first_segment } => json!({
                 "type": "stream_start",
                 "xid": xid,
@@ -124,31 +186,192 @@ impl EventFormatter {
             ReplicationMessage::StreamAbort {
                 xid,
                 subtransaction_xid,
+                abort_lsn,
+                abort_timestamp,
             } => json!({
                 "type": "stream_abort",
                 "xid": xid,
                 "subtransaction_xid": subtransaction_xid,
+                "abort_lsn": abort_lsn,
+                "abort_timestamp": abort_timestamp,
+            }),
+            ReplicationMessage::Origin { commit_lsn, name } => json!({
+                "type": "origin",
+                "commit_lsn": commit_lsn,
+                "name": name,
+            }),
+            ReplicationMessage::Type {
+                xid,
+                oid,
+                namespace,
+                name,
+            } => json!({
+                "type": "type",
+                "xid": xid,
+                "oid": oid,
+                "namespace": namespace,
+                "name": name,
+            }),
+            ReplicationMessage::LogicalMessage {
+                xid,
+                transactional,
+                lsn,
+                prefix,
+                content,
+            } => {
+                // Text payloads are passed through as-is, anything else is hex encoded
+                let (content, content_encoding) = match std::str::from_utf8(content) {
+                    Ok(text) => (text.to_string(), "utf8"),
+                    Err(_) => (Self::to_hex(content), "hex"),
+                };
+                json!({
+                    "type": "message",
+                    "xid": xid,
+                    "transactional": transactional,
+                    "lsn": lsn,
+                    "prefix": prefix,
+                    "content": content,
+                    "content_encoding": content_encoding,
+                })
+            }
+            ReplicationMessage::BeginPrepare {
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => json!({
+                "type": "begin_prepare",
+                "prepare_lsn": prepare_lsn,
+                "end_lsn": end_lsn,
+                "timestamp": timestamp,
+                "xid": xid,
+                "gid": gid,
+            }),
+            ReplicationMessage::Prepare {
+                flags,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => json!({
+                "type": "prepare",
+                "flags": flags,
+                "prepare_lsn": prepare_lsn,
+                "end_lsn": end_lsn,
+                "timestamp": timestamp,
+                "xid": xid,
+                "gid": gid,
+            }),
+            ReplicationMessage::CommitPrepared {
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => json!({
+                "type": "commit_prepared",
+                "flags": flags,
+                "commit_lsn": commit_lsn,
+                "end_lsn": end_lsn,
+                "timestamp": timestamp,
+                "xid": xid,
+                "gid": gid,
+            }),
+            ReplicationMessage::RollbackPrepared {
+                flags,
+                prepare_end_lsn,
+                rollback_end_lsn,
+                prepare_timestamp,
+                rollback_timestamp,
+                xid,
+                gid,
+            } => json!({
+                "type": "rollback_prepared",
+                "flags": flags,
+                "prepare_end_lsn": prepare_end_lsn,
+                "rollback_end_lsn": rollback_end_lsn,
+                "prepare_timestamp": prepare_timestamp,
+                "rollback_timestamp": rollback_timestamp,
+                "xid": xid,
+                "gid": gid,
+            }),
+            ReplicationMessage::StreamPrepare {
+                flags,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => json!({
+                "type": "stream_prepare",
+                "flags": flags,
+                "prepare_lsn": prepare_lsn,
+                "end_lsn": end_lsn,
+                "timestamp": timestamp,
+                "xid": xid,
+                "gid": gid,
             }),
         }
     }
 
+    /// Format a whole transaction as a single JSON envelope
+    ///
+    /// Changes keep their stream order and use the same shape as `format`.
+    /// Prepared transactions additionally carry their `gid`.
+    pub fn format_transaction(transaction: &TransactionBatch) -> serde_json::Value {
+        let mut envelope = json!({
+            "type": "transaction",
+            "xid": transaction.xid,
+            "commit_lsn": transaction.commit_lsn,
+            "end_lsn": transaction.end_lsn,
+            "timestamp": transaction.commit_timestamp,
+            "changes": transaction.changes.iter().map(Self::format).collect::<Vec<_>>(),
+        });
+        if let Some(gid) = &transaction.gid {
+            envelope["gid"] = json!(gid);
+        }
+        envelope
+    }
+
     /// Format tuple data for JSON serialization
     pub(crate) fn format_tuple_data(tuple_data: &crate::protocol::messages::TupleData) -> serde_json::Value {
         json!({
             "column_count": tuple_data.column_count,
-            "columns": tuple_data.columns.iter().map(|col| json!({
-                "data_type": col.data_type,
-                "length": col.length,
-                "data": col.data,
-            })).collect::<Vec<_>>(),
+            "columns": tuple_data.columns.iter().map(|col| match col.data_type {
+                // Unchanged TOAST values carry no data, say so explicitly
+                'u' => json!({
+                    "data_type": col.data_type,
+                    "length": col.length,
+                    "data": null,
+                    "unchanged": true,
+                }),
+                _ => json!({
+                    "data_type": col.data_type,
+                    "length": col.length,
+                    // Binary wire-format values are hex encoded
+                    "data": match &col.binary {
+                        Some(bytes) => Self::to_hex(bytes),
+                        None => col.data.clone(),
+                    },
+                }),
+            }).collect::<Vec<_>>(),
             "processed_length": tuple_data.processed_length,
         })
     }
+
+    /// Lowercase hex encoding for binary payloads
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::events::transaction::PendingTransaction;
     use crate::protocol::messages::{ColumnData, TupleData};
 
     #[test]
@@ -173,11 +396,13 @@ pub(crate) mod tests {
                     data_type: 't',
                     length: 4,
                     data: "test".to_string(),
+                    binary: None,
                 },
                 ColumnData {
                     data_type: 'n',
                     length: 0,
                     data: String::new(),
+                    binary: None,
                 },
             ],
             processed_length: 12,
@@ -188,6 +413,7 @@ pub(crate) mod tests {
             tuple_data,
             is_stream: false,
             xid: None,
+            lsn: 0,
         };
 
         let json = EventFormatter::format(&message);
@@ -195,4 +421,160 @@ pub(crate) mod tests {
         assert_eq!(json["relation_id"], 123);
         assert_eq!(json["tuple_data"]["columns"].as_array().unwrap().len(), 2);
     }
+
+    #[test]
+    fn test_format_transaction_envelope() {
+        let tuple_data = TupleData {
+            column_count: 1,
+            columns: vec![ColumnData {
+                data_type: 't',
+                length: 1,
+                data: "1".to_string(),
+                binary: None,
+            }],
+            processed_length: 8,
+        };
+
+        let mut pending = PendingTransaction::new(ReplicationMessage::Begin {
+            final_lsn: 300,
+            timestamp: 1609459200000000,
+            xid: 42,
+        });
+        pending.push(ReplicationMessage::Insert {
+            relation_id: 123,
+            tuple_data,
+            is_stream: false,
+            xid: None,
+            lsn: 0,
+        });
+        let transaction = pending
+            .finish(ReplicationMessage::Commit {
+                flags: 0,
+                commit_lsn: 300,
+                end_lsn: 340,
+                timestamp: 1609459200000001,
+            })
+            .unwrap();
+
+        let json = EventFormatter::format_transaction(&transaction);
+        assert_eq!(json["type"], "transaction");
+        assert_eq!(json["xid"], 42);
+        assert_eq!(json["commit_lsn"], 300);
+        assert_eq!(json["end_lsn"], 340);
+        assert_eq!(json["timestamp"], 1609459200000001i64);
+        assert_eq!(json["changes"].as_array().unwrap().len(), 1);
+        assert_eq!(json["changes"][0]["type"], "insert");
+
+        // Fallback delivery replays Begin, the changes and Commit in order
+        assert_eq!(transaction.messages().count(), 3);
+    }
+
+    #[test]
+    fn test_format_unchanged_toast_column() {
+        let tuple_data = TupleData {
+            column_count: 2,
+            columns: vec![
+                ColumnData {
+                    data_type: 't',
+                    length: 1,
+                    data: "1".to_string(),
+                    binary: None,
+                },
+                ColumnData {
+                    data_type: 'u',
+                    length: 0,
+                    data: String::new(),
+                    binary: None,
+                },
+            ],
+            processed_length: 9,
+        };
+
+        let json = EventFormatter::format_tuple_data(&tuple_data);
+        assert_eq!(json["columns"][0]["data"], "1");
+        assert!(json["columns"][0].get("unchanged").is_none());
+        assert!(json["columns"][1]["data"].is_null());
+        assert_eq!(json["columns"][1]["unchanged"], true);
+    }
+
+    #[test]
+    fn test_format_logical_message() {
+        let message = ReplicationMessage::LogicalMessage {
+            xid: None,
+            transactional: false,
+            lsn: 777,
+            prefix: "app".to_string(),
+            content: br#"{"order":1}"#.to_vec(),
+        };
+
+        let json = EventFormatter::format(&message);
+        assert_eq!(json["type"], "message");
+        assert_eq!(json["transactional"], false);
+        assert_eq!(json["prefix"], "app");
+        assert_eq!(json["content"], r#"{"order":1}"#);
+        assert_eq!(json["content_encoding"], "utf8");
+
+        let message = ReplicationMessage::LogicalMessage {
+            xid: Some(9),
+            transactional: true,
+            lsn: 778,
+            prefix: "app".to_string(),
+            content: vec![0xff, 0x00, 0x10],
+        };
+
+        let json = EventFormatter::format(&message);
+        assert_eq!(json["xid"], 9);
+        assert_eq!(json["content"], "ff0010");
+        assert_eq!(json["content_encoding"], "hex");
+    }
+
+    #[test]
+    fn test_format_prepared_transaction_envelope() {
+        let pending = PendingTransaction::new(ReplicationMessage::BeginPrepare {
+            prepare_lsn: 500,
+            end_lsn: 540,
+            timestamp: 1609459200000000,
+            xid: 43,
+            gid: "tx-43".to_string(),
+        });
+
+        // A Commit cannot close a prepared transaction
+        assert!(
+            PendingTransaction::new(ReplicationMessage::BeginPrepare {
+                prepare_lsn: 500,
+                end_lsn: 540,
+                timestamp: 1609459200000000,
+                xid: 43,
+                gid: "tx-43".to_string(),
+            })
+            .finish(ReplicationMessage::Commit {
+                flags: 0,
+                commit_lsn: 500,
+                end_lsn: 540,
+                timestamp: 1609459200000001,
+            })
+            .is_none()
+        );
+
+        let transaction = pending
+            .finish(ReplicationMessage::Prepare {
+                flags: 0,
+                prepare_lsn: 500,
+                end_lsn: 540,
+                timestamp: 1609459200000001,
+                xid: 43,
+                gid: "tx-43".to_string(),
+            })
+            .unwrap();
+
+        let json = EventFormatter::format_transaction(&transaction);
+        assert_eq!(json["xid"], 43);
+        assert_eq!(json["commit_lsn"], 500);
+        assert_eq!(json["end_lsn"], 540);
+        assert_eq!(json["gid"], "tx-43");
+
+        let json = EventFormatter::format(&transaction.commit);
+        assert_eq!(json["type"], "prepare");
+        assert_eq!(json["gid"], "tx-43");
+    }
 }
\ No newline at end of file
@@ -1,30 +1,31 @@
 //! Hook0 event sink implementation
 //!
 //! Provides an event sink for sending replication events to Hook0 API
-//! with comprehensive error handling, retry logic, and email notifications.
+//! with comprehensive error handling and email notifications.
 
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Local, Utc};
-use lettre::{Transport, transport::smtp::authentication::Credentials};
+use lettre::Message;
 use lettre::SmtpTransport;
 use lettre::address::Address;
-use lettre::Message;
+use lettre::{Transport, transport::smtp::authentication::Credentials};
+use serde::Deserialize;
 use serde_json::Value;
-use tokio::sync::Mutex;
-use tracing::{debug, error, warn};
-use uuid::Uuid;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
 
 use super::super::EventSink;
-use crate::core::email_config::EmailConfig;
-use crate::core::errors::{ReplicationError, ReplicationResult};
-use crate::protocol::messages::ReplicationMessage;
 use super::hook0_error::Hook0ErrorId;
 use super::pg_type_conversion::{
     ColumnValue, ReplicationEventDecoder, ReplicationRow, parse_timestamptz,
 };
+use crate::core::email_config::EmailConfig;
+use crate::core::errors::{DeliveryAttempt, ReplicationError, ReplicationResult};
+use crate::protocol::messages::ReplicationMessage;
 
 use hook0_client::{Event, Hook0Client, Hook0ClientError};
 
@@ -37,6 +38,11 @@ pub struct Hook0EventSinkConfig {
     pub application_id: Uuid,
     /// Hook0 API token
     pub api_token: String,
+    /// Prefix of logical decoding messages carrying Hook0 events
+    pub message_prefix: String,
+    /// Skip events of unknown types instead of failing delivery, for setups
+    /// without a dead-letter queue
+    pub skip_undeliverable: bool,
 }
 
 /// Hook0 event sink for sending replication events to Hook0 API
@@ -45,7 +51,9 @@ pub struct Hook0EventSink {
     pub(crate) hook0_client: Hook0Client,
     pub(crate) email_config: Option<EmailConfig>,
     pub(crate) decoder: Arc<Mutex<ReplicationEventDecoder>>,
+    message_prefix: String,
     unknown_event_types: Arc<Mutex<HashMap<String, DateTime<Local>>>>,
+    skip_undeliverable: bool,
 }
 
 /// event table row
@@ -174,21 +182,82 @@ fn parse_event_row(row: &ReplicationRow) -> Result<EventTableRow, ReplicationErr
     })
 }
 
+/// Event emitted with `pg_logical_emit_message` instead of an events table row
+#[derive(Deserialize)]
+struct EventMessage {
+    event_id: String,
+    event_type: String,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default = "empty_object")]
+    metadata: Value,
+    payload: Value,
+    #[serde(default = "empty_object")]
+    labels: Value,
+}
+
+fn empty_object() -> Value {
+    Value::Object(Default::default())
+}
+
+fn parse_event_message(content: &[u8]) -> Result<EventTableRow, ReplicationError> {
+    let message: EventMessage =
+        serde_json::from_slice(content).map_err(|e| ReplicationError::MessageParsing {
+            message: format!("Invalid event message: {}", e),
+            context: Some(String::from_utf8_lossy(content).into_owned()),
+        })?;
+
+    let event_id =
+        Uuid::parse_str(&message.event_id).map_err(|e| ReplicationError::MessageParsing {
+            message: format!("event_id is not a UUID: {}", e),
+            context: Some(message.event_id.clone()),
+        })?;
+
+    if !message.metadata.is_object() || !message.labels.is_object() {
+        return Err(ReplicationError::parse_with_context(
+            "metadata and labels must be JSON objects",
+            String::from_utf8_lossy(content).into_owned(),
+        ));
+    }
+
+    Ok(EventTableRow {
+        event_id,
+        event_type: message.event_type,
+        created_at: message.created_at.unwrap_or_else(Utc::now),
+        metadata: message.metadata,
+        payload: message.payload,
+        labels: message.labels,
+    })
+}
+
 #[async_trait]
 impl EventSink for Hook0EventSink {
     /// Send a replication event to Hook0 API
     async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()> {
-        // Decode the replication message into a row
-        let decoder = self.decoder.clone();
-        let mut decoder_guard = decoder.lock().await;
-        let decoded_event = decoder_guard.decode(event);
-
-        let row = match decoded_event {
-            Some(evt) => evt,
-            None => return Ok(()),
+        let event_row = match event {
+            // Logical messages with our prefix carry the event itself as JSON
+            ReplicationMessage::LogicalMessage {
+                prefix, content, ..
+            } => {
+                if *prefix != self.message_prefix {
+                    return Ok(());
+                }
+                parse_event_message(content)?
+            }
+            _ => {
+                // Decode the replication message into a row
+                let decoder = self.decoder.clone();
+                let mut decoder_guard = decoder.lock().await;
+                let decoded_event = decoder_guard.decode(event);
+
+                let row = match decoded_event {
+                    Some(evt) => evt,
+                    None => return Ok(()),
+                };
+
+                parse_event_row(&row)?
+            }
         };
-
-        let event_row = parse_event_row(&row)?;
         {
             let mut unknown_event_lock = self.unknown_event_types.lock().await;
 
@@ -196,7 +265,19 @@ impl EventSink for Hook0EventSink {
             if let Some(last_attempt_time) = unknown_event_lock.get(&event_row.event_type) {
                 let elapsed_time_since_last_attempt = Local::now() - last_attempt_time;
                 if elapsed_time_since_last_attempt < Duration::minutes(5) {
-                    return Ok(());
+                    if self.skip_undeliverable {
+                        return Ok(());
+                    }
+                    let reason = format!(
+                        "Event type {} was reported as unknown by Hook0 at {}",
+                        event_row.event_type, last_attempt_time
+                    );
+                    return Err(ReplicationError::delivery(
+                        "hook0",
+                        reason.clone(),
+                        true,
+                        vec![DeliveryAttempt::failed(reason)],
+                    ));
                 } else {
                     unknown_event_lock.remove(&event_row.event_type);
                 }
@@ -243,152 +324,145 @@ impl EventSink for Hook0EventSink {
                 .collect(),
         };
 
-        // Retry configuration
-        let max_retries = 5;
-        let base_delay_ms = 1000; // 1 second
-        let max_delay_ms = 30000; // 30 seconds
-
-        let mut attempt = 0;
-        let mut delay_ms = base_delay_ms;
-        let mut continue_on_retry_exceed = false;
-
-        loop {
-            attempt += 1;
-
-            debug!(
-                "About to send event to Hook0 API (attempt {}/{}): {:?}",
-                attempt, max_retries, hook0_event
-            );
+        debug!("About to send event to Hook0 API: {:?}", hook0_event);
 
-            match self.hook0_client.send_event(&hook0_event).await {
-                Ok(_) => {
-                    debug!("Successfully sent event to Hook0 API");
-                    return Ok(());
-                }
-                Err(e) => {
-                    match &e {
-                        Hook0ClientError::EventSending {
-                            body,
-                            error: _,
-                            event_id,
-                        } => {
-                            if let Some(body) = body {
-                                // Deserialize the body to get the error type
-                                let error_details: serde_json::Value =
-                                    serde_json::from_str(body).unwrap_or_default();
-                                let error_id = error_details
-                                    .get("id")
-                                    .and_then(|v| v.as_str())
-                                    .map(Hook0ErrorId::from)
-                                    .unwrap_or(Hook0ErrorId::InternalServerError);
-                                match error_id {
-                                    Hook0ErrorId::EventTypeDoesNotExist => {
-                                        let mut unknown_event_lock =
-                                            self.unknown_event_types.lock().await;
-                                        unknown_event_lock
-                                            .insert(event_row.event_type.clone(), Local::now());
-                                        self.send_email_notification(&format!(
-                                            "Failed to send replication event {} to Hook0 API: Event type does not exist or was deactivated. You should (re)create it. Event ID: {}, Error: {}",
-                                            event_row.event_type, event_id, e
-                                        )).await;
-                                        error!(
-                                            "Skipping event {} due to unknown event type. Sent notification email.",
-                                            event_row.event_type
-                                        );
-                                        return Ok(());
-                                    }
-                                    Hook0ErrorId::EventAlreadyIngested => {
-                                        warn!(
-                                            "Event already ingested by Hook0 API. Event ID: {}, Error: {}",
-                                            event_id, e
-                                        );
-
-                                        return Ok(()); // Skip the event if already ingested
-                                    }
-                                    Hook0ErrorId::InternalServerError => {
-                                        // Something went wrong on the server side, retry
-                                        error!(
-                                            "Hook0 internal server error, retrying in {}ms. Event ID: {}, Error: {}",
-                                            delay_ms, event_id, e
-                                        );
-                                    }
-                                    Hook0ErrorId::InvalidEventId => {
-                                        error!(
-                                            "Invalid event ID provided to Hook0 API. Event ID: {}, Error: {}",
-                                            event_id, e
-                                        );
-                                    }
-                                    Hook0ErrorId::InvalidPayload => {
-                                        error!(
-                                            "Invalid payload provided to Hook0 API. Event ID: {}, Error: {}",
-                                            event_id, e
-                                        );
-                                    }
-                                    Hook0ErrorId::Unauthorized => {
-                                        error!(
-                                            "Unauthorized access to Hook0 API. Event ID: {}, Error: {}",
-                                            event_id, e
-                                        );
-                                        self.send_email_notification(&format!(
-                                                                "Unauthorized access to Hook0 API. Event ID: {}, Error: {}",
-                                                                event_id, e
-                                                            ))
-                                                            .await;
-                                        panic!("Unauthorized access to Hook0 API, exiting.");
-                                    }
-                                    Hook0ErrorId::RateLimitExceeded => {
-                                        error!(
-                                            "Rate limit exceeded by Hook0 API. Event ID: {}, Error: {}",
-                                            event_id, e
-                                        );
-                                        // Rate limit exceeded, retry with exponential backoff
-                                        delay_ms = (delay_ms * 2).min(max_delay_ms);
-                                    }
-                                }
-                            }
-                        }
-                        e => {
-                            error!(
-                                "Hook0 API request failed with unexpected error type: {:?}",
-                                e
-                            ); // Log unexpected errors as well
-
-                            // In the case of an unexpected error, we should set a flag to say it's OK to continue with other events
-                            continue_on_retry_exceed = true;
-                        }
-                    }
-
-                    if attempt >= max_retries {
-                        // Send email notification and panic on final failure
+        let e = match self.hook0_client.send_event(&hook0_event).await {
+            Ok(_) => {
+                debug!("Successfully sent event to Hook0 API");
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        // The status tells the retry policy whether Hook0 answered at all
+        let attempt = DeliveryAttempt::failed(e.to_string());
+        let attempts = match &e {
+            Hook0ClientError::EventSending { error, .. } => match error.status() {
+                Some(status) => vec![attempt.with_status(status.as_u16())],
+                None => vec![attempt],
+            },
+            _ => vec![attempt],
+        };
+        match &e {
+            Hook0ClientError::EventSending {
+                body: Some(body),
+                error: _,
+                event_id,
+            } => {
+                // Deserialize the body to get the error type
+                let error_details: serde_json::Value =
+                    serde_json::from_str(body).unwrap_or_default();
+                let error_id = error_details
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .map(Hook0ErrorId::from)
+                    .unwrap_or(Hook0ErrorId::InternalServerError);
+                match error_id {
+                    Hook0ErrorId::EventTypeDoesNotExist => {
+                        let mut unknown_event_lock = self.unknown_event_types.lock().await;
+                        unknown_event_lock.insert(event_row.event_type.clone(), Local::now());
                         self.send_email_notification(&format!(
-                            "Failed to send replication event after {} attempts. Hook0 API error: {}",
-                            max_retries,
-                            e
+                            "Failed to send replication event {} to Hook0 API: Event type does not exist or was deactivated. You should (re)create it. Event ID: {}, Error: {}",
+                            event_row.event_type, event_id, e
                         )).await;
-
-                        if continue_on_retry_exceed {
+                        if self.skip_undeliverable {
+                            warn!(
+                                "Skipping event {} due to unknown event type. Sent notification email.",
+                                event_row.event_type
+                            );
                             return Ok(());
                         }
+                        error!(
+                            "Rejecting event {} due to unknown event type. Sent notification email.",
+                            event_row.event_type
+                        );
+                        Err(ReplicationError::delivery(
+                            "hook0",
+                            format!(
+                                "Event type {} does not exist or was deactivated",
+                                event_row.event_type
+                            ),
+                            true,
+                            attempts,
+                        ))
+                    }
+                    Hook0ErrorId::EventAlreadyIngested => {
+                        warn!(
+                            "Event already ingested by Hook0 API. Event ID: {}, Error: {}",
+                            event_id, e
+                        );
 
-                        panic!(
-                            "Failed to send replication event after {} attempts. Hook0 API error: {}",
-                            max_retries, e
+                        Ok(()) // Skip the event if already ingested
+                    }
+                    Hook0ErrorId::InternalServerError | Hook0ErrorId::RateLimitExceeded => {
+                        // Something went wrong on the server side or we are sending
+                        // too fast, the retry policy sends it again
+                        error!(
+                            "Hook0 API request failed as {}. Event ID: {}, Error: {}",
+                            error_id.as_str(),
+                            event_id,
+                            e
                         );
+                        Err(ReplicationError::delivery(
+                            "hook0",
+                            format!("Hook0 API error: {}", e),
+                            false,
+                            attempts,
+                        ))
+                    }
+                    Hook0ErrorId::InvalidEventId | Hook0ErrorId::InvalidPayload => {
+                        // The event itself is invalid, retrying cannot succeed
+                        error!(
+                            "Hook0 API rejected the event as {}. Event ID: {}, Error: {}",
+                            error_id.as_str(),
+                            event_id,
+                            e
+                        );
+                        Err(ReplicationError::delivery(
+                            "hook0",
+                            format!(
+                                "Hook0 API rejected the event as {}: {}",
+                                error_id.as_str(),
+                                e
+                            ),
+                            true,
+                            attempts,
+                        ))
+                    }
+                    Hook0ErrorId::Unauthorized => {
+                        error!(
+                            "Unauthorized access to Hook0 API. Event ID: {}, Error: {}",
+                            event_id, e
+                        );
+                        self.send_email_notification(&format!(
+                            "Unauthorized access to Hook0 API. Event ID: {}, Error: {}",
+                            event_id, e
+                        ))
+                        .await;
+                        // Every event would fail the same way, so this
+                        // stops replication instead of dead-lettering
+                        Err(ReplicationError::Sink {
+                            message: format!("Unauthorized access to Hook0 API: {}", e),
+                            sink: "hook0".to_string(),
+                        })
                     }
-                    error!(
-                        "Hook0 API request failed, retrying in {}ms (attempt {}/{})",
-                        delay_ms, attempt, max_retries
-                    );
                 }
             }
-
-            // Exponential backoff with jitter
-            tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
-
-            // Increase delay for next attempt (exponential backoff)
-            delay_ms = (delay_ms * 2).min(max_delay_ms);
+            e => {
+                error!("Hook0 API request failed: {:?}", e);
+                Err(ReplicationError::delivery(
+                    "hook0",
+                    format!("Hook0 API error: {}", e),
+                    false,
+                    attempts,
+                ))
+            }
         }
     }
+
+    /// Email the failure when email notifications are configured
+    async fn notify_failure(&self, message: &str) {
+        self.send_email_notification(message).await;
+    }
 }
 
 impl Hook0EventSink {
@@ -422,7 +496,9 @@ impl Hook0EventSink {
             hook0_client,
             email_config,
             decoder: Arc::new(Mutex::new(ReplicationEventDecoder::new())),
+            message_prefix: config.message_prefix,
             unknown_event_types: Arc::new(Mutex::new(HashMap::new())),
+            skip_undeliverable: config.skip_undeliverable,
         })
     }
 
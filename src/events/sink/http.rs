@@ -1,22 +1,25 @@
 //! HTTP event sink implementation
 //!
-//! Provides an event sink for sending replication events to HTTP endpoints
-//! with retry logic and email notifications on persistent failures.
+//! Provides an event sink for sending replication events to HTTP endpoints,
+//! with email notifications once retries are exhausted.
 
+use super::super::retry::parse_retry_after;
+use super::super::{EventSink, TransactionBatch};
+use super::cloudevents::CloudEvents;
+use super::event_formatter::SinkFormatter;
+use crate::core::email_config::EmailConfig;
+use crate::core::errors::{DeliveryAttempt, ReplicationError, ReplicationResult};
+use crate::protocol::messages::ReplicationMessage;
 use async_trait::async_trait;
+use lettre::Message;
+use lettre::address::Address;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{SmtpTransport, Transport};
-use lettre::address::Address;
-use lettre::Message;
 use reqwest::Client;
-use tracing::{debug, error};
-use super::super::EventSink;
-use crate::core::email_config::EmailConfig;
-use crate::core::errors::ReplicationResult;
-use crate::protocol::messages::ReplicationMessage;
-use super::event_formatter;
-use tokio::sync::Mutex;
+use reqwest::header::RETRY_AFTER;
 use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
 
 /// Configuration for HTTP event sink
 #[derive(Debug, Clone)]
@@ -31,111 +34,110 @@ pub struct HttpEventSink {
     pub(crate) config: HttpEventSinkConfig,
     pub(crate) http_client: Arc<Mutex<Client>>,
     pub(crate) email_config: Option<EmailConfig>,
+    pub(crate) formatter: SinkFormatter,
+    pub(crate) cloudevents: Option<Arc<CloudEvents>>,
 }
 
 #[async_trait]
 impl EventSink for HttpEventSink {
     /// Send a replication event to the HTTP endpoint
     async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()> {
-        let json_event = event_formatter::EventFormatter::format(event);
-
-        // Retry configuration
-        let max_retries = 5;
-        let base_delay_ms = 1000; // 1 second
-        let max_delay_ms = 30000; // 30 seconds
-
-        let mut attempt = 0;
-        let mut delay_ms = base_delay_ms;
-
-        loop {
-            attempt += 1;
-
-            let response = self
-                .http_client
-                .lock()
-                .await
-                .post(&self.config.endpoint_url)
-                .json(&json_event)
-                .send()
-                .await;
-
-            match response {
-                Ok(resp) => {
-                    if resp.status().is_success() {
-                        debug!("Successfully sent event to HTTP endpoint");
-                        return Ok(());
-                    } else {
-                        error!("Failed to send event to HTTP endpoint: {}", resp.status());
-                        if attempt >= max_retries {
-                            // Send email notification but log error instead of panicking
-                            self.send_email_notification(&format!(
-                                "Failed to send replication event after {} attempts. HTTP endpoint returned status: {}",
-                                max_retries,
-                                resp.status()
-                            )).await;
-                            error!(
-                                "Failed to send replication event after {} attempts. HTTP endpoint returned status: {}",
-                                max_retries,
-                                resp.status()
-                            );
-                            return Err(crate::core::errors::ReplicationError::Sink {
-                                message: format!(
-                                    "HTTP endpoint failed after {} retries with status: {}",
-                                    max_retries,
-                                    resp.status()
-                                ),
-                                sink: "http".to_string(),
-                            });
-                        }
-                        error!(
-                            "HTTP request failed with status {}, retrying in {}ms (attempt {}/{})",
-                            resp.status(),
-                            delay_ms,
-                            attempt,
-                            max_retries
-                        );
-                    }
+        let json_events = self.formatter.format(event);
+        match &self.cloudevents {
+            Some(cloudevents) => {
+                for cloud_event in cloudevents.wrap(event, json_events) {
+                    let (body, headers) = cloud_event.into_request(&cloudevents.mode);
+                    self.post_json(&body, &headers).await?;
                 }
-                Err(e) => {
-                    error!("HTTP request failed: {}", e);
-                    if attempt >= max_retries {
-                        // Send email notification but log error instead of panicking
-                        self.send_email_notification(&format!(
-                            "Failed to send replication event after {} attempts. HTTP request failed: {}",
-                            max_retries,
-                            e
-                        )).await;
-                        error!(
-                            "Failed to send replication event after {} attempts. HTTP request failed: {}",
-                            max_retries, e
-                        );
-                        return Err(crate::core::errors::ReplicationError::Sink {
-                            message: format!(
-                                "HTTP request failed after {} retries: {}",
-                                max_retries, e
-                            ),
-                            sink: "http".to_string(),
-                        });
-                    }
-                    error!(
-                        "HTTP request failed, retrying in {}ms (attempt {}/{})",
-                        delay_ms, attempt, max_retries
-                    );
+            }
+            None => {
+                for json_event in json_events {
+                    self.post_json(&json_event, &[]).await?;
                 }
             }
+        }
+        Ok(())
+    }
 
-            // Exponential backoff with jitter
-            tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
-
-            // Increase delay for next attempt (exponential backoff)
-            delay_ms = (delay_ms * 2).min(max_delay_ms);
+    /// Send a whole transaction to the HTTP endpoint as one request
+    async fn send_transaction(&self, transaction: &TransactionBatch) -> ReplicationResult<()> {
+        let json_event = self.formatter.format_transaction(transaction);
+        match &self.cloudevents {
+            Some(cloudevents) => {
+                let (body, headers) = cloudevents
+                    .wrap_transaction(transaction, json_event)
+                    .into_request(&cloudevents.mode);
+                self.post_json(&body, &headers).await
+            }
+            None => self.post_json(&json_event, &[]).await,
         }
     }
+
+    /// Email the failure when email notifications are configured
+    async fn notify_failure(&self, message: &str) {
+        self.send_email_notification(message).await;
+    }
 }
 
 impl HttpEventSink {
-    /// Create a new HTTP event sink
-    pub fn new(config: HttpEventSinkConfig) -> Result<Self, String> {
+    /// POST a JSON payload to the endpoint once
+    ///
+    /// `headers` are added to the request; a `content-type` among them replaces
+    /// the default `application/json`. Failures are returned as delivery errors
+    /// carrying the response status and `Retry-After`, retries are left to
+    /// [`RetryingSink`](crate::events::retry::RetryingSink).
+    async fn post_json(
+        &self,
+        json_event: &serde_json::Value,
+        headers: &[(&'static str, String)],
+    ) -> ReplicationResult<()> {
+        let mut request = self
+            .http_client
+            .lock()
+            .await
+            .post(&self.config.endpoint_url);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+
+        match request.json(json_event).send().await {
+            Ok(resp) => {
+                if resp.status().is_success() {
+                    debug!("Successfully sent event to HTTP endpoint");
+                    return Ok(());
+                }
+                error!("Failed to send event to HTTP endpoint: {}", resp.status());
+                let retry_after = resp
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_retry_after);
+                let message = format!("HTTP endpoint returned status: {}", resp.status());
+                let attempt = DeliveryAttempt::failed(message.clone())
+                    .with_status(resp.status().as_u16())
+                    .with_retry_after(retry_after);
+                Err(ReplicationError::delivery(
+                    "http",
+                    message,
+                    false,
+                    vec![attempt],
+                ))
+            }
+            Err(e) => {
+                error!("HTTP request failed: {}", e);
+                let message = format!("HTTP request failed: {}", e);
+                Err(ReplicationError::delivery(
+                    "http",
+                    message.clone(),
+                    false,
+                    vec![DeliveryAttempt::failed(message)],
+                ))
+            }
+        }
+    }
+
+    /// Create a new HTTP event sink sending events in the given format
+    pub fn new(config: HttpEventSinkConfig, formatter: SinkFormatter) -> Result<Self, String> {
         // Validate email configuration at startup
         let email_config = match EmailConfig::from_env() {
             Ok(email_config) => Some(email_config),
@@ -153,9 +155,17 @@ impl HttpEventSink {
             config,
             http_client,
             email_config,
+            formatter,
+            cloudevents: None,
         })
     }
 
+    /// Send every event wrapped as a CloudEvent
+    pub fn with_cloudevents(mut self, cloudevents: CloudEvents) -> Self {
+        self.cloudevents = Some(Arc::new(cloudevents));
+        self
+    }
+
     /// Send an email notification about a failure
     pub(crate) async fn send_email_notification(&self, message: &str) {
         // If email config is not available, return early
@@ -201,4 +211,4 @@ impl HttpEventSink {
             debug!("Email notification sent successfully");
         }
     }
-}
\ No newline at end of file
+}
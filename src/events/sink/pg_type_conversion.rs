@@ -3,9 +3,9 @@
 //! Provides utilities for converting PostgreSQL replication data into structured formats
 //! for processing by event sinks like Hook0.
 
-use crate::protocol::messages::{TupleData, ReplicationMessage};
+use crate::protocol::messages::{ColumnData, ReplicationMessage, TupleData};
 use crate::utils::binary::Oid;
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::str::FromStr;
@@ -20,6 +20,7 @@ pub(crate) enum EventConversionError {
     DateTimeParse(chrono::ParseError),
     ColumnTypeMismatch,
     MissingColumn(&'static str),
+    InvalidBinary(&'static str),
 }
 
 type RelationColumns = Vec<(String, PgType)>;
@@ -35,7 +36,12 @@ pub(crate) struct ReplicationEventDecoder {
 pub(crate) enum ColumnValue {
     String(String),
     Uuid(uuid::Uuid),
-    Int(u32),
+    Int(i64),
+    Float(f64),
+    Numeric(String),
+    Bytes(Vec<u8>),
+    /// Unchanged TOAST value that could not be resolved
+    Unchanged,
     Bool(bool),
     Json(serde_json::Value),
     Timestamp(NaiveDateTime),
@@ -51,11 +57,15 @@ impl TryInto<String> for ColumnValue {
             ColumnValue::String(value) => value.clone(),
             ColumnValue::Uuid(value) => value.to_string(),
             ColumnValue::Int(value) => value.to_string(),
+            ColumnValue::Float(value) => value.to_string(),
+            ColumnValue::Numeric(value) => value.clone(),
+            ColumnValue::Bytes(value) => bytea_to_string(&value),
             ColumnValue::Bool(value) => value.to_string(),
             ColumnValue::Json(value) => value.to_string(),
             ColumnValue::Timestamp(value) => value.to_string(),
             ColumnValue::Date(value) => value.to_string(),
             ColumnValue::TimestampTZ(value) => value.to_string(),
+            ColumnValue::Unchanged => return Err(EventConversionError::ColumnTypeMismatch),
         })
     }
 }
@@ -68,19 +78,60 @@ impl TryInto<String> for &ColumnValue {
             ColumnValue::String(value) => value.clone(),
             ColumnValue::Uuid(value) => value.to_string(),
             ColumnValue::Int(value) => value.to_string(),
+            ColumnValue::Float(value) => value.to_string(),
+            ColumnValue::Numeric(value) => value.clone(),
+            ColumnValue::Bytes(value) => bytea_to_string(value),
             ColumnValue::Bool(value) => value.to_string(),
             ColumnValue::Json(value) => value.to_string(),
             ColumnValue::Timestamp(value) => value.to_string(),
             ColumnValue::Date(value) => value.to_string(),
             ColumnValue::TimestampTZ(value) => value.to_string(),
+            ColumnValue::Unchanged => return Err(EventConversionError::ColumnTypeMismatch),
         })
     }
 }
 
+impl ColumnValue {
+    /// Native JSON representation of the value
+    ///
+    /// Numerics stay strings to keep their precision, non-finite floats use
+    /// PostgreSQL's spelling and date/times are ISO 8601.
+    pub(crate) fn to_json(&self) -> Value {
+        match self {
+            ColumnValue::String(value) | ColumnValue::Numeric(value) => Value::from(value.as_str()),
+            ColumnValue::Uuid(value) => Value::from(value.to_string()),
+            ColumnValue::Int(value) => Value::from(*value),
+            ColumnValue::Float(value) => match serde_json::Number::from_f64(*value) {
+                Some(number) => Value::Number(number),
+                None if value.is_nan() => Value::from("NaN"),
+                None if *value > 0.0 => Value::from("Infinity"),
+                None => Value::from("-Infinity"),
+            },
+            ColumnValue::Bytes(value) => Value::from(bytea_to_string(value)),
+            ColumnValue::Bool(value) => Value::Bool(*value),
+            ColumnValue::Json(value) => value.clone(),
+            ColumnValue::Timestamp(value) => {
+                Value::from(value.format("%Y-%m-%dT%H:%M:%S%.f").to_string())
+            }
+            ColumnValue::Date(value) => Value::from(value.format("%Y-%m-%d").to_string()),
+            ColumnValue::TimestampTZ(value) => {
+                Value::from(value.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            ColumnValue::Unchanged => Value::Null,
+        }
+    }
+}
+
 pub(crate) struct ReplicationRow {
     pub(crate) column_values: HashMap<String, ColumnValue>,
 }
 
+/// Renders bytes the way PostgreSQL prints bytea in hex output mode
+fn bytea_to_string(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\\x{}", hex)
+}
+
 pub(crate) fn parse_timestamptz(str: String) -> Result<DateTime<Utc>, EventConversionError> {
     DateTime::parse_from_str(&str, "%Y-%m-%d %H:%M:%S%.f%#z")
         .map(|x| x.to_utc())
@@ -119,6 +170,7 @@ impl ReplicationEventDecoder {
                 tuple_data,
                 is_stream: _,
                 xid: _,
+                lsn: _,
             } => self.tuple_to_row(*relation_id, tuple_data),
             ReplicationMessage::Update {
                 relation_id,
@@ -127,6 +179,7 @@ impl ReplicationEventDecoder {
                 new_tuple_data,
                 is_stream: _,
                 xid: _,
+                lsn: _,
             } => self.tuple_to_row(*relation_id, new_tuple_data),
             _ => None,
         }
@@ -147,6 +200,20 @@ impl ReplicationEventDecoder {
         for (i, x) in tuple_data.columns.clone().iter().enumerate() {
             let col_def = &table[i];
 
+            if x.data_type == 'u' {
+                column_values.insert(col_def.0.clone(), ColumnValue::Unchanged);
+                continue;
+            }
+
+            if let Some(bytes) = &x.binary {
+                let col_val = decode_binary(&col_def.1, bytes).unwrap_or_else(|e| {
+                    warn!("Binary column decoding failed: {:?}", e);
+                    ColumnValue::Bytes(bytes.clone())
+                });
+                column_values.insert(col_def.0.clone(), col_val);
+                continue;
+            }
+
             let data = x.data.clone();
 
             let col_val: ColumnValue = match col_def.1 {
@@ -209,6 +276,215 @@ impl ReplicationEventDecoder {
     }
 }
 
+/// Decodes a column sent in PostgreSQL's text output format
+///
+/// Values that do not parse as their declared type (e.g. `infinity` dates)
+/// are kept as strings.
+pub(crate) fn decode_text(pg_type: &PgType, data: &str) -> ColumnValue {
+    let parsed = match pg_type {
+        PgType::Bool => match data {
+            "t" => Some(ColumnValue::Bool(true)),
+            "f" => Some(ColumnValue::Bool(false)),
+            _ => None,
+        },
+        PgType::Int2 | PgType::Int4 | PgType::Int8 | PgType::Oid => {
+            data.parse().ok().map(ColumnValue::Int)
+        }
+        PgType::Float4 | PgType::Float8 => data.parse().ok().map(ColumnValue::Float),
+        PgType::Numeric => Some(ColumnValue::Numeric(data.to_string())),
+        PgType::Json | PgType::Jsonb => Value::from_str(data).ok().map(ColumnValue::Json),
+        PgType::Uuid => Uuid::try_parse(data).ok().map(ColumnValue::Uuid),
+        PgType::Date => NaiveDate::from_str(data).ok().map(ColumnValue::Date),
+        PgType::Timestamp => NaiveDateTime::parse_from_str(data, "%Y-%m-%d %H:%M:%S%.f")
+            .ok()
+            .map(ColumnValue::Timestamp),
+        PgType::Timestamptz => parse_timestamptz(data.to_string())
+            .ok()
+            .map(ColumnValue::TimestampTZ),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| ColumnValue::String(data.to_string()))
+}
+
+/// Decodes a replicated column, `None` for SQL NULL
+///
+/// Unchanged TOAST values decode to [`ColumnValue::Unchanged`]; columns of types
+/// without a [`PgType`] (enums, domains, arrays...) are strings or raw bytes.
+pub(crate) fn decode_column(column_type: Oid, column: &ColumnData) -> Option<ColumnValue> {
+    let pg_type = PgType::try_from(column_type).ok();
+    match (column.data_type, &column.binary, pg_type) {
+        ('u', _, _) => Some(ColumnValue::Unchanged),
+        ('n', _, _) => None,
+        (_, Some(bytes), Some(pg_type)) => {
+            Some(decode_binary(&pg_type, bytes).unwrap_or_else(|e| {
+                warn!("Binary column decoding failed: {:?}", e);
+                ColumnValue::Bytes(bytes.clone())
+            }))
+        }
+        (_, Some(bytes), None) => Some(ColumnValue::Bytes(bytes.clone())),
+        (_, None, Some(pg_type)) => Some(decode_text(&pg_type, &column.data)),
+        (_, None, None) => Some(ColumnValue::String(column.data.clone())),
+    }
+}
+
+/// Native JSON value of a replicated column, `None` for unchanged TOAST values
+pub(crate) fn column_to_json(column_type: Oid, column: &ColumnData) -> Option<Value> {
+    match decode_column(column_type, column) {
+        Some(ColumnValue::Unchanged) => None,
+        Some(value) => Some(value.to_json()),
+        None => Some(Value::Null),
+    }
+}
+
+/// Microseconds between the Unix epoch and the PostgreSQL epoch (2000-01-01)
+const PG_EPOCH_OFFSET_MICROS: i64 = 946_684_800_000_000;
+
+/// Days between 0001-01-01 (CE) and the PostgreSQL epoch (2000-01-01)
+const PG_EPOCH_DAYS_FROM_CE: i32 = 730_120;
+
+fn fixed_bytes<const N: usize>(bytes: &[u8]) -> Result<[u8; N], EventConversionError> {
+    bytes
+        .try_into()
+        .map_err(|_| EventConversionError::InvalidBinary("unexpected value length"))
+}
+
+/// Decodes a column sent in PostgreSQL's binary wire format
+///
+/// Types without a dedicated decoder are returned as raw bytes.
+pub(crate) fn decode_binary(
+    pg_type: &PgType,
+    bytes: &[u8],
+) -> Result<ColumnValue, EventConversionError> {
+    Ok(match pg_type {
+        PgType::Bool => ColumnValue::Bool(fixed_bytes::<1>(bytes)?[0] != 0),
+        PgType::Int2 => ColumnValue::Int(i16::from_be_bytes(fixed_bytes(bytes)?) as i64),
+        PgType::Int4 => ColumnValue::Int(i32::from_be_bytes(fixed_bytes(bytes)?) as i64),
+        PgType::Int8 => ColumnValue::Int(i64::from_be_bytes(fixed_bytes(bytes)?)),
+        PgType::Oid => ColumnValue::Int(u32::from_be_bytes(fixed_bytes(bytes)?) as i64),
+        PgType::Float4 => ColumnValue::Float(f32::from_be_bytes(fixed_bytes(bytes)?) as f64),
+        PgType::Float8 => ColumnValue::Float(f64::from_be_bytes(fixed_bytes(bytes)?)),
+        PgType::Uuid => ColumnValue::Uuid(Uuid::from_bytes(fixed_bytes(bytes)?)),
+        PgType::Timestamp => ColumnValue::Timestamp(decode_binary_timestamp(bytes)?.naive_utc()),
+        PgType::Timestamptz => ColumnValue::TimestampTZ(decode_binary_timestamp(bytes)?),
+        PgType::Date => {
+            let days = i32::from_be_bytes(fixed_bytes(bytes)?);
+            let date = days
+                .checked_add(PG_EPOCH_DAYS_FROM_CE)
+                .and_then(NaiveDate::from_num_days_from_ce_opt)
+                .ok_or(EventConversionError::InvalidBinary("date out of range"))?;
+            ColumnValue::Date(date)
+        }
+        PgType::Numeric => ColumnValue::Numeric(decode_binary_numeric(bytes)?),
+        PgType::Bytea => ColumnValue::Bytes(bytes.to_vec()),
+        PgType::Json => ColumnValue::Json(
+            serde_json::from_slice(bytes)
+                .map_err(|_| EventConversionError::InvalidBinary("invalid json"))?,
+        ),
+        PgType::Jsonb => {
+            // jsonb is sent as a version byte (currently 1) followed by the JSON text
+            match bytes.split_first() {
+                Some((1, text)) => ColumnValue::Json(
+                    serde_json::from_slice(text)
+                        .map_err(|_| EventConversionError::InvalidBinary("invalid jsonb"))?,
+                ),
+                _ => return Err(EventConversionError::InvalidBinary("unknown jsonb version")),
+            }
+        }
+        PgType::Text | PgType::Varchar | PgType::Bpchar | PgType::Name | PgType::Char => {
+            ColumnValue::String(
+                String::from_utf8(bytes.to_vec())
+                    .map_err(|_| EventConversionError::InvalidBinary("invalid utf-8 text"))?,
+            )
+        }
+        _ => ColumnValue::Bytes(bytes.to_vec()),
+    })
+}
+
+/// Decodes a binary timestamp (microseconds since 2000-01-01 UTC)
+fn decode_binary_timestamp(bytes: &[u8]) -> Result<DateTime<Utc>, EventConversionError> {
+    let micros = i64::from_be_bytes(fixed_bytes(bytes)?);
+    micros
+        .checked_add(PG_EPOCH_OFFSET_MICROS)
+        .and_then(DateTime::from_timestamp_micros)
+        .ok_or(EventConversionError::InvalidBinary(
+            "timestamp out of range",
+        ))
+}
+
+/// Decodes a binary numeric into its decimal text representation
+///
+/// The wire format is ndigits, weight, sign and display scale (all 16-bit),
+/// followed by ndigits base-10000 digits, the first one having the given weight.
+fn decode_binary_numeric(bytes: &[u8]) -> Result<String, EventConversionError> {
+    if bytes.len() < 8 {
+        return Err(EventConversionError::InvalidBinary(
+            "numeric header too short",
+        ));
+    }
+
+    let ndigits = i16::from_be_bytes([bytes[0], bytes[1]]);
+    let weight = i16::from_be_bytes([bytes[2], bytes[3]]) as i32;
+    let sign = u16::from_be_bytes([bytes[4], bytes[5]]);
+    let dscale = u16::from_be_bytes([bytes[6], bytes[7]]) as usize;
+
+    match sign {
+        0x0000 | 0x4000 => {}
+        0xC000 => return Ok("NaN".to_string()),
+        0xD000 => return Ok("Infinity".to_string()),
+        0xF000 => return Ok("-Infinity".to_string()),
+        _ => return Err(EventConversionError::InvalidBinary("invalid numeric sign")),
+    }
+
+    if ndigits < 0 || bytes.len() != 8 + ndigits as usize * 2 {
+        return Err(EventConversionError::InvalidBinary(
+            "numeric digits truncated",
+        ));
+    }
+
+    let digits: Vec<i16> = bytes[8..]
+        .chunks_exact(2)
+        .map(|pair| i16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    let digit = |index: i32| -> i16 {
+        if index >= 0 {
+            digits.get(index as usize).copied().unwrap_or(0)
+        } else {
+            0
+        }
+    };
+
+    let mut text = String::new();
+    if sign == 0x4000 {
+        text.push('-');
+    }
+
+    if weight < 0 {
+        text.push('0');
+    } else {
+        for index in 0..=weight {
+            if index == 0 {
+                text.push_str(&digit(index).to_string());
+            } else {
+                text.push_str(&format!("{:04}", digit(index)));
+            }
+        }
+    }
+
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut index = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(index)));
+            index += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+
+    Ok(text)
+}
+
 #[repr(u32)]
 #[derive(Clone)]
 pub(crate) enum PgType {
@@ -348,4 +624,127 @@ impl std::convert::TryFrom<u32> for PgType {
             _ => Err(()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numeric(weight: i16, sign: u16, dscale: u16, digits: &[i16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(digits.len() as i16).to_be_bytes());
+        bytes.extend_from_slice(&weight.to_be_bytes());
+        bytes.extend_from_slice(&sign.to_be_bytes());
+        bytes.extend_from_slice(&dscale.to_be_bytes());
+        for digit in digits {
+            bytes.extend_from_slice(&digit.to_be_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_decode_binary_integers_and_floats() {
+        assert!(matches!(
+            decode_binary(&PgType::Int2, &(-12i16).to_be_bytes()),
+            Ok(ColumnValue::Int(-12))
+        ));
+        assert!(matches!(
+            decode_binary(&PgType::Int4, &70000i32.to_be_bytes()),
+            Ok(ColumnValue::Int(70000))
+        ));
+        assert!(matches!(
+            decode_binary(&PgType::Int8, &i64::MAX.to_be_bytes()),
+            Ok(ColumnValue::Int(i64::MAX))
+        ));
+        assert!(matches!(
+            decode_binary(&PgType::Float8, &1.5f64.to_be_bytes()),
+            Ok(ColumnValue::Float(value)) if value == 1.5
+        ));
+        assert!(matches!(
+            decode_binary(&PgType::Bool, &[1]),
+            Ok(ColumnValue::Bool(true))
+        ));
+        assert!(decode_binary(&PgType::Int4, &[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_binary_dates_and_times() {
+        // 2000-01-02 00:00:01 UTC
+        let micros: i64 = 86_400_000_000 + 1_000_000;
+        match decode_binary(&PgType::Timestamptz, &micros.to_be_bytes()) {
+            Ok(ColumnValue::TimestampTZ(value)) => {
+                assert_eq!(value.to_rfc3339(), "2000-01-02T00:00:01+00:00")
+            }
+            other => panic!("unexpected value: {:?}", other),
+        }
+
+        match decode_binary(&PgType::Date, &(-1i32).to_be_bytes()) {
+            Ok(ColumnValue::Date(value)) => assert_eq!(value.to_string(), "1999-12-31"),
+            other => panic!("unexpected value: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_binary_numeric() {
+        let cases = [
+            (numeric(1, 0x0000, 3, &[1, 2345, 6780]), "12345.678"),
+            (numeric(-1, 0x0000, 4, &[12]), "0.0012"),
+            (numeric(0, 0x4000, 0, &[42]), "-42"),
+            (numeric(1, 0x0000, 0, &[7]), "70000"),
+            (numeric(0, 0x0000, 0, &[]), "0"),
+            (numeric(0, 0xC000, 0, &[]), "NaN"),
+        ];
+
+        for (bytes, expected) in cases {
+            match decode_binary(&PgType::Numeric, &bytes) {
+                Ok(ColumnValue::Numeric(value)) => assert_eq!(value, expected),
+                other => panic!("unexpected value for {}: {:?}", expected, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_binary_uuid_and_jsonb() {
+        let uuid = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert!(matches!(
+            decode_binary(&PgType::Uuid, uuid.as_bytes()),
+            Ok(ColumnValue::Uuid(value)) if value == uuid
+        ));
+
+        let mut jsonb = vec![1];
+        jsonb.extend_from_slice(br#"{"a":1}"#);
+        match decode_binary(&PgType::Jsonb, &jsonb) {
+            Ok(ColumnValue::Json(value)) => assert_eq!(value["a"], 1),
+            other => panic!("unexpected value: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_text_to_json() {
+        use serde_json::json;
+
+        assert_eq!(decode_text(&PgType::Int8, "-42").to_json(), json!(-42));
+        assert_eq!(decode_text(&PgType::Bool, "f").to_json(), json!(false));
+        assert_eq!(decode_text(&PgType::Float8, "NaN").to_json(), json!("NaN"));
+        assert_eq!(
+            decode_text(&PgType::Numeric, "12345678901234567890.01").to_json(),
+            json!("12345678901234567890.01")
+        );
+        assert_eq!(
+            decode_text(&PgType::Timestamp, "2024-05-01 10:00:00.5").to_json(),
+            json!("2024-05-01T10:00:00.500")
+        );
+        assert_eq!(
+            decode_text(&PgType::Timestamptz, "2024-05-01 12:00:00+02").to_json(),
+            json!("2024-05-01T10:00:00Z")
+        );
+        assert_eq!(
+            decode_text(&PgType::Date, "infinity").to_json(),
+            json!("infinity")
+        );
+        assert_eq!(
+            decode_text(&PgType::Jsonb, r#"{"a":[1]}"#).to_json(),
+            json!({"a": [1]})
+        );
+    }
+}
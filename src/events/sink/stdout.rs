@@ -3,18 +3,27 @@
 //! Provides a simple event sink that writes replication events to standard output.
 //! This is useful for debugging, testing, or when no other sink is configured.
 
-use async_trait::async_trait;
+use super::super::EventSink;
+use super::event_formatter::SinkFormatter;
 use crate::core::errors::ReplicationResult;
+use crate::events::TransactionBatch;
 use crate::protocol::messages::ReplicationMessage;
-use super::super::EventSink;
+use async_trait::async_trait;
 
 /// Event sink that writes events to standard output
-pub struct StdoutEventSink {}
+pub struct StdoutEventSink {
+    formatter: SinkFormatter,
+}
 
 impl StdoutEventSink {
     /// Create a new STDOUT event sink
     pub fn new() -> Self {
-        Self {}
+        Self::with_formatter(SinkFormatter::Raw)
+    }
+
+    /// Create a STDOUT event sink; the raw format prints messages in their debug form
+    pub fn with_formatter(formatter: SinkFormatter) -> Self {
+        Self { formatter }
     }
 }
 
@@ -27,7 +36,26 @@ impl Default for StdoutEventSink {
 #[async_trait]
 impl EventSink for StdoutEventSink {
     async fn send_event(&self, event: &ReplicationMessage) -> ReplicationResult<()> {
-        println!("{:?}", event);
+        match &self.formatter {
+            SinkFormatter::Raw => println!("{:?}", event),
+            formatter => {
+                for json_event in formatter.format(event) {
+                    println!("{}", json_event);
+                }
+            }
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    async fn send_transaction(&self, transaction: &TransactionBatch) -> ReplicationResult<()> {
+        match &self.formatter {
+            SinkFormatter::Raw => {
+                for message in transaction.messages() {
+                    self.send_event(message).await?;
+                }
+            }
+            formatter => println!("{}", formatter.format_transaction(transaction)),
+        }
+        Ok(())
+    }
+}
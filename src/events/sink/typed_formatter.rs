@@ -0,0 +1,376 @@
+//! Typed, schema-aware JSON event format
+//!
+//! Joins row changes with the relation cache so that events name their table
+//! and columns, and converts column values to native JSON types:
+//!
+//! ```json
+//! {"schema": "public", "table": "users", "op": "update",
+//!  "before": {"id": 1, "name": "old"}, "after": {"id": 1, "name": "new"},
+//!  "pk": {"id": 1}, "xid": 742, "commit_lsn": 23861232,
+//!  "commit_timestamp": "2024-05-01T10:00:00.123456Z"}
+//! ```
+
+use serde_json::{Map, Value, json};
+use std::sync::Mutex;
+use tracing::warn;
+
+use super::event_formatter::EventFormatter;
+use super::pg_type_conversion::column_to_json;
+use crate::events::TransactionBatch;
+use crate::protocol::messages::{RelationCache, RelationInfo, ReplicationMessage, TupleData};
+use crate::utils::binary::Xid;
+use crate::utils::timestamp::postgres_timestamp_to_datetime;
+
+/// Truncate flag set by `TRUNCATE ... CASCADE`
+const TRUNCATE_CASCADE: i8 = 1;
+/// Truncate flag set by `TRUNCATE ... RESTART IDENTITY`
+const TRUNCATE_RESTART_IDENTITY: i8 = 2;
+
+/// Transaction the changes being formatted belong to
+#[derive(Debug, Clone)]
+pub(crate) struct TransactionContext {
+    pub(crate) xid: Xid,
+    pub(crate) commit_lsn: u64,
+    pub(crate) commit_timestamp: i64,
+}
+
+impl TransactionContext {
+    /// Context opened by a Begin or BeginPrepare message
+    pub(crate) fn from_begin(message: &ReplicationMessage) -> Option<Self> {
+        match message {
+            ReplicationMessage::Begin {
+                final_lsn,
+                timestamp,
+                xid,
+            } => Some(Self {
+                xid: *xid,
+                commit_lsn: *final_lsn,
+                commit_timestamp: *timestamp,
+            }),
+            ReplicationMessage::BeginPrepare {
+                prepare_lsn,
+                timestamp,
+                xid,
+                ..
+            } => Some(Self {
+                xid: *xid,
+                commit_lsn: *prepare_lsn,
+                commit_timestamp: *timestamp,
+            }),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, event: &mut Value) {
+        event["xid"] = json!(self.xid);
+        event["commit_lsn"] = json!(self.commit_lsn);
+        event["commit_timestamp"] = iso_timestamp(self.commit_timestamp);
+    }
+}
+
+/// Formats row changes as typed events, tracking the enclosing transaction
+pub struct TypedEventFormatter {
+    relations: RelationCache,
+    transaction: Mutex<Option<TransactionContext>>,
+}
+
+impl TypedEventFormatter {
+    pub fn new(relations: RelationCache) -> Self {
+        Self {
+            relations,
+            transaction: Mutex::new(None),
+        }
+    }
+
+    /// Format a replication message delivered on its own
+    ///
+    /// Begin, Commit and schema messages only update the formatter's context
+    /// and yield `None`. Messages without a typed shape use the raw format.
+    pub fn format(&self, message: &ReplicationMessage) -> Option<Value> {
+        let mut transaction = self
+            .transaction
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match message {
+            ReplicationMessage::Begin { .. } | ReplicationMessage::BeginPrepare { .. } => {
+                *transaction = TransactionContext::from_begin(message);
+                None
+            }
+            ReplicationMessage::Commit { .. } | ReplicationMessage::Prepare { .. } => {
+                *transaction = None;
+                None
+            }
+            ReplicationMessage::Relation { .. }
+            | ReplicationMessage::Type { .. }
+            | ReplicationMessage::Origin { .. }
+            | ReplicationMessage::StreamStart { .. }
+            | ReplicationMessage::StreamStop => None,
+            _ => match self.format_change(message) {
+                Some(mut event) => {
+                    if let Some(context) = transaction.as_ref() {
+                        context.apply(&mut event);
+                    }
+                    Some(event)
+                }
+                None => Some(EventFormatter::format(message)),
+            },
+        }
+    }
+
+    /// Format a whole transaction as one envelope of typed changes
+    pub fn format_transaction(&self, transaction: &TransactionBatch) -> Value {
+        let changes = transaction
+            .changes
+            .iter()
+            .filter_map(|message| match message {
+                ReplicationMessage::Relation { .. }
+                | ReplicationMessage::Type { .. }
+                | ReplicationMessage::Origin { .. } => None,
+                _ => Some(
+                    self.format_change(message)
+                        .unwrap_or_else(|| EventFormatter::format(message)),
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        let mut envelope = json!({
+            "type": "transaction",
+            "xid": transaction.xid,
+            "commit_lsn": transaction.commit_lsn,
+            "end_lsn": transaction.end_lsn,
+            "commit_timestamp": iso_timestamp(transaction.commit_timestamp),
+            "changes": changes,
+        });
+        if let Some(gid) = &transaction.gid {
+            envelope["gid"] = json!(gid);
+        }
+        envelope
+    }
+
+    /// Typed event for a row change or truncate, `None` for any other message
+    /// or when the relation has not been received yet
+    fn format_change(&self, message: &ReplicationMessage) -> Option<Value> {
+        match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                let relation = self.relation(*relation_id)?;
+                Some(row_event(
+                    &relation,
+                    "insert",
+                    None,
+                    Some(tuple_data),
+                    tuple_data,
+                ))
+            }
+            ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                snapshot_lsn,
+            } => {
+                let relation = self.relation(*relation_id)?;
+                let mut event = row_event(&relation, "read", None, Some(tuple_data), tuple_data);
+                event["snapshot_lsn"] = json!(snapshot_lsn);
+                Some(event)
+            }
+            ReplicationMessage::Update {
+                relation_id,
+                key_type,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                let relation = self.relation(*relation_id)?;
+                let before = old_tuple_data
+                    .as_ref()
+                    .map(|old| row_image(&relation, old, *key_type == Some('K')));
+                Some(row_event(
+                    &relation,
+                    "update",
+                    before,
+                    Some(new_tuple_data),
+                    new_tuple_data,
+                ))
+            }
+            ReplicationMessage::Delete {
+                relation_id,
+                key_type,
+                tuple_data,
+                ..
+            } => {
+                let relation = self.relation(*relation_id)?;
+                let before = row_image(&relation, tuple_data, *key_type == 'K');
+                Some(row_event(
+                    &relation,
+                    "delete",
+                    Some(before),
+                    None,
+                    tuple_data,
+                ))
+            }
+            ReplicationMessage::Truncate {
+                relation_ids,
+                flags,
+                ..
+            } => {
+                let tables = relation_ids
+                    .iter()
+                    .map(|oid| match self.relations.get(*oid) {
+                        Some(relation) => json!({
+                            "schema": relation.namespace,
+                            "table": relation.relation_name,
+                        }),
+                        None => json!({ "relation_id": oid }),
+                    })
+                    .collect::<Vec<_>>();
+                Some(json!({
+                    "op": "truncate",
+                    "tables": tables,
+                    "cascade": flags & TRUNCATE_CASCADE != 0,
+                    "restart_identity": flags & TRUNCATE_RESTART_IDENTITY != 0,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    fn relation(&self, oid: u32) -> Option<RelationInfo> {
+        let relation = self.relations.get(oid);
+        if relation.is_none() {
+            warn!(
+                "No relation cached for OID {}, using the raw event format",
+                oid
+            );
+        }
+        relation
+    }
+}
+
+/// Builds the event of a single row change
+///
+/// `key_source` is the image the primary key is read from: the new row for
+/// inserts and updates, the old row for deletes.
+fn row_event(
+    relation: &RelationInfo,
+    op: &str,
+    before: Option<Value>,
+    after: Option<&TupleData>,
+    key_source: &TupleData,
+) -> Value {
+    let mut event = json!({
+        "schema": relation.namespace,
+        "table": relation.relation_name,
+        "op": op,
+        "before": before,
+        "after": after.map(|tuple| row_image(relation, tuple, false)),
+        "pk": row_image(relation, key_source, true),
+    });
+
+    // Unchanged TOAST columns are left out of the row and listed by name
+    let unchanged = after
+        .map(|tuple| {
+            relation
+                .columns
+                .iter()
+                .zip(&tuple.columns)
+                .filter(|(_, column)| column.data_type == 'u')
+                .map(|(info, _)| info.column_name.clone())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if !unchanged.is_empty() {
+        event["unchanged"] = json!(unchanged);
+    }
+    event
+}
+
+/// Maps a tuple to `{column: value}`, keeping only replica identity columns if `key_only`
+fn row_image(relation: &RelationInfo, tuple: &TupleData, key_only: bool) -> Value {
+    let mut row = Map::new();
+    for (info, column) in relation.columns.iter().zip(&tuple.columns) {
+        if key_only && info.key_flag & 1 == 0 {
+            continue;
+        }
+        if let Some(value) = column_to_json(info.column_type, column) {
+            row.insert(info.column_name.clone(), value);
+        }
+    }
+    Value::Object(row)
+}
+
+/// ISO 8601 rendering of a PostgreSQL timestamp, the raw value if out of range
+fn iso_timestamp(timestamp: i64) -> Value {
+    match postgres_timestamp_to_datetime(timestamp) {
+        Some(datetime) => json!(datetime.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)),
+        None => json!(timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{
+        INT4_OID, TEXT_OID, begin, column, delete, null, relation, text, tuple, unchanged, update,
+    };
+
+    fn formatter() -> TypedEventFormatter {
+        let relations = RelationCache::new();
+        relations.insert(relation(
+            42,
+            "public",
+            "users",
+            vec![
+                column("id", INT4_OID, true),
+                column("name", TEXT_OID, false),
+                column("active", 16, false),
+                column("bio", TEXT_OID, false),
+            ],
+        ));
+        TypedEventFormatter::new(relations)
+    }
+
+    #[test]
+    fn test_typed_update_with_transaction_context() {
+        let formatter = formatter();
+        assert!(formatter.format(&begin(1000, 7)).is_none());
+
+        let event = formatter
+            .format(&update(
+                42,
+                None,
+                tuple(vec![text("1"), text("alice"), text("t"), unchanged()]),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            event,
+            json!({
+                "schema": "public",
+                "table": "users",
+                "op": "update",
+                "before": null,
+                "after": {"id": 1, "name": "alice", "active": true},
+                "pk": {"id": 1},
+                "unchanged": ["bio"],
+                "xid": 7,
+                "commit_lsn": 1000,
+                "commit_timestamp": "2000-01-01T00:00:00Z",
+            })
+        );
+    }
+
+    #[test]
+    fn test_typed_delete_with_key_identity() {
+        let event = formatter()
+            .format(&delete(42, tuple(vec![text("3"), null(), null(), null()])))
+            .unwrap();
+
+        assert_eq!(event["op"], "delete");
+        assert_eq!(event["before"], json!({"id": 3}));
+        assert_eq!(event["after"], Value::Null);
+        assert_eq!(event["pk"], json!({"id": 3}));
+    }
+}
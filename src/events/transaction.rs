@@ -0,0 +1,109 @@
+//! Transaction envelopes for batched event delivery
+//!
+//! Groups every replication message between a `Begin` and its `Commit` so that
+//! sinks can apply a whole database transaction atomically. Prepared
+//! transactions are grouped the same way, from `BeginPrepare` to `Prepare`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::messages::ReplicationMessage;
+use crate::utils::binary::Xid;
+
+/// A complete transaction handed to a sink in one call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionBatch {
+    /// Transaction ID from the Begin message
+    pub xid: Xid,
+    /// LSN of the commit record
+    pub commit_lsn: u64,
+    /// End LSN of the commit record, acknowledged once the batch is delivered
+    pub end_lsn: u64,
+    /// Commit timestamp (microseconds since the PostgreSQL epoch)
+    pub commit_timestamp: i64,
+    /// Global identifier of a prepared transaction, `None` for regular commits
+    pub gid: Option<String>,
+    /// The Begin (or BeginPrepare) message that opened the transaction
+    pub begin: ReplicationMessage,
+    /// Ordered changes between Begin and Commit
+    pub changes: Vec<ReplicationMessage>,
+    /// The Commit (or Prepare) message that closed the transaction
+    pub commit: ReplicationMessage,
+}
+
+impl TransactionBatch {
+    /// Every message of the transaction in stream order, Begin and Commit included
+    pub fn messages(&self) -> impl Iterator<Item = &ReplicationMessage> {
+        std::iter::once(&self.begin)
+            .chain(self.changes.iter())
+            .chain(std::iter::once(&self.commit))
+    }
+}
+
+/// A transaction being accumulated until its Commit arrives
+#[derive(Debug)]
+pub struct PendingTransaction {
+    begin: ReplicationMessage,
+    changes: Vec<ReplicationMessage>,
+}
+
+impl PendingTransaction {
+    /// Start buffering a transaction from its Begin message
+    pub fn new(begin: ReplicationMessage) -> Self {
+        Self {
+            begin,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Append a change received inside the transaction
+    pub fn push(&mut self, message: ReplicationMessage) {
+        self.changes.push(message);
+    }
+
+    /// Close the transaction with its Commit (or Prepare) message
+    ///
+    /// Returns `None` if `commit` does not close the kind of transaction that
+    /// was begun.
+    pub fn finish(self, commit: ReplicationMessage) -> Option<TransactionBatch> {
+        let (commit_lsn, end_lsn, commit_timestamp, gid) = match (&self.begin, &commit) {
+            (
+                ReplicationMessage::Begin { .. },
+                ReplicationMessage::Commit {
+                    commit_lsn,
+                    end_lsn,
+                    timestamp,
+                    ..
+                },
+            ) => (*commit_lsn, *end_lsn, *timestamp, None),
+            (
+                ReplicationMessage::BeginPrepare { .. },
+                ReplicationMessage::Prepare {
+                    prepare_lsn,
+                    end_lsn,
+                    timestamp,
+                    gid,
+                    ..
+                },
+            ) => (*prepare_lsn, *end_lsn, *timestamp, Some(gid.clone())),
+            _ => return None,
+        };
+
+        let xid = match self.begin {
+            ReplicationMessage::Begin { xid, .. } | ReplicationMessage::BeginPrepare { xid, .. } => {
+                xid
+            }
+            _ => return None,
+        };
+
+        Some(TransactionBatch {
+            xid,
+            commit_lsn,
+            end_lsn,
+            commit_timestamp,
+            gid,
+            begin: self.begin,
+            changes: self.changes,
+            commit,
+        })
+    }
+}
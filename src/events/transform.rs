@@ -0,0 +1,379 @@
+//! Column transforms applied to row changes before they reach the sinks
+//!
+//! Each transform selects columns with a `[schema.]table.column` glob pattern
+//! and drops them, replaces their values with a salted SHA-256 hash, masks
+//! all but their last characters, or replaces them with a constant. Relation
+//! messages are rewritten the same way, so that every output format sees a
+//! consistent schema: dropped columns disappear and replaced ones become
+//! `text` columns.
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+use super::router::{glob_matches, table_matches};
+use super::sink::pg_type_conversion::{ColumnValue, decode_column};
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::{
+    ColumnData, ColumnInfo, RelationCache, RelationInfo, ReplicationMessage, TupleData,
+};
+
+/// OID of PostgreSQL's `text` type, the type of replaced columns
+const TEXT_OID: u32 = 25;
+
+/// What a transform does to the columns it selects
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnAction {
+    Drop,
+    /// Hex SHA-256 of the salt followed by the value
+    Hash,
+    /// Replaces every character but the last `n` with `*`
+    Mask(usize),
+    Constant(String),
+}
+
+impl fmt::Display for ColumnAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnAction::Drop => write!(f, "drop"),
+            ColumnAction::Hash => write!(f, "hash"),
+            ColumnAction::Mask(keep) => write!(f, "mask:{}", keep),
+            ColumnAction::Constant(value) => write!(f, "const:{}", value),
+        }
+    }
+}
+
+/// Action applied to the columns matching a pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnTransform {
+    /// Table glob pattern, as in `SINK_<NAME>_TABLES`
+    pub table: String,
+    /// Column name glob pattern
+    pub column: String,
+    pub action: ColumnAction,
+}
+
+impl FromStr for ColumnTransform {
+    type Err = String;
+
+    /// Parses `[schema.]table.column=action`, where action is `drop`, `hash`,
+    /// `mask`, `mask:<n>` or `const:<value>`
+    fn from_str(transform: &str) -> Result<Self, Self::Err> {
+        let (pattern, action) = transform
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' must be <table>.<column>=<action>", transform))?;
+        let (table, column) = pattern
+            .trim()
+            .rsplit_once('.')
+            .filter(|(table, column)| !table.is_empty() && !column.is_empty())
+            .ok_or_else(|| format!("'{}' must name a table and a column", pattern.trim()))?;
+
+        let action = match action.trim().split_once(':') {
+            None if action.trim() == "drop" => ColumnAction::Drop,
+            None if action.trim() == "hash" => ColumnAction::Hash,
+            None if action.trim() == "mask" => ColumnAction::Mask(4),
+            Some(("mask", keep)) => ColumnAction::Mask(
+                keep.trim()
+                    .parse()
+                    .map_err(|_| format!("'mask:{}' must keep a number of characters", keep))?,
+            ),
+            // The constant is kept as written, spaces included
+            Some(("const", _)) => {
+                ColumnAction::Constant(action.trim_start()["const:".len()..].to_string())
+            }
+            _ => {
+                return Err(format!(
+                    "unknown action '{}', expected drop, hash, mask, mask:<n> or const:<value>",
+                    action.trim()
+                ));
+            }
+        };
+        Ok(Self {
+            table: table.to_string(),
+            column: column.to_string(),
+            action,
+        })
+    }
+}
+
+impl fmt::Display for ColumnTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}={}", self.table, self.column, self.action)
+    }
+}
+
+/// Applies the configured column transforms to replication messages
+pub struct ColumnTransformer {
+    transforms: Vec<ColumnTransform>,
+    salt: String,
+}
+
+impl ColumnTransformer {
+    /// The first transform matching a column applies to it
+    pub fn new(transforms: Vec<ColumnTransform>, salt: String) -> Self {
+        Self { transforms, salt }
+    }
+
+    /// Transformed copy of `message`
+    ///
+    /// `relations` holds the untransformed schemas, needed to find the
+    /// columns of a change. A change of an unknown relation is an error
+    /// rather than being sent untransformed.
+    pub fn apply(
+        &self,
+        message: &ReplicationMessage,
+        relations: &RelationCache,
+    ) -> ReplicationResult<ReplicationMessage> {
+        let relation_id = match message {
+            ReplicationMessage::Relation { relation } => {
+                return Ok(ReplicationMessage::Relation {
+                    relation: self.transform_relation(relation),
+                });
+            }
+            ReplicationMessage::Insert { relation_id, .. }
+            | ReplicationMessage::Update { relation_id, .. }
+            | ReplicationMessage::Delete { relation_id, .. }
+            | ReplicationMessage::Read { relation_id, .. } => *relation_id,
+            _ => return Ok(message.clone()),
+        };
+        let relation = relations.get(relation_id).ok_or_else(|| {
+            ReplicationError::protocol(format!(
+                "Cannot transform a change of unknown relation {}",
+                relation_id
+            ))
+        })?;
+        let actions = self.actions(&relation);
+        if actions.iter().all(Option::is_none) {
+            return Ok(message.clone());
+        }
+
+        let tuple = |tuple: &TupleData| self.transform_tuple(tuple, &relation, &actions);
+        let mut message = message.clone();
+        match &mut message {
+            ReplicationMessage::Insert { tuple_data, .. }
+            | ReplicationMessage::Delete { tuple_data, .. }
+            | ReplicationMessage::Read { tuple_data, .. } => *tuple_data = tuple(tuple_data),
+            ReplicationMessage::Update {
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                if let Some(old) = old_tuple_data {
+                    *old = tuple(old);
+                }
+                *new_tuple_data = tuple(new_tuple_data);
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    /// Action of each column of `relation`, by position
+    fn actions(&self, relation: &RelationInfo) -> Vec<Option<&ColumnAction>> {
+        relation
+            .columns
+            .iter()
+            .map(|column| {
+                self.transforms
+                    .iter()
+                    .find(|transform| {
+                        glob_matches(transform.column.as_bytes(), column.column_name.as_bytes())
+                            && table_matches(&transform.table, relation)
+                    })
+                    .map(|transform| &transform.action)
+            })
+            .collect()
+    }
+
+    /// Schema of `relation` as the sinks receive it
+    pub fn transform_relation(&self, relation: &RelationInfo) -> RelationInfo {
+        let actions = self.actions(relation);
+        let columns: Vec<ColumnInfo> = relation
+            .columns
+            .iter()
+            .zip(&actions)
+            .filter_map(|(column, action)| match action {
+                None => Some(column.clone()),
+                Some(ColumnAction::Drop) => None,
+                Some(_) => Some(ColumnInfo {
+                    column_type: TEXT_OID,
+                    atttypmod: -1,
+                    ..column.clone()
+                }),
+            })
+            .collect();
+        RelationInfo {
+            column_count: columns.len() as i16,
+            columns,
+            ..relation.clone()
+        }
+    }
+
+    fn transform_tuple(
+        &self,
+        tuple: &TupleData,
+        relation: &RelationInfo,
+        actions: &[Option<&ColumnAction>],
+    ) -> TupleData {
+        let columns: Vec<ColumnData> = tuple
+            .columns
+            .iter()
+            .zip(relation.columns.iter().zip(actions))
+            .filter_map(|(column, (info, action))| match action {
+                None => Some(column.clone()),
+                Some(ColumnAction::Drop) => None,
+                Some(action) => Some(self.replace(column, info.column_type, action)),
+            })
+            .collect();
+        TupleData {
+            column_count: columns.len() as i16,
+            columns,
+            processed_length: tuple.processed_length,
+        }
+    }
+
+    /// Replaced value of a column, as text; NULL and unchanged TOAST values
+    /// are kept as they are
+    fn replace(&self, column: &ColumnData, column_type: u32, action: &ColumnAction) -> ColumnData {
+        let value = match decode_column(column_type, column) {
+            None | Some(ColumnValue::Unchanged) => return column.clone(),
+            Some(_) if column.binary.is_none() => column.data.clone(),
+            Some(value) => (&value).try_into().unwrap_or_default(),
+        };
+        let data = match action {
+            ColumnAction::Hash => {
+                let digest = Sha256::new()
+                    .chain_update(self.salt.as_bytes())
+                    .chain_update(value.as_bytes())
+                    .finalize();
+                digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+            }
+            ColumnAction::Mask(keep) => {
+                let length = value.chars().count();
+                let hidden = length.saturating_sub(*keep);
+                let kept: String = value.chars().skip(hidden).collect();
+                format!("{}{}", "*".repeat(hidden), kept)
+            }
+            ColumnAction::Constant(constant) => constant.clone(),
+            ColumnAction::Drop => unreachable!("dropped columns are not replaced"),
+        };
+        ColumnData {
+            data_type: 't',
+            length: data.len() as i32,
+            data,
+            binary: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::protocol::fixtures::{insert, keyed_relation, row, update};
+
+    fn relation() -> RelationInfo {
+        keyed_relation(1, "public", "users", &["id", "email", "api_token", "phone"])
+    }
+
+    fn data(tuple: &TupleData) -> Vec<&str> {
+        tuple
+            .columns
+            .iter()
+            .map(|column| column.data.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_transforms() {
+        let transform: ColumnTransform = "public.users.email = hash".parse().unwrap();
+        assert_eq!(transform.table, "public.users");
+        assert_eq!(transform.column, "email");
+        assert_eq!(transform.action, ColumnAction::Hash);
+        assert_eq!(
+            "*.phone=mask:2".parse::<ColumnTransform>().unwrap().action,
+            ColumnAction::Mask(2)
+        );
+        assert_eq!(
+            "users.ssn=const:a:b"
+                .parse::<ColumnTransform>()
+                .unwrap()
+                .action,
+            ColumnAction::Constant("a:b".to_string())
+        );
+        assert!("email=drop".parse::<ColumnTransform>().is_err());
+        assert!("users.email=encrypt".parse::<ColumnTransform>().is_err());
+    }
+
+    #[test]
+    fn test_transform_relation_and_images() {
+        let transformer = ColumnTransformer::new(
+            ["users.*token=drop", "users.email=hash", "*.phone=mask"]
+                .iter()
+                .map(|transform| transform.parse().unwrap())
+                .collect(),
+            "pepper".to_string(),
+        );
+        let relations = RelationCache::new();
+        relations.insert(relation());
+
+        let ReplicationMessage::Relation { relation } = transformer
+            .apply(
+                &ReplicationMessage::Relation {
+                    relation: relations.get(1).unwrap(),
+                },
+                &relations,
+            )
+            .unwrap()
+        else {
+            panic!("expected a relation");
+        };
+        let names: Vec<&str> = relation
+            .columns
+            .iter()
+            .map(|column| column.column_name.as_str())
+            .collect();
+        assert_eq!(names, ["id", "email", "phone"]);
+        assert_eq!(relation.column_count, 3);
+
+        let update = update(
+            1,
+            Some(row(&[
+                Some("7"),
+                Some("a@example.com"),
+                Some("s3cr3t"),
+                None,
+            ])),
+            row(&[
+                Some("7"),
+                Some("b@example.com"),
+                Some("s3cr3t"),
+                Some("+33612345678"),
+            ]),
+        );
+        let ReplicationMessage::Update {
+            old_tuple_data: Some(old),
+            new_tuple_data: new,
+            ..
+        } = transformer.apply(&update, &relations).unwrap()
+        else {
+            panic!("expected an update");
+        };
+        // echo -n "pepperb@example.com" | sha256sum
+        assert_eq!(
+            data(&new),
+            [
+                "7",
+                "6d7429131a806b247f11205264ab717e835c51e64131af9ca3fc8cfc6cddc0da",
+                "********5678"
+            ]
+        );
+        assert_eq!(new.column_count, 3);
+        // NULL values stay NULL
+        assert_eq!(old.columns[2].data_type, 'n');
+        assert_ne!(old.columns[1].data, new.columns[1].data);
+
+        let unknown = insert(2, row(&[]));
+        assert!(transformer.apply(&unknown, &relations).is_err());
+    }
+}
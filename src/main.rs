@@ -30,9 +30,12 @@ mod events;        // Event processing and sinks
 mod utils;         // Utility functions for PostgreSQL integration
 
 // Import the core types and functionality we need
-use crate::core::{ReplicationConfig, ReplicationResult};
-use crate::replication::ReplicationServer;
-use clap::Parser;
+use crate::core::{ReplicationConfig, ReplicationError, ReplicationResult};
+use crate::events::EventSinkRegistry;
+use crate::events::script::ScriptProcessor;
+use crate::protocol::messages::RelationCache;
+use crate::replication::Supervisor;
+use clap::{Parser, Subcommand};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tracing::{error, info};
@@ -42,14 +45,15 @@ use tracing_subscriber::{EnvFilter, fmt};
 /// Command line arguments structure using clap for parsing
 ///
 /// This structure defines the command-line interface for the application.
-/// Currently, it only accepts database connection parameters, but most
-/// configuration is done through environment variables for better
+/// It accepts database connection parameters and the `replay-dlq` command,
+/// but most configuration is done through environment variables for better
 /// containerization and security.
 #[derive(Parser, Debug)]
 #[command(
     name = "walpipe",
     about = "PostgreSQL Logical Replication Checker in Rust",
-    version = "0.1.0"
+    version = "0.1.0",
+    args_conflicts_with_subcommands = true
 )]
 struct Args {
     /// Database connection parameters (space-separated key=value pairs)
@@ -59,6 +63,19 @@ struct Args {
     /// environment variable instead for consistency.
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     connection_params: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands run instead of replication
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send the events of the dead-letter queue (`DLQ_STORE`) to the sink again
+    ///
+    /// Events delivered this time are removed from the queue; those that fail
+    /// again are kept with their new attempts.
+    ReplayDlq,
 }
 
 /// Application entry point
@@ -83,6 +100,8 @@ struct Args {
 /// Returns `Ok(())` on successful completion or an error if replication fails.
 #[tokio::main]
 async fn main() -> ReplicationResult<()> {
+    let args = Args::parse();
+
     // Initialize structured logging with tracing
     // This sets up logging levels and output formatting
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
@@ -142,7 +161,30 @@ async fn main() -> ReplicationResult<()> {
     info!("Configuration loaded successfully");
     info!("Slot name: {}", config.slot_name);
     info!("Publication name: {}", config.publication_name);
-    info!("Event sink type: {}", config.event_sink_type());
+    for sink in &config.sinks {
+        info!("Event sink '{}': {}", sink.name, sink.sink);
+        if let Some(filter) = &sink.rule.filter {
+            info!("Event sink '{}' filter: {}", sink.name, filter);
+        }
+    }
+    for transform in &config.column_transforms {
+        info!("Column transform: {}", transform);
+    }
+    if let Some(script) = &config.script_file {
+        info!(
+            "Event script: {} (on error: {})",
+            script.display(),
+            config.script_error_policy
+        );
+    }
+
+    if let Some(Command::ReplayDlq) = args.command {
+        return replay_dead_letters(&config, shutdown_signal).await;
+    }
+
+    if let Some(addr) = config.metrics_addr {
+        core::metrics::serve(addr).await?;
+    }
 
     match run_replication_server(config, shutdown_signal).await {
         Ok(()) => {
@@ -166,7 +208,8 @@ mod test_graceful_shutdown;
 /// 1. Creates a new ReplicationServer instance with the provided configuration
 /// 2. Identifies the PostgreSQL system (verifies connection and gets system info)
 /// 3. Creates replication slot and starts the replication process
-/// 4. Handles graceful shutdown when signaled
+/// 4. Reconnects and resumes from the last confirmed LSN when the stream is interrupted
+/// 5. Handles graceful shutdown when signaled
 ///
 /// # Arguments
 ///
@@ -180,14 +223,48 @@ async fn run_replication_server(
     config: ReplicationConfig,
     shutdown_signal: Arc<AtomicBool>, 
 ) -> ReplicationResult<()> {
-    let mut server = ReplicationServer::new(config, shutdown_signal)?;
+    Supervisor::new(config, shutdown_signal).run().await
+}
 
-    server
-        .identify_system()?;
+/// Replays the dead-letter queue through the configured event sinks
+///
+/// Fails when no queue is configured, or when some events failed again so
+/// that scripts can tell a partial replay from a complete one.
+async fn replay_dead_letters(
+    config: &ReplicationConfig,
+    shutdown_signal: Arc<AtomicBool>,
+) -> ReplicationResult<()> {
+    let Some(mut queue) = replication::dead_letter::open_queue(config) else {
+        return Err(ReplicationError::config(
+            "DLQ_STORE must be 'file' or 'postgres' to replay the dead-letter queue",
+        ));
+    };
+    let relations = RelationCache::new();
+    // Dead letters keep their events and relations as transformed for the sink
+    let router = EventSinkRegistry::create_router(
+        config,
+        relations.clone(),
+        relations.clone(),
+        shutdown_signal,
+    )?;
 
-    server
-        .create_replication_slot_and_start()
-        .await?;
+    let mut script = match &config.script_file {
+        Some(path) => Some(ScriptProcessor::load(path, relations.clone())?),
+        None => None,
+    };
 
+    let summary =
+        replication::dead_letter::replay(queue.as_mut(), &router, &relations, script.as_mut())
+            .await?;
+    info!(
+        "Dead-letter replay finished: {} delivered, {} kept",
+        summary.delivered, summary.failed
+    );
+    if summary.failed > 0 {
+        return Err(ReplicationError::Sink {
+            message: format!("{} dead letters failed again and were kept", summary.failed),
+            sink: "dead-letter queue".to_string(),
+        });
+    }
     Ok(())
 }
@@ -169,23 +169,6 @@ impl<'a> BufferReader<'a> {
         Ok(string_value)
     }
 
-    /// Peek at the next byte without advancing position
-    pub fn peek_u8(&self) -> ReplicationResult<u8> {
-        if !self.has_bytes(1) {
-            return Err(ReplicationError::parse("No bytes to peek"));
-        }
-        Ok(self.buffer[self.position])
-    }
-
-    /// Set position directly (use with caution)
-    pub fn set_position(&mut self, position: usize) -> ReplicationResult<()> {
-        if position > self.buffer.len() {
-            return Err(ReplicationError::parse("Position out of bounds"));
-        }
-        self.position = position;
-        Ok(())
-    }
-
     pub(crate) fn read_char(&mut self) -> ReplicationResult<char> {
         if !self.has_bytes(1) {
             return Err(ReplicationError::parse("Not enough bytes for char"));
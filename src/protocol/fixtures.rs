@@ -0,0 +1,137 @@
+//! Relations, tuples and row changes shared by the unit tests
+
+use crate::protocol::messages::{
+    ColumnData, ColumnInfo, RelationInfo, ReplicationMessage, TupleData,
+};
+use crate::utils::binary::{Oid, Xid};
+
+pub const INT4_OID: Oid = 23;
+pub const TEXT_OID: Oid = 25;
+
+/// Column of type `column_type`, part of the replica identity when `key`
+pub fn column(name: &str, column_type: Oid, key: bool) -> ColumnInfo {
+    ColumnInfo {
+        key_flag: key as i8,
+        column_name: name.to_string(),
+        column_type,
+        atttypmod: -1,
+    }
+}
+
+/// Relation `namespace.name` with the default replica identity
+pub fn relation(oid: Oid, namespace: &str, name: &str, columns: Vec<ColumnInfo>) -> RelationInfo {
+    RelationInfo {
+        oid,
+        namespace: namespace.to_string(),
+        relation_name: name.to_string(),
+        replica_identity: 'd',
+        column_count: columns.len() as i16,
+        columns,
+    }
+}
+
+/// Relation whose first column is an `integer` key followed by `text` columns
+pub fn keyed_relation(oid: Oid, namespace: &str, name: &str, columns: &[&str]) -> RelationInfo {
+    let columns = columns
+        .iter()
+        .enumerate()
+        .map(|(index, name)| match index {
+            0 => column(name, INT4_OID, true),
+            _ => column(name, TEXT_OID, false),
+        })
+        .collect();
+    relation(oid, namespace, name, columns)
+}
+
+/// Value in text format
+pub fn text(data: &str) -> ColumnData {
+    ColumnData {
+        data_type: 't',
+        length: data.len() as i32,
+        data: data.to_string(),
+        binary: None,
+    }
+}
+
+pub fn null() -> ColumnData {
+    ColumnData {
+        data_type: 'n',
+        length: 0,
+        data: String::new(),
+        binary: None,
+    }
+}
+
+/// Unchanged TOAST value
+pub fn unchanged() -> ColumnData {
+    ColumnData {
+        data_type: 'u',
+        ..null()
+    }
+}
+
+pub fn tuple(columns: Vec<ColumnData>) -> TupleData {
+    TupleData {
+        column_count: columns.len() as i16,
+        columns,
+        processed_length: 0,
+    }
+}
+
+/// Tuple of text values
+pub fn values(values: &[&str]) -> TupleData {
+    tuple(values.iter().map(|value| text(value)).collect())
+}
+
+/// Tuple of text values, `None` for NULL
+pub fn row(values: &[Option<&str>]) -> TupleData {
+    tuple(
+        values
+            .iter()
+            .map(|value| value.map_or_else(null, text))
+            .collect(),
+    )
+}
+
+pub fn insert(relation_id: Oid, tuple_data: TupleData) -> ReplicationMessage {
+    ReplicationMessage::Insert {
+        relation_id,
+        tuple_data,
+        is_stream: false,
+        xid: None,
+        lsn: 0,
+    }
+}
+
+/// Update carrying the whole old row when there is one
+pub fn update(relation_id: Oid, old: Option<TupleData>, new: TupleData) -> ReplicationMessage {
+    ReplicationMessage::Update {
+        relation_id,
+        key_type: old.as_ref().map(|_| 'O'),
+        old_tuple_data: old,
+        new_tuple_data: new,
+        is_stream: false,
+        xid: None,
+        lsn: 0,
+    }
+}
+
+/// Delete carrying the key columns of the old row
+pub fn delete(relation_id: Oid, key: TupleData) -> ReplicationMessage {
+    ReplicationMessage::Delete {
+        relation_id,
+        key_type: 'K',
+        tuple_data: key,
+        is_stream: false,
+        xid: None,
+        lsn: 0,
+    }
+}
+
+pub fn begin(final_lsn: u64, xid: Xid) -> ReplicationMessage {
+    ReplicationMessage::Begin {
+        final_lsn,
+        timestamp: 0,
+        xid,
+    }
+}
@@ -4,15 +4,16 @@
 //! protocol messages. These represent the different types of database changes
 //! and control messages that can be received during replication.
 
-use serde::Serialize;
-use std::collections::HashMap;
 use crate::utils::binary::{Oid, Xid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 /// Information about a table column
 ///
 /// This structure represents metadata about a column in a PostgreSQL table.
 /// It's used to understand the structure of data being replicated.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColumnInfo {
     pub key_flag: i8,
     pub column_name: String,
@@ -25,7 +26,7 @@ pub struct ColumnInfo {
 /// This structure represents metadata about a PostgreSQL table (relation) that is being
 /// replicated. It contains the schema information needed to understand and interpret
 /// the data changes flowing through the replication stream.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelationInfo {
     pub oid: Oid,
     pub namespace: String,
@@ -35,22 +36,77 @@ pub struct RelationInfo {
     pub columns: Vec<ColumnInfo>,
 }
 
+/// Relations received on the replication stream, shared with the event sinks
+///
+/// Cloning the cache yields another handle to the same relations, so sinks
+/// always see the schema the server has most recently received.
+#[derive(Debug, Clone, Default)]
+pub struct RelationCache {
+    relations: Arc<RwLock<HashMap<Oid, RelationInfo>>>,
+}
+
+impl RelationCache {
+    /// Creates an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores or replaces the schema of a relation
+    pub fn insert(&self, relation: RelationInfo) {
+        self.relations
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(relation.oid, relation);
+    }
+
+    /// Returns a copy of the schema of a relation
+    pub fn get(&self, oid: Oid) -> Option<RelationInfo> {
+        self.relations
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&oid)
+            .cloned()
+    }
+
+    /// Returns a copy of the schema of the relation `namespace.name`
+    pub fn find(&self, namespace: &str, name: &str) -> Option<RelationInfo> {
+        self.relations
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .find(|relation| relation.namespace == namespace && relation.relation_name == name)
+            .cloned()
+    }
+
+    /// Forgets every relation, for a new session that sends them again
+    pub fn clear(&self) {
+        self.relations
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+}
+
 /// Data for a single column in a tuple (row)
 ///
 /// This structure represents the actual data value for a single column in a row
 /// that has been changed. It includes type information and the value itself.
-#[derive(Debug, Clone, Serialize)]
+///
+/// Text values ('t') are stored in `data`; values sent in the binary wire
+/// format ('b', with `binary 'true'`) are kept as raw bytes in `binary`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColumnData {
     pub data_type: char,
     pub length: i32,
     pub data: String,
+    pub binary: Option<Vec<u8>>,
 }
 
 /// Data for a complete row/tuple
 ///
 /// This structure represents all the column data for a single row (tuple) in the database.
 /// It's used for INSERT operations and the NEW version of UPDATE operations.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TupleData {
     pub column_count: i16,
     pub columns: Vec<ColumnData>,
@@ -75,7 +131,11 @@ pub struct TupleData {
 /// - `StreamStart` begins the streaming
 /// - Changes are sent as they occur
 /// - `StreamCommit` or `StreamAbort` ends the streaming
-#[derive(Debug, Clone, Serialize)]
+///
+/// With two-phase commit enabled (protocol version 3+), a prepared transaction
+/// flows as `BeginPrepare`, changes, `Prepare` (or `StreamPrepare` when streamed),
+/// later followed by `CommitPrepared` or `RollbackPrepared`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ReplicationMessage {
     /// Transaction start message
     ///
@@ -115,6 +175,9 @@ pub enum ReplicationMessage {
         tuple_data: TupleData,
         is_stream: bool,
         xid: Option<Xid>,
+        /// WAL position of the change, 0 when unknown
+        #[serde(default)]
+        lsn: u64,
     },
 
     /// Row update message
@@ -128,6 +191,9 @@ pub enum ReplicationMessage {
         new_tuple_data: TupleData,
         is_stream: bool,
         xid: Option<Xid>,
+        /// WAL position of the change, 0 when unknown
+        #[serde(default)]
+        lsn: u64,
     },
 
     /// Row deletion message
@@ -140,6 +206,9 @@ pub enum ReplicationMessage {
         tuple_data: TupleData,
         is_stream: bool,
         xid: Option<Xid>,
+        /// WAL position of the change, 0 when unknown
+        #[serde(default)]
+        lsn: u64,
     },
 
     /// Table truncate message
@@ -151,6 +220,9 @@ pub enum ReplicationMessage {
         flags: i8,
         is_stream: bool,
         xid: Option<Xid>,
+        /// WAL position of the change, 0 when unknown
+        #[serde(default)]
+        lsn: u64,
     },
 
     /// Start of streaming transaction message
@@ -181,10 +253,108 @@ pub enum ReplicationMessage {
 
     /// Streaming transaction abort message
     ///
-    /// Indicates that a streaming transaction was rolled back.
+    /// Indicates that a streaming transaction was rolled back. The abort LSN
+    /// and timestamp are only sent with protocol version 4 and parallel streaming.
     StreamAbort {
         xid: Xid,
         subtransaction_xid: Xid,
+        abort_lsn: Option<u64>,
+        abort_timestamp: Option<i64>,
+    },
+
+    /// Start of a prepared transaction message
+    ///
+    /// Like `Begin`, but the transaction ends with `Prepare` instead of `Commit`.
+    BeginPrepare {
+        prepare_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+
+    /// Transaction prepare message
+    ///
+    /// Marks the end of a transaction prepared with `PREPARE TRANSACTION`.
+    /// Its changes are durable but not yet committed.
+    Prepare {
+        flags: u8,
+        prepare_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+
+    /// Commit of a previously prepared transaction
+    CommitPrepared {
+        flags: u8,
+        commit_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+
+    /// Rollback of a previously prepared transaction
+    RollbackPrepared {
+        flags: u8,
+        prepare_end_lsn: u64,
+        rollback_end_lsn: u64,
+        prepare_timestamp: i64,
+        rollback_timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+
+    /// Replication origin message
+    ///
+    /// Sent after `Begin` when the transaction was replayed from another node
+    /// (e.g. by a subscription), naming the origin it came from.
+    Origin { commit_lsn: u64, name: String },
+
+    /// Custom type description message
+    ///
+    /// Describes a non-builtin data type referenced by a following `Relation`.
+    Type {
+        xid: Option<Xid>,
+        oid: Oid,
+        namespace: String,
+        name: String,
+    },
+
+    /// Logical decoding message emitted with `pg_logical_emit_message`
+    ///
+    /// Transactional messages are delivered inside their transaction;
+    /// non-transactional ones arrive on their own as soon as they are decoded.
+    LogicalMessage {
+        xid: Option<Xid>,
+        transactional: bool,
+        lsn: u64,
+        prefix: String,
+        content: Vec<u8>,
+    },
+
+    /// Prepare of a streamed transaction
+    ///
+    /// Ends a streaming transaction the same way `Prepare` ends a regular one.
+    StreamPrepare {
+        flags: u8,
+        prepare_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+        xid: Xid,
+        gid: String,
+    },
+
+    /// Existing row read by the initial snapshot
+    ///
+    /// Not part of the pgoutput protocol: emitted for every row of the published
+    /// tables before streaming starts at `snapshot_lsn`, the slot's consistent point.
+    Read {
+        relation_id: Oid,
+        tuple_data: TupleData,
+        snapshot_lsn: u64,
     },
 }
 
@@ -195,39 +365,41 @@ pub enum ReplicationMessage {
 /// and provide proper feedback to the PostgreSQL server.
 #[derive(Debug)]
 pub struct ReplicationState {
-    /// Table schema information indexed by table OID
-    pub relations: HashMap<Oid, RelationInfo>,
+    /// Table schema information indexed by table OID, shared with the sinks
+    pub relations: RelationCache,
     /// Highest LSN received from the server
     pub received_lsn: u64,
-    /// Highest LSN flushed to disk (currently unused)
-    #[allow(unused)]
+    /// Commit end LSN of the last transaction fully accepted by the event sink
     pub flushed_lsn: u64,
     /// When we last sent feedback to the server
     pub last_feedback_time: std::time::Instant,
     /// Highest LSN successfully processed by event sink
     pub applied_lsn: u64,
+    /// Whether a Begin has been received without its matching Commit
+    pub in_transaction: bool,
 }
 
 impl ReplicationState {
     /// Creates a new replication state with default values
     pub fn new() -> Self {
         Self {
-            relations: HashMap::new(),
+            relations: RelationCache::new(),
             received_lsn: 0,
             flushed_lsn: 0,
             last_feedback_time: std::time::Instant::now(),
             applied_lsn: 0,
+            in_transaction: false,
         }
     }
 
     /// Stores table schema information for later use
     pub fn add_relation(&mut self, relation: RelationInfo) {
-        self.relations.insert(relation.oid, relation);
+        self.relations.insert(relation);
     }
 
     /// Retrieves table schema information by OID
-    pub fn get_relation(&self, oid: Oid) -> Option<&RelationInfo> {
-        self.relations.get(&oid)
+    pub fn get_relation(&self, oid: Oid) -> Option<RelationInfo> {
+        self.relations.get(oid)
     }
 
     /// Updates the received LSN if the new value is higher
@@ -244,10 +416,42 @@ impl ReplicationState {
         }
     }
 
+    /// Marks the start of a transaction whose changes are not yet acknowledged
+    pub fn begin_transaction(&mut self) {
+        self.in_transaction = true;
+    }
+
+    /// Marks the current transaction as fully delivered up to its commit `end_lsn`
+    ///
+    /// Only called once every event of the transaction has been accepted by the
+    /// sink, so a crash can never acknowledge half a transaction.
+    pub fn commit_transaction(&mut self, end_lsn: u64) {
+        self.in_transaction = false;
+        self.confirm_lsn(end_lsn);
+    }
+
+    /// Advances the flushed and applied positions reported to the server
+    pub fn confirm_lsn(&mut self, lsn: u64) {
+        if lsn > 0 {
+            self.flushed_lsn = std::cmp::max(self.flushed_lsn, lsn);
+        }
+        self.update_applied_lsn(lsn);
+    }
+
     /// Updates the last feedback time to current time
     pub fn update_feedback_time(&mut self) {
         self.last_feedback_time = std::time::Instant::now();
     }
+
+    /// Returns to the initial state, keeping the relation cache shared with the sinks
+    pub fn reset(&mut self) {
+        self.relations.clear();
+        self.received_lsn = 0;
+        self.flushed_lsn = 0;
+        self.applied_lsn = 0;
+        self.in_transaction = false;
+        self.update_feedback_time();
+    }
 }
 
 impl Default for ReplicationState {
@@ -5,6 +5,8 @@
 //! buffer management, and protocol message definitions.
 
 pub mod buffer;
+#[cfg(test)]
+pub mod fixtures;
 pub mod messages;
 pub mod parser;
 
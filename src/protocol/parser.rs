@@ -21,8 +21,25 @@ impl MessageParser {
     /// Entry point for parsing WAL messages from the replication stream
     ///
     /// Reads the message type byte and dispatches to the appropriate
-    /// parser for that message type.
-    pub fn parse_wal_message(buffer: &[u8]) -> ReplicationResult<ReplicationMessage> {
+    /// parser for that message type. `lsn` is the WAL position of the message,
+    /// from the header of the XLogData carrying it.
+    pub fn parse_wal_message(buffer: &[u8], lsn: u64) -> ReplicationResult<ReplicationMessage> {
+        Self::parse(buffer, false, lsn)
+    }
+
+    /// Parses a WAL message received between StreamStart and StreamStop
+    ///
+    /// Inside a stream segment changes, Relation, Type and transactional
+    /// Message messages are prefixed with the xid of the streamed
+    /// (sub)transaction, which cannot be detected from the message bytes alone.
+    pub fn parse_streamed_wal_message(
+        buffer: &[u8],
+        lsn: u64,
+    ) -> ReplicationResult<ReplicationMessage> {
+        Self::parse(buffer, true, lsn)
+    }
+
+    fn parse(buffer: &[u8], in_stream: bool, lsn: u64) -> ReplicationResult<ReplicationMessage> {
         let mut reader = BufferReader::new(buffer);
         let message_type = reader.skip_message_type()?;
 
@@ -31,15 +48,29 @@ impl MessageParser {
         match message_type {
             'B' => Self::parse_begin_message(&mut reader),
             'C' => Self::parse_commit_message(&mut reader),
-            'R' => Self::parse_relation_message(&mut reader),
-            'I' => Self::parse_insert_message(&mut reader),
-            'U' => Self::parse_update_message(&mut reader),
-            'D' => Self::parse_delete_message(&mut reader),
-            'T' => Self::parse_truncate_message(&mut reader),
+            'R' => {
+                if in_stream {
+                    // Xid of the streamed transaction, not needed for schema tracking
+                    reader.read_u32()?;
+                }
+                Self::parse_relation_message(&mut reader)
+            }
+            'I' => Self::parse_insert_message(&mut reader, in_stream, lsn),
+            'U' => Self::parse_update_message(&mut reader, in_stream, lsn),
+            'D' => Self::parse_delete_message(&mut reader, in_stream, lsn),
+            'T' => Self::parse_truncate_message(&mut reader, in_stream, lsn),
             'S' => Self::parse_stream_start_message(&mut reader),
             'E' => Self::parse_stream_stop_message(&mut reader),
             'c' => Self::parse_stream_commit_message(&mut reader),
             'A' => Self::parse_stream_abort_message(&mut reader),
+            'O' => Self::parse_origin_message(&mut reader),
+            'Y' => Self::parse_type_message(&mut reader, in_stream),
+            'M' => Self::parse_logical_message(&mut reader, in_stream),
+            'b' => Self::parse_begin_prepare_message(&mut reader),
+            'P' => Self::parse_prepare_message(&mut reader),
+            'K' => Self::parse_commit_prepared_message(&mut reader),
+            'r' => Self::parse_rollback_prepared_message(&mut reader),
+            'p' => Self::parse_stream_prepare_message(&mut reader),
             _ => {
                 warn!("Unknown message type: {}", message_type);
                 Err(ReplicationError::parse_with_context(
@@ -134,24 +165,19 @@ impl MessageParser {
         Ok(ReplicationMessage::Relation { relation })
     }
 
-    fn parse_insert_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
-        // INSERT message: first u32 could be relation_id or transaction_id depending on streaming
+    fn parse_insert_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+        lsn: u64,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // INSERT message: [xid (4) when streamed] + relation_id (4) + 'N' marker (1) + tuple
+        let xid = Self::parse_stream_xid(reader, in_stream)?;
         if !reader.has_bytes(5) {
-            // Minimum: transaction_id_or_oid (4) + 'N' marker (1)
+            // Minimum: relation_id (4) + 'N' marker (1)
             return Err(ReplicationError::parse("Insert message too short"));
         }
 
-        let transaction_id_or_oid = reader.read_u32()?;
-
-        // Determine if this is a streaming transaction by checking what comes next
-        let (relation_id, is_stream, xid) = if reader.peek_u8()? == b'N' {
-            // Not a streaming transaction
-            (transaction_id_or_oid, false, None)
-        } else {
-            // Streaming transaction: read the actual relation_id
-            let relation_id = reader.read_u32()?;
-            (relation_id, true, Some(transaction_id_or_oid))
-        };
+        let relation_id = reader.read_u32()?;
 
         // Expect 'N' marker for new tuple
         let marker = reader.read_u8()?;
@@ -167,31 +193,26 @@ impl MessageParser {
         Ok(ReplicationMessage::Insert {
             relation_id,
             tuple_data,
-            is_stream,
+            is_stream: in_stream,
             xid,
+            lsn,
         })
     }
 
-    fn parse_update_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
-        // UPDATE message: first u32 could be relation_id or transaction_id depending on streaming
+    fn parse_update_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+        lsn: u64,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // UPDATE message: [xid (4) when streamed] + relation_id (4) + ['K'/'O' + old tuple]
+        // + 'N' + new tuple
+        let xid = Self::parse_stream_xid(reader, in_stream)?;
         if !reader.has_bytes(5) {
-            // Minimum: transaction_id_or_oid (4) + marker (1)
+            // Minimum: relation_id (4) + marker (1)
             return Err(ReplicationError::parse("Update message too short"));
         }
 
-        let transaction_id_or_oid = reader.read_u32()?;
-
-        // Check if this is a streaming transaction by examining the next byte
-        let next_byte = reader.peek_u8()?;
-        let (relation_id, is_stream, xid) =
-            if next_byte == b'K' || next_byte == b'O' || next_byte == b'N' {
-                // Not a streaming transaction
-                (transaction_id_or_oid, false, None)
-            } else {
-                // Streaming transaction: read the actual relation_id
-                let relation_id = reader.read_u32()?;
-                (relation_id, true, Some(transaction_id_or_oid))
-            };
+        let relation_id = reader.read_u32()?;
 
         // Read the tuple marker
         let marker = reader.read_u8()? as char;
@@ -228,32 +249,26 @@ impl MessageParser {
             key_type,
             old_tuple_data,
             new_tuple_data,
-            is_stream,
+            is_stream: in_stream,
             xid,
+            lsn,
         })
     }
 
-    fn parse_delete_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
-        // DELETE message: first u32 could be relation_id or transaction_id depending on streaming
+    fn parse_delete_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+        lsn: u64,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // DELETE message: [xid (4) when streamed] + relation_id (4) + key_type (1) + tuple
+        let xid = Self::parse_stream_xid(reader, in_stream)?;
         if !reader.has_bytes(5) {
-            // Minimum: transaction_id_or_oid (4) + key_type (1)
+            // Minimum: relation_id (4) + key_type (1)
             return Err(ReplicationError::parse("Delete message too short"));
         }
 
-        let transaction_id_or_oid = reader.read_u32()?;
-
-        // Check if this is a streaming transaction by examining the next byte
-        let next_byte = reader.peek_u8()?;
-        let (relation_id, is_stream, xid, key_type) = if next_byte == b'K' || next_byte == b'O' {
-            // Not a streaming transaction
-            let key_type = reader.read_u8()? as char;
-            (transaction_id_or_oid, false, None, key_type)
-        } else {
-            // Streaming transaction: read the actual relation_id
-            let relation_id = reader.read_u32()?;
-            let key_type = reader.read_u8()? as char;
-            (relation_id, true, Some(transaction_id_or_oid), key_type)
-        };
+        let relation_id = reader.read_u32()?;
+        let key_type = reader.read_u8()? as char;
 
         let tuple_data = Self::parse_tuple_data(reader)?;
 
@@ -261,35 +276,25 @@ impl MessageParser {
             relation_id,
             key_type,
             tuple_data,
-            is_stream,
+            is_stream: in_stream,
             xid,
+            lsn,
         })
     }
 
-    fn parse_truncate_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
-        // TRUNCATE message: Complex logic to determine if streaming or not
-        if !reader.has_bytes(9) {
-            // Minimum: first_u32 (4) + second_u32 (4) + flags (1)
+    fn parse_truncate_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+        lsn: u64,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // TRUNCATE message: [xid (4) when streamed] + num_relations (4) + flags (1) + relation IDs
+        let xid = Self::parse_stream_xid(reader, in_stream)?;
+        if !reader.has_bytes(5) {
+            // Minimum: num_relations (4) + flags (1)
             return Err(ReplicationError::parse("Truncate message too short"));
         }
 
-        let first_u32 = reader.read_u32()?;
-        let second_u32 = reader.read_u32()?;
-
-        // Estimate remaining bytes needed based on second_u32 as potential relation count
-        let remaining_bytes = reader.remaining();
-        let expected_for_streaming = 1 + (second_u32 as usize * 4); // flags + relation IDs
-
-        let (is_stream, xid, num_relations) = if remaining_bytes == expected_for_streaming {
-            // Streaming transaction: first_u32 is xid, second_u32 is num_relations
-            (true, Some(first_u32), second_u32)
-        } else {
-            // Not streaming: first_u32 is num_relations, rewind to read second_u32 as flags later
-            let current_pos = reader.position();
-            reader.set_position(current_pos - 4)?; // Go back 4 bytes to re-read second_u32 as flags
-            (false, None, first_u32)
-        };
-
+        let num_relations = reader.read_u32()?;
         let flags = reader.read_u8()? as i8;
 
         let mut relation_ids = Vec::with_capacity(num_relations as usize);
@@ -307,11 +312,26 @@ impl MessageParser {
         Ok(ReplicationMessage::Truncate {
             relation_ids,
             flags,
-            is_stream,
+            is_stream: in_stream,
             xid,
+            lsn,
         })
     }
 
+    /// Reads the xid prefixing a change streamed before its transaction committed
+    fn parse_stream_xid(
+        reader: &mut BufferReader,
+        in_stream: bool,
+    ) -> ReplicationResult<Option<u32>> {
+        if !in_stream {
+            return Ok(None);
+        }
+        if !reader.has_bytes(4) {
+            return Err(ReplicationError::parse("Streamed change too short"));
+        }
+        Ok(Some(reader.read_u32()?))
+    }
+
     fn parse_stream_start_message(
         reader: &mut BufferReader,
     ) -> ReplicationResult<ReplicationMessage> {
@@ -372,12 +392,222 @@ impl MessageParser {
         let xid = reader.read_u32()?;
         let subtransaction_xid = reader.read_u32()?;
 
+        // Protocol version 4 with parallel streaming appends abort_lsn (8) + abort_timestamp (8)
+        let (abort_lsn, abort_timestamp) = if reader.has_bytes(16) {
+            (Some(reader.read_u64()?), Some(reader.read_i64()?))
+        } else {
+            (None, None)
+        };
+
         Ok(ReplicationMessage::StreamAbort {
             xid,
             subtransaction_xid,
+            abort_lsn,
+            abort_timestamp,
+        })
+    }
+
+    fn parse_origin_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
+        // ORIGIN message: commit_lsn (8) + name (null-terminated)
+        if !reader.has_bytes(9) {
+            return Err(ReplicationError::parse("Origin message too short"));
+        }
+
+        let commit_lsn = reader.read_u64()?;
+        let name = reader.read_null_terminated_string()?;
+
+        Ok(ReplicationMessage::Origin { commit_lsn, name })
+    }
+
+    fn parse_type_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // TYPE message: [xid (4) when streamed] + oid (4) + namespace (null-terminated) + name (null-terminated)
+        let xid = if in_stream {
+            Some(reader.read_u32()?)
+        } else {
+            None
+        };
+
+        if !reader.has_bytes(6) {
+            return Err(ReplicationError::parse("Type message too short"));
+        }
+
+        let oid = reader.read_u32()?;
+        let namespace = reader.read_null_terminated_string()?;
+        let name = reader.read_null_terminated_string()?;
+
+        Ok(ReplicationMessage::Type {
+            xid,
+            oid,
+            namespace,
+            name,
+        })
+    }
+
+    fn parse_logical_message(
+        reader: &mut BufferReader,
+        in_stream: bool,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // MESSAGE message: [xid (4) when streamed] + flags (1) + lsn (8) + prefix (null-terminated)
+        // + content_length (4) + content
+        let xid = if in_stream {
+            Some(reader.read_u32()?)
+        } else {
+            None
+        };
+
+        if !reader.has_bytes(14) {
+            return Err(ReplicationError::parse("Logical message too short"));
+        }
+
+        let flags = reader.read_u8()?;
+        let lsn = reader.read_u64()?;
+        let prefix = reader.read_null_terminated_string()?;
+        let length = reader.read_u32()? as usize;
+
+        if !reader.has_bytes(length) {
+            return Err(ReplicationError::parse_with_context(
+                "Logical message content truncated",
+                format!(
+                    "Expected {} bytes, {} available",
+                    length,
+                    reader.remaining()
+                ),
+            ));
+        }
+        let content = reader.read_bytes(length)?;
+
+        Ok(ReplicationMessage::LogicalMessage {
+            xid,
+            transactional: flags & 1 == 1,
+            lsn,
+            prefix,
+            content,
+        })
+    }
+
+    fn parse_begin_prepare_message(
+        reader: &mut BufferReader,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // BEGIN PREPARE message: prepare_lsn (8) + end_lsn (8) + timestamp (8) + xid (4) + gid (null-terminated)
+        if !reader.has_bytes(29) {
+            return Err(ReplicationError::parse("Begin prepare message too short"));
+        }
+
+        let prepare_lsn = reader.read_u64()?;
+        let end_lsn = reader.read_u64()?;
+        let timestamp = reader.read_i64()?;
+        let xid = reader.read_u32()?;
+        let gid = reader.read_null_terminated_string()?;
+
+        Ok(ReplicationMessage::BeginPrepare {
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        })
+    }
+
+    fn parse_prepare_message(reader: &mut BufferReader) -> ReplicationResult<ReplicationMessage> {
+        let (flags, prepare_lsn, end_lsn, timestamp, xid, gid) =
+            Self::parse_prepare_fields(reader, "Prepare message too short")?;
+
+        Ok(ReplicationMessage::Prepare {
+            flags,
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        })
+    }
+
+    fn parse_commit_prepared_message(
+        reader: &mut BufferReader,
+    ) -> ReplicationResult<ReplicationMessage> {
+        let (flags, commit_lsn, end_lsn, timestamp, xid, gid) =
+            Self::parse_prepare_fields(reader, "Commit prepared message too short")?;
+
+        Ok(ReplicationMessage::CommitPrepared {
+            flags,
+            commit_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
         })
     }
 
+    fn parse_rollback_prepared_message(
+        reader: &mut BufferReader,
+    ) -> ReplicationResult<ReplicationMessage> {
+        // ROLLBACK PREPARED message: flags (1) + prepare_end_lsn (8) + rollback_end_lsn (8)
+        // + prepare_timestamp (8) + rollback_timestamp (8) + xid (4) + gid (null-terminated)
+        if !reader.has_bytes(38) {
+            return Err(ReplicationError::parse(
+                "Rollback prepared message too short",
+            ));
+        }
+
+        let flags = reader.read_u8()?;
+        let prepare_end_lsn = reader.read_u64()?;
+        let rollback_end_lsn = reader.read_u64()?;
+        let prepare_timestamp = reader.read_i64()?;
+        let rollback_timestamp = reader.read_i64()?;
+        let xid = reader.read_u32()?;
+        let gid = reader.read_null_terminated_string()?;
+
+        Ok(ReplicationMessage::RollbackPrepared {
+            flags,
+            prepare_end_lsn,
+            rollback_end_lsn,
+            prepare_timestamp,
+            rollback_timestamp,
+            xid,
+            gid,
+        })
+    }
+
+    fn parse_stream_prepare_message(
+        reader: &mut BufferReader,
+    ) -> ReplicationResult<ReplicationMessage> {
+        let (flags, prepare_lsn, end_lsn, timestamp, xid, gid) =
+            Self::parse_prepare_fields(reader, "Stream prepare message too short")?;
+
+        Ok(ReplicationMessage::StreamPrepare {
+            flags,
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        })
+    }
+
+    /// Reads the layout shared by Prepare, Commit Prepared and Stream Prepare
+    ///
+    /// flags (1) + lsn (8) + end_lsn (8) + timestamp (8) + xid (4) + gid (null-terminated)
+    fn parse_prepare_fields(
+        reader: &mut BufferReader,
+        too_short: &str,
+    ) -> ReplicationResult<(u8, u64, u64, i64, u32, String)> {
+        if !reader.has_bytes(30) {
+            return Err(ReplicationError::parse(too_short));
+        }
+
+        let flags = reader.read_u8()?;
+        let lsn = reader.read_u64()?;
+        let end_lsn = reader.read_u64()?;
+        let timestamp = reader.read_i64()?;
+        let xid = reader.read_u32()?;
+        let gid = reader.read_null_terminated_string()?;
+
+        Ok((flags, lsn, end_lsn, timestamp, xid, gid))
+    }
+
     fn parse_tuple_data(reader: &mut BufferReader) -> ReplicationResult<TupleData> {
         // TUPLE DATA: column_count (2) + columns
         if !reader.has_bytes(2) {
@@ -406,6 +636,7 @@ impl MessageParser {
                         data_type: 'n',
                         length: 0,
                         data: String::new(),
+                        binary: None,
                     }
                 }
                 'u' => {
@@ -415,6 +646,7 @@ impl MessageParser {
                         data_type: 'u',
                         length: 0,
                         data: String::new(),
+                        binary: None,
                     }
                 }
                 't' => {
@@ -424,6 +656,21 @@ impl MessageParser {
                         data_type: 't',
                         length: text_data.len() as i32,
                         data: text_data,
+                        binary: None,
+                    }
+                }
+                'b' => {
+                    // Binary data with length prefix, decoded later by type
+                    let length = reader.read_i32()?;
+                    if length < 0 {
+                        return Err(ReplicationError::parse("Negative binary value length"));
+                    }
+                    let bytes = reader.read_bytes(length as usize)?;
+                    ColumnData {
+                        data_type: 'b',
+                        length,
+                        data: String::new(),
+                        binary: Some(bytes),
                     }
                 }
                 _ => {
@@ -446,4 +693,393 @@ impl MessageParser {
             processed_length,
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a pgoutput message of type `kind` from its encoded fields
+    fn message(kind: u8, fields: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![kind];
+        for field in fields {
+            data.extend_from_slice(field);
+        }
+        data
+    }
+
+    fn parse(data: &[u8]) -> ReplicationMessage {
+        MessageParser::parse_wal_message(data, 0).unwrap()
+    }
+
+    fn parse_streamed(data: &[u8]) -> ReplicationMessage {
+        MessageParser::parse_streamed_wal_message(data, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_begin_prepare() {
+        let data = message(
+            b'b',
+            &[
+                &0x100u64.to_be_bytes(),
+                &0x180u64.to_be_bytes(),
+                &7_000i64.to_be_bytes(),
+                &42u32.to_be_bytes(),
+                b"gid-1\0",
+            ],
+        );
+        let ReplicationMessage::BeginPrepare {
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        } = parse(&data)
+        else {
+            panic!("expected BeginPrepare");
+        };
+        assert_eq!(
+            (prepare_lsn, end_lsn, timestamp, xid, gid.as_str()),
+            (0x100, 0x180, 7_000, 42, "gid-1")
+        );
+    }
+
+    #[test]
+    fn test_parse_prepare_layouts() {
+        // Prepare, Commit Prepared and Stream Prepare share their layout
+        let fields = |kind| {
+            message(
+                kind,
+                &[
+                    &[1],
+                    &0x200u64.to_be_bytes(),
+                    &0x280u64.to_be_bytes(),
+                    &8_000i64.to_be_bytes(),
+                    &43u32.to_be_bytes(),
+                    b"gid-2\0",
+                ],
+            )
+        };
+        let expected = (1, 0x200, 0x280, 8_000, 43, "gid-2");
+
+        let ReplicationMessage::Prepare {
+            flags,
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        } = parse(&fields(b'P'))
+        else {
+            panic!("expected Prepare");
+        };
+        assert_eq!(
+            (flags, prepare_lsn, end_lsn, timestamp, xid, gid.as_str()),
+            expected
+        );
+
+        let ReplicationMessage::CommitPrepared {
+            flags,
+            commit_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        } = parse(&fields(b'K'))
+        else {
+            panic!("expected CommitPrepared");
+        };
+        assert_eq!(
+            (flags, commit_lsn, end_lsn, timestamp, xid, gid.as_str()),
+            expected
+        );
+
+        let ReplicationMessage::StreamPrepare {
+            flags,
+            prepare_lsn,
+            end_lsn,
+            timestamp,
+            xid,
+            gid,
+        } = parse(&fields(b'p'))
+        else {
+            panic!("expected StreamPrepare");
+        };
+        assert_eq!(
+            (flags, prepare_lsn, end_lsn, timestamp, xid, gid.as_str()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_rollback_prepared() {
+        let data = message(
+            b'r',
+            &[
+                &[0],
+                &0x300u64.to_be_bytes(),
+                &0x380u64.to_be_bytes(),
+                &9_000i64.to_be_bytes(),
+                &9_500i64.to_be_bytes(),
+                &44u32.to_be_bytes(),
+                b"gid-3\0",
+            ],
+        );
+        let ReplicationMessage::RollbackPrepared {
+            flags,
+            prepare_end_lsn,
+            rollback_end_lsn,
+            prepare_timestamp,
+            rollback_timestamp,
+            xid,
+            gid,
+        } = parse(&data)
+        else {
+            panic!("expected RollbackPrepared");
+        };
+        assert_eq!(
+            (
+                flags,
+                prepare_end_lsn,
+                rollback_end_lsn,
+                prepare_timestamp,
+                rollback_timestamp,
+                xid,
+                gid.as_str()
+            ),
+            (0, 0x300, 0x380, 9_000, 9_500, 44, "gid-3")
+        );
+    }
+
+    #[test]
+    fn test_parse_stream_abort_v2_and_v4() {
+        let v2 = message(b'A', &[&45u32.to_be_bytes(), &46u32.to_be_bytes()]);
+        let ReplicationMessage::StreamAbort {
+            xid,
+            subtransaction_xid,
+            abort_lsn,
+            abort_timestamp,
+        } = parse(&v2)
+        else {
+            panic!("expected StreamAbort");
+        };
+        assert_eq!(
+            (xid, subtransaction_xid, abort_lsn, abort_timestamp),
+            (45, 46, None, None)
+        );
+
+        // Protocol version 4 appends the abort position and time
+        let v4 = message(
+            b'A',
+            &[
+                &45u32.to_be_bytes(),
+                &46u32.to_be_bytes(),
+                &0x400u64.to_be_bytes(),
+                &10_000i64.to_be_bytes(),
+            ],
+        );
+        let ReplicationMessage::StreamAbort {
+            xid,
+            subtransaction_xid,
+            abort_lsn,
+            abort_timestamp,
+        } = parse(&v4)
+        else {
+            panic!("expected StreamAbort");
+        };
+        assert_eq!(
+            (xid, subtransaction_xid, abort_lsn, abort_timestamp),
+            (45, 46, Some(0x400), Some(10_000))
+        );
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        // Origin carries no xid, even within a stream
+        let data = message(b'O', &[&0x500u64.to_be_bytes(), b"node_b\0"]);
+        for parsed in [parse(&data), parse_streamed(&data)] {
+            let ReplicationMessage::Origin { commit_lsn, name } = parsed else {
+                panic!("expected Origin");
+            };
+            assert_eq!((commit_lsn, name.as_str()), (0x500, "node_b"));
+        }
+    }
+
+    #[test]
+    fn test_parse_type_in_and_out_of_stream() {
+        let fields: [&[u8]; 3] = [&16385u32.to_be_bytes(), b"public\0", b"mood\0"];
+        let streamed_xid = 47u32.to_be_bytes();
+        let mut streamed: Vec<&[u8]> = vec![&streamed_xid];
+        streamed.extend(fields);
+
+        for (parsed, expected_xid) in [
+            (parse(&message(b'Y', &fields)), None),
+            (parse_streamed(&message(b'Y', &streamed)), Some(47)),
+        ] {
+            let ReplicationMessage::Type {
+                xid,
+                oid,
+                namespace,
+                name,
+            } = parsed
+            else {
+                panic!("expected Type");
+            };
+            assert_eq!(
+                (xid, oid, namespace.as_str(), name.as_str()),
+                (expected_xid, 16385, "public", "mood")
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_logical_message_in_and_out_of_stream() {
+        let fields: [&[u8]; 5] = [
+            &[1],
+            &0x600u64.to_be_bytes(),
+            b"app\0",
+            &5u32.to_be_bytes(),
+            b"hello",
+        ];
+        let streamed_xid = 48u32.to_be_bytes();
+        let mut streamed: Vec<&[u8]> = vec![&streamed_xid];
+        streamed.extend(fields);
+
+        for (parsed, expected_xid) in [
+            (parse(&message(b'M', &fields)), None),
+            (parse_streamed(&message(b'M', &streamed)), Some(48)),
+        ] {
+            let ReplicationMessage::LogicalMessage {
+                xid,
+                transactional,
+                lsn,
+                prefix,
+                content,
+            } = parsed
+            else {
+                panic!("expected LogicalMessage");
+            };
+            assert_eq!(
+                (xid, transactional, lsn, prefix.as_str(), content.as_slice()),
+                (expected_xid, true, 0x600, "app", b"hello".as_slice())
+            );
+        }
+
+        // Non-transactional messages have the flag bit cleared
+        let mut fields = fields;
+        fields[0] = &[0];
+        let ReplicationMessage::LogicalMessage { transactional, .. } =
+            parse(&message(b'M', &fields))
+        else {
+            panic!("expected LogicalMessage");
+        };
+        assert!(!transactional);
+    }
+
+    #[test]
+    fn test_parse_binary_tuple_columns() {
+        let data = message(
+            b'I',
+            &[
+                &16390u32.to_be_bytes(),
+                b"N",
+                &3i16.to_be_bytes(),
+                b"n",
+                b"u",
+                b"b",
+                &4i32.to_be_bytes(),
+                &[0x00, 0x00, 0x01, 0x2C],
+            ],
+        );
+        let ReplicationMessage::Insert {
+            relation_id,
+            tuple_data,
+            ..
+        } = parse(&data)
+        else {
+            panic!("expected Insert");
+        };
+        assert_eq!(relation_id, 16390);
+        assert_eq!(tuple_data.column_count, 3);
+        assert_eq!(tuple_data.processed_length, data.len() - 6);
+
+        let columns: Vec<_> = tuple_data
+            .columns
+            .iter()
+            .map(|column| (column.data_type, column.length, column.binary.as_deref()))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ('n', 0, None),
+                ('u', 0, None),
+                ('b', 4, Some([0x00, 0x00, 0x01, 0x2C].as_slice())),
+            ]
+        );
+        assert!(
+            tuple_data
+                .columns
+                .iter()
+                .all(|column| column.data.is_empty())
+        );
+    }
+
+    #[test]
+    fn test_streamed_changes_whose_xid_looks_like_a_marker() {
+        // Xids starting with the 'N', 'K' or 'O' tuple markers
+        for (kind, xid, marker) in [
+            (b'I', 0x4E00_0001u32, b"N"),
+            (b'U', 0x4B00_0002, b"N"),
+            (b'D', 0x4F00_0003, b"K"),
+        ] {
+            let data = message(
+                kind,
+                &[
+                    &xid.to_be_bytes(),
+                    &16390u32.to_be_bytes(),
+                    marker,
+                    &1i16.to_be_bytes(),
+                    b"t",
+                    &1i32.to_be_bytes(),
+                    b"x",
+                ],
+            );
+            let parsed = match parse_streamed(&data) {
+                ReplicationMessage::Insert {
+                    relation_id, xid, ..
+                }
+                | ReplicationMessage::Update {
+                    relation_id, xid, ..
+                }
+                | ReplicationMessage::Delete {
+                    relation_id, xid, ..
+                } => (relation_id, xid),
+                other => panic!("unexpected message: {:?}", other),
+            };
+            assert_eq!(parsed, (16390, Some(xid)));
+        }
+
+        let fields: [&[u8]; 3] = [&1u32.to_be_bytes(), &[0], &16390u32.to_be_bytes()];
+        let streamed_xid = 0x4E00_0004u32.to_be_bytes();
+        let mut streamed: Vec<&[u8]> = vec![&streamed_xid];
+        streamed.extend(fields);
+
+        for (parsed, expected_xid) in [
+            (parse(&message(b'T', &fields)), None),
+            (parse_streamed(&message(b'T', &streamed)), Some(0x4E00_0004)),
+        ] {
+            let ReplicationMessage::Truncate {
+                relation_ids,
+                is_stream,
+                xid,
+                ..
+            } = parsed
+            else {
+                panic!("expected Truncate");
+            };
+            assert_eq!(
+                (relation_ids, is_stream, xid),
+                (vec![16390], expected_xid.is_some(), expected_xid)
+            );
+        }
+    }
+}
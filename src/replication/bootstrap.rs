@@ -0,0 +1,358 @@
+//! Provisioning of the replication slot and publication
+//!
+//! With `BOOTSTRAP=true` the server creates what is missing instead of failing
+//! with instructions:
+//! 1. the publication, created for the configured scope or altered to match it,
+//!    followed by a report of published tables whose updates and deletes carry no key
+//! 2. the logical slot (pgoutput), optionally temporary, two-phase or failover
+//!
+//! The publication is created first: pgoutput looks publications up with the
+//! slot's historic snapshot and cannot see one created after the slot.
+//!
+//! The initial snapshot also creates the slot here, exporting its snapshot.
+
+use crate::core::config::{PublicationScope, ReplicationConfig};
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::replication::snapshot::ExportedSnapshot;
+use crate::utils::connection::{PGConnection, PGResult};
+use crate::utils::lsn::parse_lsn;
+use tracing::{info, warn};
+
+/// A published table whose updates and deletes would not identify the row
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeylessTable {
+    pub schema: String,
+    pub table: String,
+    /// `relreplident`: 'd' (default, primary key) or 'n' (nothing)
+    pub replica_identity: char,
+}
+
+impl std::fmt::Display for KeylessTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.replica_identity {
+            'n' => write!(
+                f,
+                "{}.{} (REPLICA IDENTITY NOTHING)",
+                self.schema, self.table
+            ),
+            _ => write!(f, "{}.{} (no primary key)", self.schema, self.table),
+        }
+    }
+}
+
+/// Creates the slot and publication over the replication connection
+pub struct Bootstrap<'a> {
+    connection: &'a PGConnection,
+    config: &'a ReplicationConfig,
+}
+
+impl<'a> Bootstrap<'a> {
+    pub fn new(connection: &'a PGConnection, config: &'a ReplicationConfig) -> Self {
+        Self { connection, config }
+    }
+
+    /// Creates or aligns the publication, then reports its keyless tables
+    pub fn provision_publication(&self) -> ReplicationResult<()> {
+        self.ensure_publication()?;
+
+        let keyless = self.keyless_tables()?;
+        if !keyless.is_empty() {
+            warn!(
+                "Publication '{}' contains tables without a usable replica identity; their updates and deletes carry no key (and fail on the primary if the publication publishes them): {}. Add a primary key or run ALTER TABLE ... REPLICA IDENTITY FULL",
+                self.config.publication_name,
+                keyless
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates the publication, or aligns the table list of an existing one
+    fn ensure_publication(&self) -> ReplicationResult<()> {
+        let name = self
+            .connection
+            .escape_identifier(&self.config.publication_name)?;
+        let result = self.query(&format!(
+            "SELECT puballtables FROM pg_publication WHERE pubname = {};",
+            self.connection
+                .escape_literal(&self.config.publication_name)?
+        ))?;
+        let quote = |identifier: &str| self.connection.escape_identifier(identifier);
+
+        if result.ntuples() == 0 {
+            let target = publication_target(&self.config.publication_scope, quote)?;
+            info!(
+                "Creating publication '{}' for {}",
+                self.config.publication_name, self.config.publication_scope
+            );
+            self.query(&format!("CREATE PUBLICATION {} {};", name, target))?;
+            return Ok(());
+        }
+
+        let all_tables = result.getvalue(0, 0).as_deref() == Some("t");
+        match (&self.config.publication_scope, all_tables) {
+            (PublicationScope::AllTables, true) => Ok(()),
+            (PublicationScope::AllTables, false) | (PublicationScope::Objects { .. }, true) => {
+                Err(ReplicationError::config(format!(
+                    "Publication '{}' exists with a different scope than {} and cannot be altered between FOR ALL TABLES and a table list. Drop it or adjust PUBLICATION_TABLES/PUBLICATION_SCHEMAS",
+                    self.config.publication_name, self.config.publication_scope
+                )))
+            }
+            (scope @ PublicationScope::Objects { .. }, false) => {
+                // "FOR TABLE a, TABLES IN SCHEMA s" becomes "SET TABLE a, TABLES IN SCHEMA s"
+                let target = publication_target(scope, quote)?;
+                let objects = target.strip_prefix("FOR ").unwrap_or(&target);
+                info!(
+                    "Aligning publication '{}' with {}",
+                    self.config.publication_name, scope
+                );
+                self.query(&format!("ALTER PUBLICATION {} SET {};", name, objects))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the configured replication slot exists
+    pub fn slot_exists(&self) -> ReplicationResult<bool> {
+        let result = self.query(&format!(
+            "SELECT slot_name FROM pg_replication_slots WHERE slot_name = {};",
+            self.connection.escape_literal(&self.config.slot_name)?
+        ))?;
+        Ok(result.ntuples() > 0)
+    }
+
+    /// Creates the logical slot if missing; enables failover on an existing one
+    pub fn ensure_slot(&self) -> ReplicationResult<()> {
+        if self.slot_exists()? {
+            if self.config.slot_failover {
+                // ALTER_REPLICATION_SLOT is idempotent, no need to check the current setting
+                let slot = self.connection.escape_identifier(&self.config.slot_name)?;
+                self.query(&format!("ALTER_REPLICATION_SLOT {} (FAILOVER true);", slot))?;
+            }
+            return Ok(());
+        }
+
+        self.create_slot(false)?;
+        Ok(())
+    }
+
+    /// Creates the logical slot, exporting a snapshot of its consistent point
+    ///
+    /// The snapshot is only usable until the next command on the replication connection.
+    pub fn create_slot_with_snapshot(&self) -> ReplicationResult<ExportedSnapshot> {
+        let result = self.create_slot(true)?;
+        // Columns: slot_name, consistent_point, snapshot_name, output_plugin
+        let consistent_point = result
+            .getvalue(0, 1)
+            .as_deref()
+            .and_then(parse_lsn)
+            .ok_or_else(|| {
+                ReplicationError::protocol("Slot creation returned no consistent point")
+            })?;
+        let snapshot_name = result
+            .getvalue(0, 2)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| ReplicationError::protocol("Slot creation exported no snapshot"))?;
+
+        Ok(ExportedSnapshot {
+            consistent_point,
+            snapshot_name,
+        })
+    }
+
+    /// Drops the replication slot, e.g. after its initial snapshot failed
+    pub fn drop_slot(&self) -> ReplicationResult<()> {
+        info!("Dropping replication slot '{}'", self.config.slot_name);
+        let slot = self.connection.escape_identifier(&self.config.slot_name)?;
+        self.query(&format!("DROP_REPLICATION_SLOT {};", slot))?;
+        Ok(())
+    }
+
+    fn create_slot(&self, export_snapshot: bool) -> ReplicationResult<PGResult> {
+        info!(
+            "Creating {}replication slot '{}'",
+            if self.config.slot_temporary {
+                "temporary "
+            } else {
+                ""
+            },
+            self.config.slot_name
+        );
+        self.query(&create_slot_command(
+            &self.connection.escape_identifier(&self.config.slot_name)?,
+            self.config.slot_temporary,
+            self.config.two_phase,
+            self.config.slot_failover,
+            export_snapshot,
+        ))
+    }
+
+    /// Published tables whose replica identity does not identify rows
+    fn keyless_tables(&self) -> ReplicationResult<Vec<KeylessTable>> {
+        let result = self.query(&format!(
+            "SELECT pt.schemaname, pt.tablename, c.relreplident
+             FROM pg_publication_tables pt
+             JOIN pg_namespace n ON n.nspname = pt.schemaname
+             JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = pt.tablename
+             WHERE pt.pubname = {}
+               AND (c.relreplident = 'n'
+                    OR (c.relreplident = 'd'
+                        AND NOT EXISTS (SELECT 1 FROM pg_index i
+                                        WHERE i.indrelid = c.oid AND i.indisprimary)))
+             ORDER BY 1, 2;",
+            self.connection
+                .escape_literal(&self.config.publication_name)?
+        ))?;
+
+        Ok((0..result.ntuples())
+            .map(|row| KeylessTable {
+                schema: result.getvalue(row, 0).unwrap_or_default(),
+                table: result.getvalue(row, 1).unwrap_or_default(),
+                replica_identity: result
+                    .getvalue(row, 2)
+                    .and_then(|identity| identity.chars().next())
+                    .unwrap_or('d'),
+            })
+            .collect())
+    }
+
+    fn query(&self, sql: &str) -> ReplicationResult<PGResult> {
+        let result = self.connection.exec(sql)?;
+        if !result.is_ok() {
+            return Err(ReplicationError::protocol(format!(
+                "Bootstrap query failed ({:?}): {}",
+                result.status(),
+                sql
+            )));
+        }
+        Ok(result)
+    }
+}
+
+/// `FOR ...` clause of CREATE PUBLICATION for `scope`
+///
+/// Tables are `schema.table` or `table`; each part is quoted with `quote`.
+fn publication_target(
+    scope: &PublicationScope,
+    quote: impl Fn(&str) -> ReplicationResult<String>,
+) -> ReplicationResult<String> {
+    let (tables, schemas) = match scope {
+        PublicationScope::AllTables => return Ok("FOR ALL TABLES".to_string()),
+        PublicationScope::Objects { tables, schemas } => (tables, schemas),
+    };
+
+    let mut objects = Vec::new();
+    if !tables.is_empty() {
+        // "TABLE a, b" rather than "TABLE a, TABLE b", which needs PostgreSQL 15
+        let mut quoted = Vec::new();
+        for table in tables {
+            quoted.push(match table.split_once('.') {
+                Some((schema, name)) => format!("{}.{}", quote(schema)?, quote(name)?),
+                None => quote(table)?,
+            });
+        }
+        objects.push(format!("TABLE {}", quoted.join(", ")));
+    }
+    for schema in schemas {
+        objects.push(format!("TABLES IN SCHEMA {}", quote(schema)?));
+    }
+    Ok(format!("FOR {}", objects.join(", ")))
+}
+
+/// CREATE_REPLICATION_SLOT command for an already quoted slot name
+///
+/// The legacy `[NO]EXPORT_SNAPSHOT` form is kept when no option needs the
+/// PostgreSQL 15+ parenthesized syntax.
+fn create_slot_command(
+    slot: &str,
+    temporary: bool,
+    two_phase: bool,
+    failover: bool,
+    export_snapshot: bool,
+) -> String {
+    let temporary = if temporary { " TEMPORARY" } else { "" };
+    let mut options = Vec::new();
+    if two_phase {
+        options.push("TWO_PHASE true");
+    }
+    if failover {
+        options.push("FAILOVER true");
+    }
+
+    if options.is_empty() {
+        format!(
+            "CREATE_REPLICATION_SLOT {}{} LOGICAL pgoutput {};",
+            slot,
+            temporary,
+            if export_snapshot {
+                "EXPORT_SNAPSHOT"
+            } else {
+                "NOEXPORT_SNAPSHOT"
+            }
+        )
+    } else {
+        options.push(if export_snapshot {
+            "SNAPSHOT 'export'"
+        } else {
+            "SNAPSHOT 'nothing'"
+        });
+        format!(
+            "CREATE_REPLICATION_SLOT {}{} LOGICAL pgoutput ({});",
+            slot,
+            temporary,
+            options.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(identifier: &str) -> ReplicationResult<String> {
+        Ok(format!("\"{}\"", identifier.replace('"', "\"\"")))
+    }
+
+    #[test]
+    fn test_publication_target() {
+        assert_eq!(
+            publication_target(&PublicationScope::AllTables, quote).unwrap(),
+            "FOR ALL TABLES"
+        );
+        let scope = PublicationScope::Objects {
+            tables: vec!["public.orders".to_string(), "users".to_string()],
+            schemas: vec!["billing".to_string()],
+        };
+        assert_eq!(
+            publication_target(&scope, quote).unwrap(),
+            "FOR TABLE \"public\".\"orders\", \"users\", TABLES IN SCHEMA \"billing\""
+        );
+    }
+
+    #[test]
+    fn test_create_slot_command() {
+        assert_eq!(
+            create_slot_command("\"sub\"", false, false, false, false),
+            "CREATE_REPLICATION_SLOT \"sub\" LOGICAL pgoutput NOEXPORT_SNAPSHOT;"
+        );
+        assert_eq!(
+            create_slot_command("\"sub\"", true, true, false, false),
+            "CREATE_REPLICATION_SLOT \"sub\" TEMPORARY LOGICAL pgoutput (TWO_PHASE true, SNAPSHOT 'nothing');"
+        );
+        assert_eq!(
+            create_slot_command("\"sub\"", false, false, true, false),
+            "CREATE_REPLICATION_SLOT \"sub\" LOGICAL pgoutput (FAILOVER true, SNAPSHOT 'nothing');"
+        );
+        assert_eq!(
+            create_slot_command("\"sub\"", false, false, false, true),
+            "CREATE_REPLICATION_SLOT \"sub\" LOGICAL pgoutput EXPORT_SNAPSHOT;"
+        );
+        assert_eq!(
+            create_slot_command("\"sub\"", false, true, false, true),
+            "CREATE_REPLICATION_SLOT \"sub\" LOGICAL pgoutput (TWO_PHASE true, SNAPSHOT 'export');"
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! Durable record of the last transaction acknowledged by the sink
+//!
+//! The slot's confirmed position only moves when feedback reaches the server,
+//! so after a crash PostgreSQL can stream again transactions the sink already
+//! accepted. The checkpoint store records the commit end LSN of the last
+//! acknowledged transaction along with each status update; on startup the
+//! server skips the transactions that end at or before it.
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::core::metrics::METRICS;
+use crate::protocol::messages::ReplicationMessage;
+use crate::replication::side_table::SideTable;
+use crate::utils::lsn::{format_lsn, parse_lsn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+/// Storage backend for the slot's checkpoint
+pub trait CheckpointStore {
+    /// Last position recorded for the slot, if any
+    fn load(&mut self) -> ReplicationResult<Option<u64>>;
+
+    /// Records `lsn` as acknowledged; it is durable once this returns
+    fn save(&mut self, lsn: u64) -> ReplicationResult<()>;
+
+    /// Fails if the store's own writes would be replicated through `publication`
+    fn check_publication(&mut self, _publication: &str) -> ReplicationResult<()> {
+        Ok(())
+    }
+}
+
+/// Contents of a checkpoint file
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    slot: String,
+    lsn: String,
+}
+
+/// Checkpoint kept in a local file, replaced atomically and fsynced on every save
+pub struct FileCheckpointStore {
+    path: PathBuf,
+    slot: String,
+}
+
+impl FileCheckpointStore {
+    pub fn new(path: PathBuf, slot: &str) -> Self {
+        Self {
+            path,
+            slot: slot.to_string(),
+        }
+    }
+
+    fn write(&self, contents: &[u8]) -> std::io::Result<()> {
+        let dir = self
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        fs::create_dir_all(&dir)?;
+
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        // The rename itself is only durable once the directory is synced
+        File::open(&dir)?.sync_all()
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&mut self) -> ReplicationResult<Option<u64>> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(ReplicationError::config(format!(
+                    "Failed to read checkpoint file {}: {}",
+                    self.path.display(),
+                    e
+                )));
+            }
+        };
+        let checkpoint: Checkpoint = serde_json::from_slice(&contents).map_err(|e| {
+            ReplicationError::config(format!(
+                "Invalid checkpoint file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })?;
+
+        if checkpoint.slot != self.slot {
+            warn!(
+                "Ignoring checkpoint file {} recorded for slot '{}'",
+                self.path.display(),
+                checkpoint.slot
+            );
+            return Ok(None);
+        }
+        parse_lsn(&checkpoint.lsn).map(Some).ok_or_else(|| {
+            ReplicationError::config(format!(
+                "Invalid LSN '{}' in checkpoint file {}",
+                checkpoint.lsn,
+                self.path.display()
+            ))
+        })
+    }
+
+    fn save(&mut self, lsn: u64) -> ReplicationResult<()> {
+        let checkpoint = Checkpoint {
+            slot: self.slot.clone(),
+            lsn: format_lsn(lsn),
+        };
+        let contents = serde_json::to_vec(&checkpoint).map_err(|e| {
+            ReplicationError::protocol(format!("Failed to encode checkpoint: {}", e))
+        })?;
+        self.write(&contents).map_err(|e| {
+            ReplicationError::protocol(format!(
+                "Failed to save checkpoint file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+}
+
+/// Checkpoint kept in a PostgreSQL table, one row per slot
+///
+/// Writes commit synchronously, so a saved checkpoint survives a crash of
+/// either side.
+pub struct PostgresCheckpointStore {
+    table: SideTable,
+    slot: String,
+}
+
+impl PostgresCheckpointStore {
+    /// `table` is `schema.table` (or a table of the `public` schema) in the
+    /// database `conninfo` connects to
+    pub fn new(conninfo: &str, table: &str, slot: &str) -> Self {
+        Self {
+            table: SideTable::new(
+                conninfo,
+                table,
+                "slot_name text PRIMARY KEY,
+                 lsn pg_lsn NOT NULL,
+                 updated_at timestamptz NOT NULL DEFAULT now()",
+            ),
+            slot: slot.to_string(),
+        }
+    }
+}
+
+impl CheckpointStore for PostgresCheckpointStore {
+    fn load(&mut self) -> ReplicationResult<Option<u64>> {
+        let table = self.table.name();
+        let slot = &self.slot;
+        let result = self.table.run(|connection| {
+            Ok(format!(
+                "SELECT lsn::text FROM {} WHERE slot_name = {};",
+                table,
+                connection.escape_literal(slot)?
+            ))
+        })?;
+        if result.ntuples() == 0 {
+            return Ok(None);
+        }
+        let lsn = result.getvalue(0, 0).unwrap_or_default();
+        parse_lsn(&lsn)
+            .map(Some)
+            .ok_or_else(|| ReplicationError::protocol(format!("Invalid checkpoint LSN '{}'", lsn)))
+    }
+
+    fn save(&mut self, lsn: u64) -> ReplicationResult<()> {
+        let table = self.table.name();
+        let slot = &self.slot;
+        self.table.run(|connection| {
+            Ok(format!(
+                "INSERT INTO {} (slot_name, lsn) VALUES ({}, '{}')
+                 ON CONFLICT (slot_name) DO UPDATE SET lsn = EXCLUDED.lsn, updated_at = now();",
+                table,
+                connection.escape_literal(slot)?,
+                format_lsn(lsn)
+            ))
+        })?;
+        Ok(())
+    }
+
+    fn check_publication(&mut self, publication: &str) -> ReplicationResult<()> {
+        self.table
+            .check_publication(publication, "CHECKPOINT_DATABASE_URL")
+    }
+}
+
+type StoreJob = Box<dyn FnOnce(&mut dyn CheckpointStore) + Send>;
+
+/// Checkpoint store run on a thread of its own
+///
+/// Acknowledged positions are kept in memory by [`CheckpointWriter::record`]
+/// and written by [`CheckpointWriter::flush`], which the server calls with
+/// each status update: the store is written at most once per feedback
+/// interval, and its file or database I/O never blocks the runtime.
+pub struct CheckpointWriter {
+    jobs: mpsc::Sender<StoreJob>,
+    /// Last acknowledged position not written yet
+    pending: Option<u64>,
+}
+
+impl CheckpointWriter {
+    /// Starts the thread running the store `open` returns
+    pub fn new<F>(open: F) -> ReplicationResult<Self>
+    where
+        F: FnOnce() -> Box<dyn CheckpointStore> + Send + 'static,
+    {
+        let (jobs, queue) = mpsc::channel::<StoreJob>();
+        std::thread::Builder::new()
+            .name("walpipe-checkpoint".to_string())
+            .spawn(move || {
+                let mut store = open();
+                for job in queue {
+                    job(store.as_mut());
+                }
+            })
+            .map_err(|e| {
+                ReplicationError::config(format!("Failed to start checkpoint thread: {}", e))
+            })?;
+        Ok(Self {
+            jobs,
+            pending: None,
+        })
+    }
+
+    /// Last position recorded for the slot, if any
+    pub async fn load(&self) -> ReplicationResult<Option<u64>> {
+        self.run(|store| store.load()).await
+    }
+
+    /// Fails if the store's own writes would be replicated through `publication`
+    pub async fn check_publication(&self, publication: &str) -> ReplicationResult<()> {
+        let publication = publication.to_string();
+        self.run(move |store| store.check_publication(&publication))
+            .await
+    }
+
+    /// Records `lsn` as acknowledged, to be written by the next flush
+    pub fn record(&mut self, lsn: u64) {
+        self.pending = Some(lsn);
+    }
+
+    /// Writes the position recorded since the last flush, returning it
+    pub async fn flush(&mut self) -> ReplicationResult<Option<u64>> {
+        let Some(lsn) = self.pending else {
+            return Ok(None);
+        };
+        self.run(move |store| store.save(lsn)).await?;
+        self.pending = None;
+        Ok(Some(lsn))
+    }
+
+    async fn run<T, F>(&self, call: F) -> ReplicationResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn CheckpointStore) -> ReplicationResult<T> + Send + 'static,
+    {
+        let stopped = || ReplicationError::protocol("Checkpoint thread stopped");
+        let (reply, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |store| {
+                let _ = reply.send(call(store));
+            }))
+            .map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+/// What becomes of a message the server streams again after a restart
+#[derive(Debug, PartialEq)]
+pub enum Replay {
+    /// Not delivered yet, processed further
+    Process,
+    /// Part of a delivered transaction
+    Skip,
+    /// Begins a delivered transaction
+    SkipBegin,
+    /// Ends a delivered transaction at the given end LSN
+    SkipEnd(u64),
+}
+
+/// Drops the transactions the checkpoint records as delivered, which the
+/// server streams again when the slot's confirmed position lagged behind
+#[derive(Debug, Default)]
+pub struct DeliveredTransactions {
+    /// Checkpoint loaded at startup, until the stream moves past it
+    checkpoint_lsn: Option<u64>,
+    /// Set while dropping a delivered transaction
+    skipping: bool,
+    /// Transactions skipped since startup
+    skipped: u64,
+}
+
+impl DeliveredTransactions {
+    pub fn new(checkpoint_lsn: u64) -> Self {
+        Self {
+            checkpoint_lsn: Some(checkpoint_lsn),
+            ..Self::default()
+        }
+    }
+
+    /// Forgets the transaction being dropped, which a new connection streams again
+    pub fn reset(&mut self) {
+        self.skipping = false;
+    }
+
+    /// Tells what to do with `message`; `in_stream` is set between StreamStart
+    /// and StreamStop
+    pub fn check(&mut self, message: &ReplicationMessage, in_stream: bool) -> Replay {
+        // Streamed changes and stream control messages do not tell the
+        // position; a streamed transaction is checked once replayed, on its
+        // synthetic Begin and Commit
+        if in_stream
+            || matches!(
+                message,
+                ReplicationMessage::StreamStart { .. }
+                    | ReplicationMessage::StreamStop
+                    | ReplicationMessage::StreamAbort { .. }
+                    | ReplicationMessage::StreamCommit { .. }
+                    | ReplicationMessage::StreamPrepare { .. }
+            )
+        {
+            return Replay::Process;
+        }
+
+        if self.skipping {
+            return match message {
+                // Schema changes still have to reach the sinks so that later
+                // transactions can be decoded
+                ReplicationMessage::Relation { .. } | ReplicationMessage::Type { .. } => {
+                    Replay::Process
+                }
+                ReplicationMessage::Commit { end_lsn, .. }
+                | ReplicationMessage::Prepare { end_lsn, .. } => {
+                    self.skipping = false;
+                    Replay::SkipEnd(*end_lsn)
+                }
+                _ => Replay::Skip,
+            };
+        }
+
+        let Some(checkpoint_lsn) = self.checkpoint_lsn else {
+            return Replay::Process;
+        };
+        let delivered = match message {
+            // Begin only carries the commit LSN, which precedes the checkpoint
+            // exactly when the transaction ends at or before it
+            ReplicationMessage::Begin { final_lsn, .. } => *final_lsn < checkpoint_lsn,
+            ReplicationMessage::BeginPrepare { end_lsn, .. } => *end_lsn <= checkpoint_lsn,
+            ReplicationMessage::CommitPrepared { end_lsn, .. }
+            | ReplicationMessage::RollbackPrepared {
+                rollback_end_lsn: end_lsn,
+                ..
+            } => {
+                if *end_lsn <= checkpoint_lsn {
+                    self.count_skipped();
+                    return Replay::SkipEnd(*end_lsn);
+                }
+                false
+            }
+            ReplicationMessage::LogicalMessage {
+                transactional: false,
+                lsn,
+                ..
+            } => {
+                if *lsn < checkpoint_lsn {
+                    return Replay::Skip;
+                }
+                false
+            }
+            ReplicationMessage::Relation { .. } | ReplicationMessage::Type { .. } => {
+                return Replay::Process;
+            }
+            _ => false,
+        };
+
+        if delivered {
+            debug!(
+                "Skipping transaction delivered before checkpoint {}",
+                format_lsn(checkpoint_lsn)
+            );
+            self.skipping = true;
+            self.count_skipped();
+            return Replay::SkipBegin;
+        }
+
+        info!(
+            "Caught up with checkpoint {} after skipping {} delivered transactions",
+            format_lsn(checkpoint_lsn),
+            self.skipped
+        );
+        self.checkpoint_lsn = None;
+        Replay::Process
+    }
+
+    fn count_skipped(&mut self) {
+        self.skipped += 1;
+        METRICS.transactions_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_checkpoint_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "walpipe-checkpoint-test-{}/sub.json",
+            std::process::id()
+        ));
+        let mut store = FileCheckpointStore::new(path.clone(), "sub");
+        assert_eq!(store.load().unwrap(), None);
+
+        store.save(0x16B3748).unwrap();
+        store.save(0x16B3800).unwrap();
+        assert_eq!(store.load().unwrap(), Some(0x16B3800));
+
+        // A checkpoint recorded for another slot is not used
+        let mut other = FileCheckpointStore::new(path.clone(), "other");
+        assert_eq!(other.load().unwrap(), None);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writer_saves_on_flush() {
+        let path = std::env::temp_dir().join(format!(
+            "walpipe-checkpoint-writer-test-{}/sub.json",
+            std::process::id()
+        ));
+        let store_path = path.clone();
+        let mut writer =
+            CheckpointWriter::new(move || Box::new(FileCheckpointStore::new(store_path, "sub")))
+                .unwrap();
+
+        // Only the last position recorded before a flush is written
+        writer.record(0x16B3748);
+        writer.record(0x16B3800);
+        assert!(!path.exists());
+        assert_eq!(writer.flush().await.unwrap(), Some(0x16B3800));
+        assert_eq!(writer.flush().await.unwrap(), None);
+        assert_eq!(writer.load().await.unwrap(), Some(0x16B3800));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_restart_in_streamed_transaction() {
+        use crate::protocol::fixtures::{begin, insert, values};
+
+        let streamed = |xid| ReplicationMessage::Insert {
+            relation_id: 1,
+            tuple_data: values(&["1"]),
+            is_stream: true,
+            xid: Some(xid),
+            lsn: 0,
+        };
+        let stream_commit = |xid, commit_lsn| ReplicationMessage::StreamCommit {
+            xid,
+            flags: 0,
+            commit_lsn,
+            end_lsn: commit_lsn + 0x10,
+            timestamp: 0,
+        };
+        let commit = |commit_lsn| ReplicationMessage::Commit {
+            flags: 0,
+            commit_lsn,
+            end_lsn: commit_lsn + 0x10,
+            timestamp: 0,
+        };
+        let mut delivered = DeliveredTransactions::new(0x310);
+
+        // Transaction 7 was delivered before the restart: its segments pass
+        // through to be buffered, and its replay is skipped
+        for _ in 0..2 {
+            let start = ReplicationMessage::StreamStart {
+                xid: 7,
+                first_segment: true,
+            };
+            assert_eq!(delivered.check(&start, false), Replay::Process);
+            assert_eq!(delivered.check(&streamed(7), true), Replay::Process);
+            assert_eq!(
+                delivered.check(&ReplicationMessage::StreamStop, true),
+                Replay::Process
+            );
+        }
+        assert_eq!(
+            delivered.check(&stream_commit(7, 0x300), false),
+            Replay::Process
+        );
+        assert_eq!(delivered.check(&begin(0x300, 7), false), Replay::SkipBegin);
+        assert_eq!(delivered.check(&streamed(7), false), Replay::Skip);
+        assert_eq!(
+            delivered.check(&commit(0x300), false),
+            Replay::SkipEnd(0x310)
+        );
+        assert_eq!(delivered.checkpoint_lsn, Some(0x310));
+
+        // Transaction 8 commits after the checkpoint and is delivered
+        let start = ReplicationMessage::StreamStart {
+            xid: 8,
+            first_segment: true,
+        };
+        assert_eq!(delivered.check(&start, false), Replay::Process);
+        assert_eq!(delivered.check(&streamed(8), true), Replay::Process);
+        assert_eq!(delivered.checkpoint_lsn, Some(0x310));
+        assert_eq!(
+            delivered.check(&stream_commit(8, 0x400), false),
+            Replay::Process
+        );
+        assert_eq!(delivered.check(&begin(0x400, 8), false), Replay::Process);
+        assert_eq!(delivered.checkpoint_lsn, None);
+        assert_eq!(
+            delivered.check(&insert(1, values(&["2"])), false),
+            Replay::Process
+        );
+        assert_eq!(delivered.skipped, 1);
+    }
+}
@@ -0,0 +1,636 @@
+//! Dead-letter queue for events the sink gave up on
+//!
+//! When a sink rejects an event as invalid, or still fails once its retries
+//! are exhausted, the event is written to the queue with the failure reason,
+//! its LSN and every delivery attempt, and replication carries on. The
+//! `replay-dlq` command later sends queued events again to the sink they
+//! failed on.
+//!
+//! Each dead letter keeps the schema of the relations its event refers to,
+//! so that it can be formatted again without the replication stream.
+
+use crate::core::config::{DeadLetterQueueMode, ReplicationConfig};
+use crate::core::errors::{DeliveryAttempt, ReplicationError, ReplicationResult};
+use crate::events::TransactionBatch;
+use crate::events::router::EventRouter;
+use crate::events::script::ScriptProcessor;
+use crate::protocol::messages::{RelationCache, RelationInfo, ReplicationMessage};
+use crate::replication::side_table::SideTable;
+use crate::utils::binary::Oid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Event that could not be delivered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadEvent {
+    /// A single replication message, in message delivery mode
+    Message(ReplicationMessage),
+    /// A whole transaction, in transaction delivery mode
+    Transaction(TransactionBatch),
+}
+
+impl DeadEvent {
+    /// Relations whose rows the event carries
+    fn relation_ids(&self) -> Vec<Oid> {
+        let messages: Vec<&ReplicationMessage> = match self {
+            DeadEvent::Message(message) => vec![message],
+            DeadEvent::Transaction(batch) => batch.changes.iter().collect(),
+        };
+        let mut ids = Vec::new();
+        for message in messages {
+            match message {
+                ReplicationMessage::Insert { relation_id, .. }
+                | ReplicationMessage::Update { relation_id, .. }
+                | ReplicationMessage::Delete { relation_id, .. }
+                | ReplicationMessage::Read { relation_id, .. } => ids.push(*relation_id),
+                ReplicationMessage::Truncate { relation_ids, .. } => {
+                    ids.extend(relation_ids.iter().copied())
+                }
+                _ => {}
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+/// Sink name of the errors of the event script
+const SCRIPT_SINK: &str = "script";
+
+/// Delivery error of an event the event script failed on
+///
+/// The script rejects the event rather than failing transiently, so that
+/// it is dead-lettered at once.
+pub fn script_error(error: String) -> ReplicationError {
+    ReplicationError::delivery(
+        SCRIPT_SINK,
+        format!("Script error: {}", error),
+        true,
+        vec![DeliveryAttempt::failed(error)],
+    )
+}
+
+/// Entry of the dead-letter queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    pub slot: String,
+    pub sink: String,
+    /// Last failure reported by the sink
+    pub reason: String,
+    /// Whether the sink rejected the event, rather than running out of retries
+    pub permanent: bool,
+    /// Position of the event, the commit end LSN for a transaction
+    pub lsn: String,
+    pub failed_at: DateTime<Utc>,
+    /// Every failed attempt, including those of later replays
+    pub attempts: Vec<DeliveryAttempt>,
+    /// Whether the event script failed on the event, which is kept as the
+    /// script received it and runs through the script again on replay
+    #[serde(default)]
+    pub script_failed: bool,
+    pub relations: Vec<RelationInfo>,
+    pub event: DeadEvent,
+}
+
+impl DeadLetter {
+    /// Builds the entry for `event` from the delivery error the sink returned
+    ///
+    /// Returns `None` for errors other than [`ReplicationError::Delivery`],
+    /// which are not about the event itself.
+    pub fn from_error(
+        error: &ReplicationError,
+        event: DeadEvent,
+        slot: &str,
+        lsn: String,
+        relations: &RelationCache,
+    ) -> Option<Self> {
+        let ReplicationError::Delivery {
+            message,
+            sink,
+            permanent,
+            attempts,
+        } = error
+        else {
+            return None;
+        };
+        Some(Self {
+            id: Uuid::new_v4(),
+            slot: slot.to_string(),
+            sink: sink.clone(),
+            reason: message.clone(),
+            permanent: *permanent,
+            lsn,
+            failed_at: Utc::now(),
+            attempts: attempts.clone(),
+            script_failed: sink == SCRIPT_SINK,
+            relations: event
+                .relation_ids()
+                .into_iter()
+                .filter_map(|oid| relations.get(oid))
+                .collect(),
+            event,
+        })
+    }
+}
+
+/// Storage backend of the dead-letter queue
+pub trait DeadLetterQueue {
+    /// Appends a dead letter; it is durable once this returns
+    fn push(&mut self, letter: &DeadLetter) -> ReplicationResult<()>;
+
+    /// Claims the queued dead letters for a replay, oldest first
+    fn pending(&mut self) -> ReplicationResult<Vec<DeadLetter>>;
+
+    /// Removes a replayed dead letter the sink accepted
+    fn delivered(&mut self, letter: &DeadLetter) -> ReplicationResult<()>;
+
+    /// Keeps a replayed dead letter that failed again, with its new attempts
+    fn failed(&mut self, letter: &DeadLetter) -> ReplicationResult<()>;
+
+    /// Ends a replay once every claimed dead letter was delivered or kept
+    fn finish(&mut self) -> ReplicationResult<()>;
+
+    /// Fails if the queue's own writes would be replicated through `publication`
+    fn check_publication(&mut self, _publication: &str) -> ReplicationResult<()> {
+        Ok(())
+    }
+}
+
+/// Dead letters appended to a JSON Lines file, fsynced on every write
+///
+/// A replay moves the queued lines to `<file>.replaying`, so the file can
+/// keep growing meanwhile. Each replayed letter is removed from it once
+/// delivered, or once written back to the file when it fails again, so an
+/// interrupted replay is resumed by the next one where it stopped.
+pub struct FileDeadLetterQueue {
+    path: PathBuf,
+}
+
+impl FileDeadLetterQueue {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn replaying_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".replaying");
+        PathBuf::from(path)
+    }
+
+    fn open_locked(&self) -> std::io::Result<File> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        // Serializes writers with a replay claiming the file's contents
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn claim(&self) -> std::io::Result<()> {
+        let mut file = self.open_locked()?;
+        let mut queued = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut queued)?;
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        let mut replaying = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.replaying_path())?;
+        replaying.write_all(&queued)?;
+        replaying.sync_all()?;
+        file.set_len(0)?;
+        file.sync_all()
+    }
+
+    /// Rewrites `<file>.replaying` without the letter `id`
+    fn release(&self, id: Uuid) -> std::io::Result<()> {
+        /// Id of a dead letter line, without decoding the rest of it
+        #[derive(Deserialize)]
+        struct LetterId {
+            id: Uuid,
+        }
+
+        let replaying = self.replaying_path();
+        let mut kept = Vec::new();
+        for line in BufReader::new(File::open(&replaying)?).lines() {
+            let line = line?;
+            let released = serde_json::from_str::<LetterId>(&line)
+                .map(|letter| letter.id == id)
+                .unwrap_or(false);
+            if !released && !line.trim().is_empty() {
+                kept.extend_from_slice(line.as_bytes());
+                kept.push(b'\n');
+            }
+        }
+
+        let mut rewritten = replaying.clone().into_os_string();
+        rewritten.push(".tmp");
+        let mut file = File::create(&rewritten)?;
+        file.write_all(&kept)?;
+        file.sync_all()?;
+        fs::rename(&rewritten, &replaying)
+    }
+
+    fn io_error(&self, action: &str, e: std::io::Error) -> ReplicationError {
+        ReplicationError::protocol(format!(
+            "Failed to {} dead-letter file {}: {}",
+            action,
+            self.path.display(),
+            e
+        ))
+    }
+}
+
+impl DeadLetterQueue for FileDeadLetterQueue {
+    fn push(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        let mut line = serde_json::to_vec(letter).map_err(|e| {
+            ReplicationError::protocol(format!("Failed to encode dead letter: {}", e))
+        })?;
+        line.push(b'\n');
+        self.open_locked()
+            .and_then(|mut file| {
+                file.write_all(&line)?;
+                file.sync_data()
+            })
+            .map_err(|e| self.io_error("write", e))
+    }
+
+    fn pending(&mut self) -> ReplicationResult<Vec<DeadLetter>> {
+        self.claim().map_err(|e| self.io_error("claim", e))?;
+
+        let file = match File::open(self.replaying_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(self.io_error("read", e)),
+        };
+        let mut letters = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| self.io_error("read", e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let letter = serde_json::from_str(&line).map_err(|e| {
+                ReplicationError::parse(format!(
+                    "Invalid dead letter on line {} of {}: {}",
+                    number + 1,
+                    self.replaying_path().display(),
+                    e
+                ))
+            })?;
+            letters.push(letter);
+        }
+        Ok(letters)
+    }
+
+    fn delivered(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        self.release(letter.id)
+            .map_err(|e| self.io_error("update the replayed", e))
+    }
+
+    fn failed(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        // Queued again before it is released, so a crash in between keeps it
+        self.push(letter)?;
+        self.release(letter.id)
+            .map_err(|e| self.io_error("update the replayed", e))
+    }
+
+    fn finish(&mut self) -> ReplicationResult<()> {
+        match fs::remove_file(self.replaying_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(self.io_error("remove the replayed", e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Dead letters kept in a PostgreSQL table, one row each
+pub struct PostgresDeadLetterQueue {
+    table: SideTable,
+    slot: String,
+}
+
+impl PostgresDeadLetterQueue {
+    /// `table` is `schema.table` (or a table of the `public` schema) in the
+    /// database `conninfo` connects to
+    pub fn new(conninfo: &str, table: &str, slot: &str) -> Self {
+        Self {
+            table: SideTable::new(
+                conninfo,
+                table,
+                "id uuid PRIMARY KEY,
+                 slot_name text NOT NULL,
+                 sink text NOT NULL,
+                 reason text NOT NULL,
+                 lsn pg_lsn NOT NULL,
+                 failed_at timestamptz NOT NULL,
+                 letter jsonb NOT NULL",
+            ),
+            slot: slot.to_string(),
+        }
+    }
+}
+
+impl DeadLetterQueue for PostgresDeadLetterQueue {
+    fn push(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        let json = serde_json::to_string(letter).map_err(|e| {
+            ReplicationError::protocol(format!("Failed to encode dead letter: {}", e))
+        })?;
+        let table = self.table.name();
+        self.table.run(|connection| {
+            Ok(format!(
+                "INSERT INTO {} (id, slot_name, sink, reason, lsn, failed_at, letter)
+                 VALUES ('{}', {}, {}, {}, '{}', {}, {});",
+                table,
+                letter.id,
+                connection.escape_literal(&letter.slot)?,
+                connection.escape_literal(&letter.sink)?,
+                connection.escape_literal(&letter.reason)?,
+                letter.lsn,
+                connection.escape_literal(&letter.failed_at.to_rfc3339())?,
+                connection.escape_literal(&json)?
+            ))
+        })?;
+        Ok(())
+    }
+
+    fn pending(&mut self) -> ReplicationResult<Vec<DeadLetter>> {
+        let table = self.table.name();
+        let slot = &self.slot;
+        let result = self.table.run(|connection| {
+            Ok(format!(
+                "SELECT letter::text FROM {} WHERE slot_name = {} ORDER BY failed_at, lsn;",
+                table,
+                connection.escape_literal(slot)?
+            ))
+        })?;
+        (0..result.ntuples())
+            .map(|row| {
+                let json = result.getvalue(row, 0).unwrap_or_default();
+                serde_json::from_str(&json).map_err(|e| {
+                    ReplicationError::parse(format!("Invalid dead letter in {}: {}", table, e))
+                })
+            })
+            .collect()
+    }
+
+    fn delivered(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        let table = self.table.name();
+        self.table
+            .run(|_| Ok(format!("DELETE FROM {} WHERE id = '{}';", table, letter.id)))?;
+        Ok(())
+    }
+
+    fn failed(&mut self, letter: &DeadLetter) -> ReplicationResult<()> {
+        let json = serde_json::to_string(letter).map_err(|e| {
+            ReplicationError::protocol(format!("Failed to encode dead letter: {}", e))
+        })?;
+        let table = self.table.name();
+        self.table.run(|connection| {
+            Ok(format!(
+                "UPDATE {} SET reason = {}, letter = {} WHERE id = '{}';",
+                table,
+                connection.escape_literal(&letter.reason)?,
+                connection.escape_literal(&json)?,
+                letter.id
+            ))
+        })?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ReplicationResult<()> {
+        Ok(())
+    }
+
+    fn check_publication(&mut self, publication: &str) -> ReplicationResult<()> {
+        self.table
+            .check_publication(publication, "DLQ_DATABASE_URL")
+    }
+}
+
+/// Opens the queue configured with `DLQ_STORE`, if any
+pub fn open_queue(config: &ReplicationConfig) -> Option<Box<dyn DeadLetterQueue>> {
+    match config.dead_letter_queue {
+        DeadLetterQueueMode::Off => None,
+        DeadLetterQueueMode::File => {
+            Some(Box::new(FileDeadLetterQueue::new(config.dlq_file.clone())))
+        }
+        DeadLetterQueueMode::Postgres => Some(Box::new(PostgresDeadLetterQueue::new(
+            config
+                .dlq_connection_string
+                .as_deref()
+                .unwrap_or(&config.connection_string),
+            &config.dlq_table,
+            &config.slot_name,
+        ))),
+    }
+}
+
+/// Outcome of a replay
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub delivered: usize,
+    pub failed: usize,
+}
+
+/// Sends every queued dead letter again to the sink it failed on
+///
+/// The relations of each dead letter are put back in `relations`, the cache
+/// the sinks format events with, and sent to the sink once before the first
+/// event that needs them, as the replication stream would. Events the
+/// `script` failed on run through it again first. Dead letters that
+/// fail again, or whose sink is no longer configured, are kept with their new
+/// attempts. Errors that are not about the event itself stop the replay; the
+/// remaining dead letters stay queued.
+pub async fn replay(
+    queue: &mut dyn DeadLetterQueue,
+    router: &EventRouter,
+    relations: &RelationCache,
+    mut script: Option<&mut ScriptProcessor>,
+) -> ReplicationResult<ReplaySummary> {
+    let letters = queue.pending()?;
+    info!("Replaying {} dead letters", letters.len());
+
+    let mut summary = ReplaySummary::default();
+    let mut announced = HashSet::new();
+    for mut letter in letters {
+        let Some(route) = router.route(&letter.sink) else {
+            warn!(
+                "Dead letter {} at {} failed on sink '{}', which is not configured",
+                letter.id, letter.lsn, letter.sink
+            );
+            letter.attempts.push(DeliveryAttempt::failed(format!(
+                "No sink named '{}' is configured",
+                letter.sink
+            )));
+            queue.failed(&letter)?;
+            summary.failed += 1;
+            continue;
+        };
+        let sink = &route.sink;
+        for relation in &letter.relations {
+            relations.insert(relation.clone());
+            if announced.insert((route.name.clone(), relation.oid)) {
+                sink.send_event(&ReplicationMessage::Relation {
+                    relation: relation.clone(),
+                })
+                .await?;
+            }
+        }
+
+        let script = script.as_deref_mut().filter(|_| letter.script_failed);
+        let result = match (&letter.event, script) {
+            (DeadEvent::Message(message), Some(script)) => match script.process(message).await {
+                Ok(messages) => {
+                    let messages = script.announce(&messages, &route.name);
+                    async {
+                        for message in &messages {
+                            sink.send_event(message).await?;
+                        }
+                        Ok(())
+                    }
+                    .await
+                }
+                Err(e) => Err(script_error(e)),
+            },
+            (DeadEvent::Transaction(batch), Some(script)) => {
+                match script.process_batch(batch).await {
+                    Ok(changes) => {
+                        let batch = TransactionBatch {
+                            changes: script.announce(&changes.concat(), &route.name),
+                            ..batch.clone()
+                        };
+                        sink.send_transaction(&batch).await
+                    }
+                    Err(e) => Err(script_error(e)),
+                }
+            }
+            (DeadEvent::Message(message), None) => sink.send_event(message).await,
+            (DeadEvent::Transaction(batch), None) => sink.send_transaction(batch).await,
+        };
+        match result {
+            Ok(()) => {
+                queue.delivered(&letter)?;
+                summary.delivered += 1;
+            }
+            Err(ReplicationError::Delivery {
+                message,
+                permanent,
+                attempts,
+                ..
+            }) => {
+                warn!(
+                    "Dead letter {} at {} failed again: {}",
+                    letter.id, letter.lsn, message
+                );
+                letter.reason = message;
+                letter.permanent = permanent;
+                letter.attempts.extend(attempts);
+                queue.failed(&letter)?;
+                summary.failed += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    queue.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn letter(xid: u32) -> DeadLetter {
+        let error = ReplicationError::delivery(
+            "http",
+            "HTTP endpoint rejected the event with status: 422 Unprocessable Entity",
+            true,
+            vec![DeliveryAttempt::failed(
+                "HTTP endpoint returned status: 422",
+            )],
+        );
+        let event = DeadEvent::Message(ReplicationMessage::Begin {
+            final_lsn: 0x16B3748,
+            timestamp: 0,
+            xid,
+        });
+        DeadLetter::from_error(
+            &error,
+            event,
+            "sub",
+            "0/16B3748".to_string(),
+            &RelationCache::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_file_queue_claims_letters_for_replay() {
+        let dir = std::env::temp_dir().join(format!("walpipe-dlq-test-{}", std::process::id()));
+        let mut queue = FileDeadLetterQueue::new(dir.join("dlq.jsonl"));
+        queue.push(&letter(1)).unwrap();
+        queue.push(&letter(2)).unwrap();
+
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert!(pending[0].permanent);
+        assert!(matches!(
+            pending[1].event,
+            DeadEvent::Message(ReplicationMessage::Begin { xid: 2, .. })
+        ));
+
+        // Letters written during the replay and those that fail again stay queued
+        queue.push(&letter(3)).unwrap();
+        queue.failed(&pending[1]).unwrap();
+        queue.finish().unwrap();
+        assert_eq!(queue.pending().unwrap().len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_queue_resumes_interrupted_replay() {
+        let dir = std::env::temp_dir().join(format!("walpipe-dlq-resume-{}", std::process::id()));
+        let mut queue = FileDeadLetterQueue::new(dir.join("dlq.jsonl"));
+        for xid in 1..=3 {
+            queue.push(&letter(xid)).unwrap();
+        }
+
+        // The replay stops after delivering one letter and failing another
+        let pending = queue.pending().unwrap();
+        queue.delivered(&pending[0]).unwrap();
+        queue.failed(&pending[1]).unwrap();
+        drop(queue);
+
+        let mut queue = FileDeadLetterQueue::new(dir.join("dlq.jsonl"));
+        let xids: Vec<u32> = queue
+            .pending()
+            .unwrap()
+            .iter()
+            .map(|letter| match letter.event {
+                DeadEvent::Message(ReplicationMessage::Begin { xid, .. }) => xid,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(xids, vec![3, 2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,844 @@
+//! Incremental, resumable snapshots of individual tables
+//!
+//! Tables are re-read on demand while the WAL stream keeps flowing, following
+//! the watermark algorithm of Netflix's DBLog:
+//!
+//! 1. A low watermark is written to the signal table
+//! 2. The next chunk of the table is selected, ordered by primary key
+//! 3. A high watermark is written to the signal table
+//!
+//! While the stream is between the two watermarks, every change to a row of
+//! the chunk drops that row from the chunk: the change is newer than (or as
+//! new as) what was selected. When the high watermark arrives the remaining
+//! rows are delivered as `Read` messages, so a row read by the snapshot is
+//! never delivered after a later change to it.
+//!
+//! A snapshot is requested by inserting a row into the signal table:
+//!
+//! ```sql
+//! INSERT INTO walpipe_signal (id, type, data)
+//! VALUES ('resync-1', 'execute-snapshot', '{"tables": ["public.orders"]}');
+//! ```
+//!
+//! The tables still to read and the key of the last delivered row are saved
+//! after every chunk, so an interrupted snapshot resumes where it stopped.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::{
+    ColumnData, RelationCache, RelationInfo, ReplicationMessage, TupleData,
+};
+use crate::replication::snapshot::{query, table_relation};
+use crate::utils::binary::Oid;
+use crate::utils::connection::{PGConnection, QueryWorker, without_replication_param};
+
+/// Signal requesting a snapshot of the tables listed in `data`
+const EXECUTE_SNAPSHOT: &str = "execute-snapshot";
+/// Low watermark, written before a chunk is selected
+const WINDOW_OPEN: &str = "snapshot-window-open";
+/// High watermark, written after a chunk is selected
+const WINDOW_CLOSE: &str = "snapshot-window-close";
+
+/// Payload of an `execute-snapshot` signal
+#[derive(Debug, Deserialize)]
+struct SnapshotRequest {
+    tables: Vec<String>,
+}
+
+/// Snapshot progress saved between runs
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Progress {
+    /// Tables still to read, the first one being in progress
+    tables: VecDeque<String>,
+    /// Primary key of the last row delivered from the first table
+    last_key: Option<Vec<String>>,
+}
+
+/// Rows of a table selected between two watermarks
+#[derive(Debug)]
+pub struct Chunk {
+    pub relation: RelationInfo,
+    /// Rows still to deliver with their primary key
+    rows: Vec<(Vec<String>, TupleData)>,
+    key_columns: Vec<String>,
+    /// Primary key of the last row selected, `None` once the table is exhausted
+    last_key: Option<Vec<String>>,
+    /// Set for the first chunk of a table, whose schema has to be announced
+    pub announce: bool,
+}
+
+impl Chunk {
+    /// Rows left after deduplication against the stream
+    pub fn rows(&self) -> impl Iterator<Item = &TupleData> {
+        self.rows.iter().map(|(_, row)| row)
+    }
+
+    /// Drops the rows whose key appears in a change to the chunk's table
+    fn discard(&mut self, relation: &RelationInfo, tuple: &TupleData) {
+        if let Some(key) = tuple_key(relation, tuple, &self.key_columns) {
+            self.rows.retain(|(row_key, _)| *row_key != key);
+        }
+    }
+}
+
+/// Position of the current chunk in the watermark protocol
+enum Window {
+    /// No chunk in flight
+    Idle,
+    /// Watermarks written, waiting for the low one in the stream
+    Pending { id: String, chunk: Chunk },
+    /// Low watermark seen, changes to the chunk's rows are being discarded
+    Open { id: String, chunk: Chunk },
+    /// High watermark seen, the chunk is ready once its transaction is over
+    Closed(Chunk),
+}
+
+/// Drives incremental snapshots requested through the signal table
+///
+/// Chunks are read on a regular connection served by a [`QueryWorker`].
+pub struct IncrementalSnapshot {
+    worker: QueryWorker,
+    slot_name: String,
+    signal_schema: String,
+    signal_table: String,
+    chunk_size: usize,
+    state_file: PathBuf,
+    progress: Progress,
+    window: Window,
+    /// Tables whose schema was announced during this run
+    announced: HashSet<Oid>,
+}
+
+impl IncrementalSnapshot {
+    /// Loads the progress of an interrupted snapshot from `state_file`, if any
+    ///
+    /// `signal_table` is `schema.table`; chunks are read on a regular
+    /// connection to the database of `conninfo`.
+    pub fn new(
+        conninfo: &str,
+        slot_name: &str,
+        signal_table: &str,
+        chunk_size: usize,
+        state_file: PathBuf,
+    ) -> ReplicationResult<Self> {
+        let (signal_schema, signal_table) = split_table_name(signal_table);
+
+        let progress = match fs::read(&state_file) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                ReplicationError::config(format!(
+                    "Invalid incremental snapshot state in {}: {}",
+                    state_file.display(),
+                    e
+                ))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Progress::default(),
+            Err(e) => {
+                return Err(ReplicationError::config(format!(
+                    "Failed to read incremental snapshot state {}: {}",
+                    state_file.display(),
+                    e
+                )));
+            }
+        };
+        if let Some(table) = progress.tables.front() {
+            info!(
+                "Resuming incremental snapshot of {} ({} table(s) queued)",
+                table,
+                progress.tables.len()
+            );
+        }
+
+        Ok(Self {
+            worker: QueryWorker::new(without_replication_param(conninfo), "incremental-snapshot")?,
+            slot_name: slot_name.to_string(),
+            signal_schema,
+            signal_table,
+            chunk_size,
+            state_file,
+            progress,
+            window: Window::Idle,
+            announced: HashSet::new(),
+        })
+    }
+
+    /// Checks that the signal table is published along with its inserts,
+    /// otherwise no signal or watermark is ever seen
+    pub async fn check_publication(&self, publication: &str) -> ReplicationResult<()> {
+        let (name, schema, table) = (
+            publication.to_string(),
+            self.signal_schema.clone(),
+            self.signal_table.clone(),
+        );
+        let (published, inserts) = self
+            .worker
+            .run(move |connection| {
+                let result = query(
+                    connection,
+                    &format!(
+                        "SELECT EXISTS (
+                             SELECT 1 FROM pg_publication_tables t
+                             WHERE t.pubname = p.pubname AND t.schemaname = {} AND t.tablename = {}
+                         ), p.pubinsert
+                         FROM pg_publication p WHERE p.pubname = {};",
+                        connection.escape_literal(&schema)?,
+                        connection.escape_literal(&table)?,
+                        connection.escape_literal(&name)?
+                    ),
+                )?;
+                let flag = |field| result.getvalue(0, field).as_deref() == Some("t");
+                Ok((flag(0), flag(1)))
+            })
+            .await?;
+        if !published {
+            return Err(ReplicationError::config(format!(
+                "Signal table {}.{} is not part of publication '{}'",
+                self.signal_schema, self.signal_table, publication
+            )));
+        }
+        if !inserts {
+            return Err(ReplicationError::config(format!(
+                "Publication '{}' does not publish inserts, which carry the incremental snapshot signals",
+                publication
+            )));
+        }
+        Ok(())
+    }
+
+    /// Inspects a message from the stream before it is delivered
+    ///
+    /// Returns `true` for changes to the signal table, which are consumed
+    /// here and never delivered.
+    pub fn observe(
+        &mut self,
+        message: &ReplicationMessage,
+        relations: &RelationCache,
+    ) -> ReplicationResult<bool> {
+        let (relation_id, old, new) = match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            } => (*relation_id, None, Some(tuple_data)),
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => (*relation_id, old_tuple_data.as_ref(), Some(new_tuple_data)),
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => (*relation_id, Some(tuple_data), None),
+            _ => return Ok(false),
+        };
+        let Some(relation) = relations.get(relation_id) else {
+            return Ok(false);
+        };
+
+        if relation.namespace == self.signal_schema && relation.relation_name == self.signal_table {
+            if let Some(signal) = new {
+                self.signal(&relation, signal)?;
+            }
+            return Ok(true);
+        }
+
+        if let Window::Open { chunk, .. } = &mut self.window
+            && chunk.relation.oid == relation_id
+        {
+            for tuple in old.into_iter().chain(new) {
+                chunk.discard(&relation, tuple);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Makes progress outside of any transaction of the stream
+    ///
+    /// Starts the next chunk when none is in flight, and returns a chunk whose
+    /// high watermark was seen. Call [`IncrementalSnapshot::delivered`] once its
+    /// rows reached the sink.
+    pub async fn poll(&mut self) -> ReplicationResult<Option<Chunk>> {
+        match std::mem::replace(&mut self.window, Window::Idle) {
+            Window::Closed(chunk) => Ok(Some(chunk)),
+            Window::Idle => {
+                self.start_chunk().await?;
+                Ok(None)
+            }
+            window => {
+                self.window = window;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Abandons the chunk in flight, which is selected again by the next poll
+    ///
+    /// Used when the server connection is lost: its watermarks may never be
+    /// seen, and the chunk connection is reopened as well.
+    pub fn reset(&mut self) {
+        self.window = Window::Idle;
+        self.worker.disconnect();
+    }
+
+    /// Records that the rows of `chunk` were delivered
+    pub fn delivered(&mut self, chunk: Chunk) -> ReplicationResult<()> {
+        self.announced.insert(chunk.relation.oid);
+        match chunk.last_key {
+            Some(key) => self.progress.last_key = Some(key),
+            None => self.finish_table(),
+        }
+        self.save()
+    }
+
+    fn signal(&mut self, relation: &RelationInfo, tuple: &TupleData) -> ReplicationResult<()> {
+        let field = |name: &str| {
+            relation
+                .columns
+                .iter()
+                .position(|column| column.column_name == name)
+                .and_then(|index| tuple.columns.get(index))
+                .filter(|column| column.data_type == 't')
+                .map(|column| column.data.as_str())
+        };
+
+        match (field("type"), field("data")) {
+            (Some(EXECUTE_SNAPSHOT), data) => {
+                let request: SnapshotRequest = match serde_json::from_str(data.unwrap_or_default())
+                {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!(
+                            "Ignoring invalid {} signal {:?}: {}",
+                            EXECUTE_SNAPSHOT, data, e
+                        );
+                        return Ok(());
+                    }
+                };
+                for table in request.tables {
+                    let (schema, name) = split_table_name(&table);
+                    let table = format!("{}.{}", schema, name);
+                    if !self.progress.tables.contains(&table) {
+                        info!("Incremental snapshot of {} requested", table);
+                        self.progress.tables.push_back(table);
+                    }
+                }
+                self.save()?;
+            }
+            (Some(WINDOW_OPEN), Some(data)) => {
+                if let Window::Pending { id, chunk } =
+                    std::mem::replace(&mut self.window, Window::Idle)
+                {
+                    self.window = if id == data {
+                        Window::Open { id, chunk }
+                    } else {
+                        Window::Pending { id, chunk }
+                    };
+                }
+            }
+            (Some(WINDOW_CLOSE), Some(data)) => {
+                if let Window::Open { id, chunk } =
+                    std::mem::replace(&mut self.window, Window::Idle)
+                {
+                    self.window = if id == data {
+                        Window::Closed(chunk)
+                    } else {
+                        Window::Open { id, chunk }
+                    };
+                }
+            }
+            (kind, _) => debug!("Ignoring signal of type {:?}", kind),
+        }
+        Ok(())
+    }
+
+    /// Selects the next chunk between a low and a high watermark
+    async fn start_chunk(&mut self) -> ReplicationResult<()> {
+        let Some(table) = self.progress.tables.front().cloned() else {
+            return Ok(());
+        };
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string();
+        let chunk_query = ChunkQuery {
+            table: split_table_name(&table),
+            last_key: self.progress.last_key.clone(),
+            chunk_size: self.chunk_size,
+            signal_table: (self.signal_schema.clone(), self.signal_table.clone()),
+            watermark_id: format!("{}-{}", self.slot_name, id),
+            window: id.clone(),
+        };
+
+        let (relation, key_columns, rows) = match self
+            .worker
+            .run(move |connection| chunk_query.run(connection))
+            .await?
+        {
+            Selection::Skipped(reason) => {
+                warn!("Skipping incremental snapshot of {}: {}", table, reason);
+                self.finish_table();
+                return self.save();
+            }
+            Selection::Rows {
+                relation,
+                key_columns,
+                rows,
+            } => (relation, key_columns, rows),
+        };
+
+        debug!(
+            "Selected {} rows of {} in snapshot window {}",
+            rows.len(),
+            table,
+            id
+        );
+        let last_key = match rows.len() < self.chunk_size {
+            true => None,
+            false => rows.last().map(|(key, _)| key.clone()),
+        };
+        self.window = Window::Pending {
+            id,
+            chunk: Chunk {
+                announce: !self.announced.contains(&relation.oid),
+                relation,
+                rows,
+                key_columns,
+                last_key,
+            },
+        };
+        Ok(())
+    }
+
+    fn finish_table(&mut self) {
+        if let Some(table) = self.progress.tables.pop_front() {
+            info!("Incremental snapshot of {} complete", table);
+        }
+        self.progress.last_key = None;
+    }
+
+    fn save(&self) -> ReplicationResult<()> {
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.state_file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let temporary = self.state_file.with_extension("tmp");
+            fs::write(&temporary, serde_json::to_vec(&self.progress)?)?;
+            fs::rename(&temporary, &self.state_file)
+        };
+        write().map_err(|e| {
+            ReplicationError::protocol(format!(
+                "Failed to save incremental snapshot state {}: {}",
+                self.state_file.display(),
+                e
+            ))
+        })
+    }
+}
+
+/// Outcome of a [`ChunkQuery`]
+enum Selection {
+    /// The table cannot be read incrementally, for the given reason
+    Skipped(&'static str),
+    Rows {
+        relation: RelationInfo,
+        key_columns: Vec<String>,
+        rows: Vec<(Vec<String>, TupleData)>,
+    },
+}
+
+/// Queries selecting one chunk between its watermarks, run on the worker thread
+struct ChunkQuery {
+    /// Schema and name of the table being read
+    table: (String, String),
+    /// Primary key of the last row delivered, the chunk starts after it
+    last_key: Option<Vec<String>>,
+    chunk_size: usize,
+    /// Schema and name of the signal table
+    signal_table: (String, String),
+    /// Prefix of the watermark row ids, unique to the window
+    watermark_id: String,
+    /// Window id, the `data` of both watermarks
+    window: String,
+}
+
+impl ChunkQuery {
+    fn run(self, connection: &PGConnection) -> ReplicationResult<Selection> {
+        let (schema, name) = &self.table;
+        let Some(relation) = table_relation(connection, schema, name)? else {
+            return Ok(Selection::Skipped("table not found"));
+        };
+        let key_columns = primary_key(connection, relation.oid)?;
+        if key_columns.is_empty() {
+            return Ok(Selection::Skipped("table has no primary key"));
+        }
+
+        self.write_watermark(connection, WINDOW_OPEN)?;
+        let rows = self.select_chunk(connection, &relation, &key_columns)?;
+        self.write_watermark(connection, WINDOW_CLOSE)?;
+        Ok(Selection::Rows {
+            relation,
+            key_columns,
+            rows,
+        })
+    }
+
+    fn select_chunk(
+        &self,
+        connection: &PGConnection,
+        relation: &RelationInfo,
+        key_columns: &[String],
+    ) -> ReplicationResult<Vec<(Vec<String>, TupleData)>> {
+        let quote = |names: &mut dyn Iterator<Item = &String>| -> ReplicationResult<String> {
+            let mut quoted = Vec::new();
+            for name in names {
+                quoted.push(connection.escape_identifier(name)?);
+            }
+            Ok(quoted.join(", "))
+        };
+        let columns = quote(&mut relation.columns.iter().map(|column| &column.column_name))?;
+        let keys = quote(&mut key_columns.iter())?;
+
+        let mut sql = format!(
+            "SELECT {} FROM {}.{}",
+            columns,
+            connection.escape_identifier(&relation.namespace)?,
+            connection.escape_identifier(&relation.relation_name)?
+        );
+        if let Some(last_key) = &self.last_key {
+            let mut values = Vec::new();
+            for value in last_key {
+                values.push(connection.escape_literal(value)?);
+            }
+            sql.push_str(&format!(" WHERE ({}) > ({})", keys, values.join(", ")));
+        }
+        sql.push_str(&format!(" ORDER BY {} LIMIT {};", keys, self.chunk_size));
+
+        let result = query(connection, &sql)?;
+        let mut rows = Vec::new();
+        for row in 0..result.ntuples() {
+            let columns: Vec<ColumnData> = (0..result.nfields())
+                .map(|field| match result.is_null(row, field) {
+                    true => ColumnData {
+                        data_type: 'n',
+                        length: 0,
+                        data: String::new(),
+                        binary: None,
+                    },
+                    false => {
+                        let data = result.getvalue(row, field).unwrap_or_default();
+                        ColumnData {
+                            data_type: 't',
+                            length: data.len() as i32,
+                            data,
+                            binary: None,
+                        }
+                    }
+                })
+                .collect();
+            let tuple = TupleData {
+                column_count: columns.len() as i16,
+                columns,
+                processed_length: 0,
+            };
+            if let Some(key) = tuple_key(relation, &tuple, key_columns) {
+                rows.push((key, tuple));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Inserts a watermark and deletes it in the same transaction
+    ///
+    /// Only the insert has to be published; the signal table is left as it was,
+    /// and a new row is used for every watermark.
+    fn write_watermark(&self, connection: &PGConnection, kind: &str) -> ReplicationResult<()> {
+        let (schema, table) = &self.signal_table;
+        let table = format!(
+            "{}.{}",
+            connection.escape_identifier(schema)?,
+            connection.escape_identifier(table)?
+        );
+        let id = connection.escape_literal(&format!("{}-{}", self.watermark_id, kind))?;
+        query(
+            connection,
+            &format!(
+                "INSERT INTO {} (id, type, data) VALUES ({}, {}, {});
+                 DELETE FROM {} WHERE id = {};",
+                table,
+                id,
+                connection.escape_literal(kind)?,
+                connection.escape_literal(&self.window)?,
+                table,
+                id
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// Primary key columns of a table, in index order
+fn primary_key(connection: &PGConnection, oid: Oid) -> ReplicationResult<Vec<String>> {
+    let result = query(
+        connection,
+        &format!(
+            "SELECT a.attname
+             FROM pg_index i
+             JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+             WHERE i.indrelid = {} AND i.indisprimary
+             ORDER BY array_position(i.indkey::int2[], a.attnum);",
+            oid
+        ),
+    )?;
+    Ok((0..result.ntuples())
+        .filter_map(|row| result.getvalue(row, 0))
+        .collect())
+}
+
+/// Text values of `key_columns` in `tuple`, `None` if any of them is missing
+fn tuple_key(
+    relation: &RelationInfo,
+    tuple: &TupleData,
+    key_columns: &[String],
+) -> Option<Vec<String>> {
+    key_columns
+        .iter()
+        .map(|name| {
+            let index = relation
+                .columns
+                .iter()
+                .position(|column| &column.column_name == name)?;
+            tuple
+                .columns
+                .get(index)
+                .filter(|column| column.data_type == 't')
+                .map(|column| column.data.clone())
+        })
+        .collect()
+}
+
+/// Splits `schema.table`, defaulting to the `public` schema
+fn split_table_name(name: &str) -> (String, String) {
+    match name.trim().split_once('.') {
+        Some((schema, table)) => (schema.to_string(), table.to_string()),
+        None => ("public".to_string(), name.trim().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{delete, insert, keyed_relation, update, values};
+    use std::path::Path;
+
+    fn state_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "walpipe-incremental-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn snapshot(state_file: &Path) -> IncrementalSnapshot {
+        IncrementalSnapshot::new(
+            "dbname=app",
+            "sub",
+            "walpipe_signal",
+            3,
+            state_file.to_path_buf(),
+        )
+        .unwrap()
+    }
+
+    /// The signal table as relation 1, `orders` as 2 and `customers` as 3
+    fn relations() -> RelationCache {
+        let relations = RelationCache::new();
+        relations.insert(keyed_relation(
+            1,
+            "public",
+            "walpipe_signal",
+            &["id", "type", "data"],
+        ));
+        relations.insert(keyed_relation(2, "public", "orders", &["id", "status"]));
+        relations.insert(keyed_relation(3, "public", "customers", &["id", "name"]));
+        relations
+    }
+
+    /// Chunk of `orders` holding the rows with the given ids, ending at the last one
+    fn chunk(relations: &RelationCache, ids: &[&str]) -> Chunk {
+        Chunk {
+            rows: ids
+                .iter()
+                .map(|id| (vec![id.to_string()], values(&[id, "new"])))
+                .collect(),
+            relation: relations.get(2).unwrap(),
+            key_columns: vec!["id".to_string()],
+            last_key: ids.last().map(|id| vec![id.to_string()]),
+            announce: true,
+        }
+    }
+
+    fn watermark(kind: &str, id: &str) -> ReplicationMessage {
+        insert(1, values(&["sub-watermark", kind, id]))
+    }
+
+    fn chunk_ids(chunk: &Chunk) -> Vec<&str> {
+        chunk
+            .rows()
+            .map(|row| row.columns[0].data.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_watermark_window_deduplicates_chunk() {
+        let state_file = state_file("window");
+        let mut snapshot = snapshot(&state_file);
+        let relations = relations();
+
+        // A snapshot request is queued and consumed
+        let request = insert(
+            1,
+            values(&["r1", EXECUTE_SNAPSHOT, r#"{"tables": ["orders"]}"#]),
+        );
+        assert!(snapshot.observe(&request, &relations).unwrap());
+        assert_eq!(snapshot.progress.tables, ["public.orders"]);
+
+        snapshot.window = Window::Pending {
+            id: "7".to_string(),
+            chunk: chunk(&relations, &["1", "2", "3"]),
+        };
+
+        // Changes before the low watermark leave the chunk untouched
+        assert!(
+            !snapshot
+                .observe(&insert(2, values(&["1", "paid"])), &relations)
+                .unwrap()
+        );
+        snapshot
+            .observe(&watermark(WINDOW_OPEN, "7"), &relations)
+            .unwrap();
+        // Changes inside the window win over the selected rows
+        assert!(
+            !snapshot
+                .observe(&insert(2, values(&["2", "paid"])), &relations)
+                .unwrap()
+        );
+        snapshot
+            .observe(&watermark(WINDOW_CLOSE, "7"), &relations)
+            .unwrap();
+        // Changes after the high watermark are delivered after the chunk
+        snapshot
+            .observe(&insert(2, values(&["3", "paid"])), &relations)
+            .unwrap();
+
+        let chunk = snapshot.poll().await.unwrap().unwrap();
+        assert_eq!(chunk_ids(&chunk), ["1", "3"]);
+
+        snapshot.delivered(chunk).unwrap();
+        let saved: Progress = serde_json::from_slice(&fs::read(&state_file).unwrap()).unwrap();
+        assert_eq!(saved.last_key, Some(vec!["3".to_string()]));
+        fs::remove_file(&state_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_window_conflicts() {
+        let state_file = state_file("conflicts");
+        let mut snapshot = snapshot(&state_file);
+        let relations = relations();
+        snapshot.window = Window::Pending {
+            id: "7".to_string(),
+            chunk: chunk(&relations, &["1", "2", "3", "4", "5"]),
+        };
+
+        // Watermarks of another window, left over from before a reconnection
+        // or written by another instance, are consumed and ignored
+        assert!(
+            snapshot
+                .observe(&watermark(WINDOW_OPEN, "6"), &relations)
+                .unwrap()
+        );
+        snapshot
+            .observe(&insert(2, values(&["1", "paid"])), &relations)
+            .unwrap();
+        assert!(matches!(snapshot.window, Window::Pending { .. }));
+
+        snapshot
+            .observe(&watermark(WINDOW_OPEN, "7"), &relations)
+            .unwrap();
+        snapshot
+            .observe(&watermark(WINDOW_CLOSE, "6"), &relations)
+            .unwrap();
+        assert!(matches!(snapshot.window, Window::Open { .. }));
+        // The deletion of a watermark carries no signal
+        assert!(
+            snapshot
+                .observe(&delete(1, values(&["sub-watermark"])), &relations)
+                .unwrap()
+        );
+
+        // Any change naming a selected key drops the row: the old key of an
+        // update, its new key and the key of a delete
+        let changes = [
+            update(2, Some(values(&["2", "new"])), values(&["9", "new"])),
+            update(2, None, values(&["3", "paid"])),
+            delete(2, values(&["4"])),
+            // Same key in another table
+            insert(3, values(&["5", "Ada"])),
+        ];
+        for change in &changes {
+            assert!(!snapshot.observe(change, &relations).unwrap());
+        }
+        snapshot
+            .observe(&watermark(WINDOW_CLOSE, "7"), &relations)
+            .unwrap();
+
+        let chunk = snapshot.poll().await.unwrap().unwrap();
+        assert_eq!(chunk_ids(&chunk), ["1", "5"]);
+        assert!(matches!(snapshot.window, Window::Idle));
+    }
+
+    #[tokio::test]
+    async fn test_resumes_from_state_file() {
+        let state_file = state_file("resume");
+        let progress = Progress {
+            tables: VecDeque::from(["public.orders".to_string(), "public.customers".to_string()]),
+            last_key: Some(vec!["3".to_string()]),
+        };
+        fs::write(&state_file, serde_json::to_vec(&progress).unwrap()).unwrap();
+
+        let mut snapshot = snapshot(&state_file);
+        assert_eq!(snapshot.progress, progress);
+
+        // A table already queued is not read twice
+        let relations = relations();
+        let request = insert(
+            1,
+            values(&["r2", EXECUTE_SNAPSHOT, r#"{"tables": ["orders"]}"#]),
+        );
+        snapshot.observe(&request, &relations).unwrap();
+        assert_eq!(snapshot.progress.tables.len(), 2);
+
+        // The last chunk of a table moves on to the next one
+        let mut last = chunk(&relations, &["4"]);
+        last.last_key = None;
+        snapshot.delivered(last).unwrap();
+        let saved: Progress = serde_json::from_slice(&fs::read(&state_file).unwrap()).unwrap();
+        assert_eq!(saved.tables, ["public.customers"]);
+        assert_eq!(saved.last_key, None);
+
+        // A corrupt state is reported rather than restarting from scratch
+        fs::write(&state_file, "{").unwrap();
+        assert!(
+            IncrementalSnapshot::new("dbname=app", "sub", "walpipe_signal", 3, state_file.clone())
+                .is_err()
+        );
+        fs::remove_file(&state_file).unwrap();
+    }
+}
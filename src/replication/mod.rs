@@ -4,8 +4,18 @@
 //! the complete logical replication lifecycle, including database connection,
 //! replication slot management, WAL streaming, and event processing.
 
+pub mod bootstrap;
+pub mod checkpoint;
+pub mod dead_letter;
+pub mod incremental;
 pub mod server;
+pub mod side_table;
+pub mod snapshot;
 pub mod state;
+pub mod stream_buffer;
+pub mod supervisor;
+pub mod toast;
 
 // Re-export for convenience
 pub use server::ReplicationServer;
+pub use supervisor::Supervisor;
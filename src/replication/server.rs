@@ -6,20 +6,46 @@
 //! - WAL streaming and message processing
 //! - Event delivery to configured sinks
 
-use crate::core::config::ReplicationConfig;
-use crate::core::errors::ReplicationResult;
-use crate::events::{EventSink, EventSinkRegistry};
+use crate::core::config::{
+    CheckpointStoreMode, DeliveryMode, OriginFilter, ReplicationConfig, ScriptErrorPolicy,
+    SnapshotMode, StreamingMode, ToastCacheMode,
+};
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::core::metrics::METRICS;
+use crate::events::router::{EventRouter, Route};
+use crate::events::script::ScriptProcessor;
+use crate::events::transaction::PendingTransaction;
+use crate::events::transform::ColumnTransformer;
+use crate::events::{EventSinkRegistry, TransactionBatch};
 use crate::protocol::buffer::{BufferReader, BufferWriter};
 use crate::protocol::messages::*;
 use crate::protocol::parser::MessageParser;
-use crate::utils::connection::PGConnection;
+use crate::replication::bootstrap::Bootstrap;
+use crate::replication::checkpoint::{
+    CheckpointStore, CheckpointWriter, DeliveredTransactions, FileCheckpointStore,
+    PostgresCheckpointStore, Replay,
+};
+use crate::replication::dead_letter::{self, DeadEvent, DeadLetter, DeadLetterQueue};
+use crate::replication::incremental::IncrementalSnapshot;
+use crate::replication::snapshot::{ExportedSnapshot, SnapshotReader};
+use crate::replication::stream_buffer::StreamBuffer;
+use crate::replication::toast::{
+    DiskToastStore, MemoryToastStore, ToastRefetcher, ToastResolver, ToastStore,
+};
+use crate::utils::binary::Xid;
+use crate::utils::connection::{ExecStatusType, PGConnection};
+use crate::utils::lsn::{format_lsn, parse_lsn};
 use crate::utils::timestamp::system_time_to_postgres_timestamp;
-use libpq_sys::ExecStatusType;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, error, info, warn};
 
+/// How long the replication loop waits for data before checking timers and shutdown
+const IDLE_WAKEUP_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Main replication server that manages the logical replication connection
 ///
 /// This struct coordinates all aspects of the replication process, maintaining
@@ -29,8 +55,43 @@ pub struct ReplicationServer {
     connection: PGConnection,
     config: ReplicationConfig,
     state: ReplicationState,
-    event_sink: Option<Arc<dyn EventSink + Send + Sync>>,
+    router: Arc<EventRouter>,
     shutdown_signal: Arc<AtomicBool>,
+    /// Transaction being buffered when delivering in transaction mode
+    pending_transaction: Option<PendingTransaction>,
+    /// Streamed transactions held back until they commit
+    stream_buffer: StreamBuffer,
+    /// Transaction of the stream segment currently being received
+    stream_xid: Option<Xid>,
+    /// Begin held back until the transaction's origin is known
+    held_begin: Option<ReplicationMessage>,
+    /// Set while dropping a transaction from a filtered origin
+    skipping_origin_transaction: bool,
+    /// Prepared transactions dropped by the origin filter, awaiting their outcome
+    skipped_prepared_gids: HashSet<String>,
+    /// Fills in unchanged TOAST columns when enabled
+    toast_resolver: Option<ToastResolver>,
+    /// Incremental snapshots requested through the signal table, when configured
+    incremental_snapshot: Option<IncrementalSnapshot>,
+    /// Set once `START_REPLICATION` succeeded on the current connection
+    streaming: bool,
+    /// Records the last transaction acknowledged by the sink, when enabled
+    checkpoint: Option<CheckpointWriter>,
+    /// Transactions the checkpoint loaded at startup records as delivered
+    delivered: DeliveredTransactions,
+    /// Whether the current transaction delivered anything besides Begin
+    transaction_has_changes: bool,
+    /// Current WAL position reported by IDENTIFY_SYSTEM
+    server_wal_lsn: Option<u64>,
+    /// Keeps events the sink gave up on, when enabled
+    dead_letters: Option<Box<dyn DeadLetterQueue>>,
+    /// Drops, hashes, masks or replaces columns before delivery, when configured
+    transformer: Option<ColumnTransformer>,
+    /// Rewrites row changes after the column transforms, when configured
+    script: Option<ScriptProcessor>,
+    /// Relations as the sinks receive them; the server's relation cache
+    /// itself when neither column transforms nor a script are configured
+    sink_relations: RelationCache,
 }
 
 impl ReplicationServer {
@@ -46,32 +107,181 @@ impl ReplicationServer {
         let connection = PGConnection::connect(&config.connection_string)?;
         info!("Successfully connected to database server");
 
-        // Configure event sink based on configuration
-        let sink_type = config.event_sink_type();
-        info!("Initializing {} event sink", sink_type);
-
-        let event_sink = match EventSinkRegistry::create_sink(sink_type, &config) {
-            Ok(sink) => {
-                info!("Successfully initialized {} event sink", sink_type);
-                Some(sink)
+        // Configure event sinks based on configuration
+        let state = ReplicationState::new();
+        let transformer = (!config.column_transforms.is_empty()).then(|| {
+            ColumnTransformer::new(
+                config.column_transforms.clone(),
+                config.transform_hash_salt.clone().unwrap_or_default(),
+            )
+        });
+        let sink_relations = if transformer.is_some() || config.script_file.is_some() {
+            RelationCache::new()
+        } else {
+            state.relations.clone()
+        };
+        let script = match &config.script_file {
+            Some(path) => Some(ScriptProcessor::load(path, sink_relations.clone())?),
+            None => None,
+        };
+        let router = match EventSinkRegistry::create_router(
+            &config,
+            state.relations.clone(),
+            sink_relations.clone(),
+            shutdown_signal.clone(),
+        ) {
+            Ok(router) => {
+                info!(
+                    "Successfully initialized {} event sinks",
+                    config.sinks.len()
+                );
+                Arc::new(router)
             }
             Err(e) => {
-                error!("Failed to initialize {} event sink: {}", sink_type, e);
-                return Err(crate::core::errors::ReplicationError::protocol(
-                    e.to_string(),
-                ));
+                error!("Failed to initialize event sinks: {}", e);
+                return Err(ReplicationError::protocol(e.to_string()));
+            }
+        };
+
+        let stream_buffer = StreamBuffer::new(
+            config.stream_spill_dir.clone(),
+            &config.slot_name,
+            config.stream_spill_threshold_bytes,
+        );
+        stream_buffer.remove_stale_spill_files()?;
+
+        let toast_store: Option<Box<dyn ToastStore>> = match config.toast_cache {
+            ToastCacheMode::Off => None,
+            ToastCacheMode::Memory => {
+                Some(Box::new(MemoryToastStore::new(config.toast_cache_capacity)))
+            }
+            ToastCacheMode::Disk => Some(Box::new(DiskToastStore::new(
+                config.toast_cache_dir.clone(),
+                config.toast_cache_capacity,
+            )?)),
+        };
+        let toast_refetcher = if config.toast_refetch {
+            Some(ToastRefetcher::new(&config.connection_string)?)
+        } else {
+            None
+        };
+        let toast_resolver = (toast_store.is_some() || toast_refetcher.is_some())
+            .then(|| ToastResolver::new(toast_store, toast_refetcher));
+
+        let incremental_snapshot = match &config.signal_table {
+            Some(signal_table) => Some(IncrementalSnapshot::new(
+                &config.connection_string,
+                &config.slot_name,
+                signal_table,
+                config.incremental_snapshot_chunk_size,
+                config.incremental_snapshot_state_file.clone(),
+            )?),
+            None => None,
+        };
+
+        let slot_name = config.slot_name.clone();
+        let checkpoint = match config.checkpoint_store {
+            CheckpointStoreMode::Off => None,
+            CheckpointStoreMode::File => {
+                let path = config.checkpoint_file.clone();
+                Some(CheckpointWriter::new(move || {
+                    Box::new(FileCheckpointStore::new(path, &slot_name)) as Box<dyn CheckpointStore>
+                })?)
+            }
+            CheckpointStoreMode::Postgres => {
+                let conninfo = config
+                    .checkpoint_connection_string
+                    .clone()
+                    .unwrap_or_else(|| config.connection_string.clone());
+                let table = config.checkpoint_table.clone();
+                Some(CheckpointWriter::new(move || {
+                    Box::new(PostgresCheckpointStore::new(&conninfo, &table, &slot_name))
+                        as Box<dyn CheckpointStore>
+                })?)
             }
         };
+        let dead_letters = dead_letter::open_queue(&config);
 
         Ok(Self {
             connection,
             config,
-            state: ReplicationState::new(),
-            event_sink,
+            state,
+            router,
             shutdown_signal,
+            pending_transaction: None,
+            stream_buffer,
+            stream_xid: None,
+            held_begin: None,
+            skipping_origin_transaction: false,
+            skipped_prepared_gids: HashSet::new(),
+            toast_resolver,
+            incremental_snapshot,
+            streaming: false,
+            checkpoint,
+            delivered: DeliveredTransactions::default(),
+            transaction_has_changes: false,
+            server_wal_lsn: None,
+            dead_letters,
+            transformer,
+            script,
+            sink_relations,
         })
     }
 
+    /// Whether the stream was started on the current connection
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Last LSN acknowledged to the server
+    pub fn flushed_lsn(&self) -> u64 {
+        self.state.flushed_lsn
+    }
+
+    /// Replaces a lost connection and drops everything tied to the old session
+    ///
+    /// Unacknowledged transactions are streamed again from the last flushed
+    /// LSN, and the server sends every relation again before its first use,
+    /// so partially received transactions and cached relations are discarded.
+    pub fn reconnect(&mut self) -> ReplicationResult<()> {
+        info!(
+            "Reconnecting to database: {}",
+            self.config.connection_string
+        );
+        self.streaming = false;
+        self.connection = PGConnection::connect(&self.config.connection_string)?;
+
+        let flushed_lsn = self.state.flushed_lsn;
+        self.state.reset();
+        self.sink_relations.clear();
+        if let Some(script) = self.script.as_mut() {
+            script.reset();
+        }
+        self.state.confirm_lsn(flushed_lsn);
+        self.state.update_lsn(flushed_lsn);
+
+        self.pending_transaction = None;
+        self.stream_buffer.clear();
+        self.stream_xid = None;
+        self.held_begin = None;
+        self.skipping_origin_transaction = false;
+        self.delivered.reset();
+        self.transaction_has_changes = false;
+        if let Some(resolver) = self.toast_resolver.as_mut() {
+            resolver.disconnect();
+        }
+        if let Some(incremental_snapshot) = self.incremental_snapshot.as_mut() {
+            incremental_snapshot.reset();
+        }
+
+        self.identify_system()
+    }
+
+    /// Restarts the stream after [`ReplicationServer::reconnect`], from the last flushed LSN
+    pub async fn resume_replication(&mut self) -> ReplicationResult<()> {
+        self.start_replication(self.state.flushed_lsn).await
+    }
+
     /// Verifies that PostgreSQL is configured for logical replication
     ///
     /// Checks that the wal_level setting is 'logical', which is required
@@ -113,7 +323,7 @@ impl ReplicationServer {
     ///
     /// Executes IDENTIFY_SYSTEM to verify the connection supports replication
     /// and retrieves system information including timeline and WAL position.
-    pub fn identify_system(&self) -> ReplicationResult<()> {
+    pub fn identify_system(&mut self) -> ReplicationResult<()> {
         debug!("Identifying system");
         match self.connection.exec("IDENTIFY_SYSTEM") {
             Ok(result) => {
@@ -132,6 +342,7 @@ impl ReplicationServer {
                     result.getvalue(0, 2),
                     result.getvalue(0, 3)
                 );
+                self.server_wal_lsn = result.getvalue(0, 2).as_deref().and_then(parse_lsn);
             }
             Err(err) => {
                 return Err(crate::core::errors::ReplicationError::protocol(format!(
@@ -149,25 +360,167 @@ impl ReplicationServer {
     ///
     /// Performs all necessary validation and setup before starting replication:
     /// 1. Verifies wal_level is 'logical'
-    /// 2. Checks replication slot exists
-    /// 3. Verifies publication exists
+    /// 2. Creates or aligns the publication when bootstrapping, then verifies it exists
+    /// 3. Creates the slot with an exported snapshot and reads the published tables
+    ///    when an initial snapshot is requested and the slot is missing; otherwise
+    ///    creates the slot when bootstrapping and checks it exists
     /// 4. Starts the replication stream
     pub async fn create_replication_slot_and_start(&mut self) -> ReplicationResult<()> {
         self.check_wal_level()?;
-        self.check_replication_slot()?;
+        self.restore_checkpoint().await?;
+        if let Some(dead_letters) = self.dead_letters.as_mut() {
+            dead_letters.check_publication(&self.config.publication_name)?;
+        }
+
+        let bootstrap = Bootstrap::new(&self.connection, &self.config);
+        if self.config.bootstrap {
+            bootstrap.provision_publication()?;
+        }
         self.check_publication()?;
+        if let Some(incremental_snapshot) = self.incremental_snapshot.as_ref() {
+            incremental_snapshot
+                .check_publication(&self.config.publication_name)
+                .await?;
+        }
+
+        // A snapshot can only be exported while the slot is being created
+        let snapshot =
+            if self.config.snapshot_mode == SnapshotMode::Initial && !bootstrap.slot_exists()? {
+                Some(bootstrap.create_slot_with_snapshot()?)
+            } else {
+                if self.config.snapshot_mode == SnapshotMode::Initial {
+                    info!(
+                        "Replication slot '{}' already exists, skipping the initial snapshot",
+                        self.config.slot_name
+                    );
+                }
+                if self.config.bootstrap {
+                    bootstrap.ensure_slot()?;
+                }
+                self.check_replication_slot()?;
+                None
+            };
+
+        let start_lsn = match snapshot {
+            Some(snapshot) => {
+                if let Err(error) = self.run_initial_snapshot(&snapshot).await {
+                    // Without the slot, the next start takes the snapshot again
+                    // instead of streaming from a consistent point whose rows
+                    // were never all delivered
+                    if let Err(e) = Bootstrap::new(&self.connection, &self.config).drop_slot() {
+                        error!(
+                            "Failed to drop replication slot '{}' after the initial snapshot failed, drop it to take the snapshot again: {}",
+                            self.config.slot_name, e
+                        );
+                    }
+                    return Err(error);
+                }
+                snapshot.consistent_point
+            }
+            None => 0,
+        };
+        self.start_replication(start_lsn).await?;
+
+        Ok(())
+    }
+
+    /// Loads the checkpoint so that transactions already delivered before a
+    /// crash are skipped instead of being sent to the sink again
+    ///
+    /// A checkpoint ahead of the server's WAL position cannot come from this
+    /// server (restored backup, another cluster) and is ignored.
+    async fn restore_checkpoint(&mut self) -> ReplicationResult<()> {
+        let Some(checkpoint) = self.checkpoint.as_ref() else {
+            return Ok(());
+        };
+        checkpoint
+            .check_publication(&self.config.publication_name)
+            .await?;
+
+        match checkpoint.load().await? {
+            Some(lsn) if self.server_wal_lsn.is_some_and(|wal_lsn| lsn > wal_lsn) => {
+                warn!(
+                    "Ignoring checkpoint {} of slot '{}', ahead of the server WAL position {}",
+                    format_lsn(lsn),
+                    self.config.slot_name,
+                    format_lsn(self.server_wal_lsn.unwrap_or_default())
+                );
+            }
+            Some(lsn) => {
+                info!(
+                    "Restored checkpoint {} of slot '{}' from the {} store, transactions up to it are skipped",
+                    format_lsn(lsn),
+                    self.config.slot_name,
+                    self.config.checkpoint_store
+                );
+                METRICS.checkpoint_lsn.store(lsn, Ordering::Relaxed);
+                self.delivered = DeliveredTransactions::new(lsn);
+            }
+            None => info!(
+                "No checkpoint recorded for slot '{}' in the {} store",
+                self.config.slot_name, self.config.checkpoint_store
+            ),
+        }
+        Ok(())
+    }
 
-        self.start_replication().await?;
+    /// Delivers every row of the published tables as `Read` messages
+    ///
+    /// Each table is preceded by a synthetic Relation message so that sinks
+    /// know its schema before the first row.
+    async fn run_initial_snapshot(&mut self, snapshot: &ExportedSnapshot) -> ReplicationResult<()> {
+        info!(
+            "Starting initial snapshot at {}",
+            format_lsn(snapshot.consistent_point)
+        );
+        let reader = SnapshotReader::open(&self.config.connection_string, snapshot)?;
 
+        for relation in reader.published_tables(&self.config.publication_name)? {
+            let relation_id = relation.oid;
+            let table = format!("{}.{}", relation.namespace, relation.relation_name);
+            self.dispatch_message(ReplicationMessage::Relation {
+                relation: relation.clone(),
+            })
+            .await?;
+            reader.start_copy(&relation)?;
+
+            let mut rows = 0u64;
+            while let Some(tuple_data) = reader.next_row()? {
+                if self.shutdown_signal.load(Ordering::SeqCst) {
+                    return Err(crate::core::errors::ReplicationError::protocol(format!(
+                        "Initial snapshot of {} interrupted",
+                        table
+                    )));
+                }
+                self.dispatch_message(ReplicationMessage::Read {
+                    relation_id,
+                    tuple_data,
+                    snapshot_lsn: snapshot.consistent_point,
+                })
+                .await?;
+                rows += 1;
+            }
+            info!("Snapshot of {} complete: {} rows", table, rows);
+        }
+
+        reader.finish()?;
+        info!("Initial snapshot complete");
         Ok(())
     }
 
     fn check_replication_slot(&self) -> ReplicationResult<()> {
         // Check if the replication slot already exists
-        let check_slot_sql = format!(
-            "SELECT slot_name FROM pg_replication_slots WHERE slot_name = '{}';",
-            self.config.slot_name
-        );
+        let check_slot_sql = if self.config.two_phase {
+            format!(
+                "SELECT slot_name, two_phase FROM pg_replication_slots WHERE slot_name = '{}';",
+                self.config.slot_name
+            )
+        } else {
+            format!(
+                "SELECT slot_name FROM pg_replication_slots WHERE slot_name = '{}';",
+                self.config.slot_name
+            )
+        };
 
         let result = self.connection.exec(&check_slot_sql)?;
         if !result.is_ok() {
@@ -178,8 +531,21 @@ impl ReplicationServer {
         }
 
         if result.ntuples() == 0 {
+            let create_options = if self.config.two_phase {
+                " (TWO_PHASE true)"
+            } else {
+                " NOEXPORT_SNAPSHOT"
+            };
+            return Err(crate::core::errors::ReplicationError::protocol(format!(
+                "Replication slot '{}' does not exist. Please create it manually using the following SQL command, or set BOOTSTRAP=true:\n\nCREATE_REPLICATION_SLOT \"{}\" LOGICAL pgoutput{};\n",
+                self.config.slot_name, self.config.slot_name, create_options
+            )));
+        }
+
+        // two_phase can only be enabled when the slot is created
+        if self.config.two_phase && result.getvalue(0, 1).as_deref() != Some("t") {
             return Err(crate::core::errors::ReplicationError::protocol(format!(
-                "Replication slot '{}' does not exist. Please create it manually using the following SQL command:\n\nCREATE_REPLICATION_SLOT \"{}\" LOGICAL pgoutput NOEXPORT_SNAPSHOT;\n",
+                "Replication slot '{}' was not created with two-phase decoding. Drop it and recreate it with:\n\nCREATE_REPLICATION_SLOT \"{}\" LOGICAL pgoutput (TWO_PHASE true);\n",
                 self.config.slot_name, self.config.slot_name
             )));
         }
@@ -207,7 +573,7 @@ impl ReplicationServer {
 
         if result.ntuples() == 0 {
             return Err(crate::core::errors::ReplicationError::protocol(format!(
-                "Publication '{}' does not exist. Please create it manually using the following SQL command:\n\nCREATE PUBLICATION \"{}\" FOR TABLE {};\n\nor\n\nCREATE PUBLICATION \"{}\" FOR ALL TABLES;\n\ndepending on your configuration.",
+                "Publication '{}' does not exist. Please create it manually using the following SQL command, or set BOOTSTRAP=true:\n\nCREATE PUBLICATION \"{}\" FOR TABLE {};\n\nor\n\nCREATE PUBLICATION \"{}\" FOR ALL TABLES;\n\ndepending on your configuration.",
                 self.config.publication_name,
                 self.config.publication_name,
                 "<your_table_name>",
@@ -218,10 +584,37 @@ impl ReplicationServer {
         Ok(())
     }
 
-    async fn start_replication(&mut self) -> ReplicationResult<()> {
+    /// Builds the pgoutput plugin options passed to START_REPLICATION
+    fn replication_options(&self) -> String {
+        let mut options = vec![format!("proto_version '{}'", self.config.proto_version)];
+
+        if self.config.streaming != StreamingMode::Off {
+            options.push(format!("streaming '{}'", self.config.streaming));
+        }
+        if self.config.two_phase {
+            options.push("two_phase 'on'".to_string());
+        }
+        if self.config.logical_messages {
+            options.push("messages 'true'".to_string());
+        }
+        if self.config.binary_tuples {
+            options.push("binary 'true'".to_string());
+        }
+        options.push(format!(
+            "publication_names '{}'",
+            self.config.publication_name
+        ));
+
+        options.join(", ")
+    }
+
+    /// Starts streaming from `start_lsn`, or from the slot's confirmed position when 0
+    async fn start_replication(&mut self, start_lsn: u64) -> ReplicationResult<()> {
         let start_replication_sql = format!(
-            "START_REPLICATION SLOT \"{}\" LOGICAL 0/0 (proto_version '2', streaming 'on', publication_names '{}');",
-            self.config.slot_name, self.config.publication_name
+            "START_REPLICATION SLOT \"{}\" LOGICAL {} ({});",
+            self.config.slot_name,
+            format_lsn(start_lsn),
+            self.replication_options()
         );
 
         info!(
@@ -238,6 +631,8 @@ impl ReplicationServer {
         }
 
         info!("Started receiving data from database server");
+        self.connection.enable_async()?;
+        self.streaming = true;
         self.replication_loop().await?;
 
         Ok(())
@@ -252,13 +647,21 @@ impl ReplicationServer {
                 break;
             }
 
-            self.check_and_send_feedback()?;
+            self.check_and_send_feedback().await?;
+            self.advance_incremental_snapshot().await?;
+            self.connection.flush_async().await?;
 
-            match self.connection.get_copy_data()? {
+            // Wake up regularly to send feedback and notice shutdown while the stream is idle
+            let data = tokio::select! {
+                data = self.connection.get_copy_data_async() => data?,
+                _ = tokio::time::sleep(IDLE_WAKEUP_INTERVAL) => continue,
+            };
+
+            match data {
                 None => {
-                    info!("No data received, continuing");
-                    tokio::time::sleep(Duration::from_millis(10)).await;
-                    continue;
+                    return Err(ReplicationError::connection(
+                        "Replication stream ended by the server",
+                    ));
                 }
                 Some(data) => {
                     if data.is_empty() {
@@ -311,6 +714,14 @@ impl ReplicationServer {
 
         let k: KeepaliveMessage = reader.try_into()?;
 
+        // Outside a transaction every change before the server's sent position
+        // has already been delivered, so it is safe to confirm it. This keeps
+        // the slot advancing while only unpublished tables are being written.
+        if !self.state.in_transaction {
+            self.state.update_lsn(k.log_pos);
+            self.state.confirm_lsn(k.log_pos);
+        }
+
         if k.reply_requested {
             debug!("Server requested feedback in keepalive");
             self.send_feedback()?;
@@ -334,8 +745,30 @@ impl ReplicationServer {
             self.state.update_lsn(w.data_start);
         }
 
+        // Changes of a streamed transaction are held back until it commits
+        if let Some(xid) = self.stream_xid
+            && w.data[0] != b'E'
+        {
+            if w.data.len() < 5 {
+                return Err(crate::core::errors::ReplicationError::protocol(
+                    "Streamed message too short",
+                ));
+            }
+            // Streamed messages carry the xid of the (sub)transaction that made
+            // the change, except Origin which belongs to the whole transaction
+            let subxid = if w.data[0] == b'O' {
+                xid
+            } else {
+                Xid::from_be_bytes([w.data[1], w.data[2], w.data[3], w.data[4]])
+            };
+            self.stream_buffer
+                .append(xid, subxid, w.data_start, &w.data)?;
+            self.send_feedback()?;
+            return Ok(());
+        }
+
         // Parse the actual logical replication message
-        match MessageParser::parse_wal_message(&w.data) {
+        match MessageParser::parse_wal_message(&w.data, w.data_start) {
             Ok(message) => {
                 self.process_replication_message(message).await?;
             }
@@ -353,38 +786,688 @@ impl ReplicationServer {
         &mut self,
         message: ReplicationMessage,
     ) -> ReplicationResult<()> {
-        // Handle relation messages by storing schema information
-        if let ReplicationMessage::Relation { relation } = &message {
-            self.state.add_relation(relation.clone());
+        match self.delivered.check(&message, self.stream_xid.is_some()) {
+            Replay::Process => {}
+            Replay::Skip => return Ok(()),
+            Replay::SkipBegin => {
+                self.state.begin_transaction();
+                return Ok(());
+            }
+            Replay::SkipEnd(end_lsn) => {
+                self.state.commit_transaction(end_lsn);
+                return Ok(());
+            }
         }
 
-        // Send event to configured sink if available
-        if let Some(ref event_sink) = self.event_sink {
-            debug!("Sending event to event sink: {:?}", message);
+        match &message {
+            ReplicationMessage::StreamStart { xid, .. } => {
+                self.stream_xid = Some(*xid);
+                return Ok(());
+            }
+            ReplicationMessage::StreamStop => {
+                self.stream_xid = None;
+                return Ok(());
+            }
+            ReplicationMessage::StreamAbort {
+                xid,
+                subtransaction_xid,
+                ..
+            } => {
+                self.stream_buffer.abort(*xid, *subtransaction_xid);
+                return Ok(());
+            }
+            ReplicationMessage::StreamCommit { .. } | ReplicationMessage::StreamPrepare { .. } => {
+                // Boxed because the replay feeds messages back through this method
+                return Box::pin(self.replay_streamed_transaction(message)).await;
+            }
+            // Non-transactional messages are not part of any transaction and
+            // are never rolled back, so they are delivered right away
+            ReplicationMessage::LogicalMessage {
+                transactional: false,
+                ..
+            } => {
+                return self.deliver_message(message).await;
+            }
+            _ => {}
+        }
+
+        if self.config.origin_filter != OriginFilter::Any {
+            for message in self.filter_origin(message) {
+                self.dispatch_message(message).await?;
+            }
+            return Ok(());
+        }
 
-            match event_sink.send_event(&message).await {
-                Ok(()) => {
+        self.dispatch_message(message).await
+    }
+
+    /// Drops transactions that originate from filtered replication origins
+    ///
+    /// The Origin message follows Begin, so Begin is held back until the next
+    /// message tells whether the transaction is local or replayed from a
+    /// filtered origin. Returns the messages that should be processed further.
+    fn filter_origin(&mut self, message: ReplicationMessage) -> Vec<ReplicationMessage> {
+        if self.skipping_origin_transaction {
+            return match message {
+                // Schema changes still have to reach the sinks so that later
+                // transactions can be decoded
+                ReplicationMessage::Relation { .. } | ReplicationMessage::Type { .. } => {
+                    vec![message]
+                }
+                ReplicationMessage::Commit { end_lsn, .. } => {
+                    self.skipping_origin_transaction = false;
+                    self.state.commit_transaction(end_lsn);
+                    Vec::new()
+                }
+                ReplicationMessage::Prepare { end_lsn, gid, .. } => {
+                    self.skipping_origin_transaction = false;
+                    self.skipped_prepared_gids.insert(gid);
+                    self.state.commit_transaction(end_lsn);
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            };
+        }
+
+        match message {
+            ReplicationMessage::Begin { .. } | ReplicationMessage::BeginPrepare { .. } => {
+                self.state.begin_transaction();
+                self.held_begin = Some(message);
+                Vec::new()
+            }
+            ReplicationMessage::Origin { ref name, .. } if self.held_begin.is_some() => {
+                if self.config.origin_filter.drops(name) {
+                    debug!("Skipping transaction replayed from origin '{}'", name);
+                    self.held_begin = None;
+                    self.skipping_origin_transaction = true;
+                    Vec::new()
+                } else {
+                    self.held_begin
+                        .take()
+                        .into_iter()
+                        .chain([message])
+                        .collect()
+                }
+            }
+            // The outcome of a skipped prepared transaction is skipped as well
+            ReplicationMessage::CommitPrepared {
+                end_lsn, ref gid, ..
+            }
+            | ReplicationMessage::RollbackPrepared {
+                rollback_end_lsn: end_lsn,
+                ref gid,
+                ..
+            } if self.skipped_prepared_gids.contains(gid) => {
+                self.skipped_prepared_gids.remove(gid);
+                self.state.commit_transaction(end_lsn);
+                Vec::new()
+            }
+            message => self
+                .held_begin
+                .take()
+                .into_iter()
+                .chain([message])
+                .collect(),
+        }
+    }
+
+    /// Starts the next incremental snapshot chunk or delivers a completed one
+    ///
+    /// Only runs between transactions, so that snapshot rows never end up
+    /// inside a transaction of the stream.
+    async fn advance_incremental_snapshot(&mut self) -> ReplicationResult<()> {
+        if self.state.in_transaction {
+            return Ok(());
+        }
+        let chunk = match self.incremental_snapshot.as_mut() {
+            Some(snapshot) => snapshot.poll().await?,
+            None => None,
+        };
+        let Some(chunk) = chunk else {
+            return Ok(());
+        };
+
+        if chunk.announce {
+            self.dispatch_message(ReplicationMessage::Relation {
+                relation: chunk.relation.clone(),
+            })
+            .await?;
+        }
+        let snapshot_lsn = self.state.received_lsn;
+        for tuple_data in chunk.rows() {
+            self.dispatch_message(ReplicationMessage::Read {
+                relation_id: chunk.relation.oid,
+                tuple_data: tuple_data.clone(),
+                snapshot_lsn,
+            })
+            .await?;
+        }
+
+        if let Some(snapshot) = self.incremental_snapshot.as_mut() {
+            snapshot.delivered(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Updates replication state for a message and hands it to the delivery path
+    async fn dispatch_message(&mut self, mut message: ReplicationMessage) -> ReplicationResult<()> {
+        // Signal table changes drive incremental snapshots and are not delivered
+        if let Some(snapshot) = self.incremental_snapshot.as_mut()
+            && snapshot.observe(&message, &self.state.relations)?
+        {
+            return Ok(());
+        }
+
+        if let Some(resolver) = self.toast_resolver.as_mut() {
+            resolver
+                .resolve(&mut message, &self.state.relations)
+                .await?;
+        }
+
+        match &message {
+            // Handle relation messages by storing schema information
+            ReplicationMessage::Relation { relation } => {
+                self.state.add_relation(relation.clone());
+                match &self.transformer {
+                    Some(transformer) => self
+                        .sink_relations
+                        .insert(transformer.transform_relation(relation)),
+                    None if self.script.is_some() => self.sink_relations.insert(relation.clone()),
+                    None => {}
+                }
+            }
+            ReplicationMessage::Begin { .. } | ReplicationMessage::BeginPrepare { .. } => {
+                self.state.begin_transaction();
+            }
+            _ => {}
+        }
+
+        if self.config.delivery_mode == DeliveryMode::Transaction {
+            match message {
+                ReplicationMessage::Begin { .. } | ReplicationMessage::BeginPrepare { .. } => {
+                    self.pending_transaction = Some(PendingTransaction::new(message));
+                    return Ok(());
+                }
+                ReplicationMessage::Commit { end_lsn, .. }
+                | ReplicationMessage::Prepare { end_lsn, .. } => {
+                    if let Some(batch) = self
+                        .pending_transaction
+                        .take()
+                        .and_then(|pending| pending.finish(message))
+                    {
+                        self.deliver_transaction(&batch).await?;
+                        debug!("Transaction delivered up to end LSN: {:x}", end_lsn);
+                        self.acknowledge(end_lsn, !batch.changes.is_empty());
+                    } else {
+                        warn!("Received commit without a buffered transaction, ignoring");
+                    }
+                    return Ok(());
+                }
+                message => {
+                    if let Some(pending) = self.pending_transaction.as_mut() {
+                        pending.push(message);
+                        return Ok(());
+                    }
+
+                    // Messages outside a transaction are still delivered one at a time
+                    return self.deliver_message(message).await;
+                }
+            }
+        }
+
+        self.deliver_message(message).await
+    }
+
+    /// Replays a committed or prepared streamed transaction as a regular
+    /// Begin/changes/Commit (or BeginPrepare/changes/Prepare) sequence
+    ///
+    /// Sinks never see stream control messages, and acknowledgement and
+    /// batching work exactly as for transactions that were not streamed.
+    async fn replay_streamed_transaction(
+        &mut self,
+        stream_end: ReplicationMessage,
+    ) -> ReplicationResult<()> {
+        let (xid, begin, end) = match stream_end {
+            ReplicationMessage::StreamCommit {
+                xid,
+                flags,
+                commit_lsn,
+                end_lsn,
+                timestamp,
+            } => (
+                xid,
+                ReplicationMessage::Begin {
+                    final_lsn: commit_lsn,
+                    timestamp,
+                    xid,
+                },
+                ReplicationMessage::Commit {
+                    flags,
+                    commit_lsn,
+                    end_lsn,
+                    timestamp,
+                },
+            ),
+            ReplicationMessage::StreamPrepare {
+                flags,
+                prepare_lsn,
+                end_lsn,
+                timestamp,
+                xid,
+                gid,
+            } => (
+                xid,
+                ReplicationMessage::BeginPrepare {
+                    prepare_lsn,
+                    end_lsn,
+                    timestamp,
+                    xid,
+                    gid: gid.clone(),
+                },
+                ReplicationMessage::Prepare {
+                    flags,
+                    prepare_lsn,
+                    end_lsn,
+                    timestamp,
+                    xid,
+                    gid,
+                },
+            ),
+            _ => return Ok(()),
+        };
+
+        debug!("Replaying streamed transaction {}", xid);
+        let changes = self.stream_buffer.take(xid)?;
+
+        self.process_replication_message(begin).await?;
+
+        for change in changes {
+            self.process_replication_message(change?).await?;
+        }
+
+        self.process_replication_message(end).await
+    }
+
+    /// Sends a single message to the sink and acknowledges commits once delivered
+    async fn deliver_message(&mut self, message: ReplicationMessage) -> ReplicationResult<()> {
+        let delivers_changes = !matches!(
+            message,
+            ReplicationMessage::Begin { .. }
+                | ReplicationMessage::BeginPrepare { .. }
+                | ReplicationMessage::Commit { .. }
+                | ReplicationMessage::Prepare { .. }
+        );
+
+        // Send event to every sink it is routed to
+        let router = self.router.clone();
+        let routes = router.routes_for(&message);
+        let delivered = self.transform(&message)?;
+        // The script runs once, whichever sinks receive its output
+        let scripted = match self.script.as_mut() {
+            Some(script) => {
+                script.observe(&delivered);
+                match routes.is_empty() {
+                    true => None,
+                    false => Some(script.process(&delivered).await),
+                }
+            }
+            None => None,
+        };
+        for route in routes {
+            let announced;
+            let events = match &scripted {
+                None => std::slice::from_ref(delivered.as_ref()),
+                Some(scripted) => match self.script_events(&delivered, scripted, route)? {
+                    Some(events) => {
+                        announced = events;
+                        announced.as_slice()
+                    }
+                    None => continue,
+                },
+            };
+
+            for event in events {
+                debug!("Sending event to {} sink: {:?}", route.name, event);
+
+                match route.sink.send_event(event).await {
+                    Ok(()) => {
+                        debug!(
+                            "Successfully sent event to {} sink for LSN: {:x}",
+                            route.name, self.state.received_lsn
+                        );
+                    }
+                    Err(e) => {
+                        error!("Failed to send event to {} sink: {}", route.name, e);
+                        let lsn = self.state.received_lsn;
+                        let event = DeadEvent::Message(event.clone());
+                        if !self.dead_letter(&e, &route.name, event, lsn)? {
+                            self.route_failed(&route.name, route.blocking, e)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(resolver) = self.toast_resolver.as_mut() {
+            resolver.commit()?;
+        }
+
+        // Every earlier event of the transaction was accepted before its commit
+        // reached the sink, so the whole transaction can now be acknowledged.
+        // A prepared transaction is acknowledged once prepared; its later
+        // COMMIT PREPARED or ROLLBACK PREPARED is acknowledged on its own.
+        match message {
+            ReplicationMessage::Commit { end_lsn, .. }
+            | ReplicationMessage::Prepare { end_lsn, .. }
+            | ReplicationMessage::CommitPrepared { end_lsn, .. }
+            | ReplicationMessage::RollbackPrepared {
+                rollback_end_lsn: end_lsn,
+                ..
+            } => {
+                debug!("Transaction delivered up to end LSN: {:x}", end_lsn);
+                let has_changes = self.transaction_has_changes || delivers_changes;
+                self.transaction_has_changes = false;
+                self.acknowledge(end_lsn, has_changes);
+            }
+            _ if delivers_changes && self.state.in_transaction => {
+                self.transaction_has_changes = true;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Marks a transaction the sink accepted as flushed, to be recorded in
+    /// the checkpoint store with the next status update
+    ///
+    /// Transactions without changes are not recorded: with the Postgres store
+    /// on servers that stream empty transactions, each checkpoint write would
+    /// otherwise come back as one more transaction to record.
+    fn acknowledge(&mut self, end_lsn: u64, has_changes: bool) {
+        self.state.commit_transaction(end_lsn);
+        METRICS
+            .transactions_delivered
+            .fetch_add(1, Ordering::Relaxed);
+
+        if has_changes && let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.record(end_lsn);
+        }
+    }
+
+    /// Writes the last transaction acknowledged since the previous status
+    /// update to the checkpoint store
+    async fn save_checkpoint(&mut self) -> ReplicationResult<()> {
+        let Some(checkpoint) = self.checkpoint.as_mut() else {
+            return Ok(());
+        };
+        if let Some(lsn) = checkpoint.flush().await? {
+            METRICS.checkpoint_lsn.store(lsn, Ordering::Relaxed);
+            debug!("Checkpoint saved at {}", format_lsn(lsn));
+        }
+        Ok(())
+    }
+
+    /// Sends a buffered transaction to each sink as one envelope, with the
+    /// changes routed to that sink
+    ///
+    /// Each routed change is transformed and run through the script once,
+    /// whichever sinks receive it.
+    async fn deliver_transaction(&mut self, batch: &TransactionBatch) -> ReplicationResult<()> {
+        let router = self.router.clone();
+        let routes = router.split_transaction(batch);
+        let mut routed: Vec<usize> = routes
+            .iter()
+            .flat_map(|(_, changes)| changes.iter().copied())
+            .collect();
+        routed.sort_unstable();
+        routed.dedup();
+        let routed_batch = self.transform_batch(TransactionBatch {
+            changes: routed
+                .iter()
+                .map(|&position| batch.changes[position].clone())
+                .collect(),
+            ..batch.clone()
+        })?;
+        let scripted = match self.script.as_mut() {
+            Some(script) if !routes.is_empty() => Some(script.process_batch(&routed_batch).await),
+            _ => None,
+        };
+
+        for (route, changes) in routes {
+            // Positions of the route's changes in the routed batch
+            let changes: Vec<usize> = changes
+                .iter()
+                .filter_map(|position| routed.binary_search(position).ok())
+                .collect();
+            let batch = TransactionBatch {
+                changes: changes
+                    .iter()
+                    .map(|&change| routed_batch.changes[change].clone())
+                    .collect(),
+                ..routed_batch.clone()
+            };
+            let batch = match &scripted {
+                None => batch,
+                Some(scripted) => match self.script_batch(batch, scripted, &changes, route)? {
+                    Some(batch) => batch,
+                    None => continue,
+                },
+            };
+            debug!(
+                "Sending transaction {} with {} changes to {} sink",
+                batch.xid,
+                batch.changes.len(),
+                route.name
+            );
+
+            if let Err(e) = route.sink.send_transaction(&batch).await {
+                error!("Failed to send transaction to {} sink: {}", route.name, e);
+                let end_lsn = batch.end_lsn;
+                if !self.dead_letter(&e, &route.name, DeadEvent::Transaction(batch), end_lsn)? {
+                    self.route_failed(&route.name, route.blocking, e)?;
+                }
+            }
+        }
+
+        if let Some(resolver) = self.toast_resolver.as_mut() {
+            resolver.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Message as the sinks receive it, after the column transforms
+    ///
+    /// Routes are chosen on the message as received, so that table patterns
+    /// and row filters see the columns before they are transformed.
+    fn transform<'a>(
+        &self,
+        message: &'a ReplicationMessage,
+    ) -> ReplicationResult<Cow<'a, ReplicationMessage>> {
+        match &self.transformer {
+            Some(transformer) => transformer
+                .apply(message, &self.state.relations)
+                .map(Cow::Owned),
+            None => Ok(Cow::Borrowed(message)),
+        }
+    }
+
+    /// Transaction as a sink receives it, after the column transforms
+    fn transform_batch(&self, batch: TransactionBatch) -> ReplicationResult<TransactionBatch> {
+        let Some(transformer) = &self.transformer else {
+            return Ok(batch);
+        };
+        let changes = batch
+            .changes
+            .iter()
+            .map(|change| transformer.apply(change, &self.state.relations))
+            .collect::<ReplicationResult<_>>()?;
+        Ok(TransactionBatch { changes, ..batch })
+    }
+
+    /// Messages of the event script's result for `message` as `route`
+    /// receives them, `None` when the script failed and the error policy
+    /// drops the event for the route
+    fn script_events(
+        &mut self,
+        message: &ReplicationMessage,
+        scripted: &Result<Vec<ReplicationMessage>, String>,
+        route: &Route,
+    ) -> ReplicationResult<Option<Vec<ReplicationMessage>>> {
+        let Some(script) = self.script.as_mut() else {
+            return Ok(Some(vec![message.clone()]));
+        };
+        match scripted {
+            Ok(messages) => Ok(Some(script.announce(messages, &route.name))),
+            Err(e) => {
+                let lsn = self.state.received_lsn;
+                let event = DeadEvent::Message(message.clone());
+                self.script_failed(e.clone(), &route.name, event, lsn)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Transaction as the event script made it for `route`, from its result
+    /// for the routed batch of which `batch` holds the `changes`; `None` when
+    /// the script dropped every change or failed and the error policy drops it
+    fn script_batch(
+        &mut self,
+        batch: TransactionBatch,
+        scripted: &Result<Vec<Vec<ReplicationMessage>>, String>,
+        changes: &[usize],
+        route: &Route,
+    ) -> ReplicationResult<Option<TransactionBatch>> {
+        let Some(script) = self.script.as_mut() else {
+            return Ok(Some(batch));
+        };
+        match scripted {
+            Ok(scripted) => {
+                let messages: Vec<ReplicationMessage> = changes
+                    .iter()
+                    .flat_map(|&change| scripted[change].iter().cloned())
+                    .collect();
+                if messages.is_empty() && !batch.changes.is_empty() {
                     debug!(
-                        "Successfully sent event to sink for LSN: {:x}",
-                        self.state.received_lsn
+                        "Script dropped every change of transaction {} for {} sink",
+                        batch.xid, route.name
                     );
-                    self.state.update_applied_lsn(self.state.received_lsn);
+                    return Ok(None);
                 }
-                Err(e) => {
-                    error!("Failed to send event to event sink: {}", e);
-                    return Err(crate::core::errors::ReplicationError::protocol(format!(
-                        "Event sink failed: {}",
-                        e
-                    )));
+                Ok(Some(TransactionBatch {
+                    changes: script.announce(&messages, &route.name),
+                    ..batch
+                }))
+            }
+            Err(e) => {
+                let end_lsn = batch.end_lsn;
+                self.script_failed(
+                    e.clone(),
+                    &route.name,
+                    DeadEvent::Transaction(batch),
+                    end_lsn,
+                )?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Applies `SCRIPT_ERROR_POLICY` to an event the script failed on for the `route` sink
+    fn script_failed(
+        &mut self,
+        error: String,
+        route: &str,
+        event: DeadEvent,
+        lsn: u64,
+    ) -> ReplicationResult<()> {
+        METRICS.script_errors.fetch_add(1, Ordering::Relaxed);
+        match self.config.script_error_policy {
+            ScriptErrorPolicy::Skip => {
+                warn!(
+                    "Skipping event at {} for {} sink, the script failed: {}",
+                    format_lsn(lsn),
+                    route,
+                    error
+                );
+                return Ok(());
+            }
+            ScriptErrorPolicy::DeadLetter => {
+                let script_error = dead_letter::script_error(error.clone());
+                if self.dead_letter(&script_error, route, event, lsn)? {
+                    return Ok(());
                 }
             }
-        } else {
-            self.state.update_applied_lsn(self.state.received_lsn);
+            ScriptErrorPolicy::Fail => {}
         }
+        Err(ReplicationError::protocol(format!(
+            "Event script failed at {}: {}",
+            format_lsn(lsn),
+            error
+        )))
+    }
 
+    /// Handles a failed delivery that was not dead-lettered
+    ///
+    /// A blocking route interrupts replication so that the transaction is not
+    /// acknowledged; the event is dropped for other routes.
+    fn route_failed(
+        &self,
+        route: &str,
+        blocking: bool,
+        error: ReplicationError,
+    ) -> ReplicationResult<()> {
+        // An event interrupted by shutdown is streamed again on the next start
+        if blocking || self.shutdown_signal.load(Ordering::SeqCst) {
+            return Err(ReplicationError::protocol(format!(
+                "Event sink failed: {}",
+                error
+            )));
+        }
+        METRICS.events_dropped.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Dropping event at {} for non-blocking {} sink: {}",
+            format_lsn(self.state.received_lsn),
+            route,
+            error
+        );
         Ok(())
     }
 
+    /// Writes an event the `sink` route gave up on to the dead-letter queue
+    ///
+    /// Returns false, leaving the error to the route's failure policy, when no
+    /// queue is configured or the error is not about the event itself.
+    fn dead_letter(
+        &mut self,
+        error: &ReplicationError,
+        sink: &str,
+        event: DeadEvent,
+        lsn: u64,
+    ) -> ReplicationResult<bool> {
+        let Some(queue) = self.dead_letters.as_mut() else {
+            return Ok(false);
+        };
+        let Some(mut letter) = DeadLetter::from_error(
+            error,
+            event,
+            &self.config.slot_name,
+            format_lsn(lsn),
+            &self.sink_relations,
+        ) else {
+            return Ok(false);
+        };
+        // Replays go to the route the event failed on
+        letter.sink = sink.to_string();
+
+        queue.push(&letter)?;
+        METRICS.dead_letters.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Event at {} written to the dead-letter queue as {}: {}",
+            letter.lsn, letter.id, letter.reason
+        );
+        Ok(true)
+    }
+
     fn send_feedback(&mut self) -> ReplicationResult<()> {
         debug!("Sending feedback to server");
 
@@ -396,7 +1479,7 @@ impl ReplicationServer {
 
             writer.write_u8(b'r')?;
             writer.write_u64(self.state.received_lsn)?;
-            writer.write_u64(self.state.received_lsn)?;
+            writer.write_u64(self.state.flushed_lsn)?;
             writer.write_u64(self.state.applied_lsn)?;
             writer.write_i64(timestamp)?;
             writer.write_u8(0)?;
@@ -411,10 +1494,16 @@ impl ReplicationServer {
         }
 
         self.connection.put_copy_data(&reply_buf)?;
+        METRICS
+            .received_lsn
+            .store(self.state.received_lsn, Ordering::Relaxed);
+        METRICS
+            .flushed_lsn
+            .store(self.state.flushed_lsn, Ordering::Relaxed);
 
         debug!(
-            "Sent feedback with received LSN: {:x}, applied LSN: {:x}",
-            self.state.received_lsn, self.state.applied_lsn
+            "Sent feedback with received LSN: {:x}, flushed LSN: {:x}, applied LSN: {:x}",
+            self.state.received_lsn, self.state.flushed_lsn, self.state.applied_lsn
         );
         Ok(())
     }
@@ -422,6 +1511,10 @@ impl ReplicationServer {
     async fn perform_graceful_shutdown(&mut self) -> ReplicationResult<()> {
         info!("Starting graceful shutdown process");
 
+        if let Err(e) = self.save_checkpoint().await {
+            warn!("Failed to save the checkpoint during shutdown: {}", e);
+        }
+
         // Send final feedback to PostgreSQL with the latest LSN position
         if let Err(e) = self.send_feedback() {
             warn!("Failed to send final feedback during shutdown: {}", e);
@@ -430,7 +1523,7 @@ impl ReplicationServer {
         }
 
         // Flush any remaining data in the connection
-        if let Err(e) = self.connection.flush() {
+        if let Err(e) = self.connection.flush_async().await {
             warn!("Failed to flush connection during shutdown: {}", e);
         }
 
@@ -438,11 +1531,13 @@ impl ReplicationServer {
         Ok(())
     }
 
-    fn check_and_send_feedback(&mut self) -> ReplicationResult<()> {
+    /// Sends a status update once per feedback interval, after saving the checkpoint
+    async fn check_and_send_feedback(&mut self) -> ReplicationResult<()> {
         let now = Instant::now();
         if now.duration_since(self.state.last_feedback_time)
             > Duration::from_secs(self.config.feedback_interval_secs)
         {
+            self.save_checkpoint().await?;
             self.send_feedback()?;
             self.state.update_feedback_time();
         }
@@ -0,0 +1,120 @@
+//! Bookkeeping tables written over a separate, non-replication connection
+//!
+//! The checkpoint store and the dead-letter queue keep their state in small
+//! tables of their own. The table is created on first use; a failed statement
+//! drops the connection so that the next one reconnects.
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::utils::connection::{PGConnection, PGResult, without_replication_param};
+use tracing::debug;
+
+/// A table owned by walpipe and the connection used to reach it
+pub(crate) struct SideTable {
+    conninfo: String,
+    schema: String,
+    table: String,
+    /// Column definitions of `CREATE TABLE`
+    columns: &'static str,
+    connection: Option<PGConnection>,
+}
+
+impl SideTable {
+    /// `table` is `schema.table` (or a table of the `public` schema) in the
+    /// database `conninfo` connects to
+    pub(crate) fn new(conninfo: &str, table: &str, columns: &'static str) -> Self {
+        let (schema, table) = match table.trim().split_once('.') {
+            Some((schema, table)) => (schema.to_string(), table.to_string()),
+            None => ("public".to_string(), table.trim().to_string()),
+        };
+        Self {
+            conninfo: without_replication_param(conninfo),
+            schema,
+            table,
+            columns,
+            connection: None,
+        }
+    }
+
+    /// Quoted, schema-qualified name of the table
+    pub(crate) fn name(&self) -> String {
+        format!(
+            "{}.{}",
+            quote_identifier(&self.schema),
+            quote_identifier(&self.table)
+        )
+    }
+
+    /// Runs the statement built by `sql`, dropping the connection on failure
+    /// so that the next call reconnects
+    pub(crate) fn run(
+        &mut self,
+        sql: impl FnOnce(&PGConnection) -> ReplicationResult<String>,
+    ) -> ReplicationResult<PGResult> {
+        let result = self
+            .connection()
+            .and_then(|connection| execute(connection, &sql(connection)?));
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    /// Refuses a table that is part of the replicated publication
+    ///
+    /// Every write to it would otherwise be streamed back as a new transaction.
+    /// `setting` names the variable that moves the table to another database.
+    pub(crate) fn check_publication(
+        &mut self,
+        publication: &str,
+        setting: &str,
+    ) -> ReplicationResult<()> {
+        let (schema, table) = (self.schema.clone(), self.table.clone());
+        let result = self.run(|connection| {
+            Ok(format!(
+                "SELECT 1 FROM pg_publication_tables
+                 WHERE pubname = {} AND schemaname = {} AND tablename = {};",
+                connection.escape_literal(publication)?,
+                connection.escape_literal(&schema)?,
+                connection.escape_literal(&table)?
+            ))
+        })?;
+        if result.ntuples() > 0 {
+            return Err(ReplicationError::config(format!(
+                "Table {}.{} is part of publication '{}'; exclude it or set {} to another database",
+                schema, table, publication, setting
+            )));
+        }
+        Ok(())
+    }
+
+    fn connection(&mut self) -> ReplicationResult<&PGConnection> {
+        if self.connection.is_none() {
+            debug!("Opening side connection for table {}", self.name());
+            let connection = PGConnection::connect(&self.conninfo)?;
+            let create_table = format!(
+                "CREATE TABLE IF NOT EXISTS {} ({});",
+                self.name(),
+                self.columns
+            );
+            execute(&connection, &create_table)?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_ref().unwrap())
+    }
+}
+
+fn execute(connection: &PGConnection, sql: &str) -> ReplicationResult<PGResult> {
+    let result = connection.exec(sql)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Query failed ({:?}): {}",
+            result.status(),
+            sql
+        )));
+    }
+    Ok(result)
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
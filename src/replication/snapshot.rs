@@ -0,0 +1,303 @@
+//! Initial snapshot of the published tables
+//!
+//! When the slot is created with `EXPORT_SNAPSHOT`, PostgreSQL returns the name
+//! of a snapshot matching the slot's consistent point. Reading every published
+//! table inside that snapshot, on a second connection, yields exactly the rows
+//! committed before the first change the slot will stream: nothing is lost or
+//! read twice.
+//!
+//! The exported snapshot stays valid only until the next command runs on the
+//! replication connection, so the snapshot transaction is opened right after
+//! the slot is created and before anything else is sent on it.
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::{ColumnData, ColumnInfo, RelationInfo, TupleData};
+use crate::utils::connection::{ExecStatusType, PGConnection, PGResult, without_replication_param};
+use tracing::debug;
+
+/// Slot created with an exported snapshot
+#[derive(Debug, Clone)]
+pub struct ExportedSnapshot {
+    /// LSN from which the slot streams changes
+    pub consistent_point: u64,
+    /// Snapshot name to pass to `SET TRANSACTION SNAPSHOT`
+    pub snapshot_name: String,
+}
+
+/// Reads the published tables inside an exported snapshot
+pub struct SnapshotReader {
+    connection: PGConnection,
+}
+
+impl SnapshotReader {
+    /// Opens a regular connection and imports `snapshot` into a read-only transaction
+    pub fn open(
+        replication_conninfo: &str,
+        snapshot: &ExportedSnapshot,
+    ) -> ReplicationResult<Self> {
+        let connection = PGConnection::connect(&without_replication_param(replication_conninfo))?;
+        let reader = Self { connection };
+
+        reader.query("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY;")?;
+        reader.query(&format!(
+            "SET TRANSACTION SNAPSHOT {};",
+            reader.connection.escape_literal(&snapshot.snapshot_name)?
+        ))?;
+        Ok(reader)
+    }
+
+    /// Schema of every table in `publication`, as a Relation message would describe it
+    pub fn published_tables(&self, publication: &str) -> ReplicationResult<Vec<RelationInfo>> {
+        let tables = query(
+            &self.connection,
+            &format!(
+                "SELECT c.oid, n.nspname, c.relname, c.relreplident
+                 FROM pg_publication_tables pt
+                 JOIN pg_namespace n ON n.nspname = pt.schemaname
+                 JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = pt.tablename
+                 WHERE pt.pubname = {}
+                 ORDER BY 2, 3;",
+                self.connection.escape_literal(publication)?
+            ),
+        )?;
+        relations(&self.connection, &tables)
+    }
+
+    /// Starts copying the rows of `relation`; read them with [`SnapshotReader::next_row`]
+    pub fn start_copy(&self, relation: &RelationInfo) -> ReplicationResult<()> {
+        let mut columns = Vec::with_capacity(relation.columns.len());
+        for column in &relation.columns {
+            columns.push(self.connection.escape_identifier(&column.column_name)?);
+        }
+        // The SELECT form also works for partitioned tables
+        let sql = format!(
+            "COPY (SELECT {} FROM {}.{}) TO STDOUT;",
+            columns.join(", "),
+            self.connection.escape_identifier(&relation.namespace)?,
+            self.connection.escape_identifier(&relation.relation_name)?
+        );
+        debug!("Copying snapshot rows: {}", sql);
+
+        let result = self.connection.exec(&sql)?;
+        if result.status() != ExecStatusType::PGRES_COPY_OUT {
+            return Err(ReplicationError::protocol(format!(
+                "Failed to copy {}.{}: {:?}",
+                relation.namespace,
+                relation.relation_name,
+                result.status()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Next row of the table being copied, `None` once the copy is complete
+    pub fn next_row(&self) -> ReplicationResult<Option<TupleData>> {
+        Ok(self
+            .connection
+            .get_copy_data()?
+            .map(|line| parse_copy_row(&line)))
+    }
+
+    /// Ends the snapshot transaction
+    pub fn finish(self) -> ReplicationResult<()> {
+        self.query("COMMIT;")?;
+        Ok(())
+    }
+
+    fn query(&self, sql: &str) -> ReplicationResult<PGResult> {
+        query(&self.connection, sql)
+    }
+}
+
+/// Schema of the table `schema.table`, `None` if it does not exist
+pub(crate) fn table_relation(
+    connection: &PGConnection,
+    schema: &str,
+    table: &str,
+) -> ReplicationResult<Option<RelationInfo>> {
+    let tables = query(
+        connection,
+        &format!(
+            "SELECT c.oid, n.nspname, c.relname, c.relreplident
+             FROM pg_class c
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE n.nspname = {} AND c.relname = {} AND c.relkind IN ('r', 'p');",
+            connection.escape_literal(schema)?,
+            connection.escape_literal(table)?
+        ),
+    )?;
+    Ok(relations(connection, &tables)?.pop())
+}
+
+/// Builds relations from `(oid, schema, name, replica identity)` rows
+///
+/// Key flags follow pgoutput: the replica identity columns, or every column
+/// with `REPLICA IDENTITY FULL`. Generated columns are not replicated and left out.
+fn relations(connection: &PGConnection, tables: &PGResult) -> ReplicationResult<Vec<RelationInfo>> {
+    let mut relations = Vec::new();
+    for row in 0..tables.ntuples() {
+        let oid = tables
+            .getvalue(row, 0)
+            .and_then(|oid| oid.parse().ok())
+            .ok_or_else(|| ReplicationError::protocol("Invalid table OID in table list"))?;
+        let columns = columns(connection, oid)?;
+        relations.push(RelationInfo {
+            oid,
+            namespace: tables.getvalue(row, 1).unwrap_or_default(),
+            relation_name: tables.getvalue(row, 2).unwrap_or_default(),
+            replica_identity: tables
+                .getvalue(row, 3)
+                .and_then(|identity| identity.chars().next())
+                .unwrap_or('d'),
+            column_count: columns.len() as i16,
+            columns,
+        });
+    }
+    Ok(relations)
+}
+
+fn columns(connection: &PGConnection, oid: u32) -> ReplicationResult<Vec<ColumnInfo>> {
+    let result = query(
+        connection,
+        &format!(
+            "SELECT a.attname, a.atttypid, a.atttypmod,
+                    c.relreplident = 'f' OR COALESCE(a.attnum = ANY(i.indkey), false)
+             FROM pg_attribute a
+             JOIN pg_class c ON c.oid = a.attrelid
+             LEFT JOIN pg_index i ON i.indrelid = a.attrelid
+                  AND ((c.relreplident = 'd' AND i.indisprimary)
+                       OR (c.relreplident = 'i' AND i.indisreplident))
+             WHERE a.attrelid = {} AND a.attnum > 0 AND NOT a.attisdropped
+               AND a.attgenerated = ''
+             ORDER BY a.attnum;",
+            oid
+        ),
+    )?;
+
+    Ok((0..result.ntuples())
+        .map(|row| ColumnInfo {
+            key_flag: (result.getvalue(row, 3).as_deref() == Some("t")) as i8,
+            column_name: result.getvalue(row, 0).unwrap_or_default(),
+            column_type: result
+                .getvalue(row, 1)
+                .and_then(|oid| oid.parse().ok())
+                .unwrap_or_default(),
+            atttypmod: result
+                .getvalue(row, 2)
+                .and_then(|typmod| typmod.parse().ok())
+                .unwrap_or(-1),
+        })
+        .collect())
+}
+
+pub(crate) fn query(connection: &PGConnection, sql: &str) -> ReplicationResult<PGResult> {
+    let result = connection.exec(sql)?;
+    if !result.is_ok() {
+        return Err(ReplicationError::protocol(format!(
+            "Snapshot query failed ({:?}): {}",
+            result.status(),
+            sql
+        )));
+    }
+    Ok(result)
+}
+
+/// Parses one row of `COPY ... TO STDOUT` text output into text columns
+///
+/// Fields are tab separated, `\N` is NULL and backslash escapes are decoded.
+fn parse_copy_row(line: &[u8]) -> TupleData {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let columns: Vec<ColumnData> = line
+        .split(|byte| *byte == b'\t')
+        .map(|field| {
+            if field == b"\\N" {
+                return ColumnData {
+                    data_type: 'n',
+                    length: 0,
+                    data: String::new(),
+                    binary: None,
+                };
+            }
+            let data = String::from_utf8_lossy(&unescape_copy_field(field)).into_owned();
+            ColumnData {
+                data_type: 't',
+                length: data.len() as i32,
+                data,
+                binary: None,
+            }
+        })
+        .collect();
+
+    TupleData {
+        column_count: columns.len() as i16,
+        processed_length: line.len(),
+        columns,
+    }
+}
+
+fn unescape_copy_field(field: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(field.len());
+    let mut bytes = field.iter().copied().peekable();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            value.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'b') => value.push(0x08),
+            Some(b'f') => value.push(0x0c),
+            Some(b'n') => value.push(b'\n'),
+            Some(b'r') => value.push(b'\r'),
+            Some(b't') => value.push(b'\t'),
+            Some(b'v') => value.push(0x0b),
+            Some(b'x') => {
+                // \xH or \xHH
+                let mut code = 0u8;
+                for _ in 0..2 {
+                    match bytes.peek().and_then(|digit| (*digit as char).to_digit(16)) {
+                        Some(digit) => {
+                            code = code * 16 + digit as u8;
+                            bytes.next();
+                        }
+                        None => break,
+                    }
+                }
+                value.push(code);
+            }
+            Some(digit @ b'0'..=b'7') => {
+                // \N, \NN or \NNN in octal
+                let mut code = digit - b'0';
+                for _ in 0..2 {
+                    match bytes.peek() {
+                        Some(next @ b'0'..=b'7') => {
+                            code = code.wrapping_mul(8) + (next - b'0');
+                            bytes.next();
+                        }
+                        _ => break,
+                    }
+                }
+                value.push(code);
+            }
+            Some(other) => value.push(other),
+            None => value.push(b'\\'),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_copy_row() {
+        let row = parse_copy_row(b"1\tline\\none\\ttab\t\\N\tback\\\\slash \\101\\x42\n");
+
+        assert_eq!(row.column_count, 4);
+        assert_eq!(row.columns[0].data, "1");
+        assert_eq!(row.columns[1].data, "line\none\ttab");
+        assert_eq!(row.columns[2].data_type, 'n');
+        assert_eq!(row.columns[3].data, "back\\slash AB");
+    }
+}
@@ -39,6 +39,28 @@ mod tests {
         assert_eq!(state.applied_lsn, 80);
     }
 
+    #[test]
+    fn test_transaction_aligned_confirmation() {
+        let mut state = ReplicationState::new();
+
+        // Changes received mid-transaction must not be acknowledged
+        state.begin_transaction();
+        state.update_lsn(150);
+        assert!(state.in_transaction);
+        assert_eq!(state.flushed_lsn, 0);
+        assert_eq!(state.applied_lsn, 0);
+
+        // Delivering the commit confirms the whole transaction at its end LSN
+        state.commit_transaction(200);
+        assert!(!state.in_transaction);
+        assert_eq!(state.flushed_lsn, 200);
+        assert_eq!(state.applied_lsn, 200);
+
+        // Confirmed positions never move backwards
+        state.confirm_lsn(120);
+        assert_eq!(state.flushed_lsn, 200);
+    }
+
     #[test]
     fn test_feedback_timing() {
         let state = ReplicationState::new();
@@ -0,0 +1,415 @@
+//! Reassembly of streamed (in-progress) transactions
+//!
+//! With `streaming 'on'`, PostgreSQL sends large transactions in segments
+//! before they commit, and may later abort the whole transaction or one of
+//! its subtransactions. This buffer keeps the raw pgoutput payloads per
+//! top-level xid, spilling to disk once a transaction grows past a memory
+//! threshold, and only hands the changes back once `StreamCommit` arrives.
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::protocol::messages::ReplicationMessage;
+use crate::protocol::parser::MessageParser;
+use crate::utils::binary::Xid;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+/// Buffers streamed transactions until they commit or abort
+pub struct StreamBuffer {
+    spill_dir: PathBuf,
+    spill_prefix: String,
+    memory_limit: usize,
+    transactions: HashMap<Xid, StreamedTransaction>,
+}
+
+impl StreamBuffer {
+    /// Creates a buffer spilling to `spill_dir` once a transaction exceeds `memory_limit` bytes
+    ///
+    /// Spill files are named after `slot_name` so several pipelines can share a directory.
+    pub fn new(spill_dir: PathBuf, slot_name: &str, memory_limit: usize) -> Self {
+        Self {
+            spill_dir,
+            spill_prefix: format!("walpipe-{}-", slot_name),
+            memory_limit,
+            transactions: HashMap::new(),
+        }
+    }
+
+    /// Removes spill files left behind by a previous run
+    ///
+    /// Their transactions were never acknowledged, so the server streams them again.
+    pub fn remove_stale_spill_files(&self) -> ReplicationResult<()> {
+        let entries = match fs::read_dir(&self.spill_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with(&self.spill_prefix) {
+                info!("Removing stale stream spill file: {:?}", entry.path());
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a raw message belonging to (sub)transaction `subxid` of streamed transaction `xid`
+    ///
+    /// `lsn` is the WAL position of the message, handed back with it on commit.
+    pub fn append(
+        &mut self,
+        xid: Xid,
+        subxid: Xid,
+        lsn: u64,
+        data: &[u8],
+    ) -> ReplicationResult<()> {
+        let transaction = self
+            .transactions
+            .entry(xid)
+            .or_insert_with(|| StreamedTransaction::new(xid));
+
+        transaction.push(subxid, lsn, data.to_vec());
+
+        if transaction.spill.is_none() && transaction.memory_bytes > self.memory_limit {
+            let path = self
+                .spill_dir
+                .join(format!("{}{}.spill", self.spill_prefix, xid));
+            info!(
+                "Streamed transaction {} exceeded {} bytes in memory, spilling to {:?}",
+                xid, self.memory_limit, path
+            );
+            fs::create_dir_all(&self.spill_dir)?;
+            transaction.spill = Some(SpillFile::create(path)?);
+        }
+
+        if let Some(spill) = transaction.spill.as_mut() {
+            for (subxid, lsn, data) in transaction.entries.drain(..) {
+                spill.write_entry(subxid, lsn, &data)?;
+            }
+            transaction.memory_bytes = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Handles a StreamAbort for `subxid` of streamed transaction `xid`
+    ///
+    /// Aborting the top-level xid discards the whole transaction; aborting a
+    /// subtransaction discards only the changes made by that subtransaction.
+    pub fn abort(&mut self, xid: Xid, subxid: Xid) {
+        if xid == subxid {
+            if self.transactions.remove(&xid).is_some() {
+                debug!("Discarded aborted streamed transaction {}", xid);
+            }
+        } else if let Some(transaction) = self.transactions.get_mut(&xid) {
+            debug!(
+                "Discarding aborted subtransaction {} of streamed transaction {}",
+                subxid, xid
+            );
+            transaction.abort_subtransaction(subxid);
+        }
+    }
+
+    /// Discards every buffered transaction, along with its spill file
+    ///
+    /// Used when the connection is lost: the server streams them again.
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+    }
+
+    /// Removes a committed streamed transaction and returns its surviving changes in order
+    pub fn take(&mut self, xid: Xid) -> ReplicationResult<StreamedMessages> {
+        match self.transactions.remove(&xid) {
+            Some(transaction) => transaction.into_messages(),
+            None => {
+                warn!("Commit for unknown streamed transaction {}", xid);
+                Ok(StreamedMessages::empty())
+            }
+        }
+    }
+}
+
+/// Buffered state of a single streamed transaction
+struct StreamedTransaction {
+    xid: Xid,
+    entries: Vec<(Xid, u64, Vec<u8>)>,
+    memory_bytes: usize,
+    spill: Option<SpillFile>,
+    aborted_subtransactions: HashSet<Xid>,
+}
+
+impl StreamedTransaction {
+    fn new(xid: Xid) -> Self {
+        Self {
+            xid,
+            entries: Vec::new(),
+            memory_bytes: 0,
+            spill: None,
+            aborted_subtransactions: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, subxid: Xid, lsn: u64, data: Vec<u8>) {
+        self.memory_bytes += data.len();
+        self.entries.push((subxid, lsn, data));
+    }
+
+    fn abort_subtransaction(&mut self, subxid: Xid) {
+        // In-memory changes can be dropped right away; spilled ones are skipped on replay
+        self.entries
+            .retain(|(entry_subxid, _, _)| *entry_subxid != subxid);
+        self.aborted_subtransactions.insert(subxid);
+    }
+
+    fn into_messages(mut self) -> ReplicationResult<StreamedMessages> {
+        let spilled = match self.spill.take() {
+            Some(spill) => Some(spill.into_reader()?),
+            None => None,
+        };
+
+        debug!("Replaying committed streamed transaction {}", self.xid);
+
+        Ok(StreamedMessages {
+            spilled,
+            in_memory: std::mem::take(&mut self.entries).into_iter(),
+            aborted_subtransactions: std::mem::take(&mut self.aborted_subtransactions),
+        })
+    }
+}
+
+/// Append-only spill file holding `[subxid u32][lsn u64][length u32][payload]` entries
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf) -> ReplicationResult<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write_entry(&mut self, subxid: Xid, lsn: u64, data: &[u8]) -> ReplicationResult<()> {
+        let length = u32::try_from(data.len())
+            .map_err(|_| ReplicationError::buffer("Streamed message too large to spill"))?;
+        self.writer.write_all(&subxid.to_be_bytes())?;
+        self.writer.write_all(&lsn.to_be_bytes())?;
+        self.writer.write_all(&length.to_be_bytes())?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    fn into_reader(mut self) -> ReplicationResult<SpillReader> {
+        self.writer.flush()?;
+        let file = File::open(&self.path)?;
+        let path = std::mem::take(&mut self.path);
+        Ok(SpillReader {
+            path,
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Reads spilled entries back, deleting the file once done
+struct SpillReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl SpillReader {
+    fn next_entry(&mut self) -> ReplicationResult<Option<(Xid, u64, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let subxid = Xid::from_be_bytes(header[..4].try_into().unwrap());
+        let lsn = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let length = u32::from_be_bytes(header[12..].try_into().unwrap()) as usize;
+        let mut data = vec![0u8; length];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some((subxid, lsn, data)))
+    }
+}
+
+impl Drop for SpillReader {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Changes of a committed streamed transaction, parsed lazily in stream order
+pub struct StreamedMessages {
+    spilled: Option<SpillReader>,
+    in_memory: std::vec::IntoIter<(Xid, u64, Vec<u8>)>,
+    aborted_subtransactions: HashSet<Xid>,
+}
+
+impl StreamedMessages {
+    fn empty() -> Self {
+        Self {
+            spilled: None,
+            in_memory: Vec::new().into_iter(),
+            aborted_subtransactions: HashSet::new(),
+        }
+    }
+
+    fn next_entry(&mut self) -> ReplicationResult<Option<(Xid, u64, Vec<u8>)>> {
+        if let Some(spilled) = self.spilled.as_mut() {
+            if let Some(entry) = spilled.next_entry()? {
+                return Ok(Some(entry));
+            }
+            self.spilled = None;
+        }
+        Ok(self.in_memory.next())
+    }
+}
+
+impl Iterator for StreamedMessages {
+    type Item = ReplicationResult<ReplicationMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_entry() {
+                Ok(Some((subxid, lsn, data))) => {
+                    if self.aborted_subtransactions.contains(&subxid) {
+                        continue;
+                    }
+                    return Some(MessageParser::parse_streamed_wal_message(&data, lsn));
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a streamed pgoutput Insert with a single text column
+    fn streamed_insert(subxid: Xid, relation_id: u32, value: &str) -> Vec<u8> {
+        let mut data = vec![b'I'];
+        data.extend_from_slice(&subxid.to_be_bytes());
+        data.extend_from_slice(&relation_id.to_be_bytes());
+        data.push(b'N');
+        data.extend_from_slice(&1i16.to_be_bytes());
+        data.push(b't');
+        data.extend_from_slice(&(value.len() as i32).to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data
+    }
+
+    fn inserted_values(messages: StreamedMessages) -> Vec<String> {
+        messages
+            .map(|message| match message.unwrap() {
+                ReplicationMessage::Insert { tuple_data, .. } => tuple_data.columns[0].data.clone(),
+                other => panic!("unexpected message: {:?}", other),
+            })
+            .collect()
+    }
+
+    fn spill_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("walpipe-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_commit_returns_changes_in_order() {
+        let mut buffer = StreamBuffer::new(spill_dir("order"), "slot", 1024);
+
+        buffer
+            .append(100, 100, 0, &streamed_insert(100, 1, "a"))
+            .unwrap();
+        buffer
+            .append(100, 100, 0, &streamed_insert(100, 1, "b"))
+            .unwrap();
+
+        let values = inserted_values(buffer.take(100).unwrap());
+        assert_eq!(values, vec!["a", "b"]);
+
+        // Nothing is left behind once the transaction has been taken
+        assert_eq!(buffer.take(100).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_aborts_discard_rolled_back_changes() {
+        let mut buffer = StreamBuffer::new(spill_dir("abort"), "slot", 1024);
+
+        buffer
+            .append(100, 100, 0, &streamed_insert(100, 1, "kept"))
+            .unwrap();
+        buffer
+            .append(100, 101, 0, &streamed_insert(101, 1, "rolled back"))
+            .unwrap();
+        buffer.abort(100, 101);
+        assert_eq!(inserted_values(buffer.take(100).unwrap()), vec!["kept"]);
+
+        buffer
+            .append(200, 200, 0, &streamed_insert(200, 1, "x"))
+            .unwrap();
+        buffer.abort(200, 200);
+        assert_eq!(buffer.take(200).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_spilled_transaction_replays_from_disk() {
+        let dir = spill_dir("spill");
+        let mut buffer = StreamBuffer::new(dir.clone(), "slot", 16);
+
+        buffer
+            .append(100, 100, 10, &streamed_insert(100, 1, "first"))
+            .unwrap();
+        buffer
+            .append(100, 101, 20, &streamed_insert(101, 1, "aborted"))
+            .unwrap();
+        buffer
+            .append(100, 100, 30, &streamed_insert(100, 1, "second"))
+            .unwrap();
+        buffer.abort(100, 101);
+
+        let spill_path = dir.join("walpipe-slot-100.spill");
+        assert!(spill_path.exists());
+
+        // Each change comes back with its WAL position
+        let changes: Vec<(String, u64)> = buffer
+            .take(100)
+            .unwrap()
+            .map(|message| match message.unwrap() {
+                ReplicationMessage::Insert {
+                    tuple_data, lsn, ..
+                } => (tuple_data.columns[0].data.clone(), lsn),
+                other => panic!("unexpected message: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![("first".to_string(), 10), ("second".to_string(), 30)]
+        );
+        assert!(!spill_path.exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
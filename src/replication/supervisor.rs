@@ -0,0 +1,193 @@
+//! Automatic reconnection around the replication server
+//!
+//! A lost connection, a PostgreSQL restart or a failed delivery ends the
+//! stream with an error. Once the stream has started, the supervisor
+//! reconnects with an exponential backoff and resumes from the last LSN
+//! acknowledged to the server: every transaction that was not confirmed is
+//! streamed and delivered again. Errors before the stream first starts
+//! (invalid settings, missing slot, interrupted initial snapshot) are
+//! returned as before.
+
+use crate::core::config::ReplicationConfig;
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use crate::replication::ReplicationServer;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Granularity at which a reconnection delay checks for shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    attempts: u32,
+    /// Flushed LSN when the last attempt was made
+    flushed_lsn: u64,
+}
+
+impl Backoff {
+    /// Starts at `initial`, doubling up to `max`
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+            attempts: 0,
+            flushed_lsn: 0,
+        }
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next.saturating_mul(2), self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    /// Attempts made since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Starts over after a successful reconnection
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.attempts = 0;
+    }
+
+    /// Starts over when the session acknowledged transactions since the last
+    /// attempt, so that a connection that keeps failing or an event that
+    /// keeps failing delivery still backs off
+    pub fn record_progress(&mut self, flushed_lsn: u64) {
+        if flushed_lsn > self.flushed_lsn {
+            self.flushed_lsn = flushed_lsn;
+            self.reset();
+        }
+    }
+}
+
+/// Runs the replication server, reconnecting when the stream is interrupted
+pub struct Supervisor {
+    config: ReplicationConfig,
+    shutdown_signal: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    pub fn new(config: ReplicationConfig, shutdown_signal: Arc<AtomicBool>) -> Self {
+        Self {
+            config,
+            shutdown_signal,
+        }
+    }
+
+    /// Sets up replication and streams until shutdown or a fatal error
+    pub async fn run(self) -> ReplicationResult<()> {
+        let mut server = ReplicationServer::new(self.config.clone(), self.shutdown_signal.clone())?;
+        server.identify_system()?;
+
+        let mut result = server.create_replication_slot_and_start().await;
+        if !server.is_streaming() {
+            return result;
+        }
+
+        let mut backoff = Backoff::new(
+            Duration::from_millis(self.config.reconnect_initial_delay_ms),
+            Duration::from_millis(self.config.reconnect_max_delay_ms),
+        );
+        loop {
+            let error = match result {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if self.shutdown_signal.load(Ordering::SeqCst)
+                || matches!(error, ReplicationError::Configuration { .. })
+            {
+                return Err(error);
+            }
+
+            // A session that made progress starts a new series of attempts
+            backoff.record_progress(server.flushed_lsn());
+            let max_attempts = self.config.reconnect_max_attempts;
+            if max_attempts > 0 && backoff.attempts() >= max_attempts {
+                error!("Giving up after {} reconnection attempts", max_attempts);
+                return Err(error);
+            }
+
+            let delay = backoff.next_delay();
+            warn!(
+                "Replication interrupted: {}. Reconnecting in {:?} (attempt {})",
+                error,
+                delay,
+                backoff.attempts()
+            );
+            if !self.wait(delay).await {
+                info!("Shutdown requested while waiting to reconnect");
+                return Ok(());
+            }
+
+            result = match server.reconnect() {
+                Ok(()) => server.resume_replication().await,
+                Err(error) => Err(error),
+            };
+        }
+    }
+
+    /// Sleeps for `delay`, returning `false` early if shutdown is requested
+    async fn wait(&self, delay: Duration) -> bool {
+        let mut remaining = delay;
+        while !remaining.is_zero() {
+            if self.shutdown_signal.load(Ordering::SeqCst) {
+                return false;
+            }
+            let step = std::cmp::min(remaining, SHUTDOWN_POLL_INTERVAL);
+            tokio::time::sleep(step).await;
+            remaining -= step;
+        }
+        !self.shutdown_signal.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+        assert_eq!(backoff.attempts(), 5);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_backoff_grows_without_progress() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        backoff.record_progress(0x100);
+
+        // Failed connections and sessions that deliver nothing leave the
+        // flushed LSN where it was
+        let delays: Vec<_> = (0..3)
+            .map(|_| {
+                backoff.record_progress(0x100);
+                backoff.next_delay().as_millis()
+            })
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000]);
+        assert_eq!(backoff.attempts(), 3);
+
+        backoff.record_progress(0x200);
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,872 @@
+//! Resolution of unchanged TOAST columns
+//!
+//! When an UPDATE leaves a large out-of-line (TOASTed) value untouched,
+//! pgoutput sends the column as `'u'` without its value. The resolver fills
+//! such columns in before the change reaches the sinks, trying in order:
+//! 1. the old tuple of the same update (REPLICA IDENTITY FULL)
+//! 2. a cache of the last values seen for the row's primary key
+//! 3. optionally, a query against the table on a separate connection
+//!
+//! Columns that cannot be resolved are left as `'u'` and reported as
+//! unchanged by the formatters.
+
+use crate::core::errors::ReplicationResult;
+use crate::events::sink::pg_type_conversion::{PgType, decode_binary};
+use crate::protocol::messages::{
+    ColumnData, RelationCache, RelationInfo, ReplicationMessage, TupleData,
+};
+use crate::utils::binary::Oid;
+use crate::utils::connection::{QueryWorker, without_replication_param};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Last known value of a single column
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedValue {
+    pub data: String,
+    pub binary: Option<Vec<u8>>,
+}
+
+/// Last known values of a row's TOASTable columns, indexed by column position
+pub type CachedRow = HashMap<usize, CachedValue>;
+
+/// Storage backend for cached row values
+pub trait ToastStore {
+    /// Last values stored for the row with primary key `key`
+    fn get(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<Option<CachedRow>>;
+
+    /// Replaces the values stored for the row with primary key `key`
+    fn put(&mut self, relation: Oid, key: Vec<u8>, row: CachedRow) -> ReplicationResult<()>;
+
+    /// Forgets a deleted row
+    fn remove(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<()>;
+
+    /// Forgets every row of a truncated table
+    fn clear_relation(&mut self, relation: Oid) -> ReplicationResult<()>;
+}
+
+/// Keys ordered by last use, for least-recently-used eviction
+struct Recency<K> {
+    clock: u64,
+    last_used: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash> Recency<K> {
+    fn new() -> Self {
+        Self {
+            clock: 0,
+            last_used: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    /// Marks `key` as the most recently used
+    fn touch(&mut self, key: K) {
+        self.remove(&key);
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.last_used.insert(key, self.clock);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(last_used) = self.last_used.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    /// Forgets and returns the least recently used key
+    fn pop_oldest(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.last_used.remove(&key);
+        Some(key)
+    }
+
+    fn retain(&mut self, keep: impl Fn(&K) -> bool) {
+        self.order.retain(|_, key| keep(key));
+        self.last_used.retain(|key, _| keep(key));
+    }
+}
+
+/// In-memory store evicting the least recently used rows beyond `capacity`
+pub struct MemoryToastStore {
+    capacity: usize,
+    rows: HashMap<(Oid, Vec<u8>), CachedRow>,
+    recency: Recency<(Oid, Vec<u8>)>,
+}
+
+impl MemoryToastStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            rows: HashMap::new(),
+            recency: Recency::new(),
+        }
+    }
+}
+
+impl ToastStore for MemoryToastStore {
+    fn get(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<Option<CachedRow>> {
+        let entry = (relation, key.to_vec());
+        let Some(row) = self.rows.get(&entry) else {
+            return Ok(None);
+        };
+        let row = row.clone();
+        self.recency.touch(entry);
+        Ok(Some(row))
+    }
+
+    fn put(&mut self, relation: Oid, key: Vec<u8>, row: CachedRow) -> ReplicationResult<()> {
+        let entry = (relation, key);
+        self.recency.touch(entry.clone());
+        self.rows.insert(entry, row);
+
+        while self.recency.len() > self.capacity {
+            let Some(oldest) = self.recency.pop_oldest() else {
+                break;
+            };
+            self.rows.remove(&oldest);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<()> {
+        let entry = (relation, key.to_vec());
+        self.rows.remove(&entry);
+        self.recency.remove(&entry);
+        Ok(())
+    }
+
+    fn clear_relation(&mut self, relation: Oid) -> ReplicationResult<()> {
+        self.rows.retain(|(oid, _), _| *oid != relation);
+        self.recency.retain(|(oid, _)| *oid != relation);
+        Ok(())
+    }
+}
+
+/// On-disk store keeping one JSON file per row, surviving restarts
+///
+/// Like the memory store it keeps at most `capacity` rows, deleting the files
+/// of the least recently used ones. Files left by a previous run are ranked
+/// by modification time.
+pub struct DiskToastStore {
+    dir: PathBuf,
+    capacity: usize,
+    files: Recency<PathBuf>,
+}
+
+/// File contents of a disk cache entry; the full key guards against hash collisions
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    key: Vec<u8>,
+    row: CachedRow,
+}
+
+impl DiskToastStore {
+    pub fn new(dir: PathBuf, capacity: usize) -> ReplicationResult<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut existing = Vec::new();
+        for relation_dir in fs::read_dir(&dir)? {
+            let relation_dir = relation_dir?.path();
+            if !relation_dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&relation_dir)? {
+                let file = file?;
+                let modified = file.metadata()?.modified()?;
+                existing.push((modified, file.path()));
+            }
+        }
+        existing.sort();
+
+        let mut store = Self {
+            dir,
+            capacity,
+            files: Recency::new(),
+        };
+        for (_, path) in existing {
+            store.files.touch(path);
+        }
+        store.evict()?;
+        Ok(store)
+    }
+
+    fn relation_dir(&self, relation: Oid) -> PathBuf {
+        self.dir.join(relation.to_string())
+    }
+
+    fn entry_path(&self, relation: Oid, key: &[u8]) -> PathBuf {
+        // FNV-1a keeps file names short and stable across builds
+        let hash = key.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.relation_dir(relation)
+            .join(format!("{:016x}.json", hash))
+    }
+
+    /// Deletes the least recently used files beyond the capacity
+    fn evict(&mut self) -> ReplicationResult<()> {
+        while self.files.len() > self.capacity {
+            let Some(oldest) = self.files.pop_oldest() else {
+                break;
+            };
+            remove_file(&oldest)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToastStore for DiskToastStore {
+    fn get(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<Option<CachedRow>> {
+        let path = self.entry_path(relation, key);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        match serde_json::from_slice::<DiskEntry>(&contents) {
+            Ok(entry) if entry.key == key => {
+                self.files.touch(path);
+                Ok(Some(entry.row))
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                warn!("Ignoring corrupt TOAST cache entry: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn put(&mut self, relation: Oid, key: Vec<u8>, row: CachedRow) -> ReplicationResult<()> {
+        fs::create_dir_all(self.relation_dir(relation))?;
+        let path = self.entry_path(relation, &key);
+        let contents = serde_json::to_vec(&DiskEntry { key, row })
+            .map_err(|e| crate::core::errors::ReplicationError::buffer(e.to_string()))?;
+        fs::write(&path, contents)?;
+        self.files.touch(path);
+        self.evict()
+    }
+
+    fn remove(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<()> {
+        let path = self.entry_path(relation, key);
+        self.files.remove(&path);
+        remove_file(&path)
+    }
+
+    fn clear_relation(&mut self, relation: Oid) -> ReplicationResult<()> {
+        let relation_dir = self.relation_dir(relation);
+        self.files.retain(|path| !path.starts_with(&relation_dir));
+        match fs::remove_dir_all(relation_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn remove_file(path: &Path) -> ReplicationResult<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Re-reads unresolved columns from the table over a regular (non-replication) connection
+///
+/// The query returns the row as it is now, which may be newer than the change being
+/// resolved when the row was modified again since. Queries run on a thread of
+/// their own, see [`QueryWorker`].
+pub struct ToastRefetcher {
+    worker: QueryWorker,
+}
+
+impl ToastRefetcher {
+    pub fn new(replication_conninfo: &str) -> ReplicationResult<Self> {
+        Ok(Self {
+            worker: QueryWorker::new(
+                without_replication_param(replication_conninfo),
+                "toast-refetch",
+            )?,
+        })
+    }
+
+    /// Closes the side connection, reopened on the next re-fetch
+    pub fn disconnect(&mut self) {
+        self.worker.disconnect();
+    }
+
+    /// Fetches the text value of `columns` for the row identified by the key columns of `tuple`
+    async fn fetch(
+        &mut self,
+        relation: &RelationInfo,
+        tuple: &TupleData,
+        columns: &[usize],
+    ) -> ReplicationResult<Option<Vec<Option<String>>>> {
+        let mut conditions = Vec::new();
+        for (index, column) in relation.columns.iter().enumerate() {
+            if column.key_flag & 1 == 0 {
+                continue;
+            }
+            let Some(value) = tuple
+                .columns
+                .get(index)
+                .and_then(|c| key_text(column.column_type, c))
+            else {
+                return Ok(None);
+            };
+            conditions.push((column.column_name.clone(), value));
+        }
+        if conditions.is_empty() {
+            return Ok(None);
+        }
+
+        let relation = relation.clone();
+        let columns = columns.to_vec();
+        self.worker
+            .run(move |connection| {
+                let select_list = columns
+                    .iter()
+                    .map(|index| {
+                        let name =
+                            connection.escape_identifier(&relation.columns[*index].column_name)?;
+                        Ok(format!("{}::text", name))
+                    })
+                    .collect::<ReplicationResult<Vec<_>>>()?
+                    .join(", ");
+                let where_clause = conditions
+                    .iter()
+                    .map(|(name, value)| {
+                        Ok(format!(
+                            "{} = {}",
+                            connection.escape_identifier(name)?,
+                            connection.escape_literal(value)?
+                        ))
+                    })
+                    .collect::<ReplicationResult<Vec<_>>>()?
+                    .join(" AND ");
+                let query = format!(
+                    "SELECT {} FROM {}.{} WHERE {};",
+                    select_list,
+                    connection.escape_identifier(&relation.namespace)?,
+                    connection.escape_identifier(&relation.relation_name)?,
+                    where_clause
+                );
+
+                let result = connection.exec(&query)?;
+                if !result.is_ok() {
+                    warn!(
+                        "TOAST re-fetch failed for {}.{}: {:?}",
+                        relation.namespace,
+                        relation.relation_name,
+                        result.status()
+                    );
+                    return Ok(None);
+                }
+                if result.ntuples() == 0 {
+                    return Ok(None);
+                }
+
+                Ok(Some(
+                    (0..columns.len() as i32)
+                        .map(|field| {
+                            if result.is_null(0, field) {
+                                None
+                            } else {
+                                result.getvalue(0, field)
+                            }
+                        })
+                        .collect(),
+                ))
+            })
+            .await
+    }
+}
+
+/// Cache updates of the changes not delivered yet
+///
+/// A row maps to its new values, or to `None` once deleted.
+#[derive(Default)]
+struct PendingUpdates {
+    rows: HashMap<(Oid, Vec<u8>), Option<CachedRow>>,
+    cleared: HashSet<Oid>,
+}
+
+/// Fills in unchanged TOAST columns and keeps the cache up to date
+///
+/// The values of a change only reach the store once the change was delivered,
+/// so that a change streamed again after a failure is resolved as the first
+/// time. Later changes of the same transaction already see them.
+pub struct ToastResolver {
+    store: Option<Box<dyn ToastStore>>,
+    refetcher: Option<ToastRefetcher>,
+    pending: PendingUpdates,
+}
+
+impl ToastResolver {
+    pub fn new(store: Option<Box<dyn ToastStore>>, refetcher: Option<ToastRefetcher>) -> Self {
+        Self {
+            store,
+            refetcher,
+            pending: PendingUpdates::default(),
+        }
+    }
+
+    /// Drops the re-fetch connection and the cache updates of undelivered
+    /// changes after the server connection was lost
+    pub fn disconnect(&mut self) {
+        if let Some(refetcher) = self.refetcher.as_mut() {
+            refetcher.disconnect();
+        }
+        self.pending = PendingUpdates::default();
+    }
+
+    /// Stores the values of the changes resolved since the last call, once
+    /// they were delivered
+    pub fn commit(&mut self) -> ReplicationResult<()> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        let pending = std::mem::take(&mut self.pending);
+        for relation in pending.cleared {
+            store.clear_relation(relation)?;
+        }
+        for ((relation, key), row) in pending.rows {
+            match row {
+                Some(row) => store.put(relation, key, row)?,
+                None => store.remove(relation, &key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves unchanged columns of a change message in place
+    pub async fn resolve(
+        &mut self,
+        message: &mut ReplicationMessage,
+        relations: &RelationCache,
+    ) -> ReplicationResult<()> {
+        match message {
+            ReplicationMessage::Insert {
+                relation_id,
+                tuple_data,
+                ..
+            }
+            | ReplicationMessage::Read {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                if let Some(relation) = relations.get(*relation_id) {
+                    self.remember(&relation, tuple_data)?;
+                }
+            }
+            ReplicationMessage::Update {
+                relation_id,
+                old_tuple_data,
+                new_tuple_data,
+                ..
+            } => {
+                if let Some(relation) = relations.get(*relation_id) {
+                    self.resolve_update(&relation, old_tuple_data.as_ref(), new_tuple_data)
+                        .await?;
+                }
+            }
+            ReplicationMessage::Delete {
+                relation_id,
+                tuple_data,
+                ..
+            } => {
+                if let Some(relation) = relations.get(*relation_id)
+                    && let Some(key) = row_key(&relation, tuple_data)
+                {
+                    self.forget(relation.oid, key);
+                }
+            }
+            ReplicationMessage::Truncate { relation_ids, .. } if self.store.is_some() => {
+                for relation_id in relation_ids.iter() {
+                    self.pending.rows.retain(|(oid, _), _| oid != relation_id);
+                    self.pending.cleared.insert(*relation_id);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn resolve_update(
+        &mut self,
+        relation: &RelationInfo,
+        old_tuple: Option<&TupleData>,
+        new_tuple: &mut TupleData,
+    ) -> ReplicationResult<()> {
+        let new_key = row_key(relation, new_tuple);
+        // The old key is only sent when the key changed (or with REPLICA IDENTITY FULL)
+        let old_key = old_tuple.and_then(|old| row_key(relation, old));
+
+        if has_unchanged(new_tuple) {
+            // 1. Previous image of the same row
+            if let Some(old_tuple) = old_tuple {
+                for (column, old_column) in new_tuple.columns.iter_mut().zip(&old_tuple.columns) {
+                    if column.data_type == 'u' && old_column.data_type != 'u' {
+                        *column = old_column.clone();
+                    }
+                }
+            }
+
+            // 2. Cached values from the last change to this row
+            let lookup_key = old_key.as_ref().or(new_key.as_ref());
+            if has_unchanged(new_tuple)
+                && let Some(key) = lookup_key
+                && let Some(cached) = self.cached(relation.oid, key)?
+            {
+                for (index, column) in new_tuple.columns.iter_mut().enumerate() {
+                    if column.data_type == 'u'
+                        && let Some(value) = cached.get(&index)
+                    {
+                        *column = value.to_column_data();
+                    }
+                }
+            }
+
+            // 3. Current row from the table
+            if has_unchanged(new_tuple)
+                && let Some(refetcher) = self.refetcher.as_mut()
+            {
+                let missing: Vec<usize> = new_tuple
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, column)| column.data_type == 'u')
+                    .map(|(index, _)| index)
+                    .collect();
+
+                match refetcher.fetch(relation, new_tuple, &missing).await {
+                    Ok(Some(values)) => {
+                        for (index, value) in missing.into_iter().zip(values) {
+                            new_tuple.columns[index] = match value {
+                                Some(data) => ColumnData {
+                                    data_type: 't',
+                                    length: data.len() as i32,
+                                    data,
+                                    binary: None,
+                                },
+                                None => ColumnData {
+                                    data_type: 'n',
+                                    length: 0,
+                                    data: String::new(),
+                                    binary: None,
+                                },
+                            };
+                        }
+                    }
+                    Ok(None) => debug!(
+                        "Row of {}.{} not found when re-fetching TOAST columns",
+                        relation.namespace, relation.relation_name
+                    ),
+                    Err(e) => warn!("TOAST re-fetch failed: {}", e),
+                }
+            }
+        }
+
+        if let Some(old_key) = old_key
+            && new_key.as_ref() != Some(&old_key)
+        {
+            self.forget(relation.oid, old_key);
+        }
+        self.remember(relation, new_tuple)
+    }
+
+    /// Last values of a row, from the undelivered changes or the store
+    fn cached(&mut self, relation: Oid, key: &[u8]) -> ReplicationResult<Option<CachedRow>> {
+        if let Some(row) = self.pending.rows.get(&(relation, key.to_vec())) {
+            return Ok(row.clone());
+        }
+        match self.store.as_mut() {
+            Some(_) if self.pending.cleared.contains(&relation) => Ok(None),
+            Some(store) => store.get(relation, key),
+            None => Ok(None),
+        }
+    }
+
+    /// Records a deleted row, or the old key of a row whose key changed
+    fn forget(&mut self, relation: Oid, key: Vec<u8>) {
+        if self.store.is_some() {
+            self.pending.rows.insert((relation, key), None);
+        }
+    }
+
+    /// Records the TOASTable values of a row image
+    fn remember(&mut self, relation: &RelationInfo, tuple: &TupleData) -> ReplicationResult<()> {
+        if self.store.is_none() {
+            return Ok(());
+        }
+        let Some(key) = row_key(relation, tuple) else {
+            return Ok(());
+        };
+
+        let mut row: CachedRow = tuple
+            .columns
+            .iter()
+            .zip(&relation.columns)
+            .enumerate()
+            .filter(|(_, (column, info))| {
+                matches!(column.data_type, 't' | 'b') && is_toastable(info.column_type)
+            })
+            .map(|(index, (column, _))| {
+                (
+                    index,
+                    CachedValue {
+                        data: column.data.clone(),
+                        binary: column.binary.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        if row.is_empty() {
+            return Ok(());
+        }
+
+        // Keep previously cached values for columns that are still unresolved
+        if let Some(mut previous) = self.cached(relation.oid, &key)? {
+            previous.extend(row);
+            row = previous;
+        }
+        self.pending.rows.insert((relation.oid, key), Some(row));
+        Ok(())
+    }
+}
+
+impl CachedValue {
+    fn to_column_data(&self) -> ColumnData {
+        ColumnData {
+            data_type: if self.binary.is_some() { 'b' } else { 't' },
+            length: self
+                .binary
+                .as_ref()
+                .map_or(self.data.len(), |bytes| bytes.len()) as i32,
+            data: self.data.clone(),
+            binary: self.binary.clone(),
+        }
+    }
+}
+
+fn has_unchanged(tuple: &TupleData) -> bool {
+    tuple.columns.iter().any(|column| column.data_type == 'u')
+}
+
+/// Encodes the replica identity key columns of a tuple, `None` if any is missing
+fn row_key(relation: &RelationInfo, tuple: &TupleData) -> Option<Vec<u8>> {
+    let mut key = Vec::new();
+    let mut has_key_column = false;
+
+    for (info, column) in relation.columns.iter().zip(&tuple.columns) {
+        if info.key_flag & 1 == 0 {
+            continue;
+        }
+        let bytes = match column.data_type {
+            't' => column.data.as_bytes(),
+            'b' => column.binary.as_deref()?,
+            _ => return None,
+        };
+        has_key_column = true;
+        key.push(column.data_type as u8);
+        key.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        key.extend_from_slice(bytes);
+    }
+
+    has_key_column.then_some(key)
+}
+
+/// Text form of a key column, usable as a SQL literal
+fn key_text(column_type: Oid, column: &ColumnData) -> Option<String> {
+    match column.data_type {
+        't' => Some(column.data.clone()),
+        'b' => {
+            let pg_type = PgType::try_from(column_type).ok()?;
+            let value = decode_binary(&pg_type, column.binary.as_deref()?).ok()?;
+            (&value).try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Whether values of this type can be stored out of line
+///
+/// Fixed-length types never are, so they are not worth caching.
+fn is_toastable(column_type: Oid) -> bool {
+    !matches!(
+        column_type,
+        16 | 18
+            | 20
+            | 21
+            | 23
+            | 26
+            | 700
+            | 701
+            | 790
+            | 829
+            | 774
+            | 1082
+            | 1083
+            | 1114
+            | 1184
+            | 1186
+            | 1266
+            | 2950
+            | 600
+            | 601
+            | 603
+            | 628
+            | 718
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::fixtures::{
+        INT4_OID, TEXT_OID, column, insert, relation, text, tuple, unchanged,
+    };
+
+    fn documents() -> RelationInfo {
+        relation(
+            42,
+            "public",
+            "documents",
+            vec![
+                column("id", INT4_OID, true),
+                column("body", TEXT_OID, false),
+            ],
+        )
+    }
+
+    fn update(old: Option<TupleData>, new: TupleData) -> ReplicationMessage {
+        crate::protocol::fixtures::update(42, old, new)
+    }
+
+    fn body(message: &ReplicationMessage) -> &ColumnData {
+        match message {
+            ReplicationMessage::Update { new_tuple_data, .. } => &new_tuple_data.columns[1],
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_column_resolved_from_cache() {
+        let relations = RelationCache::new();
+        relations.insert(documents());
+        let mut resolver = ToastResolver::new(Some(Box::new(MemoryToastStore::new(10))), None);
+
+        let mut message = insert(42, tuple(vec![text("1"), text("large body")]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+
+        // Values seen earlier in the same transaction are used before delivery
+        let mut message = update(None, tuple(vec![text("1"), unchanged()]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        assert_eq!(body(&message).data_type, 't');
+        assert_eq!(body(&message).data, "large body");
+
+        // Other rows are not affected
+        let mut message = update(None, tuple(vec![text("2"), unchanged()]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        assert_eq!(body(&message).data_type, 'u');
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_column_resolved_from_old_image() {
+        let relations = RelationCache::new();
+        relations.insert(documents());
+        let mut resolver = ToastResolver::new(None, None);
+
+        let mut message = update(
+            Some(tuple(vec![text("1"), text("previous")])),
+            tuple(vec![text("1"), unchanged()]),
+        );
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        assert_eq!(body(&message).data, "previous");
+    }
+
+    #[tokio::test]
+    async fn test_cache_updated_once_delivered() {
+        let relations = RelationCache::new();
+        relations.insert(documents());
+        let mut resolver = ToastResolver::new(Some(Box::new(MemoryToastStore::new(10))), None);
+
+        let mut message = insert(42, tuple(vec![text("1"), text("first")]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        resolver.commit().unwrap();
+
+        // The connection is lost before this update was delivered
+        let mut message = update(None, tuple(vec![text("1"), text("second")]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        resolver.disconnect();
+
+        let mut message = update(None, tuple(vec![text("1"), unchanged()]));
+        resolver.resolve(&mut message, &relations).await.unwrap();
+        assert_eq!(body(&message).data, "first");
+    }
+
+    #[test]
+    fn test_memory_store_evicts_least_recently_used() {
+        let mut store = MemoryToastStore::new(2);
+        let row = |value: &str| {
+            CachedRow::from([(
+                1,
+                CachedValue {
+                    data: value.to_string(),
+                    binary: None,
+                },
+            )])
+        };
+
+        store.put(1, b"a".to_vec(), row("a")).unwrap();
+        store.put(1, b"b".to_vec(), row("b")).unwrap();
+        assert!(store.get(1, b"a").unwrap().is_some());
+        store.put(1, b"c".to_vec(), row("c")).unwrap();
+
+        assert!(store.get(1, b"a").unwrap().is_some());
+        assert!(store.get(1, b"b").unwrap().is_none());
+        assert!(store.get(1, b"c").unwrap().is_some());
+
+        store.clear_relation(1).unwrap();
+        assert!(store.get(1, b"a").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_disk_store_evicts_beyond_capacity() {
+        let dir = std::env::temp_dir().join(format!("walpipe-toast-test-{}", std::process::id()));
+        let row = CachedRow::from([(
+            1,
+            CachedValue {
+                data: "value".to_string(),
+                binary: None,
+            },
+        )]);
+
+        let mut store = DiskToastStore::new(dir.clone(), 2).unwrap();
+        store.put(1, b"a".to_vec(), row.clone()).unwrap();
+        store.put(1, b"b".to_vec(), row.clone()).unwrap();
+        assert!(store.get(1, b"a").unwrap().is_some());
+        store.put(1, b"c".to_vec(), row.clone()).unwrap();
+        assert!(store.get(1, b"b").unwrap().is_none());
+        assert_eq!(fs::read_dir(dir.join("1")).unwrap().count(), 2);
+
+        // Files of a previous run count towards the capacity
+        let mut store = DiskToastStore::new(dir.clone(), 1).unwrap();
+        assert_eq!(fs::read_dir(dir.join("1")).unwrap().count(), 1);
+        store.put(2, b"d".to_vec(), row).unwrap();
+        assert_eq!(fs::read_dir(dir.join("1")).unwrap().count(), 0);
+        assert!(store.get(2, b"d").unwrap().is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
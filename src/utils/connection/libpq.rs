@@ -0,0 +1,496 @@
+//! libpq connection backend
+//!
+//! Provides a safe wrapper around PostgreSQL's C library (libpq)
+//! for replication operations. Handles connection lifecycle, query execution,
+//! and replication protocol operations.
+
+use crate::core::errors::ReplicationResult;
+use libpq_sys::*;
+use std::ffi::{CStr, CString};
+use std::os::fd::{AsRawFd, RawFd};
+use std::ptr;
+use tokio::io::unix::AsyncFd;
+
+pub use libpq_sys::ExecStatusType;
+
+/// Safe wrapper for PostgreSQL connection using libpq
+///
+/// Provides a safe Rust interface to PostgreSQL's C library (libpq)
+/// for replication operations. Handles connection lifecycle, query execution,
+/// and replication protocol operations.
+pub struct PGConnection {
+    conn: *mut PGconn,
+    /// Socket registered with the tokio reactor once in non-blocking mode
+    socket: Option<AsyncFd<PQSocket>>,
+}
+
+/// The connection's socket, owned and closed by libpq
+struct PQSocket(RawFd);
+
+impl AsRawFd for PQSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Outcome of a single attempt to read a COPY message
+enum CopyData {
+    /// A complete message
+    Row(Vec<u8>),
+    /// No complete message available yet (non-blocking mode only)
+    Pending,
+    /// The COPY is done
+    Done,
+}
+
+impl PGConnection {
+    /// Establishes a connection to PostgreSQL using the provided connection info.
+    ///
+    /// This function creates a new PostgreSQL connection using libpq's PQconnectdb function.
+    /// It handles error checking and returns an appropriate error if the connection fails.
+    ///
+    /// # Arguments
+    /// * `conninfo` - A string containing connection parameters (e.g., "host=localhost port=5432 dbname=test")
+    ///
+    /// # Returns
+    /// A Result containing either a PGConnection instance or a ReplicationError
+    pub fn connect(conninfo: &str) -> ReplicationResult<Self> {
+        let c_conninfo = CString::new(conninfo)?;
+        let conn = unsafe { PQconnectdb(c_conninfo.as_ptr()) };
+
+        if conn.is_null() {
+            return Err(crate::core::errors::ReplicationError::connection(
+                "Failed to allocate connection object",
+            ));
+        }
+
+        let status = unsafe { PQstatus(conn) };
+        if status != ConnStatusType::CONNECTION_OK {
+            let error_msg = get_error_message(conn).unwrap_or("Unknown error".to_string());
+            unsafe { PQfinish(conn) };
+            return Err(crate::core::errors::ReplicationError::connection(format!(
+                "Connection failed: {}",
+                error_msg
+            )));
+        }
+
+        Ok(Self { conn, socket: None })
+    }
+
+    /// Executes a query on the PostgreSQL connection.
+    ///
+    /// This function executes a SQL query using libpq's PQexec function and returns
+    /// a PGResult wrapper for handling the results.
+    ///
+    /// # Arguments
+    /// * `query` - The SQL query string to execute
+    ///
+    /// # Returns
+    /// A Result containing either a PGResult instance or a ReplicationError
+    pub fn exec(&self, query: &str) -> ReplicationResult<PGResult> {
+        let c_query = CString::new(query)?;
+        let result = unsafe { PQexec(self.conn, c_query.as_ptr()) };
+
+        if result.is_null() {
+            let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+
+            return Err(crate::core::errors::ReplicationError::protocol(format!(
+                "Query execution failed: {}",
+                error_msg
+            )));
+        }
+
+        Ok(PGResult { result })
+    }
+
+    /// Gets data from a COPY operation (blocking).
+    ///
+    /// Used for plain `COPY ... TO STDOUT` reads; the replication stream uses
+    /// [`PGConnection::get_copy_data_async`] instead.
+    ///
+    /// This function retrieves data from a PostgreSQL COPY operation. It's a wrapper around
+    /// libpq's PQgetCopyData function which handles the low-level details of reading
+    /// data from a COPY stream.
+    ///
+    /// # Note
+    /// If no COPY operation is in progress, this function will return an error.
+    ///
+    /// # Returns
+    /// A Result containing either Some(Vec<u8>) with the data, None if no more data or timeout,
+    /// or a ReplicationError if the operation fails
+    pub fn get_copy_data(&self) -> ReplicationResult<Option<Vec<u8>>> {
+        /*
+        PQgetCopyData attempts to read a row of data from a COPY operation.
+
+        Data is always returned one data row at a time; if only a partial row is available, it is not returned.
+        Successful return of a data row involves allocating a chunk of memory to hold the data.
+        The buffer parameter must be non-NULL. *buffer is set to point to the allocated memory, or to NULL in cases where no buffer is returned. A non-NULL result buffer must be freed using PQfreemem when no longer needed.
+
+        When a row is successfully returned, the return value is the number of data bytes in the row (this will always be greater than zero).
+            The returned string is always null-terminated, though this is probably only useful for textual COPY.
+        A result of zero indicates that the COPY is still in progress, but no row is yet available (this is only possible when async is true).
+        A result of -1 indicates that the COPY is done.
+        A result of -2 indicates that an error occurred (consult PQerrorMessage for the reason).
+
+        When async is true (not zero), PQgetCopyData will not block waiting for input; it will return zero if the COPY is still in progress but no complete row is available.
+            (In this case wait for read-ready and then call PQconsumeInput before calling PQgetCopyData again.)
+        When async is false (zero), PQgetCopyData will block until data is available or the operation completes.
+
+        After PQgetCopyData returns -1, call PQgetResult to obtain the final result status of the COPY command. One may wait for this result to be available in the usual way. Then return to normal operation.
+        */
+        match self.read_copy_data(false)? {
+            CopyData::Row(data) => Ok(Some(data)),
+            CopyData::Pending | CopyData::Done => Ok(None),
+        }
+    }
+
+    /// Switches the connection to non-blocking mode and registers its socket
+    /// with the tokio reactor, for use with the `*_async` methods.
+    ///
+    /// Must be called from within the tokio runtime, once the connection is
+    /// only used for COPY traffic: queries are not run in non-blocking mode.
+    pub fn enable_async(&mut self) -> ReplicationResult<()> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+
+        if unsafe { PQsetnonblocking(self.conn, 1) } != 0 {
+            let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+            return Err(crate::core::errors::ReplicationError::connection(format!(
+                "Failed to switch to non-blocking mode: {}",
+                error_msg
+            )));
+        }
+
+        let fd = unsafe { PQsocket(self.conn) };
+        if fd < 0 {
+            return Err(crate::core::errors::ReplicationError::connection(
+                "Connection has no open socket",
+            ));
+        }
+        self.socket = Some(AsyncFd::new(PQSocket(fd))?);
+        Ok(())
+    }
+
+    /// Waits for the next message of a COPY operation without blocking the runtime.
+    ///
+    /// Requires [`PGConnection::enable_async`]. Returns `None` once the COPY is done.
+    /// Cancel safe: a message is only read once it is complete.
+    pub async fn get_copy_data_async(&self) -> ReplicationResult<Option<Vec<u8>>> {
+        let socket = self.async_socket()?;
+        loop {
+            match self.read_copy_data(true)? {
+                CopyData::Row(data) => return Ok(Some(data)),
+                CopyData::Done => return Ok(None),
+                CopyData::Pending => {
+                    let mut guard = socket.readable().await?;
+                    self.consume_input()?;
+                    match self.read_copy_data(true)? {
+                        CopyData::Row(data) => return Ok(Some(data)),
+                        CopyData::Done => return Ok(None),
+                        // Everything available was read, wait for more
+                        CopyData::Pending => guard.clear_ready(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends all queued output without blocking the runtime.
+    ///
+    /// Requires [`PGConnection::enable_async`]. While the socket is not
+    /// writable, incoming data is consumed so that the server never blocks on us.
+    pub async fn flush_async(&self) -> ReplicationResult<()> {
+        let socket = self.async_socket()?;
+        loop {
+            match unsafe { PQflush(self.conn) } {
+                0 => return Ok(()),
+                1 => {
+                    tokio::select! {
+                        guard = socket.writable() => guard?.clear_ready(),
+                        guard = socket.readable() => {
+                            let mut guard = guard?;
+                            self.consume_input()?;
+                            guard.clear_ready();
+                        }
+                    }
+                }
+                _ => {
+                    return Err(crate::core::errors::ReplicationError::protocol(
+                        "Failed to flush connection",
+                    ));
+                }
+            }
+        }
+    }
+
+    fn async_socket(&self) -> ReplicationResult<&AsyncFd<PQSocket>> {
+        self.socket.as_ref().ok_or_else(|| {
+            crate::core::errors::ReplicationError::connection(
+                "Connection is not in non-blocking mode",
+            )
+        })
+    }
+
+    /// Reads input available on the socket into libpq's buffer
+    fn consume_input(&self) -> ReplicationResult<()> {
+        if unsafe { PQconsumeInput(self.conn) } == 0 {
+            let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+            return Err(crate::core::errors::ReplicationError::connection(error_msg));
+        }
+        Ok(())
+    }
+
+    /// Reads one COPY message, waiting for it unless `async_mode` is set
+    fn read_copy_data(&self, async_mode: bool) -> ReplicationResult<CopyData> {
+        let mut buffer: *mut std::os::raw::c_char = ptr::null_mut();
+        let copy_data_len =
+            unsafe { PQgetCopyData(self.conn, &mut buffer, async_mode as std::os::raw::c_int) };
+
+        match copy_data_len {
+            -2 => {
+                let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+
+                Err(crate::core::errors::ReplicationError::protocol(error_msg))
+            }
+            -1 => {
+                let result = PGResult {
+                    result: unsafe { PQgetResult(self.conn) },
+                };
+
+                if !result.is_ok() {
+                    let error_msg =
+                        get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+                    return Err(crate::core::errors::ReplicationError::protocol(error_msg));
+                }
+
+                Ok(CopyData::Done)
+            } // COPY is done
+            0 => Ok(CopyData::Pending), // COPY still in progress, no data available (async mode only)
+            len => {
+                if buffer.is_null() {
+                    return Err(crate::core::errors::ReplicationError::buffer(
+                        "Received null buffer",
+                    ));
+                }
+
+                let data = unsafe {
+                    std::slice::from_raw_parts(buffer as *const u8, len as usize).to_vec()
+                };
+
+                unsafe { PQfreemem(buffer as *mut std::os::raw::c_void) };
+                Ok(CopyData::Row(data))
+            }
+        }
+    }
+
+    /// Sends data to a COPY operation.
+    ///
+    /// This function sends data to a PostgreSQL COPY operation. It's a wrapper around
+    /// libpq's PQputCopyData function which handles the low-level details of sending
+    /// data to a COPY stream.
+    ///
+    /// # Arguments
+    /// * `data` - The data to send as bytes
+    ///
+    /// # Returns
+    /// A Result indicating success or failure of the operation
+    pub fn put_copy_data(&self, data: &[u8]) -> ReplicationResult<()> {
+        let result = unsafe {
+            PQputCopyData(
+                self.conn,
+                data.as_ptr() as *const std::os::raw::c_char,
+                data.len() as i32,
+            )
+        };
+
+        if result != 1 {
+            let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+
+            return Err(crate::core::errors::ReplicationError::protocol(format!(
+                "Failed to send copy data: {}",
+                error_msg
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Escapes a string for use as an SQL identifier.
+    ///
+    /// Wraps libpq's PQescapeIdentifier, which also adds the surrounding double quotes.
+    pub fn escape_identifier(&self, identifier: &str) -> ReplicationResult<String> {
+        self.escape(identifier, PQescapeIdentifier)
+    }
+
+    /// Escapes a string for use as an SQL string literal.
+    ///
+    /// Wraps libpq's PQescapeLiteral, which also adds the surrounding single quotes.
+    pub fn escape_literal(&self, literal: &str) -> ReplicationResult<String> {
+        self.escape(literal, PQescapeLiteral)
+    }
+
+    fn escape(
+        &self,
+        value: &str,
+        escape_fn: unsafe extern "C" fn(
+            *mut PGconn,
+            *const std::os::raw::c_char,
+            usize,
+        ) -> *mut std::os::raw::c_char,
+    ) -> ReplicationResult<String> {
+        let escaped = unsafe {
+            escape_fn(
+                self.conn,
+                value.as_ptr() as *const std::os::raw::c_char,
+                value.len(),
+            )
+        };
+
+        if escaped.is_null() {
+            let error_msg = get_error_message(self.conn).unwrap_or("Unknown error".to_string());
+            return Err(crate::core::errors::ReplicationError::protocol(format!(
+                "Failed to escape value: {}",
+                error_msg
+            )));
+        }
+
+        let result = unsafe { CStr::from_ptr(escaped).to_string_lossy().into_owned() };
+        unsafe { PQfreemem(escaped as *mut std::os::raw::c_void) };
+        Ok(result)
+    }
+
+    /// Flushes the connection buffer.
+    ///
+    /// This function flushes any buffered output on the connection. It's a wrapper around
+    /// libpq's PQflush function which ensures that all pending data is sent to the server.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure of the operation
+    pub fn flush(&self) -> ReplicationResult<()> {
+        match unsafe { PQflush(self.conn) } {
+            0 => Ok(()),
+            // In non-blocking mode the rest is sent by a later flush
+            1 if self.socket.is_some() => Ok(()),
+            _ => Err(crate::core::errors::ReplicationError::protocol(
+                "Failed to flush connection",
+            )),
+        }
+    }
+}
+
+impl Drop for PGConnection {
+    fn drop(&mut self) {
+        // Deregister the socket before libpq closes it
+        self.socket = None;
+        if !self.conn.is_null() {
+            unsafe { PQfinish(self.conn) };
+        }
+    }
+}
+
+fn get_error_message(conn: *const PGconn) -> Option<String> {
+    unsafe {
+        let error_ptr = PQerrorMessage(conn);
+        if error_ptr.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(error_ptr).to_string_lossy().into_owned())
+        }
+    }
+}
+
+/// Safe wrapper for PostgreSQL result.
+///
+/// This struct provides a safe interface to PostgreSQL query results using libpq.
+/// It handles access to result metadata and data values.
+pub struct PGResult {
+    result: *mut PGresult,
+}
+
+impl PGResult {
+    /// Gets the status of the PostgreSQL result.
+    ///
+    /// This function returns the execution status of the query that produced this result.
+    ///
+    /// # Returns
+    /// An ExecStatusType representing the result status
+    pub fn status(&self) -> ExecStatusType {
+        unsafe { PQresultStatus(self.result) }
+    }
+
+    /// Checks if the PostgreSQL result is successful.
+    ///
+    /// This function determines whether the query that produced this result was successful.
+    /// It returns true for PGRES_TUPLES_OK and PGRES_COMMAND_OK status codes.
+    ///
+    /// # Returns
+    /// A boolean indicating whether the operation was successful
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self.status(),
+            ExecStatusType::PGRES_TUPLES_OK | ExecStatusType::PGRES_COMMAND_OK
+        )
+    }
+
+    /// Gets the number of tuples (rows) in the result.
+    ///
+    /// This function returns the number of rows returned by the query.
+    ///
+    /// # Returns
+    /// An i32 representing the number of tuples
+    #[allow(unused)]
+    pub fn ntuples(&self) -> i32 {
+        unsafe { PQntuples(self.result) }
+    }
+
+    /// Gets the number of fields (columns) in the result.
+    ///
+    /// This function returns the number of columns returned by the query.
+    ///
+    /// # Returns
+    /// An i32 representing the number of fields
+    #[allow(unused)]
+    pub fn nfields(&self) -> i32 {
+        unsafe { PQnfields(self.result) }
+    }
+
+    /// Gets a value from the result by row and column index.
+    ///
+    /// This function retrieves a specific value from the query result based on its position.
+    ///
+    /// # Arguments
+    /// * `row` - The row index (0-based)
+    /// * `col` - The column index (0-based)
+    ///
+    /// # Returns
+    /// An Option<String> containing the value, or None if not found
+    pub fn getvalue(&self, row: i32, col: i32) -> Option<String> {
+        let value_ptr = unsafe { PQgetvalue(self.result, row, col) };
+        if value_ptr.is_null() {
+            None
+        } else {
+            unsafe { Some(CStr::from_ptr(value_ptr).to_string_lossy().into_owned()) }
+        }
+    }
+
+    /// Checks whether a value in the result is SQL NULL.
+    ///
+    /// `getvalue` returns an empty string for NULL values, so this is needed
+    /// to tell them apart from empty strings.
+    ///
+    /// # Arguments
+    /// * `row` - The row index (0-based)
+    /// * `col` - The column index (0-based)
+    pub fn is_null(&self, row: i32, col: i32) -> bool {
+        unsafe { PQgetisnull(self.result, row, col) == 1 }
+    }
+}
+
+impl Drop for PGResult {
+    fn drop(&mut self) {
+        if !self.result.is_null() {
+            unsafe { PQclear(self.result) };
+        }
+    }
+}
\ No newline at end of file
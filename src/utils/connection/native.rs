@@ -0,0 +1,832 @@
+//! Native connection backend
+//!
+//! Speaks the PostgreSQL frontend/backend protocol (version 3.0) directly,
+//! without libpq: startup (with `replication=database` for replication
+//! connections), cleartext, md5 and SCRAM-SHA-256 authentication, simple
+//! queries, COPY OUT and CopyBoth streaming, over TCP or Unix sockets with
+//! optional TLS.
+//!
+//! Like libpq, queries and the initial handshake block. Once
+//! [`PGConnection::enable_async`] is called the socket is registered with the
+//! tokio reactor and the `*_async` methods never block the runtime.
+
+mod conninfo;
+mod tls;
+
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use conninfo::{ConnectionParams, Host};
+use postgres_protocol::authentication::md5_hash;
+use postgres_protocol::authentication::sasl::{ChannelBinding, SCRAM_SHA_256, ScramSha256};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use tokio::io::unix::AsyncFd;
+use tracing::debug;
+
+/// Protocol version 3.0
+const PROTOCOL_VERSION: i32 = 196608;
+/// Request code of an SSLRequest message
+const SSL_REQUEST_CODE: i32 = 80877103;
+/// Size of a single socket read
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Status of a query result, named after libpq's `ExecStatusType`
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecStatusType {
+    PGRES_EMPTY_QUERY,
+    PGRES_COMMAND_OK,
+    PGRES_TUPLES_OK,
+    PGRES_COPY_OUT,
+    PGRES_COPY_IN,
+    PGRES_COPY_BOTH,
+    PGRES_FATAL_ERROR,
+}
+
+/// Connection to PostgreSQL over the native protocol implementation
+///
+/// Offers the same operations as the libpq backend, with the same
+/// `&self` signatures: the session state lives behind a `RefCell`.
+pub struct PGConnection {
+    session: RefCell<Session>,
+    /// Socket registered with the tokio reactor once in non-blocking mode
+    socket: Option<AsyncFd<SessionSocket>>,
+}
+
+/// The session's socket, owned and closed by the session's stream
+struct SessionSocket(RawFd);
+
+impl AsRawFd for SessionSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<tls::TlsStream>),
+}
+
+impl Stream {
+    fn raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tls(stream) => stream.sock.as_raw_fd(),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(stream) => stream.sock.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A message received from the server
+struct BackendMessage {
+    tag: u8,
+    body: Vec<u8>,
+}
+
+/// Socket with its input and output buffers
+struct Session {
+    stream: Stream,
+    /// Received bytes not parsed into messages yet
+    input: Vec<u8>,
+    /// Queued messages not sent yet
+    output: Vec<u8>,
+}
+
+impl Session {
+    /// Queues a message with a type byte
+    fn queue(&mut self, tag: u8, body: &[u8]) {
+        self.output.push(tag);
+        self.output
+            .extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        self.output.extend_from_slice(body);
+    }
+
+    /// Next complete message in the input buffer
+    fn take_message(&mut self) -> Option<BackendMessage> {
+        if self.input.len() < 5 {
+            return None;
+        }
+        let length =
+            i32::from_be_bytes([self.input[1], self.input[2], self.input[3], self.input[4]]);
+        let end = 1 + length.max(4) as usize;
+        if self.input.len() < end {
+            return None;
+        }
+        let body = self.input[5..end].to_vec();
+        let tag = self.input[0];
+        self.input.drain(..end);
+        Some(BackendMessage { tag, body })
+    }
+
+    /// Reads what the socket has into the input buffer
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        match self.stream.read(&mut chunk)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection unexpectedly",
+            )),
+            read => {
+                self.input.extend_from_slice(&chunk[..read]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for the next message (blocking mode)
+    fn receive(&mut self) -> ReplicationResult<BackendMessage> {
+        loop {
+            if let Some(message) = self.take_message() {
+                return Ok(message);
+            }
+            self.fill().map_err(connection_error)?;
+        }
+    }
+
+    /// Sends all queued output (blocking mode)
+    fn send(&mut self) -> ReplicationResult<()> {
+        self.stream
+            .write_all(&self.output)
+            .map_err(connection_error)?;
+        self.output.clear();
+        self.stream.flush().map_err(connection_error)
+    }
+
+    /// Sends as much queued output as the socket takes (non-blocking mode)
+    ///
+    /// Fails with `WouldBlock` while output remains.
+    fn send_available(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => {
+                    self.output.drain(..written);
+                }
+            }
+        }
+        // Encrypted data may still be buffered by the TLS layer
+        self.stream.flush()
+    }
+}
+
+impl PGConnection {
+    /// Establishes a connection to PostgreSQL using the provided connection info.
+    ///
+    /// Accepts the key/value and URI formats of libpq. TLS is negotiated
+    /// according to `sslmode` (`prefer` by default) on TCP connections.
+    ///
+    /// # Arguments
+    /// * `conninfo` - A string containing connection parameters (e.g., "host=localhost port=5432 dbname=test")
+    ///
+    /// # Returns
+    /// A Result containing either a PGConnection instance or a ReplicationError
+    pub fn connect(conninfo: &str) -> ReplicationResult<Self> {
+        let params = ConnectionParams::parse(conninfo)?;
+        let stream = Self::open_stream(&params)
+            .map_err(|e| ReplicationError::connection(format!("Connection failed: {}", e)))?;
+
+        let mut session = Session {
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        startup(&mut session, &params)
+            .map_err(|e| ReplicationError::connection(format!("Connection failed: {}", e)))?;
+
+        Ok(Self {
+            session: RefCell::new(session),
+            socket: None,
+        })
+    }
+
+    fn open_stream(params: &ConnectionParams) -> ReplicationResult<Stream> {
+        let host = match &params.host {
+            Host::Unix(dir) => {
+                let path = dir.join(format!(".s.PGSQL.{}", params.port));
+                let stream = UnixStream::connect(&path).map_err(|e| {
+                    ReplicationError::connection(format!("{}: {}", path.display(), e))
+                })?;
+                return Ok(Stream::Unix(stream));
+            }
+            Host::Tcp(host) => host,
+        };
+
+        let mut stream = TcpStream::connect((host.as_str(), params.port)).map_err(|e| {
+            ReplicationError::connection(format!("{}:{}: {}", host, params.port, e))
+        })?;
+        stream.set_nodelay(true).map_err(connection_error)?;
+        if !params.sslmode.attempts_tls() {
+            return Ok(Stream::Tcp(stream));
+        }
+
+        let mut request = Vec::with_capacity(8);
+        request.extend_from_slice(&8i32.to_be_bytes());
+        request.extend_from_slice(&SSL_REQUEST_CODE.to_be_bytes());
+        stream.write_all(&request).map_err(connection_error)?;
+        let mut answer = [0u8; 1];
+        stream.read_exact(&mut answer).map_err(connection_error)?;
+
+        match answer[0] {
+            b'S' => {
+                let stream =
+                    tls::connect(stream, host, params.sslmode, params.sslrootcert.as_deref())?;
+                Ok(Stream::Tls(Box::new(stream)))
+            }
+            b'N' if params.sslmode.requires_tls() => Err(ReplicationError::connection(
+                "server does not support SSL, but SSL was required",
+            )),
+            b'N' => Ok(Stream::Tcp(stream)),
+            other => Err(ReplicationError::protocol(format!(
+                "Unexpected response to SSL negotiation: {:?}",
+                other as char
+            ))),
+        }
+    }
+
+    /// Executes a query on the PostgreSQL connection.
+    ///
+    /// Runs `query` with the simple query protocol and collects its rows.
+    /// Returns as soon as the server starts a COPY, leaving the connection
+    /// in COPY mode as libpq does.
+    ///
+    /// # Arguments
+    /// * `query` - The SQL query string to execute
+    ///
+    /// # Returns
+    /// A Result containing either a PGResult instance or a ReplicationError
+    pub fn exec(&self, query: &str) -> ReplicationResult<PGResult> {
+        if self.socket.is_some() {
+            return Err(ReplicationError::protocol(
+                "Query execution failed: connection is in non-blocking mode",
+            ));
+        }
+
+        let mut session = self.session.borrow_mut();
+        let mut body = query.as_bytes().to_vec();
+        body.push(0);
+        session.queue(b'Q', &body);
+        session.send()?;
+
+        // Like PQexec, the result of the last statement is returned
+        let mut result = PGResult::new(ExecStatusType::PGRES_EMPTY_QUERY);
+        let mut described = false;
+        let mut error = None;
+        loop {
+            let message = session.receive()?;
+            let mut body = Reader::new(&message.body);
+            match message.tag {
+                // RowDescription
+                b'T' => {
+                    result = PGResult::new(ExecStatusType::PGRES_TUPLES_OK);
+                    result.nfields = body.i16()? as i32;
+                    described = true;
+                }
+                // DataRow
+                b'D' => {
+                    let count = body.i16()?;
+                    let mut row = Vec::with_capacity(count.max(0) as usize);
+                    for _ in 0..count {
+                        row.push(match body.i32()? {
+                            -1 => None,
+                            length => Some(
+                                String::from_utf8_lossy(body.bytes(length.max(0) as usize)?)
+                                    .into_owned(),
+                            ),
+                        });
+                    }
+                    result.rows.push(row);
+                }
+                // CommandComplete
+                b'C' => {
+                    if !described {
+                        result = PGResult::new(ExecStatusType::PGRES_COMMAND_OK);
+                    }
+                    described = false;
+                }
+                b'I' => result = PGResult::new(ExecStatusType::PGRES_EMPTY_QUERY),
+                b'E' => error = Some(error_message(&message.body)),
+                b'H' => return Ok(PGResult::new(ExecStatusType::PGRES_COPY_OUT)),
+                b'G' => return Ok(PGResult::new(ExecStatusType::PGRES_COPY_IN)),
+                b'W' => return Ok(PGResult::new(ExecStatusType::PGRES_COPY_BOTH)),
+                // ReadyForQuery
+                b'Z' => break,
+                // Notices, parameter changes and notifications
+                _ => {}
+            }
+        }
+
+        if let Some(error) = error {
+            debug!("Query failed: {}", error);
+            return Ok(PGResult::new(ExecStatusType::PGRES_FATAL_ERROR));
+        }
+        Ok(result)
+    }
+
+    /// Gets data from a COPY operation (blocking).
+    ///
+    /// Used for plain `COPY ... TO STDOUT` reads; the replication stream uses
+    /// [`PGConnection::get_copy_data_async`] instead.
+    ///
+    /// # Returns
+    /// A Result containing either Some(Vec<u8>) with the data, None once the COPY is done,
+    /// or a ReplicationError if the operation fails
+    pub fn get_copy_data(&self) -> ReplicationResult<Option<Vec<u8>>> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            let message = session.receive()?;
+            if let Some(data) = copy_message(&mut session, message, true)? {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Switches the connection to non-blocking mode and registers its socket
+    /// with the tokio reactor, for use with the `*_async` methods.
+    ///
+    /// Must be called from within the tokio runtime, once the connection is
+    /// only used for COPY traffic: queries are not run in non-blocking mode.
+    pub fn enable_async(&mut self) -> ReplicationResult<()> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+
+        let session = self.session.get_mut();
+        session.stream.set_nonblocking(true).map_err(|e| {
+            ReplicationError::connection(format!("Failed to switch to non-blocking mode: {}", e))
+        })?;
+        self.socket = Some(AsyncFd::new(SessionSocket(session.stream.raw_fd()))?);
+        Ok(())
+    }
+
+    /// Waits for the next message of a COPY operation without blocking the runtime.
+    ///
+    /// Requires [`PGConnection::enable_async`]. Returns `None` once the COPY is done.
+    /// Cancel safe: a message is only taken from the buffer once it is complete.
+    pub async fn get_copy_data_async(&self) -> ReplicationResult<Option<Vec<u8>>> {
+        let socket = self.async_socket()?;
+        loop {
+            {
+                let mut session = self.session.borrow_mut();
+                while let Some(message) = session.take_message() {
+                    if let Some(data) = copy_message(&mut session, message, false)? {
+                        return Ok(data);
+                    }
+                }
+                // The TLS layer may hold decrypted data the socket no longer signals
+                match session.fill() {
+                    Ok(()) => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(connection_error(e)),
+                }
+            }
+
+            let mut guard = socket.readable().await?;
+            if let Ok(result) = guard.try_io(|_| self.session.borrow_mut().fill()) {
+                result.map_err(connection_error)?;
+            }
+        }
+    }
+
+    /// Sends all queued output without blocking the runtime.
+    ///
+    /// Requires [`PGConnection::enable_async`]. Like libpq, input is read into
+    /// the buffer while the socket is not writable: a server blocked on sending
+    /// to us would otherwise never read what we send.
+    pub async fn flush_async(&self) -> ReplicationResult<()> {
+        let socket = self.async_socket()?;
+        loop {
+            tokio::select! {
+                guard = socket.writable() => {
+                    let mut guard = guard?;
+                    if let Ok(result) =
+                        guard.try_io(|_| self.session.borrow_mut().send_available())
+                    {
+                        return result.map_err(connection_error);
+                    }
+                }
+                guard = socket.readable() => {
+                    let mut guard = guard?;
+                    if let Ok(result) = guard.try_io(|_| self.session.borrow_mut().fill()) {
+                        result.map_err(connection_error)?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn async_socket(&self) -> ReplicationResult<&AsyncFd<SessionSocket>> {
+        self.socket
+            .as_ref()
+            .ok_or_else(|| ReplicationError::connection("Connection is not in non-blocking mode"))
+    }
+
+    /// Sends data to a COPY operation.
+    ///
+    /// The data is queued as a CopyData message and sent by the next flush.
+    ///
+    /// # Arguments
+    /// * `data` - The data to send as bytes
+    ///
+    /// # Returns
+    /// A Result indicating success or failure of the operation
+    pub fn put_copy_data(&self, data: &[u8]) -> ReplicationResult<()> {
+        self.session.borrow_mut().queue(b'd', data);
+        Ok(())
+    }
+
+    /// Escapes a string for use as an SQL identifier.
+    ///
+    /// Follows libpq's PQescapeIdentifier, which also adds the surrounding double quotes.
+    pub fn escape_identifier(&self, identifier: &str) -> ReplicationResult<String> {
+        Ok(format!("\"{}\"", identifier.replace('"', "\"\"")))
+    }
+
+    /// Escapes a string for use as an SQL string literal.
+    ///
+    /// Follows libpq's PQescapeLiteral, which also adds the surrounding single
+    /// quotes and switches to the `E''` syntax when backslashes are present.
+    pub fn escape_literal(&self, literal: &str) -> ReplicationResult<String> {
+        let escaped = literal.replace('\'', "''");
+        // libpq puts a space before the E so it cannot join a preceding word
+        if literal.contains('\\') {
+            Ok(format!(" E'{}'", escaped.replace('\\', "\\\\")))
+        } else {
+            Ok(format!("'{}'", escaped))
+        }
+    }
+
+    /// Flushes the connection buffer.
+    ///
+    /// Sends any queued output to the server. In non-blocking mode the rest
+    /// is sent by a later flush.
+    ///
+    /// # Returns
+    /// A Result indicating success or failure of the operation
+    pub fn flush(&self) -> ReplicationResult<()> {
+        let mut session = self.session.borrow_mut();
+        if self.socket.is_none() {
+            return session.send();
+        }
+        match session.send_available() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(connection_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for PGConnection {
+    fn drop(&mut self) {
+        // Deregister the socket before the stream closes it
+        self.socket = None;
+        let session = self.session.get_mut();
+        session.output.clear();
+        session.queue(b'X', &[]);
+        let _ = session.send_available();
+    }
+}
+
+/// Authenticates and waits for the server to be ready for queries
+fn startup(session: &mut Session, params: &ConnectionParams) -> ReplicationResult<()> {
+    let mut parameters = vec![
+        ("user", params.user.as_str()),
+        ("database", params.dbname.as_str()),
+        ("client_encoding", "UTF8"),
+    ];
+    if let Some(replication) = &params.replication {
+        parameters.push(("replication", replication));
+    }
+    if let Some(application_name) = &params.application_name {
+        parameters.push(("application_name", application_name));
+    }
+    if let Some(options) = &params.options {
+        parameters.push(("options", options));
+    }
+
+    // The startup message has no type byte
+    let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    for (name, value) in parameters {
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    session
+        .output
+        .extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+    session.output.extend_from_slice(&body);
+    session.send()?;
+
+    let password = || {
+        params.password.as_deref().ok_or_else(|| {
+            ReplicationError::connection("password authentication failed: no password supplied")
+        })
+    };
+    let mut scram: Option<ScramSha256> = None;
+
+    loop {
+        let message = session.receive()?;
+        let mut body = Reader::new(&message.body);
+        match message.tag {
+            b'R' => match body.i32()? {
+                // AuthenticationOk
+                0 => {}
+                // AuthenticationCleartextPassword
+                3 => {
+                    session.queue(b'p', &null_terminated(password()?));
+                    session.send()?;
+                }
+                // AuthenticationMD5Password
+                5 => {
+                    let salt: [u8; 4] = body.bytes(4)?.try_into().unwrap_or_default();
+                    let hash = md5_hash(params.user.as_bytes(), password()?.as_bytes(), salt);
+                    session.queue(b'p', &null_terminated(&hash));
+                    session.send()?;
+                }
+                // AuthenticationSASL
+                10 => {
+                    let mechanisms = body.rest();
+                    let offered = mechanisms
+                        .split(|byte| *byte == 0)
+                        .any(|mechanism| mechanism == SCRAM_SHA_256.as_bytes());
+                    if !offered {
+                        return Err(ReplicationError::connection(
+                            "server offered no supported SASL mechanism",
+                        ));
+                    }
+                    let exchange =
+                        ScramSha256::new(password()?.as_bytes(), ChannelBinding::unsupported());
+                    let mut response = null_terminated(SCRAM_SHA_256);
+                    response.extend_from_slice(&(exchange.message().len() as i32).to_be_bytes());
+                    response.extend_from_slice(exchange.message());
+                    session.queue(b'p', &response);
+                    session.send()?;
+                    scram = Some(exchange);
+                }
+                // AuthenticationSASLContinue
+                11 => {
+                    let exchange = scram
+                        .as_mut()
+                        .ok_or_else(|| ReplicationError::protocol("Unexpected SASL message"))?;
+                    exchange.update(body.rest()).map_err(connection_error)?;
+                    let response = exchange.message().to_vec();
+                    session.queue(b'p', &response);
+                    session.send()?;
+                }
+                // AuthenticationSASLFinal
+                12 => {
+                    let mut exchange = scram
+                        .take()
+                        .ok_or_else(|| ReplicationError::protocol("Unexpected SASL message"))?;
+                    exchange.finish(body.rest()).map_err(connection_error)?;
+                }
+                other => {
+                    return Err(ReplicationError::connection(format!(
+                        "unsupported authentication method (code {})",
+                        other
+                    )));
+                }
+            },
+            b'E' => return Err(ReplicationError::connection(error_message(&message.body))),
+            // ReadyForQuery
+            b'Z' => return Ok(()),
+            // ParameterStatus, BackendKeyData and notices
+            _ => {}
+        }
+    }
+}
+
+/// Handles a message received during a COPY
+///
+/// Returns `Some(Some(data))` for a row, `Some(None)` once the COPY is done
+/// and `None` for messages that carry no data. In blocking mode the end of
+/// the COPY is read through to ReadyForQuery so that queries can follow.
+fn copy_message(
+    session: &mut Session,
+    message: BackendMessage,
+    blocking: bool,
+) -> ReplicationResult<Option<Option<Vec<u8>>>> {
+    match message.tag {
+        // CopyData
+        b'd' => Ok(Some(Some(message.body))),
+        // CopyDone, then the command's completion and ReadyForQuery
+        b'c' if blocking => {
+            let mut error = None;
+            loop {
+                let message = session.receive()?;
+                match message.tag {
+                    b'E' => error = Some(error_message(&message.body)),
+                    b'Z' => break,
+                    _ => {}
+                }
+            }
+            match error {
+                Some(error) => Err(ReplicationError::protocol(error)),
+                None => Ok(Some(None)),
+            }
+        }
+        b'c' => Ok(Some(None)),
+        b'E' => Err(ReplicationError::protocol(error_message(&message.body))),
+        _ => Ok(None),
+    }
+}
+
+/// Human readable text of an ErrorResponse
+fn error_message(body: &[u8]) -> String {
+    let mut severity = "ERROR".to_string();
+    let mut text = String::new();
+    for field in body
+        .split(|byte| *byte == 0)
+        .filter(|field| !field.is_empty())
+    {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'S' => severity = value,
+            b'M' => text = value,
+            _ => {}
+        }
+    }
+    format!("{}:  {}", severity, text)
+}
+
+fn null_terminated(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn connection_error(error: io::Error) -> ReplicationError {
+    ReplicationError::connection(error.to_string())
+}
+
+/// Reads the fields of a message body
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> ReplicationResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(ReplicationError::protocol(
+                "Truncated message from the server",
+            ));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn i16(&mut self) -> ReplicationResult<i16> {
+        let bytes = self.bytes(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i32(&mut self) -> ReplicationResult<i32> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// Result of a query run over the native protocol
+pub struct PGResult {
+    status: ExecStatusType,
+    nfields: i32,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+impl PGResult {
+    fn new(status: ExecStatusType) -> Self {
+        Self {
+            status,
+            nfields: 0,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Gets the status of the PostgreSQL result.
+    pub fn status(&self) -> ExecStatusType {
+        self.status
+    }
+
+    /// Checks if the PostgreSQL result is successful.
+    ///
+    /// Returns true for PGRES_TUPLES_OK and PGRES_COMMAND_OK status codes.
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self.status,
+            ExecStatusType::PGRES_TUPLES_OK | ExecStatusType::PGRES_COMMAND_OK
+        )
+    }
+
+    /// Gets the number of tuples (rows) in the result.
+    #[allow(unused)]
+    pub fn ntuples(&self) -> i32 {
+        self.rows.len() as i32
+    }
+
+    /// Gets the number of fields (columns) in the result.
+    #[allow(unused)]
+    pub fn nfields(&self) -> i32 {
+        self.nfields
+    }
+
+    /// Gets a value from the result by row and column index.
+    ///
+    /// Like libpq, SQL NULL values are returned as empty strings; `None`
+    /// means the position is out of range.
+    pub fn getvalue(&self, row: i32, col: i32) -> Option<String> {
+        self.value(row, col)
+            .map(|value| value.clone().unwrap_or_default())
+    }
+
+    /// Checks whether a value in the result is SQL NULL.
+    pub fn is_null(&self, row: i32, col: i32) -> bool {
+        matches!(self.value(row, col), Some(None))
+    }
+
+    fn value(&self, row: i32, col: i32) -> Option<&Option<String>> {
+        let row = self.rows.get(usize::try_from(row).ok()?)?;
+        row.get(usize::try_from(col).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message_fields() {
+        let body = b"SERROR\0VERROR\0C42P01\0Mrelation \"missing\" does not exist\0\0";
+        assert_eq!(
+            error_message(body),
+            "ERROR:  relation \"missing\" does not exist"
+        );
+    }
+
+    #[test]
+    fn test_escape_like_libpq() {
+        let session = Session {
+            stream: Stream::Unix(UnixStream::pair().unwrap().0),
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        let connection = PGConnection {
+            session: RefCell::new(session),
+            socket: None,
+        };
+
+        assert_eq!(
+            connection.escape_identifier("my \"table\"").unwrap(),
+            "\"my \"\"table\"\"\""
+        );
+        assert_eq!(connection.escape_literal("it's").unwrap(), "'it''s'");
+        assert_eq!(connection.escape_literal("a\\b").unwrap(), " E'a\\\\b'");
+    }
+}
@@ -0,0 +1,218 @@
+//! TLS for the native backend, with libpq's `sslmode` semantics
+//!
+//! - `prefer` and `require` encrypt without checking the server certificate
+//! - `verify-ca` checks the certificate chain against `sslrootcert`, which is
+//!   required: a public authority certifies any domain, so its roots alone
+//!   would let any server through without the host name check
+//! - `verify-full` additionally checks that the certificate names the host,
+//!   against the WebPKI roots when no root certificate is given
+
+use super::conninfo::SslMode;
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Performs the TLS handshake over a socket the server accepted SSL on
+pub fn connect(
+    mut socket: TcpStream,
+    host: &str,
+    sslmode: SslMode,
+    sslrootcert: Option<&Path>,
+) -> ReplicationResult<TlsStream> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier: Arc<dyn ServerCertVerifier> = match sslmode {
+        SslMode::VerifyCa | SslMode::VerifyFull => {
+            let roots = root_certificates(sslmode, sslrootcert)?;
+            let webpki =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()
+                    .map_err(|e| {
+                        ReplicationError::connection(format!("Invalid TLS roots: {}", e))
+                    })?;
+            Arc::new(ChainVerifier {
+                inner: webpki,
+                check_host: sslmode == SslMode::VerifyFull,
+            })
+        }
+        _ => Arc::new(AcceptAnyCertificate(
+            provider.signature_verification_algorithms,
+        )),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ReplicationError::connection(format!("TLS setup failed: {}", e)))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| ReplicationError::connection(format!("Invalid TLS server name: {}", e)))?;
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)
+        .map_err(|e| ReplicationError::connection(format!("TLS setup failed: {}", e)))?;
+
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut socket)
+            .map_err(|e| ReplicationError::connection(format!("TLS handshake failed: {}", e)))?;
+    }
+    Ok(StreamOwned::new(connection, socket))
+}
+
+fn root_certificates(
+    sslmode: SslMode,
+    sslrootcert: Option<&Path>,
+) -> ReplicationResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match sslrootcert {
+        Some(path) => {
+            let certificates = CertificateDer::pem_file_iter(path)
+                .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| {
+                    ReplicationError::connection(format!(
+                        "Failed to read root certificate {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+            let (_, ignored) = roots.add_parsable_certificates(certificates);
+            if roots.is_empty() {
+                return Err(ReplicationError::connection(format!(
+                    "No usable root certificate in {} ({} ignored)",
+                    path.display(),
+                    ignored
+                )));
+            }
+        }
+        None if sslmode == SslMode::VerifyFull => {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+        }
+        None => {
+            return Err(ReplicationError::connection(
+                "sslmode=verify-ca requires a root certificate in sslrootcert",
+            ));
+        }
+    }
+    Ok(roots)
+}
+
+/// Checks the certificate chain, and the host name only if `check_host`
+#[derive(Debug)]
+struct ChainVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    check_host: bool,
+}
+
+impl ServerCertVerifier for ChainVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if !self.check_host => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accepts any certificate, as libpq does for `prefer` and `require`
+///
+/// Handshake signatures are still checked so the session keys belong to the
+/// certificate presented.
+#[derive(Debug)]
+struct AcceptAnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_ca_requires_root_certificate() {
+        assert!(root_certificates(SslMode::VerifyCa, None).is_err());
+        assert!(
+            !root_certificates(SslMode::VerifyFull, None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
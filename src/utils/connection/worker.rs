@@ -0,0 +1,84 @@
+//! Side connection served by a thread of its own
+//!
+//! Queries on a regular connection block until the server answers. The worker
+//! owns the connection on a dedicated thread and runs queries there, so that
+//! the replication loop awaits them instead of blocking the runtime.
+
+use super::PGConnection;
+use crate::core::errors::{ReplicationError, ReplicationResult};
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+type Job = Box<dyn FnOnce(&mut Option<PGConnection>) + Send>;
+
+/// Regular connection used from async code
+///
+/// The connection is opened on first use and dropped after a failed query,
+/// so that the next one reconnects. The thread ends with the worker.
+pub struct QueryWorker {
+    conninfo: String,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl QueryWorker {
+    /// Starts the worker thread; `name` tells what the connection is for in logs
+    pub fn new(conninfo: String, name: &str) -> ReplicationResult<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let thread_name = format!("walpipe-{}", name);
+        std::thread::Builder::new()
+            .name(thread_name.clone())
+            .spawn(move || {
+                let mut connection = None;
+                for job in queue {
+                    job(&mut connection);
+                }
+                debug!("{} stopped", thread_name);
+            })
+            .map_err(|e| {
+                ReplicationError::connection(format!("Failed to start {} thread: {}", name, e))
+            })?;
+        Ok(Self { conninfo, jobs })
+    }
+
+    /// Runs `query` with the connection on the worker thread
+    pub async fn run<T, F>(&self, query: F) -> ReplicationResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PGConnection) -> ReplicationResult<T> + Send + 'static,
+    {
+        let conninfo = self.conninfo.clone();
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |connection| {
+            if connection.is_none() {
+                match PGConnection::connect(&conninfo) {
+                    Ok(opened) => *connection = Some(opened),
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                        return;
+                    }
+                }
+            }
+            let result = connection.as_ref().map_or_else(
+                || Err(ReplicationError::connection("Side connection is closed")),
+                query,
+            );
+            if result.is_err() {
+                *connection = None;
+            }
+            let _ = reply.send(result);
+        });
+
+        self.jobs
+            .send(job)
+            .map_err(|_| ReplicationError::connection("Side connection thread stopped"))?;
+        result
+            .await
+            .map_err(|_| ReplicationError::connection("Side connection thread stopped"))?
+    }
+
+    /// Closes the connection, reopened by the next query
+    pub fn disconnect(&self) {
+        let _ = self.jobs.send(Box::new(|connection| *connection = None));
+    }
+}
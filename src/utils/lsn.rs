@@ -0,0 +1,31 @@
+//! Log sequence number (LSN) conversion utilities
+//!
+//! PostgreSQL prints LSNs as two hexadecimal halves, e.g. `16/B374D848`.
+
+/// Parse an LSN in PostgreSQL's `X/Y` text form.
+///
+/// Returns `None` when the text is not a valid LSN.
+pub fn parse_lsn(text: &str) -> Option<u64> {
+    let (high, low) = text.trim().split_once('/')?;
+    let high = u32::from_str_radix(high, 16).ok()?;
+    let low = u32::from_str_radix(low, 16).ok()?;
+    Some(((high as u64) << 32) | low as u64)
+}
+
+/// Format an LSN in PostgreSQL's `X/Y` text form.
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsn_round_trip() {
+        assert_eq!(parse_lsn("16/B374D848"), Some(0x16_B374_D848));
+        assert_eq!(format_lsn(0x16_B374_D848), "16/B374D848");
+        assert_eq!(format_lsn(0), "0/0");
+        assert_eq!(parse_lsn("not an lsn"), None);
+    }
+}
@@ -4,9 +4,11 @@
 //! - Binary data manipulation
 //! - Timestamp conversion
 //! - PostgreSQL connection handling
+//! - LSN parsing and formatting
 
 pub mod binary;
 pub mod connection;
+pub mod lsn;
 pub mod timestamp;
 
 // Re-export for convenience
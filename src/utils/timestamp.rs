@@ -3,7 +3,7 @@
 //! Provides functions for converting between different timestamp formats
 //! used by PostgreSQL and standard Unix timestamps.
 
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // PostgreSQL epoch constants
@@ -32,6 +32,14 @@ pub fn system_time_to_postgres_timestamp(time: SystemTime) -> crate::utils::bina
     unix_micros - PG_EPOCH_OFFSET_SECS * 1_000_000
 }
 
+/// Convert a PostgreSQL timestamp (microseconds since 2000-01-01) to a UTC date time.
+///
+/// Returns `None` when the timestamp is outside the range chrono can represent.
+pub fn postgres_timestamp_to_datetime(ts: i64) -> Option<DateTime<Utc>> {
+    ts.checked_add(PG_EPOCH_OFFSET_SECS * 1_000_000)
+        .and_then(DateTime::from_timestamp_micros)
+}
+
 /// Convert a microsecond or nanosecond timestamp to a formatted UTC date string.
 ///
 /// This function converts a PostgreSQL timestamp (in microseconds since epoch)